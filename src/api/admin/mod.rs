@@ -1,20 +1,35 @@
+use std::{convert::Infallible, time::Instant};
+
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
     middleware,
-    response::Html,
-    routing::get,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
     Extension, Json, Router,
 };
 
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::{sync::mpsc, task};
+use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 
 use super::{
     super::AppState,
-    state::{DatabaseActionResult, DatabaseLinkedInsertionResult, DatabaseValueResult},
-    Authenticated, Model, Quota, Role, User,
+    find_conflicting_model, find_invalid_example, get_usage_key, has_unknown_tokenizer, is_admin,
+    model::{self, RequestType},
+    state::{
+        DatabaseActionResult, DatabaseFunctionResult, DatabaseLinkedInsertionResult,
+        DatabaseValueResult,
+    },
+    validate_model_request, Authenticated, Model, Quota, Role, UsageRecord, User,
 };
 
+#[cfg(test)]
+mod tests;
+
 pub fn admin_router() -> Router<AppState> {
     Router::new()
         .route(
@@ -41,6 +56,7 @@ pub fn admin_router() -> Router<AppState> {
             "/models/:uuid",
             get(get_model).put(update_model).delete(delete_model),
         )
+        .route("/models/:uuid/rotate-key", post(rotate_model_key))
         .route(
             "/quotas",
             get(get_quotas).post(add_quota_post).put(add_quota_put),
@@ -49,6 +65,10 @@ pub fn admin_router() -> Router<AppState> {
             "/quotas/:uuid",
             get(get_quota).put(update_quota).delete(delete_quota),
         )
+        .route("/convert", post(preview_conversion))
+        .route("/validate", post(validate_request))
+        .route("/usage/export", get(export_usage))
+        .route("/stats", get(get_stats))
         .route("/help", get(help_page))
         .fallback(StatusCode::NOT_FOUND)
         .layer(middleware::from_fn(super::authenticate_admin))
@@ -62,6 +82,175 @@ async fn help_page(Extension(auth): Extension<Authenticated>) -> Html<&'static s
     }
 }
 
+#[derive(Deserialize)]
+struct ConversionQuery {
+    from: RequestType,
+    to: RequestType,
+}
+
+// Responses of every type have at least one of these fields, while requests don't have any of them.
+const RESPONSE_FIELDS: [&str; 5] = ["choices", "data", "results", "text", "error"];
+
+async fn preview_conversion(
+    Query(query): Query<ConversionQuery>,
+    Json(payload): Json<Map<String, Value>>,
+) -> Result<Json<Value>, StatusCode> {
+    let preview = match RESPONSE_FIELDS
+        .iter()
+        .any(|field| payload.contains_key(*field))
+    {
+        true => model::preview_response_conversion(query.from, query.to, payload),
+        false => model::preview_request_conversion(query.from, query.to, payload),
+    };
+
+    preview.map(Json).ok_or(StatusCode::BAD_REQUEST)
+}
+
+#[derive(Deserialize)]
+struct ValidationRequest {
+    user: Uuid,
+    path: String,
+    body: Map<String, Value>,
+}
+
+// Reports how a request would be handled if it was sent by the given User, without sending it to the model or updating any Quotas.
+async fn validate_request(
+    State(state): State<AppState>,
+    Json(payload): Json<ValidationRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let user = match state.database.get_item::<_, User>("users", &payload.user) {
+        DatabaseValueResult::Success(user) => user,
+        DatabaseValueResult::NotFound => return Err(StatusCode::NOT_FOUND),
+        DatabaseValueResult::BackendError => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let roles: Vec<Uuid> = user.roles.iter().copied().collect();
+    let roles = match state
+        .database
+        .get_items_skip_missing::<_, Role>("roles", &roles)
+    {
+        DatabaseValueResult::Success(roles) => roles,
+        DatabaseValueResult::NotFound => return Err(StatusCode::NOT_FOUND),
+        DatabaseValueResult::BackendError => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let auth = Authenticated {
+        timestamp: Instant::now(),
+        admin: is_admin(&user, &roles, state.role_admin),
+        user,
+        roles,
+    };
+
+    Ok(Json(validate_model_request(
+        &state,
+        &auth,
+        &payload.path,
+        payload.body,
+    )))
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+fn format_csv_row(record: &UsageRecord) -> String {
+    format!(
+        "{},{},{},{:?},{},{},{},{},{},{}\n",
+        record.timestamp,
+        record.user,
+        record.model,
+        record.r#type,
+        record.status,
+        record
+            .input_tokens
+            .map(|tokens| tokens.to_string())
+            .unwrap_or_default(),
+        record
+            .output_tokens
+            .map(|tokens| tokens.to_string())
+            .unwrap_or_default(),
+        record.total_tokens,
+        record.charged_tokens,
+        record.cost.map(|cost| cost.to_string()).unwrap_or_default(),
+    )
+}
+
+async fn export_usage(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    let start = get_usage_key(query.since.unwrap_or_default().saturating_mul(1000));
+    let end = match query.until {
+        Some(until) => get_usage_key(until.saturating_mul(1000)),
+        None => Uuid::max(),
+    };
+
+    let records = match state
+        .database
+        .iter_table_range::<_, UsageRecord>("usage", &start, &end)
+    {
+        DatabaseValueResult::Success(records) => records,
+        DatabaseValueResult::NotFound => return Err(StatusCode::NOT_FOUND),
+        DatabaseValueResult::BackendError => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let (sender, receiver) = mpsc::channel::<Result<String, Infallible>>(64);
+    let format = query.format;
+
+    // Records are read and sent one at a time, so that large exports don't have to be buffered in memory.
+    task::spawn_blocking(move || {
+        let header = match format {
+            ExportFormat::Csv => "timestamp,user,model,type,status,input_tokens,output_tokens,total_tokens,charged_tokens,cost\n",
+            ExportFormat::Json => "[",
+        };
+        if sender.blocking_send(Ok(header.to_string())).is_err() {
+            return;
+        }
+
+        let mut separator = "";
+        for record in records {
+            let row = match format {
+                ExportFormat::Csv => format_csv_row(&record),
+                ExportFormat::Json => match serde_json::to_string(&record) {
+                    Ok(json) => format!("{}{}", std::mem::replace(&mut separator, ","), json),
+                    Err(_) => continue,
+                },
+            };
+
+            if sender.blocking_send(Ok(row)).is_err() {
+                return;
+            }
+        }
+
+        if let ExportFormat::Json = format {
+            let _ = sender.blocking_send(Ok("]".to_string()));
+        }
+    });
+
+    let content_type = match format {
+        ExportFormat::Csv => "text/csv",
+        ExportFormat::Json => "application/json",
+    };
+
+    Ok((
+        [(CONTENT_TYPE, content_type)],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
 impl From<DatabaseActionResult> for StatusCode {
     fn from(value: DatabaseActionResult) -> Self {
         match value {
@@ -92,6 +281,14 @@ impl<T> From<DatabaseValueResult<T>> for Result<Json<T>, StatusCode> {
     }
 }
 
+async fn get_stats(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({
+        "models": state.health.get_statuses(),
+        "retry_budgets": state.retry_budget.get_remaining(),
+        "database_size": state.database.get_size_on_disk(),
+    }))
+}
+
 async fn get_users(State(state): State<AppState>) -> Result<Json<Vec<User>>, StatusCode> {
     state.database.get_table("users").into()
 }
@@ -257,8 +454,28 @@ async fn delete_role(State(state): State<AppState>, Path(uuid): Path<Uuid>) -> S
     state.database.remove_item("roles", &uuid).into()
 }
 
-async fn get_models(State(state): State<AppState>) -> Result<Json<Vec<Model>>, StatusCode> {
-    state.database.get_table("models").into()
+#[derive(Deserialize)]
+struct ModelsQuery {
+    deprecated: Option<bool>,
+}
+
+async fn get_models(
+    State(state): State<AppState>,
+    Query(query): Query<ModelsQuery>,
+) -> Result<Json<Vec<Model>>, StatusCode> {
+    let result: Result<Json<Vec<Model>>, StatusCode> = state.database.get_table("models").into();
+
+    match query.deprecated {
+        Some(deprecated) => result.map(|Json(models)| {
+            Json(
+                models
+                    .into_iter()
+                    .filter(|model| model.deprecated.is_some() == deprecated)
+                    .collect(),
+            )
+        }),
+        None => result,
+    }
 }
 
 async fn get_model(
@@ -272,50 +489,119 @@ async fn get_model(
     state.database.get_item("models", &uuid).into()
 }
 
+#[allow(clippy::result_large_err)]
+fn validate_model(state: &AppState, model: &Model) -> Result<(), Response> {
+    if state.fallback_tokenizer.is_none() && has_unknown_tokenizer(model) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Unknown tokenizer",
+                "tokenizer": model.tokenizer,
+            })),
+        )
+            .into_response());
+    }
+
+    if model.api.has_invalid_pacing() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "max_requests_per_second must be a positive number",
+            })),
+        )
+            .into_response());
+    }
+
+    if let Some(index) = find_invalid_example(model) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Example is not a valid request body for any of the model's types",
+                "example": index,
+            })),
+        )
+            .into_response());
+    }
+
+    match state.database.get_table::<Model>("models") {
+        DatabaseValueResult::Success(models) => match find_conflicting_model(&models, model) {
+            Some((conflict, types)) => Err((
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "A model with the same name and region already serves these types",
+                    "model": conflict.uuid,
+                    "name": conflict.name,
+                    "types": types,
+                })),
+            )
+                .into_response()),
+            None => Ok(()),
+        },
+        DatabaseValueResult::NotFound => Ok(()),
+        DatabaseValueResult::BackendError => Err(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    }
+}
+
 async fn add_model_post(
     State(state): State<AppState>,
     Json(mut payload): Json<Model>,
-) -> Result<Json<Uuid>, StatusCode> {
+) -> Result<Json<Uuid>, Response> {
     if payload.uuid != Uuid::default() {
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(StatusCode::BAD_REQUEST.into_response());
     }
     payload.uuid = Uuid::new_v4();
 
+    validate_model(&state, &payload)?;
+
     match state
         .database
         .insert_item("models", &payload.uuid, &payload)
     {
         DatabaseActionResult::Success => Ok(Json(payload.uuid)),
-        DatabaseActionResult::NotFound => Err(StatusCode::NOT_FOUND),
-        DatabaseActionResult::BackendError => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        DatabaseActionResult::NotFound => Err(StatusCode::NOT_FOUND.into_response()),
+        DatabaseActionResult::BackendError => {
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
     }
 }
 
-async fn add_model_put(State(state): State<AppState>, Json(payload): Json<Model>) -> StatusCode {
+async fn add_model_put(State(state): State<AppState>, Json(payload): Json<Model>) -> Response {
     if payload.uuid == Uuid::default() {
-        return StatusCode::BAD_REQUEST;
+        return StatusCode::BAD_REQUEST.into_response();
     }
 
-    state
-        .database
-        .insert_item("models", &payload.uuid, &payload)
-        .into()
+    if let Err(response) = validate_model(&state, &payload) {
+        return response;
+    }
+
+    StatusCode::from(
+        state
+            .database
+            .insert_item("models", &payload.uuid, &payload),
+    )
+    .into_response()
 }
 
 async fn update_model(
     State(state): State<AppState>,
     Path(uuid): Path<Uuid>,
     Json(mut payload): Json<Model>,
-) -> StatusCode {
+) -> Response {
     if (payload.uuid != Uuid::default() && payload.uuid != uuid) || uuid == Uuid::default() {
-        return StatusCode::BAD_REQUEST;
+        return StatusCode::BAD_REQUEST.into_response();
     }
     payload.uuid = uuid;
 
-    state
-        .database
-        .insert_item("models", &payload.uuid, &payload)
-        .into()
+    if let Err(response) = validate_model(&state, &payload) {
+        return response;
+    }
+
+    StatusCode::from(
+        state
+            .database
+            .insert_item("models", &payload.uuid, &payload),
+    )
+    .into_response()
 }
 
 async fn delete_model(State(state): State<AppState>, Path(uuid): Path<Uuid>) -> StatusCode {
@@ -326,6 +612,64 @@ async fn delete_model(State(state): State<AppState>, Path(uuid): Path<Uuid>) ->
     state.database.remove_item("models", &uuid).into()
 }
 
+#[derive(Deserialize)]
+struct KeyRotation {
+    api_key: String,
+    #[serde(default)]
+    replaces: Option<String>,
+}
+
+async fn rotate_model_key(
+    State(state): State<AppState>,
+    Path(uuid): Path<Uuid>,
+    Json(payload): Json<KeyRotation>,
+) -> Response {
+    if uuid == Uuid::default() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let mut backend = match state.database.get_item::<_, Model>("models", &uuid) {
+        DatabaseValueResult::Success(model) => model.api,
+        DatabaseValueResult::NotFound => return StatusCode::NOT_FOUND.into_response(),
+        DatabaseValueResult::BackendError => {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    };
+
+    if backend
+        .clone()
+        .rotate_api_key(payload.api_key.clone(), payload.replaces.as_deref())
+        .is_err()
+    {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    // Only the new key is probed, as the backend's other keys may be chosen first.
+    if backend.set_api_key(payload.api_key.clone()).is_err() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    if let Err(error) = backend.probe(&state.http).await {
+        tracing::warn!("Unable to validate new API key for model {}", uuid);
+
+        return (StatusCode::BAD_GATEWAY, Json(error)).into_response();
+    }
+
+    match state
+        .database
+        .modify_items_skip_missing("models", &[uuid], |model: &mut Model| {
+            model
+                .api
+                .rotate_api_key(payload.api_key.clone(), payload.replaces.as_deref())
+        }) {
+        DatabaseFunctionResult::Success(models) if !models.is_empty() => StatusCode::OK,
+        DatabaseFunctionResult::Success(_) => StatusCode::NOT_FOUND,
+        DatabaseFunctionResult::FunctionError(_) => StatusCode::BAD_REQUEST,
+        DatabaseFunctionResult::BackendError => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+    .into_response()
+}
+
 async fn get_quotas(State(state): State<AppState>) -> Result<Json<Vec<Quota>>, StatusCode> {
     state.database.get_table("quotas").into()
 }
@@ -345,7 +689,7 @@ async fn add_quota_post(
     State(state): State<AppState>,
     Json(mut payload): Json<Quota>,
 ) -> Result<Json<Uuid>, StatusCode> {
-    if payload.uuid != Uuid::default() {
+    if payload.uuid != Uuid::default() || payload.has_invalid_concurrency() {
         return Err(StatusCode::BAD_REQUEST);
     }
     payload.uuid = Uuid::new_v4();
@@ -361,7 +705,7 @@ async fn add_quota_post(
 }
 
 async fn add_quota_put(State(state): State<AppState>, Json(payload): Json<Quota>) -> StatusCode {
-    if payload.uuid == Uuid::default() {
+    if payload.uuid == Uuid::default() || payload.has_invalid_concurrency() {
         return StatusCode::BAD_REQUEST;
     }
 
@@ -376,7 +720,10 @@ async fn update_quota(
     Path(uuid): Path<Uuid>,
     Json(mut payload): Json<Quota>,
 ) -> StatusCode {
-    if (payload.uuid != Uuid::default() && payload.uuid != uuid) || uuid == Uuid::default() {
+    if (payload.uuid != Uuid::default() && payload.uuid != uuid)
+        || uuid == Uuid::default()
+        || payload.has_invalid_concurrency()
+    {
         return StatusCode::BAD_REQUEST;
     }
     payload.uuid = uuid;
@@ -392,5 +739,7 @@ async fn delete_quota(State(state): State<AppState>, Path(uuid): Path<Uuid>) ->
         return StatusCode::BAD_REQUEST;
     }
 
+    state.quota_concurrency.remove(&uuid);
+
     state.database.remove_item("quotas", &uuid).into()
 }
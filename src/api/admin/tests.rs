@@ -0,0 +1,49 @@
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{api::Pricing, model::TokenUsage};
+
+use super::{format_csv_row, RequestType, UsageRecord};
+
+#[test]
+fn usage_record_cost() {
+    let pricing: Pricing = serde_json::from_value(json!({ "input": 2.0, "output": 10.0 })).unwrap();
+    let usage = TokenUsage {
+        total: 300_000,
+        input: Some(100_000),
+        output: Some(200_000),
+    };
+
+    let record = UsageRecord {
+        timestamp: 1,
+        user: Uuid::nil(),
+        model: Uuid::nil(),
+        r#type: RequestType::TextChat,
+        status: 200,
+        input_tokens: usage.input,
+        output_tokens: usage.output,
+        total_tokens: usage.total,
+        charged_tokens: usage.total,
+        cost: Some(pricing.get_usage_cost(&usage)),
+    };
+    assert_eq!(record.cost, Some(2.2));
+
+    assert!(format_csv_row(&record).ends_with(",300000,300000,2.2\n"));
+    assert_eq!(serde_json::to_value(&record).unwrap()["cost"], json!(2.2));
+
+    // Models without pricing don't have a cost.
+    let record = UsageRecord {
+        cost: None,
+        ..record
+    };
+    assert!(format_csv_row(&record).ends_with(",300000,300000,\n"));
+    assert_eq!(serde_json::to_value(&record).unwrap()["cost"], json!(null));
+
+    // Tokens which weren't split into input and output tokens are priced as output tokens.
+    let usage = TokenUsage {
+        total: 300_000,
+        input: Some(100_000),
+        output: None,
+    };
+    assert_eq!(pricing.get_usage_cost(&usage), 2.2);
+}
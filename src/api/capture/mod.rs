@@ -0,0 +1,175 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests;
+
+// The number of requests kept for each user, regardless of the retention period.
+const MAX_CAPTURES_PER_USER: usize = 20;
+
+// Longer strings (such as long prompts or completions) are truncated to this many characters.
+const MAX_CAPTURED_STRING_LENGTH: usize = 8192;
+
+// Object fields whose values are always redacted, as they're likely to contain credentials.
+const REDACTED_FIELDS: [&str; 5] = ["api_key", "authorization", "password", "secret", "token"];
+
+const REDACTED_VALUE: &str = "[redacted]";
+
+#[derive(Serialize, Debug, Clone)]
+pub(super) struct CapturedRequest {
+    #[serde(skip)]
+    captured_at: Instant,
+    pub(super) timestamp: u64,
+    pub(super) request_id: Option<String>,
+    pub(super) model: Option<String>,
+    pub(super) status: u16,
+    pub(super) request: Value,
+    pub(super) response: Value,
+}
+
+impl CapturedRequest {
+    pub(super) fn new(
+        request_id: Option<String>,
+        model: Option<String>,
+        status: u16,
+        request: Value,
+        response: Value,
+    ) -> Self {
+        CapturedRequest {
+            captured_at: Instant::now(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            request_id,
+            model,
+            status,
+            request: redact_value(request),
+            response: redact_value(response),
+        }
+    }
+}
+
+// Keeps the recent requests of users who opted in to capturing them, so that they can debug their own integrations. Captures are only kept in memory, and are removed once they're older than the retention period.
+pub struct RequestCapture {
+    retention: Duration,
+    enabled: Mutex<HashSet<Uuid>>,
+    captures: Mutex<HashMap<Uuid, VecDeque<CapturedRequest>>>,
+}
+
+impl RequestCapture {
+    pub fn new(retention: Duration) -> Self {
+        RequestCapture {
+            retention,
+            enabled: Mutex::new(HashSet::new()),
+            captures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn get_retention(&self) -> Duration {
+        self.retention
+    }
+
+    fn remove_expired(&self, captures: &mut HashMap<Uuid, VecDeque<CapturedRequest>>) {
+        let now = Instant::now();
+
+        captures.retain(|_, captures| {
+            while captures
+                .front()
+                .is_some_and(|capture| now.duration_since(capture.captured_at) >= self.retention)
+            {
+                captures.pop_front();
+            }
+
+            !captures.is_empty()
+        });
+    }
+
+    // Disabling capture also removes the user's existing captures.
+    pub(super) fn set_enabled(&self, user: Uuid, enabled: bool) {
+        if let Ok(mut users) = self.enabled.lock() {
+            match enabled {
+                true => users.insert(user),
+                false => users.remove(&user),
+            };
+        }
+
+        if !enabled {
+            if let Ok(mut captures) = self.captures.lock() {
+                captures.remove(&user);
+            }
+        }
+    }
+
+    pub(super) fn is_enabled(&self, user: Uuid) -> bool {
+        self.enabled.lock().is_ok_and(|users| users.contains(&user))
+    }
+
+    pub(super) fn record(&self, user: Uuid, capture: CapturedRequest) {
+        if !self.is_enabled(user) {
+            return;
+        }
+
+        if let Ok(mut captures) = self.captures.lock() {
+            self.remove_expired(&mut captures);
+
+            let user_captures = captures.entry(user).or_default();
+            user_captures.push_back(capture);
+            while user_captures.len() > MAX_CAPTURES_PER_USER {
+                user_captures.pop_front();
+            }
+        }
+    }
+
+    // Returns the user's captured requests, newest first.
+    pub(super) fn get_captures(&self, user: Uuid) -> Vec<CapturedRequest> {
+        match self.captures.lock() {
+            Ok(mut captures) => {
+                self.remove_expired(&mut captures);
+
+                captures
+                    .get(&user)
+                    .map(|captures| captures.iter().rev().cloned().collect())
+                    .unwrap_or_default()
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+// Inline files (such as base64-encoded images and audio) and likely credentials are redacted, and long strings are truncated.
+fn redact_value(value: Value) -> Value {
+    match value {
+        Value::String(string) if string.starts_with("data:") => {
+            Value::String(REDACTED_VALUE.to_string())
+        }
+        Value::String(string) if string.chars().count() > MAX_CAPTURED_STRING_LENGTH => {
+            let mut truncated: String = string.chars().take(MAX_CAPTURED_STRING_LENGTH).collect();
+            truncated.push_str("...[truncated]");
+
+            Value::String(truncated)
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(redact_value).collect()),
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match REDACTED_FIELDS.contains(&key.to_ascii_lowercase().as_str()) {
+                        true => Value::String(REDACTED_VALUE.to_string()),
+                        false => redact_value(value),
+                    };
+
+                    (key, value)
+                })
+                .collect::<Map<String, Value>>(),
+        ),
+        value => value,
+    }
+}
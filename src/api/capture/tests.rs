@@ -0,0 +1,83 @@
+use std::{thread, time::Duration};
+
+use serde_json::json;
+use uuid::Uuid;
+
+use super::{CapturedRequest, RequestCapture, MAX_CAPTURES_PER_USER};
+
+fn capture(prompt: &str) -> CapturedRequest {
+    CapturedRequest::new(
+        None,
+        Some("gpt-4".to_string()),
+        200,
+        json!({ "model": "gpt-4", "prompt": prompt }),
+        json!({ "choices": [{ "index": 0, "text": "Hi!" }] }),
+    )
+}
+
+#[test]
+fn capture_and_retrieval() {
+    let requests = RequestCapture::new(Duration::from_millis(200));
+    let user = Uuid::new_v4();
+    let other_user = Uuid::new_v4();
+
+    // Requests are only captured for users who opted in.
+    requests.record(user, capture("a"));
+    assert!(requests.get_captures(user).is_empty());
+
+    requests.set_enabled(user, true);
+    requests.record(user, capture("b"));
+    requests.record(user, capture("c"));
+    requests.record(other_user, capture("d"));
+
+    let captures = requests.get_captures(user);
+    assert_eq!(captures.len(), 2);
+    assert_eq!(captures[0].request["prompt"], json!("c"));
+    assert_eq!(captures[1].response["choices"][0]["text"], json!("Hi!"));
+    assert!(requests.get_captures(other_user).is_empty());
+
+    for _ in 0..MAX_CAPTURES_PER_USER {
+        requests.record(user, capture("e"));
+    }
+    assert_eq!(requests.get_captures(user).len(), MAX_CAPTURES_PER_USER);
+
+    // Captures expire after the retention period, and are removed when capture is disabled.
+    thread::sleep(Duration::from_millis(250));
+    assert!(requests.get_captures(user).is_empty());
+
+    requests.record(user, capture("f"));
+    requests.set_enabled(user, false);
+    assert!(!requests.is_enabled(user));
+    assert!(requests.get_captures(user).is_empty());
+}
+
+#[test]
+fn capture_redaction() {
+    let captured = CapturedRequest::new(
+        None,
+        None,
+        200,
+        json!({
+            "messages": [{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "x".repeat(10_000) },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+                ]
+            }],
+            "extra_body": { "API_Key": "sk-secret" }
+        }),
+        json!({}),
+    );
+
+    let content = &captured.request["messages"][0]["content"];
+    assert!(content[0]["text"]
+        .as_str()
+        .unwrap()
+        .ends_with("...[truncated]"));
+    assert_eq!(content[1]["image_url"]["url"], json!("[redacted]"));
+    assert_eq!(
+        captured.request["extra_body"]["API_Key"],
+        json!("[redacted]")
+    );
+}
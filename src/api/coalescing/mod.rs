@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::OnceCell;
+
+#[cfg(test)]
+mod tests;
+
+// The start time of each request and the cell which its response is shared through, keyed by the request.
+type PendingRequests<T> = HashMap<Vec<u8>, (Instant, Arc<OnceCell<T>>)>;
+
+// Deduplicates identical requests which are in flight at the same time, such as when a client retries aggressively. Requests only share a response with a request which is still running and started within the window, so finished responses are never reused.
+pub struct RequestCoalescer<T> {
+    window: Duration,
+    requests: Mutex<PendingRequests<T>>,
+}
+
+impl<T: Clone> RequestCoalescer<T> {
+    pub fn new(window: Duration) -> Self {
+        RequestCoalescer {
+            window,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_cell(&self, key: Vec<u8>) -> Arc<OnceCell<T>> {
+        match self.requests.lock() {
+            Ok(mut requests) => {
+                let now = Instant::now();
+                requests.retain(|_, (started_at, cell)| {
+                    !cell.initialized() && now.duration_since(*started_at) < self.window
+                });
+
+                requests
+                    .entry(key)
+                    .or_insert_with(|| (now, Arc::new(OnceCell::new())))
+                    .1
+                    .clone()
+            }
+            Err(_) => Arc::new(OnceCell::new()),
+        }
+    }
+
+    fn remove_cell(&self, key: &[u8], cell: &Arc<OnceCell<T>>) {
+        if let Ok(mut requests) = self.requests.lock() {
+            if requests
+                .get(key)
+                .is_some_and(|(_, current)| Arc::ptr_eq(current, cell))
+            {
+                requests.remove(key);
+            }
+        }
+    }
+
+    // Only the first request's future is run; the other requests wait for it and share its output. If the first request is cancelled, one of the waiting requests will run its own future instead. Returns true along with the output if it came from another request.
+    pub(super) async fn run<F>(&self, key: Vec<u8>, future: F) -> (T, bool)
+    where
+        F: Future<Output = T>,
+    {
+        let cell = self.get_cell(key.clone());
+        let ran = AtomicBool::new(false);
+
+        let output = cell
+            .get_or_init(|| async {
+                ran.store(true, Ordering::Relaxed);
+                future.await
+            })
+            .await
+            .clone();
+        self.remove_cell(&key, &cell);
+
+        (output, !ran.load(Ordering::Relaxed))
+    }
+}
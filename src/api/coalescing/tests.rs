@@ -0,0 +1,88 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{task::JoinSet, time};
+
+use super::RequestCoalescer;
+
+#[tokio::test]
+async fn concurrent_identical_requests() {
+    let coalescer = Arc::new(RequestCoalescer::new(Duration::from_millis(500)));
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..5 {
+        let coalescer = coalescer.clone();
+        let calls = calls.clone();
+
+        tasks.spawn(async move {
+            coalescer
+                .run(b"request".to_vec(), async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    time::sleep(Duration::from_millis(50)).await;
+
+                    "response".to_string()
+                })
+                .await
+        });
+    }
+
+    let mut shared = 0;
+    while let Some(result) = tasks.join_next().await {
+        let (response, coalesced) = result.unwrap();
+        assert_eq!(response, "response");
+        shared += coalesced as usize;
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(shared, 4);
+
+    let response = coalescer
+        .run(b"other request".to_vec(), async {
+            calls.fetch_add(1, Ordering::SeqCst);
+
+            "other response".to_string()
+        })
+        .await;
+    assert_eq!(response, ("other response".to_string(), false));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn finished_requests_not_reused() {
+    let coalescer = RequestCoalescer::new(Duration::from_millis(500));
+    let calls = AtomicUsize::new(0);
+
+    // Requests within the window which don't overlap are each sent to the backend.
+    for _ in 0..2 {
+        let (_, coalesced) = coalescer
+            .run(b"request".to_vec(), async {
+                calls.fetch_add(1, Ordering::SeqCst);
+            })
+            .await;
+        assert!(!coalesced);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(coalescer.requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn requests_outside_window() {
+    let coalescer = RequestCoalescer::new(Duration::from_millis(10));
+    let calls = AtomicUsize::new(0);
+
+    for _ in 0..2 {
+        coalescer
+            .run(b"request".to_vec(), async {
+                calls.fetch_add(1, Ordering::SeqCst);
+            })
+            .await;
+
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use reqwest::{Client, Url};
+
+use super::{state::DatabaseValueResult, User};
+
+#[cfg(test)]
+mod tests;
+
+// Invalid keys are only cached briefly, so that keys which become valid can be used soon afterwards.
+const INVALID_KEY_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// Validates API keys using an external HTTP service, which responds to authorized keys with a User object.
+pub struct ExternalAuth {
+    endpoint: Url,
+    cache_ttl: Duration,
+    // Invalid keys are cached without a User.
+    cache: Mutex<HashMap<String, (Instant, Option<User>)>>,
+}
+
+impl ExternalAuth {
+    pub fn new(endpoint: Url, cache_ttl: Duration) -> Self {
+        ExternalAuth {
+            endpoint,
+            cache_ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_cached_user(&self, api_key: &str) -> Option<Option<User>> {
+        let cache = self.cache.lock().ok()?;
+
+        cache
+            .get(api_key)
+            .filter(|(expires_at, _)| *expires_at > Instant::now())
+            .map(|(_, user)| user.clone())
+    }
+
+    fn cache_user(&self, api_key: &str, user: Option<&User>) {
+        if let Ok(mut cache) = self.cache.lock() {
+            let now = Instant::now();
+            let ttl = match user {
+                Some(_) => self.cache_ttl,
+                None => INVALID_KEY_CACHE_TTL.min(self.cache_ttl),
+            };
+
+            cache.retain(|_, (expires_at, _)| *expires_at > now);
+            cache.insert(api_key.to_string(), (now + ttl, user.cloned()));
+        }
+    }
+
+    #[tracing::instrument(name = "external_authenticate", level = "debug", skip_all)]
+    pub(super) async fn get_user(
+        &self,
+        http_client: &Client,
+        api_key: &str,
+    ) -> DatabaseValueResult<User> {
+        match self.get_cached_user(api_key) {
+            Some(Some(user)) => return DatabaseValueResult::Success(user),
+            Some(None) => return DatabaseValueResult::NotFound,
+            None => {}
+        }
+
+        let response = match http_client
+            .get(self.endpoint.clone())
+            .bearer_auth(api_key)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(error) => {
+                tracing::error!(
+                    "Unable to reach external authentication service: {:?}",
+                    error
+                );
+                return DatabaseValueResult::BackendError;
+            }
+        };
+
+        match StatusCode::from_u16(response.status().as_u16()) {
+            Ok(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND) => {
+                self.cache_user(api_key, None);
+
+                DatabaseValueResult::NotFound
+            }
+            Ok(status) if status.is_success() => match response.json::<User>().await {
+                Ok(mut user) => {
+                    // Administrative access can't be granted by the external service, including through roles.
+                    user.api_keys.clear();
+                    user.admin = false;
+                    user.admin_override = Some(false);
+                    self.cache_user(api_key, Some(&user));
+
+                    DatabaseValueResult::Success(user)
+                }
+                Err(error) => {
+                    tracing::error!(
+                        "Unable to parse external authentication response: {:?}",
+                        error
+                    );
+                    DatabaseValueResult::BackendError
+                }
+            },
+            _ => {
+                tracing::error!(
+                    "External authentication service returned {} error",
+                    response.status()
+                );
+                DatabaseValueResult::BackendError
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use axum::{http::HeaderMap, http::StatusCode, routing::get, Json, Router};
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+use super::{super::state::DatabaseValueResult, ExternalAuth};
+
+async fn mock_auth_service(headers: HeaderMap) -> Result<Json<Value>, StatusCode> {
+    match headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some("Bearer valid-key") => Ok(Json(json!({
+            "label": "External User",
+            "uuid": "00000000-0000-0000-0000-000000000001",
+            "models": ["00000000-0000-0000-0000-000000000002"],
+            "quotas": ["00000000-0000-0000-0000-000000000003"],
+            "api_keys": ["valid-key"],
+            "admin": true
+        }))),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[tokio::test]
+async fn external_authentication() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, Router::new().route("/", get(mock_auth_service)))
+            .await
+            .unwrap()
+    });
+
+    let http_client = Client::new();
+    let external_auth = ExternalAuth::new(
+        Url::parse(&format!("http://{}/", address)).unwrap(),
+        Duration::from_secs(60),
+    );
+
+    match external_auth.get_user(&http_client, "valid-key").await {
+        DatabaseValueResult::Success(user) => {
+            assert_eq!(user.label, "External User");
+            assert_eq!(user.models.len(), 1);
+            assert_eq!(user.quotas.len(), 1);
+            assert!(user.api_keys.is_empty());
+            assert!(!user.admin);
+            assert_eq!(user.admin_override, Some(false));
+        }
+        _ => panic!("expected valid key to be accepted"),
+    }
+
+    assert!(matches!(
+        external_auth.get_user(&http_client, "invalid-key").await,
+        DatabaseValueResult::NotFound
+    ));
+
+    server.abort();
+    let _ = server.await;
+
+    // Results are cached, so they remain valid while the service is unreachable. Invalid keys are only cached briefly.
+    let http_client = Client::new();
+    assert!(matches!(
+        external_auth.get_user(&http_client, "valid-key").await,
+        DatabaseValueResult::Success(_)
+    ));
+    assert!(matches!(
+        external_auth.get_user(&http_client, "invalid-key").await,
+        DatabaseValueResult::NotFound
+    ));
+    assert!(matches!(
+        external_auth.get_user(&http_client, "unknown-key").await,
+        DatabaseValueResult::BackendError
+    ));
+
+    let external_auth = ExternalAuth::new(
+        Url::parse(&format!("http://{}/", address)).unwrap(),
+        Duration::ZERO,
+    );
+    external_auth.cache_user("invalid-key", None);
+    assert!(matches!(
+        external_auth.get_user(&http_client, "invalid-key").await,
+        DatabaseValueResult::BackendError
+    ));
+}
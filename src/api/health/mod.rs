@@ -0,0 +1,119 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub(super) struct HealthStatus {
+    requests: usize,
+    errors: usize,
+    error_rate: f64,
+    healthy: bool,
+}
+
+// Tracks the outcome of each model's recent requests, so that models returning a high rate of errors can be avoided until the errors fall out of the window.
+pub struct ModelHealth {
+    window: Duration,
+    threshold: Option<f64>,
+    min_requests: usize,
+    outcomes: Mutex<HashMap<Uuid, VecDeque<(Instant, bool)>>>,
+}
+
+impl ModelHealth {
+    pub fn new(window: Duration, threshold: Option<f64>, min_requests: usize) -> Self {
+        ModelHealth {
+            window,
+            threshold,
+            min_requests: min_requests.max(1),
+            outcomes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_status(&self, outcomes: &VecDeque<(Instant, bool)>) -> HealthStatus {
+        let requests = outcomes.len();
+        let errors = outcomes.iter().filter(|(_, success)| !success).count();
+        let error_rate = match requests {
+            0 => 0.0,
+            requests => errors as f64 / requests as f64,
+        };
+
+        HealthStatus {
+            requests,
+            errors,
+            error_rate,
+            healthy: requests < self.min_requests
+                || self
+                    .threshold
+                    .is_none_or(|threshold| error_rate <= threshold),
+        }
+    }
+
+    fn remove_expired(&self, outcomes: &mut HashMap<Uuid, VecDeque<(Instant, bool)>>) {
+        let now = Instant::now();
+
+        outcomes.retain(|_, outcomes| {
+            while outcomes
+                .front()
+                .is_some_and(|(timestamp, _)| now.duration_since(*timestamp) >= self.window)
+            {
+                outcomes.pop_front();
+            }
+
+            !outcomes.is_empty()
+        });
+    }
+
+    pub(super) fn record(&self, model: Uuid, success: bool) {
+        if let Ok(mut outcomes) = self.outcomes.lock() {
+            self.remove_expired(&mut outcomes);
+
+            let model_outcomes = outcomes.entry(model).or_default();
+            let was_healthy = self.get_status(model_outcomes).healthy;
+            model_outcomes.push_back((Instant::now(), success));
+            let status = self.get_status(model_outcomes);
+
+            if was_healthy && !status.healthy {
+                tracing::warn!(
+                    "Model {} is unhealthy, as {} of its last {} requests failed",
+                    model,
+                    status.errors,
+                    status.requests
+                );
+            }
+        }
+    }
+
+    pub(super) fn is_healthy(&self, model: Uuid) -> bool {
+        match self.outcomes.lock() {
+            Ok(mut outcomes) => {
+                self.remove_expired(&mut outcomes);
+
+                outcomes
+                    .get(&model)
+                    .is_none_or(|outcomes| self.get_status(outcomes).healthy)
+            }
+            Err(_) => true,
+        }
+    }
+
+    pub(super) fn get_statuses(&self) -> HashMap<Uuid, HealthStatus> {
+        match self.outcomes.lock() {
+            Ok(mut outcomes) => {
+                self.remove_expired(&mut outcomes);
+
+                outcomes
+                    .iter()
+                    .map(|(model, outcomes)| (*model, self.get_status(outcomes)))
+                    .collect()
+            }
+            Err(_) => HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::{thread, time::Duration};
+
+use uuid::Uuid;
+
+use super::ModelHealth;
+
+#[test]
+fn error_rate_recovery() {
+    let health = ModelHealth::new(Duration::from_millis(200), Some(0.5), 4);
+    let model = Uuid::new_v4();
+
+    // Models aren't marked unhealthy until they've handled enough requests.
+    for _ in 0..3 {
+        health.record(model, false);
+    }
+    assert!(health.is_healthy(model));
+
+    health.record(model, true);
+    assert!(!health.is_healthy(model));
+
+    let status = health.get_statuses()[&model];
+    assert_eq!(status.requests, 4);
+    assert_eq!(status.errors, 3);
+    assert_eq!(status.error_rate, 0.75);
+    assert!(!status.healthy);
+
+    health.record(model, true);
+    health.record(model, true);
+    assert!(health.is_healthy(model));
+    health.record(model, false);
+    assert!(!health.is_healthy(model));
+
+    // Models recover once their errors fall out of the window.
+    thread::sleep(Duration::from_millis(250));
+    assert!(health.is_healthy(model));
+    assert!(health.get_statuses().is_empty());
+
+    let untracked = ModelHealth::new(Duration::from_millis(200), None, 1);
+    untracked.record(model, false);
+    assert!(untracked.is_healthy(model));
+    assert_eq!(untracked.get_statuses()[&model].errors, 1);
+}
@@ -1,51 +1,89 @@
 use std::{
     clone::Clone,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    time::{Duration, Instant},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
-    body::Body,
-    extract::{DefaultBodyLimit, Extension, Request, State},
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Extension, Path, Request, State},
     http::StatusCode,
     middleware::{self, Next},
-    response::Response,
-    Router,
+    response::{IntoResponse, Response},
+    routing::{any, get, post, put},
+    Json, Router,
 };
 
-use fast32::base64::RFC4648;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use http::{
-    header::{AUTHORIZATION, USER_AGENT, WWW_AUTHENTICATE},
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT, WWW_AUTHENTICATE},
     Version,
 };
 use http::{
     header::{CONTENT_LENGTH, CONTENT_TYPE},
     uri::Scheme,
 };
+use http_body::{Frame, SizeHint};
 use serde::{Deserialize, Serialize};
-use tokio::time;
+use serde_json::{json, Map, Value};
+use tokio::{
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time,
+};
 use tower::ServiceBuilder;
 use tower_http::{classify::ServerErrorsFailureClass, trace::TraceLayer};
 use tracing::{field::Empty, Instrument, Span};
 use uuid::Uuid;
 
 mod admin;
+mod capture;
+mod coalescing;
+mod external_auth;
+mod health;
 mod state;
 
+#[cfg(test)]
+mod tests;
+
+pub use capture::RequestCapture;
+pub use coalescing::RequestCoalescer;
+pub use external_auth::ExternalAuth;
+pub use health::ModelHealth;
 pub use state::Database;
 use state::{RelatedToItem, RelatedToItemSet};
 
-use crate::limiter::{self, LimiterResult};
+use crate::limiter::{self, LimiterClock, LimiterResult};
 
-use self::state::{DatabaseFunctionResult, DatabaseValueResult};
+use self::{
+    capture::CapturedRequest,
+    state::{DatabaseActionResult, DatabaseFunctionResult, DatabaseValueResult},
+};
 
 use super::{
     limiter::Limit,
-    model::{ModelBackend, ModelError, ModelRequest, ModelResponse, RequestType},
+    model::{
+        self, FingerprintStrategy, ImageSizes, JsonRepair, JsonSchemaSupport, ModelBackend,
+        ModelError, ModelRequest, ModelResponse, ModelTimings, ModelWarnings, ModerationAction,
+        OutputModeration, PenaltyRange, ProxyMetadata, RequestType, SizeCharging,
+        SystemFingerprint, TokenInputSupport, TokenUsage, Tokenizer,
+    },
     AppState,
 };
 
+const MAX_BATCH_SIZE: usize = 128;
+const MAX_BATCH_CONCURRENCY: usize = 8;
+const MAX_BATCH_TOKENS: u64 = 1_048_576;
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+const CAPABILITY_MODEL_PREFIX: &str = "auto:";
+const DEFAULT_MAX_SPLIT_N: u64 = 16;
+const OUTPUT_MODERATION_FAILED_WARNING: &str = "The model's output couldn't be checked by its moderation model, so it was returned without moderation annotations.";
+
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
 struct User {
@@ -53,12 +91,19 @@ struct User {
     uuid: Uuid,
 
     admin: bool,
+    admin_override: Option<bool>,
 
     api_keys: HashSet<String>,
     roles: HashSet<Uuid>,
 
     models: HashSet<Uuid>,
+    denied_models: HashSet<Uuid>,
     quotas: HashSet<Uuid>,
+
+    region: Option<String>,
+    upstream_timeout: Option<u64>,
+    max_request_cost: Option<f64>,
+    allow_request_capture: bool,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
@@ -71,6 +116,11 @@ struct Role {
 
     models: HashSet<Uuid>,
     quotas: HashSet<Uuid>,
+
+    region: Option<String>,
+    upstream_timeout: Option<u64>,
+    max_request_cost: Option<f64>,
+    allow_request_capture: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -91,6 +141,112 @@ struct Model {
 
     #[serde(default)]
     quotas: HashSet<Uuid>,
+
+    #[serde(default)]
+    prompt_template: Option<String>,
+
+    #[serde(default)]
+    fallbacks: Vec<Uuid>,
+
+    #[serde(default)]
+    deprecated: Option<String>,
+
+    #[serde(default)]
+    json_schema_support: JsonSchemaSupport,
+
+    #[serde(default)]
+    token_input_support: TokenInputSupport,
+
+    #[serde(default)]
+    output_token_weight: Option<f64>,
+
+    #[serde(default)]
+    size_charging: Option<SizeCharging>,
+
+    #[serde(default)]
+    pricing: Option<Pricing>,
+
+    #[serde(default)]
+    penalty_range: Option<PenaltyRange>,
+
+    #[serde(default)]
+    image_sizes: Option<ImageSizes>,
+
+    #[serde(default, with = "crate::model::json_map")]
+    param_profiles: HashMap<String, Map<String, Value>>,
+
+    #[serde(default)]
+    check_context_length: bool,
+
+    #[serde(default)]
+    tokenizer: Option<String>,
+
+    #[serde(default)]
+    region: Option<String>,
+
+    #[serde(default)]
+    capabilities: HashSet<String>,
+
+    #[serde(default)]
+    proxy_metadata: bool,
+
+    #[serde(default)]
+    system_fingerprint: FingerprintStrategy,
+
+    #[serde(default)]
+    allow_chat_to_completion: bool,
+
+    #[serde(default)]
+    emulate_streaming: bool,
+
+    #[serde(default)]
+    output_moderation: Option<OutputModeration>,
+
+    #[serde(default)]
+    split_n: bool,
+
+    #[serde(default = "default_max_split_n")]
+    max_split_n: u64,
+
+    #[serde(default)]
+    request_timeout: Option<u64>,
+
+    #[serde(default)]
+    max_prompt_tokens: Option<u64>,
+
+    #[serde(default, with = "crate::model::json_map")]
+    examples: Vec<Value>,
+}
+
+fn default_max_split_n() -> u64 {
+    DEFAULT_MAX_SPLIT_N
+}
+
+// Prices are per million tokens, in whatever currency the administrator chooses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Pricing {
+    input: f64,
+    output: f64,
+    #[serde(default)]
+    max_request_cost: Option<f64>,
+}
+
+impl Pricing {
+    fn get_cost(&self, input_tokens: u64, output_tokens: u64) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
+
+    // Tokens which the backend didn't report as input or output tokens are priced as output tokens.
+    fn get_usage_cost(&self, usage: &TokenUsage) -> f64 {
+        let input_tokens = usage.input.unwrap_or_default();
+
+        self.get_cost(
+            input_tokens,
+            usage
+                .output
+                .unwrap_or(usage.total.saturating_sub(input_tokens)),
+        )
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
@@ -100,6 +256,35 @@ struct Quota {
     uuid: Uuid,
 
     limits: Vec<Limit>,
+    max_wait: Option<u64>,
+    max_concurrent: Option<u32>,
+    notification_thresholds: Vec<u8>,
+}
+
+impl Quota {
+    // A limit of 0 would reject every request using the Quota.
+    fn has_invalid_concurrency(&self) -> bool {
+        self.max_concurrent == Some(0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UsageRecord {
+    timestamp: u64,
+    user: Uuid,
+    model: Uuid,
+    r#type: RequestType,
+    status: u16,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
+    total_tokens: u64,
+    charged_tokens: u64,
+    cost: Option<f64>,
+}
+
+// Usage records are keyed by UUIDv7, which sorts by creation time.
+fn get_usage_key(timestamp_millis: u64) -> Uuid {
+    Uuid::from_u128(((timestamp_millis as u128) & 0xFFFF_FFFF_FFFF) << 80)
 }
 
 #[derive(Debug, Clone)]
@@ -111,13 +296,25 @@ struct Authenticated {
 }
 
 pub fn api_router(state: AppState) -> Router {
+    // Only model requests count towards the concurrency limit, and they're only counted once they're authenticated, so that a flood of other requests can't lock out administrators.
+    let concurrency_limit = middleware::from_fn_with_state(state.clone(), limit_concurrency);
+
     Router::new()
-        .fallback(handle_model_request)
+        .route(
+            "/v1/batch",
+            post(handle_batch_request).layer(concurrency_limit.clone()),
+        )
+        .route("/v1/models/:name/profiles", get(get_param_profiles))
+        .route("/v1/models/:name/examples", get(get_model_examples))
+        .route("/v1/me/requests", get(get_captured_requests))
+        .route("/v1/me/request_capture", put(set_request_capture))
+        .fallback(any(handle_model_request).layer(concurrency_limit))
         .nest("/admin", admin::admin_router())
         .with_state(state.clone())
         .layer(
             ServiceBuilder::new()
                 .layer(DefaultBodyLimit::max(16_777_216))
+                .layer(Extension(JsonRepair(state.repair_json)))
                 .layer(
                     TraceLayer::new_for_http()
                         .make_span_with(|request: &Request<Body>| {
@@ -217,9 +414,145 @@ pub fn api_router(state: AppState) -> Router {
                             },
                         ),
                 )
-                .layer(middleware::map_response(modify_response))
-                .layer(middleware::from_fn_with_state(state, authenticate)),
+                .layer(middleware::map_response_with_state(
+                    state.clone(),
+                    modify_response,
+                ))
+                .layer(middleware::from_fn_with_state(state.clone(), authenticate)),
+        )
+        .merge(health_router(state))
+}
+
+// Health checks and metrics are used by orchestrators and monitoring systems, so they're added after (and skip) the authentication middleware.
+fn health_router(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/health", get(get_liveness))
+        .route("/ready", get(get_readiness));
+
+    match state.prometheus_metrics.is_some() {
+        true => router.route("/metrics", get(get_metrics)),
+        false => router,
+    }
+    .with_state(state)
+}
+
+async fn get_metrics(State(state): State<AppState>) -> Response {
+    match &state.prometheus_metrics {
+        Some(metrics) => (
+            [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render(),
         )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn get_liveness() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn get_readiness(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    check_readiness(&state.database, &state.clock)
+}
+
+fn check_readiness(database: &Database, clock: &LimiterClock) -> (StatusCode, Json<Value>) {
+    let database_ready = database.is_ready();
+    let clock_ready = clock.is_ready();
+
+    let (status, message) = match database_ready && clock_ready {
+        true => (StatusCode::OK, "ok"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    };
+
+    (
+        status,
+        Json(json!({
+            "status": message,
+            "database": database_ready,
+            "clock": clock_ready,
+        })),
+    )
+}
+
+// Tracks a request in the active request metric, holding a permit from the global concurrency limit (if one is set) until the request completes or is cancelled.
+struct ActiveRequest {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveRequest {
+    #[allow(clippy::result_large_err)]
+    fn start(limit: Option<&Arc<Semaphore>>) -> Result<Self, ModelResponse> {
+        let permit = match limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    tracing::debug!(monotonic_counter.http.server.rejected_requests = 1_u64);
+
+                    return Err(ModelResponse::from(ModelError::ServerOverloaded)
+                        .with_retry_after(OVERLOADED_RETRY_AFTER));
+                }
+            },
+            None => None,
+        };
+
+        tracing::debug!(counter.http.server.active_requests = 1_i64);
+
+        Ok(ActiveRequest { _permit: permit })
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        tracing::debug!(counter.http.server.active_requests = -1_i64);
+    }
+}
+
+// Holds a value until the response's body has been sent (or dropped), so that streamed responses are counted as in-flight until they end.
+struct HeldBody<T> {
+    body: Body,
+    _held: T,
+}
+
+impl<T: Send + Unpin + 'static> http_body::Body for HeldBody<T> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+// Requests over the limit are rejected immediately instead of being queued, so that an overloaded proxy doesn't accumulate an unbounded backlog.
+async fn limit_concurrency(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match ActiveRequest::start(state.concurrency_limit.as_ref()) {
+        Ok(active_request) => {
+            let (parts, body) = next.run(request).await.into_parts();
+
+            Response::from_parts(
+                parts,
+                Body::new(HeldBody {
+                    body,
+                    _held: active_request,
+                }),
+            )
+        }
+        Err(response) => response.into_response(),
+    }
 }
 
 async fn authenticate(
@@ -227,11 +560,9 @@ async fn authenticate(
     mut request: Request,
     next: Next,
 ) -> Result<Response, ModelError> {
-    let span = tracing::debug_span!("authenticate").entered();
-
     let timestamp = Instant::now();
 
-    match request
+    let api_key = request
         .headers()
         .get(AUTHORIZATION)
         .and_then(|header_value| {
@@ -242,7 +573,7 @@ async fn authenticate(
                     .or_else(|| {
                         header_string
                             .strip_prefix("Basic ")
-                            .and_then(|auth_encoded| RFC4648.decode_str(auth_encoded).ok())
+                            .and_then(|auth_encoded| STANDARD.decode(auth_encoded).ok())
                             .and_then(|auth_decoded| {
                                 String::from_utf8(auth_decoded).ok().and_then(|value| {
                                     value.strip_prefix(':').map(|value| value.to_string())
@@ -250,13 +581,27 @@ async fn authenticate(
                             })
                     })
             })
-        }) {
+        });
+
+    let external_user = match (&state.external_auth, &api_key) {
+        (Some(external_auth), Some(api_key)) => {
+            Some(external_auth.get_user(&state.http, api_key).await)
+        }
+        _ => None,
+    };
+
+    let span = tracing::debug_span!("authenticate").entered();
+
+    match api_key {
         Some(api_key) => {
             if cfg!(debug_assertions) {
                 tracing::trace!(api_key = api_key);
             }
 
-            if state.database.is_table_empty("users") && api_key == "setup-key" {
+            if state.external_auth.is_none()
+                && state.database.is_table_empty("users")
+                && api_key == "setup-key"
+            {
                 request.extensions_mut().insert(Authenticated {
                     timestamp,
                     admin: true,
@@ -271,10 +616,11 @@ async fn authenticate(
                 return Ok(next.run(request).await);
             }
 
-            match state
-                .database
-                .get_related_item::<_, Uuid, User>(("api_keys", "users"), &api_key)
-            {
+            match external_user.unwrap_or_else(|| {
+                state
+                    .database
+                    .get_related_item::<_, Uuid, User>(("api_keys", "users"), &api_key)
+            }) {
                 DatabaseValueResult::Success(user) => {
                     if cfg!(debug_assertions) {
                         tracing::debug!(user = ?user);
@@ -289,13 +635,7 @@ async fn authenticate(
                         .get_items_skip_missing::<_, Role>("roles", &roles)
                     {
                         DatabaseValueResult::Success(roles) => {
-                            let mut admin = user.admin;
-
-                            for role in &roles {
-                                if role.admin {
-                                    admin = true;
-                                }
-                            }
+                            let admin = is_admin(&user, &roles, state.role_admin);
 
                             if cfg!(debug_assertions) {
                                 tracing::debug!(roles = ?roles)
@@ -341,7 +681,10 @@ async fn authenticate_admin(
     Err(ModelError::UnknownEndpoint)
 }
 
-async fn modify_response<B>(mut response: Response<B>) -> Response<B> {
+async fn modify_response<B>(
+    State(state): State<AppState>,
+    mut response: Response<B>,
+) -> Response<B> {
     if response.status() == StatusCode::UNAUTHORIZED {
         response.headers_mut().insert(
             WWW_AUTHENTICATE,
@@ -351,6 +694,33 @@ async fn modify_response<B>(mut response: Response<B>) -> Response<B> {
         );
     }
 
+    if let Some(timings) = response.extensions_mut().remove::<ModelTimings>() {
+        if state.timing_headers {
+            if let Some(upstream) = timings.upstream {
+                response.headers_mut().insert(
+                    "X-Upstream-Latency-Ms",
+                    HeaderValue::from(upstream.as_millis() as u64),
+                );
+            }
+            if let Some(queue) = timings.queue {
+                response.headers_mut().insert(
+                    "X-Proxy-Queue-Ms",
+                    HeaderValue::from(queue.as_millis() as u64),
+                );
+            }
+        }
+    }
+
+    if let Some(ModelWarnings(warnings)) = response.extensions_mut().remove::<ModelWarnings>() {
+        if state.proxy_warnings {
+            for warning in warnings {
+                if let Ok(value) = HeaderValue::from_str(&warning) {
+                    response.headers_mut().append("X-Proxy-Warnings", value);
+                }
+            }
+        }
+    }
+
     response
 }
 
@@ -358,167 +728,2017 @@ async fn modify_response<B>(mut response: Response<B>) -> Response<B> {
 async fn handle_model_request(
     Extension(auth): Extension<Authenticated>,
     State(state): State<AppState>,
+    headers: HeaderMap,
     mut request: ModelRequest,
-) -> Result<ModelResponse, ModelError> {
-    let models_result = state.database.get_items_skip_missing::<_, Model>(
-        "models",
-        &auth
-            .user
-            .models
-            .iter()
-            .chain(auth.roles.iter().flat_map(|role| role.models.iter()))
-            .cloned()
-            .collect::<Vec<_>>(),
-    );
+) -> Result<Response, ModelError> {
+    let deadline = get_deadline(&headers, &auth);
 
-    let model_name = request.get_model().unwrap_or_default();
-    let model = match models_result {
-        DatabaseValueResult::Success(models) => {
-            if cfg!(debug_assertions) {
-                tracing::trace!(models = ?models);
-            }
+    request.request_id = headers
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
 
-            match models
-                .iter()
-                .find(|model| model.types.contains(&request.r#type) && model.name == model_name)
-            {
-                Some(model) => model.clone(),
-                None => return Err(ModelError::UnknownModel),
+    if let Some(param_profile) = headers
+        .get("X-Param-Profile")
+        .and_then(|value| value.to_str().ok())
+    {
+        request.param_profile = Some(param_profile.to_string());
+    }
+
+    if headers
+        .get("X-Proxy-Metadata")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+    {
+        request.proxy_metadata = Some(ProxyMetadata {
+            deployment: state.deployment_name.clone(),
+        });
+    }
+
+    request.normalize_body(state.body_normalization)?;
+    let (model, fallbacks) = resolve_models(&state, &auth, &request)?;
+
+    let r#type = request.r#type;
+    let stream = request.take_stream();
+    request.stream = stream;
+    let deprecation = get_deprecation_header(&model);
+
+    let capture =
+        get_enabled_request_capture(state.request_capture.as_deref(), &auth).map(|capture| {
+            (
+                capture,
+                request.request_id.clone(),
+                request.get_model().map(|model| model.to_string()),
+                request.to_log_value(),
+            )
+        });
+
+    let result = route_model_request(&state, &auth, model, fallbacks, request, deadline).await;
+    if let Some((capture, request_id, model, request)) = capture {
+        let (status, response) = match &result {
+            Ok((_, response)) => (response.status, response.to_log_value()),
+            Err(error) => {
+                let response = ModelResponse::from(error.clone());
+                (response.status, response.to_log_value())
             }
+        };
+
+        capture.record(
+            auth.user.uuid,
+            CapturedRequest::new(request_id, model, status.as_u16(), request, response),
+        );
+    }
+    let (served_model, mut response) = result?;
+    let mut response = match stream {
+        _ if response.is_stream() => response.into_response(),
+        Some(include_usage) if served_model.emulate_streaming => {
+            response.into_event_stream(r#type, include_usage)
         }
-        DatabaseValueResult::NotFound => return Err(ModelError::UnknownModel),
-        DatabaseValueResult::BackendError => return Err(ModelError::InternalError),
+        Some(_) => {
+            response
+                .warnings
+                .push(model::STREAMING_UNSUPPORTED_WARNING.to_string());
+            response.into_response()
+        }
+        None => response.into_response(),
     };
 
-    if cfg!(debug_assertions) {
-        tracing::debug!(model = ?model);
-    } else {
-        tracing::debug!(model = ?model.uuid);
+    if state.served_model_header {
+        if let Ok(value) = HeaderValue::from_str(&served_model.name) {
+            response.headers_mut().insert("X-Served-Model", value);
+        }
     }
 
-    let model_max_tokens = model.api.get_max_tokens();
-    let request_max_tokens = request.get_max_tokens();
-    let request_count = request.get_count() as u64;
-    if request_max_tokens.unwrap_or(model_max_tokens) > model_max_tokens {
-        return Err(ModelError::UserRateLimit);
-    }
-    if let Some(max_tokens) = request_max_tokens {
-        tracing::debug!(histogram.request.max_tokens = max_tokens, unit = "tokens");
+    if let Some(deprecation) = deprecation {
+        response
+            .headers_mut()
+            .insert("X-Model-Deprecated", deprecation);
     }
-    tracing::debug!(histogram.request.count = request_count);
 
-    let quotas: HashSet<Uuid> = auth
-        .user
-        .quotas
-        .iter()
-        .chain(auth.roles.iter().flat_map(|role| role.quotas.iter()))
-        .chain(model.quotas.iter())
-        .copied()
-        .collect();
-    let quotas: Vec<Uuid> = quotas.iter().copied().collect();
+    Ok(response)
+}
 
-    tracing::debug!(quotas = ?quotas);
+fn get_system_fingerprint(model: &Model) -> SystemFingerprint {
+    match model.system_fingerprint {
+        FingerprintStrategy::Static => SystemFingerprint::from_model(model.uuid),
+        FingerprintStrategy::Passthrough => SystemFingerprint::Passthrough,
+        // The fingerprint changes whenever the parts of the Model's configuration which affect its outputs change, so that clients can tell when responses may no longer be reproducible.
+        FingerprintStrategy::Config => SystemFingerprint::Override(model::get_fingerprint(
+            json!({
+                "api": model.api.get_config_value(),
+                "prompt_template": model.prompt_template,
+            })
+            .to_string()
+            .as_bytes(),
+        )),
+    }
+}
 
-    request.user = Some(auth.user.uuid);
+// Deprecated models are still served, but clients are told about the deprecation (using the migration note if it can be sent as a header).
+fn get_deprecation_header(model: &Model) -> Option<HeaderValue> {
+    model.deprecated.as_ref().map(|note| {
+        HeaderValue::from_str(note)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .unwrap_or(HeaderValue::from_static("true"))
+    })
+}
 
-    let limiter_request = limiter::Request {
-        arrived_at: auth.timestamp,
-        estimated_tokens: request_max_tokens.unwrap_or(model_max_tokens) * request_count,
-    };
-    tracing::debug!(
-        histogram.quota.estimated_tokens = limiter_request.estimated_tokens,
-        unit = "tokens"
-    );
+// Models without a tokenizer use cl100k_base, while models with an unknown tokenizer use the fallback tokenizer (if it hasn't been disabled).
+fn get_model_tokenizer(model: &Model, fallback: Option<Tokenizer>) -> Option<Tokenizer> {
+    match &model.tokenizer {
+        Some(name) => Tokenizer::from_name(name).or(fallback),
+        None => Some(Tokenizer::default()),
+    }
+}
 
-    let limit_request = |quota: &mut Quota| {
-        let mut wait_until = Instant::now();
+fn has_unknown_tokenizer(model: &Model) -> bool {
+    model
+        .tokenizer
+        .as_ref()
+        .is_some_and(|name| Tokenizer::from_name(name).is_none())
+}
 
-        for limit in &mut quota.limits {
-            match limit.request(&state.clock, &limiter_request) {
-                LimiterResult::Ready => {}
-                LimiterResult::WaitUntil(timestamp) => wait_until = wait_until.max(timestamp),
-                LimiterResult::Oversized => return Err(ModelError::UserRateLimit),
+pub fn check_model_tokenizers(state: &AppState) {
+    if let DatabaseValueResult::Success(models) = state.database.get_table::<Model>("models") {
+        let models: Vec<Uuid> = models
+            .iter()
+            .filter(|model| has_unknown_tokenizer(model))
+            .map(|model| model.uuid)
+            .collect();
+
+        if !models.is_empty() {
+            match state.fallback_tokenizer {
+                Some(fallback) => tracing::warn!(
+                    "The following models have an unknown tokenizer, and will use the {:?} tokenizer instead: {:?}",
+                    fallback,
+                    models
+                ),
+                None => tracing::warn!(
+                    "The following models have an unknown tokenizer, and won't have their requests' tokens counted: {:?}",
+                    models
+                ),
             }
         }
+    }
+}
 
-        Ok(wait_until)
-    };
+const CONNECTION_WARMING_INTERVAL: Duration = Duration::from_secs(60);
 
-    match state
-        .database
-        .modify_items_skip_missing("quotas", &quotas, limit_request)
-    {
-        DatabaseFunctionResult::Success(timestamps) => {
-            if let Some(wait_until) = timestamps.iter().max().cloned() {
-                time::sleep_until(time::Instant::from_std(wait_until))
-                    .instrument(tracing::debug_span!("rate_limit_request"))
-                    .await
-            }
-        }
-        DatabaseFunctionResult::FunctionError(error) => return Err(error),
-        DatabaseFunctionResult::BackendError => return Err(ModelError::InternalError),
-    }
+// Periodically re-warms connections, as idle connections are eventually closed by the HTTP client.
+pub async fn warm_model_connections(state: AppState) {
+    let mut interval = time::interval(CONNECTION_WARMING_INTERVAL);
 
-    let response = model.api.generate(&state.http, model.uuid, request).await;
+    loop {
+        interval.tick().await;
 
-    let limiter_response = limiter::Response {
-        request: limiter_request,
-        actual_tokens: response.usage.total,
-    };
-    tracing::debug!(
-        histogram.quota.actual_tokens = limiter_response.actual_tokens,
-        unit = "tokens"
-    );
-    if let Some(input_tokens) = response.usage.input {
-        tracing::debug!(
-            histogram.quota.actual_tokens.input = input_tokens,
-            unit = "tokens"
-        );
-    }
-    if let Some(output_tokens) = response.usage.output {
-        tracing::debug!(
-            histogram.quota.actual_tokens.output = output_tokens,
-            unit = "tokens"
-        );
-    }
-    tracing::debug!(
-        histogram.quota.estimate_offset = limiter_response.request.estimated_tokens as i64
-            - limiter_response.actual_tokens as i64,
-        unit = "tokens"
-    );
+        if let DatabaseValueResult::Success(models) = state.database.get_table::<Model>("models") {
+            let mut tasks = JoinSet::new();
 
-    let limit_response = |quota: &mut Quota| {
-        let mut wait_until = Instant::now();
+            for model in models {
+                let http_client = state.http.clone();
 
-        for limit in &mut quota.limits {
-            match limit.response(&state.clock, &limiter_response) {
-                LimiterResult::Ready => {}
-                LimiterResult::WaitUntil(timestamp) => wait_until = wait_until.max(timestamp),
-                LimiterResult::Oversized => return Err(ModelError::UserRateLimit),
+                tasks.spawn(
+                    async move { model.api.warm_connections(&http_client).await }.in_current_span(),
+                );
             }
-        }
 
-        Ok(wait_until)
-    };
+            while tasks.join_next().await.is_some() {}
+        }
+    }
+}
 
+#[tracing::instrument(level = "debug", skip_all)]
+async fn get_param_profiles(
+    Extension(auth): Extension<Authenticated>,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ModelError> {
     match state
         .database
-        .modify_items_skip_missing("quotas", &quotas, limit_response)
+        .get_items_skip_missing::<_, Model>("models", &get_accessible_models(&auth))
     {
-        DatabaseFunctionResult::Success(timestamps) => {
-            if let Some(wait_until) = timestamps.iter().max().cloned() {
-                time::sleep_until(time::Instant::from_std(wait_until))
-                    .instrument(tracing::debug_span!("rate_limit_response"))
-                    .await
+        DatabaseValueResult::Success(models) => {
+            let models: Vec<Model> = models
+                .into_iter()
+                .filter(|model| model.name == name)
+                .collect();
+
+            if models.is_empty() {
+                return Err(ModelError::UnknownModel);
+            }
+
+            Ok(Json(json!({
+                "object": "list",
+                "data": list_param_profiles(&models),
+            })))
+        }
+        DatabaseValueResult::NotFound => Err(ModelError::UnknownModel),
+        DatabaseValueResult::BackendError => Err(ModelError::InternalError),
+    }
+}
+
+fn list_param_profiles(models: &[Model]) -> Vec<Value> {
+    let mut profiles: Vec<(&String, &Map<String, Value>)> = Vec::new();
+
+    for model in models {
+        for (name, parameters) in &model.param_profiles {
+            if !profiles.iter().any(|(existing, _)| *existing == name) {
+                profiles.push((name, parameters));
+            }
+        }
+    }
+    profiles.sort_by_key(|(name, _)| *name);
+
+    profiles
+        .into_iter()
+        .map(|(name, parameters)| {
+            json!({
+                "object": "param_profile",
+                "name": name,
+                "parameters": parameters,
+            })
+        })
+        .collect()
+}
+
+// Unknown profile names are rejected with a list of the model's profiles, so that clients can tell what was wrong with their request.
+fn get_param_profile<'a>(
+    model: &'a Model,
+    name: &str,
+) -> Result<&'a Map<String, Value>, ModelError> {
+    model.param_profiles.get(name).ok_or_else(|| {
+        let mut supported: Vec<String> = model.param_profiles.keys().cloned().collect();
+        supported.sort();
+
+        ModelError::UnsupportedValue {
+            param: "param_profile",
+            supported,
+        }
+    })
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+async fn get_model_examples(
+    Extension(auth): Extension<Authenticated>,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ModelError> {
+    match state
+        .database
+        .get_items_skip_missing::<_, Model>("models", &get_accessible_models(&auth))
+    {
+        DatabaseValueResult::Success(models) => {
+            let models: Vec<Model> = models
+                .into_iter()
+                .filter(|model| model.name == name)
+                .collect();
+
+            if models.is_empty() {
+                return Err(ModelError::UnknownModel);
+            }
+
+            Ok(Json(json!({
+                "object": "list",
+                "data": list_model_examples(&models),
+            })))
+        }
+        DatabaseValueResult::NotFound => Err(ModelError::UnknownModel),
+        DatabaseValueResult::BackendError => Err(ModelError::InternalError),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RequestCaptureSettings {
+    enabled: bool,
+}
+
+// Users can only opt in to request capture if they (or one of their roles) are allowed to.
+fn can_capture_requests(auth: &Authenticated) -> bool {
+    auth.user.allow_request_capture || auth.roles.iter().any(|role| role.allow_request_capture)
+}
+
+// Permission is checked again whenever the user's capture is used, so that revoking it stops (and removes) their captures.
+fn get_enabled_request_capture<'a>(
+    capture: Option<&'a RequestCapture>,
+    auth: &Authenticated,
+) -> Option<&'a RequestCapture> {
+    let capture = capture?;
+
+    if !capture.is_enabled(auth.user.uuid) {
+        return None;
+    }
+    if !can_capture_requests(auth) {
+        capture.set_enabled(auth.user.uuid, false);
+        return None;
+    }
+
+    Some(capture)
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+async fn set_request_capture(
+    Extension(auth): Extension<Authenticated>,
+    State(state): State<AppState>,
+    Json(settings): Json<RequestCaptureSettings>,
+) -> Result<Json<Value>, ModelError> {
+    let capture = state
+        .request_capture
+        .as_ref()
+        .ok_or(ModelError::UnknownEndpoint)?;
+
+    if settings.enabled && !can_capture_requests(&auth) {
+        return Err(ModelError::PermissionDenied);
+    }
+
+    capture.set_enabled(auth.user.uuid, settings.enabled);
+
+    Ok(Json(json!({
+        "enabled": settings.enabled,
+        "retention_seconds": capture.get_retention().as_secs(),
+    })))
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+async fn get_captured_requests(
+    Extension(auth): Extension<Authenticated>,
+    State(state): State<AppState>,
+) -> Result<Json<Value>, ModelError> {
+    let capture = state
+        .request_capture
+        .as_ref()
+        .ok_or(ModelError::UnknownEndpoint)?;
+
+    Ok(Json(json!({
+        "object": "list",
+        "enabled": get_enabled_request_capture(state.request_capture.as_deref(), &auth).is_some(),
+        "data": capture.get_captures(auth.user.uuid),
+    })))
+}
+
+fn list_model_examples(models: &[Model]) -> Vec<Value> {
+    let mut examples: Vec<&Value> = Vec::new();
+
+    for model in models {
+        for example in &model.examples {
+            if !examples.contains(&example) {
+                examples.push(example);
+            }
+        }
+    }
+
+    examples.into_iter().cloned().collect()
+}
+
+// Examples must be request bodies containing the required fields of at least one of the model's request types.
+fn find_invalid_example(model: &Model) -> Option<usize> {
+    model.examples.iter().position(|example| match example {
+        Value::Object(body) => !model.types.iter().any(|r#type| {
+            r#type
+                .get_required_fields()
+                .iter()
+                .all(|field| body.contains_key(*field))
+        }),
+        _ => true,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchItem {
+    #[serde(default = "default_batch_method")]
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Map<String, Value>,
+}
+
+fn default_batch_method() -> String {
+    "POST".to_string()
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+async fn handle_batch_request(
+    Extension(auth): Extension<Authenticated>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(items): Json<Vec<BatchItem>>,
+) -> Result<Json<Vec<Value>>, ModelError> {
+    tracing::debug!(histogram.batch.count = items.len());
+
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(ModelError::BatchTooLarge);
+    }
+
+    let deadline = get_deadline(&headers, &auth);
+
+    let mut estimated_tokens: u64 = 0;
+    let mut results: Vec<Option<Value>> = Vec::with_capacity(items.len());
+    let mut requests = Vec::new();
+
+    for (index, item) in items.into_iter().enumerate() {
+        let routed = ModelRequest::from_batch_item(&item.method, &item.path, item.body)
+            .and_then(|mut request| {
+                request
+                    .normalize_body(state.body_normalization)
+                    .map(|_| request)
+            })
+            .and_then(|request| {
+                resolve_models(&state, &auth, &request).map(|models| (request, models))
+            });
+
+        match routed {
+            Ok((request, (model, fallbacks))) => {
+                estimated_tokens = estimated_tokens.saturating_add(
+                    request
+                        .get_max_tokens()
+                        .or(model.api.get_default_max_tokens())
+                        .unwrap_or(model.api.get_max_tokens())
+                        .saturating_mul(request.get_count() as u64),
+                );
+
+                requests.push((index, request, model, fallbacks));
+                results.push(None);
+            }
+            Err(error) => {
+                results.push(Some(
+                    ModelResponse::from(error).into_batch_item(state.proxy_warnings),
+                ));
+            }
+        }
+    }
+
+    tracing::debug!(
+        histogram.batch.estimated_tokens = estimated_tokens,
+        unit = "tokens"
+    );
+
+    if estimated_tokens > MAX_BATCH_TOKENS {
+        return Err(ModelError::BatchTooLarge);
+    }
+
+    let permits = Arc::new(Semaphore::new(MAX_BATCH_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+
+    for (index, request, model, fallbacks) in requests {
+        let state = state.clone();
+        let auth = auth.clone();
+        let permits = permits.clone();
+
+        tasks.spawn(
+            async move {
+                let _permit = permits.acquire_owned().await;
+
+                let response =
+                    match route_model_request(&state, &auth, model, fallbacks, request, deadline)
+                        .await
+                    {
+                        Ok((_, response)) => response,
+                        Err(error) => ModelResponse::from(error),
+                    };
+
+                (index, response.into_batch_item(state.proxy_warnings))
+            }
+            .in_current_span(),
+        );
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((index, item)) => results[index] = Some(item),
+            Err(_) => return Err(ModelError::InternalError),
+        }
+    }
+
+    Ok(Json(results.into_iter().flatten().collect()))
+}
+
+fn get_deadline(headers: &HeaderMap, auth: &Authenticated) -> Option<Instant> {
+    let deadline = headers
+        .get("X-Request-Deadline")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_deadline(value, auth.timestamp));
+    tracing::debug!(deadline = ?deadline);
+
+    deadline
+}
+
+// A User's admin_override takes precedence over everything else, followed by the User's own admin status, followed by their Roles.
+fn is_admin(user: &User, roles: &[Role], role_admin: bool) -> bool {
+    match user.admin_override {
+        Some(admin) => admin,
+        None => user.admin || (role_admin && roles.iter().any(|role| role.admin)),
+    }
+}
+
+fn get_accessible_models(auth: &Authenticated) -> Vec<Uuid> {
+    let models: HashSet<Uuid> = auth
+        .user
+        .models
+        .iter()
+        .chain(auth.roles.iter().flat_map(|role| role.models.iter()))
+        .filter(|uuid| !auth.user.denied_models.contains(uuid))
+        .copied()
+        .collect();
+
+    models.into_iter().collect()
+}
+
+fn get_region(auth: &Authenticated) -> Option<&str> {
+    auth.user
+        .region
+        .as_deref()
+        .or_else(|| auth.roles.iter().find_map(|role| role.region.as_deref()))
+}
+
+// A User's timeout takes precedence over their Roles' timeouts. Overrides can only raise the server's upstream timeout, and can't raise it past the server's maximum.
+fn get_upstream_timeout(
+    auth: &Authenticated,
+    default: Option<Duration>,
+    max: Duration,
+) -> Option<Duration> {
+    let default = default?;
+
+    let timeout = auth
+        .user
+        .upstream_timeout
+        .or_else(|| {
+            auth.roles
+                .iter()
+                .filter_map(|role| role.upstream_timeout)
+                .max()
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(default);
+
+    Some(timeout.clamp(default, max.max(default)))
+}
+
+// A User's cost limit takes precedence over their Roles' limits. The model's own limit applies to all Users.
+fn get_max_request_cost(auth: &Authenticated, pricing: &Pricing) -> Option<f64> {
+    let user_limit = auth.user.max_request_cost.or_else(|| {
+        auth.roles
+            .iter()
+            .filter_map(|role| role.max_request_cost)
+            .reduce(f64::max)
+    });
+
+    match (user_limit, pricing.max_request_cost) {
+        (Some(user_limit), Some(model_limit)) => Some(user_limit.min(model_limit)),
+        (user_limit, model_limit) => user_limit.or(model_limit),
+    }
+}
+
+fn check_request_cost(
+    auth: &Authenticated,
+    pricing: &Pricing,
+    input_tokens: u64,
+    output_tokens: u64,
+) -> Result<(), ModelError> {
+    let estimated = pricing.get_cost(input_tokens, output_tokens);
+    tracing::debug!(histogram.request.estimated_cost = estimated);
+
+    match get_max_request_cost(auth, pricing) {
+        Some(max) if estimated > max => Err(ModelError::CostLimitExceeded { estimated, max }),
+        _ => Ok(()),
+    }
+}
+
+fn is_convertible(model: &Model, r#type: RequestType) -> bool {
+    r#type == RequestType::TextChat
+        && model.allow_chat_to_completion
+        && model.types.contains(&RequestType::TextCompletion)
+}
+
+// Models in the same region as the user are preferred, but models in other regions will be used if none are available. Models which natively support the request type are preferred over models which require the request to be converted.
+fn select_model<'a>(
+    models: &'a [Model],
+    r#type: RequestType,
+    name: &str,
+    region: Option<&str>,
+    prompt_tokens: Option<u64>,
+) -> Option<&'a Model> {
+    let mut candidates: Vec<&Model> = models
+        .iter()
+        .filter(|model| model.types.contains(&r#type) && model.name == name)
+        .collect();
+
+    if candidates.is_empty() {
+        candidates = models
+            .iter()
+            .filter(|model| is_convertible(model, r#type) && model.name == name)
+            .collect();
+    }
+
+    let candidates = filter_by_prompt_length(candidates, prompt_tokens);
+
+    region
+        .and_then(|region| {
+            candidates
+                .iter()
+                .find(|model| model.region.as_deref() == Some(region))
+        })
+        .or(candidates.first())
+        .copied()
+}
+
+// Models with a max_prompt_tokens only serve requests whose longest prompt has at most that many tokens. The smallest limit which fits the prompt is used, and models without a limit serve prompts which don't fit any limit. Prompts which can't be counted are sent to models without a limit, if there are any.
+fn filter_by_prompt_length(candidates: Vec<&Model>, prompt_tokens: Option<u64>) -> Vec<&Model> {
+    if candidates
+        .iter()
+        .all(|model| model.max_prompt_tokens.is_none())
+    {
+        return candidates;
+    }
+
+    let fits = |model: &Model| match (model.max_prompt_tokens, prompt_tokens) {
+        (Some(max), Some(tokens)) => tokens <= max,
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+    let limit = candidates
+        .iter()
+        .filter(|model| fits(model))
+        .map(|model| model.max_prompt_tokens.unwrap_or(u64::MAX))
+        .min()
+        // If the prompt is too long for every model, the model with the largest limit is used, so that the request fails with a context length error rather than an unknown model error.
+        .or_else(|| {
+            candidates
+                .iter()
+                .filter_map(|model| model.max_prompt_tokens)
+                .max()
+        });
+
+    candidates
+        .into_iter()
+        .filter(|model| Some(model.max_prompt_tokens.unwrap_or(u64::MAX)) == limit)
+        .collect()
+}
+
+// Cheaper models (by output_token_weight) are preferred, followed by models in the user's region. Remaining ties are broken by name, so that the same model is consistently chosen.
+// Models with pricing are ranked by their combined input and output token price, ahead of models which can only be ranked by their output token weight.
+fn get_selection_price(model: &Model) -> (bool, f64) {
+    match &model.pricing {
+        Some(pricing) => (false, pricing.input + pricing.output),
+        None => (true, model.output_token_weight.unwrap_or(1.0)),
+    }
+}
+
+fn select_model_by_capabilities<'a>(
+    models: &'a [Model],
+    r#type: RequestType,
+    capabilities: &[&str],
+    region: Option<&str>,
+    load: &ModelLoad,
+) -> Option<&'a Model> {
+    // An empty list of capabilities would match every model.
+    if capabilities.is_empty() {
+        return None;
+    }
+
+    models
+        .iter()
+        .filter(|model| {
+            model.types.contains(&r#type)
+                && capabilities
+                    .iter()
+                    .all(|capability| model.capabilities.contains(*capability))
+        })
+        .min_by(|a, b| {
+            let (a_unpriced, a_price) = get_selection_price(a);
+            let (b_unpriced, b_price) = get_selection_price(b);
+
+            a_unpriced
+                .cmp(&b_unpriced)
+                .then_with(|| a_price.total_cmp(&b_price))
+                .then_with(|| {
+                    let a_region = region.is_some() && a.region.as_deref() == region;
+                    let b_region = region.is_some() && b.region.as_deref() == region;
+
+                    b_region.cmp(&a_region)
+                })
+                .then_with(|| load.get(a.uuid).cmp(&load.get(b.uuid)))
+                .then_with(|| a.name.cmp(&b.name))
+        })
+}
+
+// Models with the same name, region, and prompt length limit can't serve the same request types, as requests would be routed between them arbitrarily.
+fn find_conflicting_model<'a>(
+    models: &'a [Model],
+    model: &Model,
+) -> Option<(&'a Model, Vec<RequestType>)> {
+    models
+        .iter()
+        .filter(|existing| {
+            existing.uuid != model.uuid
+                && existing.name == model.name
+                && existing.region == model.region
+                && existing.max_prompt_tokens == model.max_prompt_tokens
+        })
+        .find_map(|existing| {
+            let types: Vec<RequestType> =
+                existing.types.intersection(&model.types).copied().collect();
+
+            (!types.is_empty()).then_some((existing, types))
+        })
+}
+
+const MAX_MODEL_SUGGESTIONS: usize = 3;
+
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(a_char != *b_char))
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Suggestions are limited to the models passed in, so callers must only pass models the user can access.
+fn suggest_model_names(models: &[Model], r#type: RequestType, name: &str) -> Vec<String> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+
+    let mut suggestions: Vec<(usize, &str)> = Vec::new();
+    for model in models {
+        if !model.types.contains(&r#type) && !is_convertible(model, r#type) {
+            continue;
+        }
+
+        let distance = get_edit_distance(&name, &model.name.to_lowercase());
+        if distance <= max_distance
+            && !suggestions
+                .iter()
+                .any(|(_, existing)| *existing == model.name)
+        {
+            suggestions.push((distance, &model.name));
+        }
+    }
+    suggestions.sort();
+
+    suggestions
+        .into_iter()
+        .take(MAX_MODEL_SUGGESTIONS)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+fn get_healthy_models(models: &[Model], health: &ModelHealth) -> Vec<Model> {
+    models
+        .iter()
+        .filter(|model| health.is_healthy(model.uuid))
+        .cloned()
+        .collect()
+}
+
+// Unhealthy models are moved to the end of the fallback chain, so that they're only used if every healthy model fails.
+fn prefer_healthy_models(mut models: Vec<Model>, health: &ModelHealth) -> Vec<Model> {
+    models.sort_by_key(|model| !health.is_healthy(model.uuid));
+
+    models
+}
+
+fn resolve_models(
+    state: &AppState,
+    auth: &Authenticated,
+    request: &ModelRequest,
+) -> Result<(Model, Vec<Model>), ModelError> {
+    let models_result = state
+        .database
+        .get_items_skip_missing::<_, Model>("models", &get_accessible_models(auth));
+
+    let model_name = request.get_model().unwrap_or_default();
+    match models_result {
+        DatabaseValueResult::Success(models) => {
+            if cfg!(debug_assertions) {
+                tracing::trace!(models = ?models);
+            }
+
+            // Prompts are only counted if they're needed for routing, as counting tokens is expensive. Models sharing a name are expected to share a tokenizer, so the first limited model's tokenizer is used.
+            let prompt_tokens = models
+                .iter()
+                .find(|model| model.name == model_name && model.max_prompt_tokens.is_some())
+                .and_then(|model| {
+                    request.get_token_count(get_model_tokenizer(model, state.fallback_tokenizer))
+                });
+
+            let select = |models: &[Model]| match model_name.strip_prefix(CAPABILITY_MODEL_PREFIX) {
+                Some(capabilities) => {
+                    let capabilities: Vec<&str> = capabilities
+                        .split('+')
+                        .map(|capability| capability.trim())
+                        .filter(|capability| !capability.is_empty())
+                        .collect();
+
+                    select_model_by_capabilities(
+                        models,
+                        request.r#type,
+                        &capabilities,
+                        get_region(auth),
+                        &state.model_load,
+                    )
+                    .cloned()
+                }
+                None => select_model(
+                    models,
+                    request.r#type,
+                    model_name,
+                    get_region(auth),
+                    prompt_tokens,
+                )
+                .cloned(),
+            };
+            let selected_model =
+                select(&get_healthy_models(&models, &state.health)).or_else(|| select(&models));
+
+            match selected_model {
+                Some(model) => {
+                    let fallbacks: Vec<Model> = model
+                        .fallbacks
+                        .iter()
+                        .filter_map(|uuid| {
+                            models.iter().find(|fallback| {
+                                fallback.uuid == *uuid
+                                    && (fallback.types.contains(&request.r#type)
+                                        || is_convertible(fallback, request.r#type))
+                            })
+                        })
+                        .cloned()
+                        .collect();
+
+                    let mut models = prefer_healthy_models(
+                        [model].into_iter().chain(fallbacks).collect(),
+                        &state.health,
+                    );
+                    let model = models.remove(0);
+
+                    Ok((model, models))
+                }
+                None if state.model_suggestions => {
+                    match suggest_model_names(&models, request.r#type, model_name) {
+                        suggestions if suggestions.is_empty() => Err(ModelError::UnknownModel),
+                        suggestions => Err(ModelError::UnknownModelSuggestions(suggestions)),
+                    }
+                }
+                None => Err(ModelError::UnknownModel),
+            }
+        }
+        DatabaseValueResult::NotFound => Err(ModelError::UnknownModel),
+        DatabaseValueResult::BackendError => Err(ModelError::InternalError),
+    }
+}
+
+// Runs a request through the same model resolution, token estimation, and Quota checks as a model request, without sending it to the model or updating any Quotas. Returns a report of how the request would be handled.
+fn validate_model_request(
+    state: &AppState,
+    auth: &Authenticated,
+    path: &str,
+    body: Map<String, Value>,
+) -> Value {
+    let mut report = Map::new();
+    report.insert("user".to_string(), json!(auth.user.uuid));
+
+    match check_model_request(state, auth, path, body, &mut report) {
+        Ok(()) => {
+            report.insert("valid".to_string(), Value::Bool(true));
+        }
+        Err(error) => {
+            let response = ModelResponse::from(error);
+
+            report.insert("valid".to_string(), Value::Bool(false));
+            report.insert(
+                "error".to_string(),
+                json!({
+                    "status": response.status.as_u16(),
+                    "body": response.to_log_value(),
+                }),
+            );
+        }
+    }
+
+    Value::Object(report)
+}
+
+fn check_model_request(
+    state: &AppState,
+    auth: &Authenticated,
+    path: &str,
+    body: Map<String, Value>,
+    report: &mut Map<String, Value>,
+) -> Result<(), ModelError> {
+    let mut request = ModelRequest::from_batch_item("POST", path, body)?;
+    request.user = Some(auth.user.uuid);
+    report.insert("type".to_string(), json!(request.r#type));
+
+    request.normalize_body(state.body_normalization)?;
+    let (model, fallbacks) = resolve_models(state, auth, &request)?;
+    report.insert(
+        "model".to_string(),
+        json!({
+            "uuid": model.uuid,
+            "name": model.name,
+            "label": model.label,
+        }),
+    );
+    report.insert(
+        "fallbacks".to_string(),
+        json!(fallbacks
+            .iter()
+            .map(|fallback| fallback.uuid)
+            .collect::<Vec<Uuid>>()),
+    );
+
+    let prepared = prepare_model_request(state, auth, &model, &mut request)?;
+    report.insert(
+        "tokens".to_string(),
+        json!({
+            "prompt": prepared.prompt_tokens,
+            "size": prepared.size_tokens,
+            "max": prepared.max_tokens,
+            "count": prepared.count,
+            "estimated": prepared.estimated_tokens,
+        }),
+    );
+    report.insert(
+        "converted_to_completion".to_string(),
+        Value::Bool(prepared.convert_to_completion),
+    );
+    report.insert("warnings".to_string(), json!(request.warnings));
+
+    let quotas = match state
+        .database
+        .get_items_skip_missing::<_, Quota>("quotas", &get_request_quotas(auth, &model))
+    {
+        DatabaseValueResult::Success(quotas) => quotas,
+        DatabaseValueResult::NotFound => Vec::new(),
+        DatabaseValueResult::BackendError => return Err(ModelError::InternalError),
+    };
+
+    let limiter_request = limiter::Request {
+        arrived_at: auth.timestamp,
+        estimated_tokens: prepared.estimated_tokens,
+    };
+    let now = Instant::now();
+    let mut oversized = false;
+    let mut timestamps = Vec::new();
+    let mut quota_reports = Vec::new();
+
+    for quota in &quotas {
+        let mut wait_until = now;
+        let mut limit_reports = Vec::new();
+
+        for limit in &quota.limits {
+            // Limits are checked using a copy of their state, so that the request isn't counted against them.
+            let result = match limit.clone().request(&state.clock, &limiter_request) {
+                LimiterResult::Ready => "ready",
+                LimiterResult::WaitUntil(timestamp) => {
+                    wait_until = wait_until.max(timestamp);
+                    "wait"
+                }
+                LimiterResult::Oversized => {
+                    oversized = true;
+                    "oversized"
+                }
+            };
+
+            limit_reports.push(json!({
+                "type": limit.r#type,
+                "count": limit.count,
+                "period": limit.period,
+                "usage": limit.get_usage(&state.clock, limiter_request.arrived_at),
+                "result": result,
+            }));
+        }
+
+        timestamps.push((wait_until, quota.max_wait.map(Duration::from_secs)));
+        quota_reports.push(json!({
+            "uuid": quota.uuid,
+            "label": quota.label,
+            "limits": limit_reports,
+            "wait": wait_until.saturating_duration_since(now).as_secs_f64(),
+        }));
+    }
+    report.insert("quotas".to_string(), Value::Array(quota_reports));
+
+    if oversized {
+        return Err(ModelError::UserRateLimit);
+    }
+    if let Err(retry_after) = check_max_wait(now, &timestamps, state.max_rate_limit_wait) {
+        report.insert("retry_after".to_string(), json!(retry_after.as_secs_f64()));
+        return Err(ModelError::UserRateLimit);
+    }
+
+    Ok(())
+}
+
+async fn route_model_request(
+    state: &AppState,
+    auth: &Authenticated,
+    mut model: Model,
+    fallbacks: Vec<Model>,
+    mut request: ModelRequest,
+    deadline: Option<Instant>,
+) -> Result<(Model, ModelResponse), ModelError> {
+    request.user = Some(auth.user.uuid);
+    request.timeout =
+        get_upstream_timeout(auth, state.upstream_timeout, state.max_upstream_timeout);
+
+    // Streamed responses are sent to the client before they can be moderated.
+    if model.output_moderation.is_some()
+        || fallbacks
+            .iter()
+            .any(|fallback| fallback.output_moderation.is_some())
+    {
+        request.stream = None;
+    }
+
+    for fallback in fallbacks {
+        match send_model_request(state, auth, &model, request.clone(), deadline).await {
+            Ok(mut response) => {
+                record_model_health(state, &model, &response);
+
+                if !response.is_fallback_eligible() {
+                    moderate_output(state, auth, &model, &mut response, deadline).await?;
+
+                    return Ok((model, response));
+                }
+
+                tracing::warn!(
+                    "Model {} returned {} error, retrying request with fallback model {}",
+                    model.uuid,
+                    response.status,
+                    fallback.uuid
+                );
+            }
+            Err(error) if ModelResponse::from(error.clone()).is_fallback_eligible() => {
+                tracing::warn!(
+                    "Model {} returned {:?} error, retrying request with fallback model {}",
+                    model.uuid,
+                    error,
+                    fallback.uuid
+                );
+            }
+            Err(error) => return Err(error),
+        }
+
+        model = fallback;
+    }
+
+    let mut response = send_model_request(state, auth, &model, request, deadline).await?;
+    record_model_health(state, &model, &response);
+    moderate_output(state, auth, &model, &mut response, deadline).await?;
+
+    Ok((model, response))
+}
+
+// Responses which were rejected before being sent to the model's backend (such as by the User's own rate limits) don't say anything about the model's health. Timeouts are recorded, as the backend didn't respond in time.
+fn record_model_health(state: &AppState, model: &Model, response: &ModelResponse) {
+    if response.timings.upstream.is_some() || response.status.is_server_error() {
+        state
+            .health
+            .record(model.uuid, !response.status.is_server_error());
+    }
+}
+
+// Checks the response's output using the model's moderation model, which is charged to the same User as the original request.
+async fn moderate_output(
+    state: &AppState,
+    auth: &Authenticated,
+    model: &Model,
+    response: &mut ModelResponse,
+    deadline: Option<Instant>,
+) -> Result<(), ModelError> {
+    let moderation = match &model.output_moderation {
+        Some(moderation) if response.status.is_success() && !response.is_stream() => moderation,
+        _ => return Ok(()),
+    };
+
+    let texts = response.get_output_texts();
+    if texts.iter().all(|text| text.is_empty()) {
+        return Ok(());
+    }
+
+    let flags = match state
+        .database
+        .get_item::<_, Model>("models", &moderation.model)
+    {
+        DatabaseValueResult::Success(moderation_model) => {
+            match ModelRequest::from_batch_item(
+                "POST",
+                "/v1/moderations",
+                json!({ "model": moderation_model.name, "input": texts })
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default(),
+            ) {
+                Ok(mut request) => {
+                    request.user = Some(auth.user.uuid);
+
+                    send_model_request(state, auth, &moderation_model, request, deadline)
+                        .await
+                        .map(|moderation| moderation.get_moderation_flags(texts.len()))
+                }
+                Err(error) => Err(error),
+            }
+        }
+        _ => Ok(None),
+    };
+
+    match (flags, moderation.action) {
+        (Ok(Some(flags)), _) => response.apply_output_moderation(&flags, moderation),
+        // Outputs which couldn't be checked are only sent to the client if the model's outputs are being annotated instead of blocked.
+        (Ok(None), ModerationAction::Block) => {
+            tracing::error!("Unable to moderate output of model {}", model.uuid);
+            return Err(ModelError::BackendError);
+        }
+        (Err(error), ModerationAction::Block) => {
+            tracing::error!(
+                "Unable to moderate output of model {}: {:?}",
+                model.uuid,
+                error
+            );
+            return Err(error);
+        }
+        (Ok(None), ModerationAction::Annotate) => {
+            tracing::warn!("Unable to moderate output of model {}", model.uuid);
+            response
+                .warnings
+                .push(OUTPUT_MODERATION_FAILED_WARNING.to_string());
+        }
+        (Err(error), ModerationAction::Annotate) => {
+            tracing::warn!(
+                "Unable to moderate output of model {}: {:?}",
+                model.uuid,
+                error
+            );
+            response
+                .warnings
+                .push(OUTPUT_MODERATION_FAILED_WARNING.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_deadline(value: &str, now: Instant) -> Option<Instant> {
+    let value = value.trim();
+
+    match value.parse::<u64>() {
+        Ok(milliseconds) => now.checked_add(Duration::from_millis(milliseconds)),
+        Err(_) => httpdate::parse_http_date(value).ok().and_then(|timestamp| {
+            match timestamp.duration_since(SystemTime::now()) {
+                Ok(duration) => Instant::now().checked_add(duration),
+                Err(_) => Some(Instant::now()),
+            }
+        }),
+    }
+}
+
+// Thresholds are percentages of a Limit's capacity, and are only crossed when usage rises past them, so that each crossing is only reported once.
+fn get_crossed_thresholds(thresholds: &[u8], usage: f64, updated_usage: f64) -> Vec<u8> {
+    thresholds
+        .iter()
+        .copied()
+        .filter(|threshold| {
+            let threshold = *threshold as f64 / 100.0;
+
+            usage < threshold && updated_usage >= threshold
+        })
+        .collect()
+}
+
+fn check_deadline(wait_until: Instant, deadline: Option<Instant>) -> Result<(), ModelError> {
+    match deadline {
+        Some(deadline) if wait_until > deadline => Err(ModelError::UserRateLimit),
+        _ => Ok(()),
+    }
+}
+
+fn check_max_wait(
+    now: Instant,
+    timestamps: &[(Instant, Option<Duration>)],
+    default_max_wait: Duration,
+) -> Result<Option<Instant>, Duration> {
+    let mut latest = None;
+
+    for (wait_until, max_wait) in timestamps {
+        let wait = wait_until.saturating_duration_since(now);
+
+        if wait > max_wait.unwrap_or(default_max_wait) {
+            return Err(wait);
+        }
+
+        latest = latest.max(Some(*wait_until));
+    }
+
+    Ok(latest)
+}
+
+fn limit_quota_response(
+    clock: &LimiterClock,
+    quota: &mut Quota,
+    response: &limiter::Response,
+) -> Result<Instant, ModelError> {
+    let mut wait_until = Instant::now();
+
+    for limit in &mut quota.limits {
+        match limit.response(clock, response) {
+            LimiterResult::Ready => {}
+            LimiterResult::WaitUntil(timestamp) => wait_until = wait_until.max(timestamp),
+            LimiterResult::Oversized => return Err(ModelError::UserRateLimit),
+        }
+    }
+
+    Ok(wait_until)
+}
+
+// Reverts a request's quota usage if it is dropped before the model responds, such as when the client disconnects.
+struct QuotaReservation<'a> {
+    database: &'a Database,
+    clock: &'a LimiterClock,
+    quotas: &'a [Uuid],
+    arrived_at: Instant,
+    estimated_tokens: u64,
+    completed: bool,
+    rejection: Option<&'static str>,
+}
+
+impl QuotaReservation<'_> {
+    fn complete(&mut self) {
+        self.completed = true;
+    }
+
+    // Requests which are rejected while waiting for their quotas aren't logged as cancelled by the client.
+    fn reject(&mut self, reason: &'static str) {
+        self.rejection = Some(reason);
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        match self.rejection {
+            Some(reason) => tracing::info!(
+                reason,
+                "Request was rejected before being sent to the model, reverting quota usage"
+            ),
+            None => tracing::info!(
+                http.response.status_code = 499,
+                "Request was cancelled before the model responded, reverting quota usage"
+            ),
+        }
+
+        let limiter_response = limiter::Response {
+            request: limiter::Request {
+                arrived_at: self.arrived_at,
+                estimated_tokens: self.estimated_tokens,
+            },
+            actual_tokens: 0,
+        };
+
+        let _ =
+            self.database
+                .modify_items_skip_missing("quotas", self.quotas, |quota: &mut Quota| {
+                    limit_quota_response(self.clock, quota, &limiter_response)
+                });
+    }
+}
+
+// Limits the number of in-flight requests using each Quota which has a max_concurrent set.
+#[derive(Debug, Default)]
+pub struct QuotaConcurrency {
+    semaphores: Mutex<HashMap<Uuid, QuotaSemaphore>>,
+}
+
+#[derive(Debug)]
+struct QuotaSemaphore {
+    limit: u32,
+    semaphore: Arc<Semaphore>,
+    // The number of permits which still need to be removed after the limit was lowered, as they were held by in-flight requests at the time.
+    excess: usize,
+}
+
+impl QuotaSemaphore {
+    fn new(limit: u32) -> Self {
+        QuotaSemaphore {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            excess: 0,
+        }
+    }
+
+    // The existing semaphore is resized rather than replaced, so that requests which are already in flight keep counting towards the new limit.
+    fn resize(&mut self, limit: u32) {
+        if limit > self.limit {
+            let added = (limit - self.limit) as usize;
+            let cancelled = added.min(self.excess);
+
+            self.excess -= cancelled;
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            self.excess += (self.limit - limit) as usize;
+        }
+        self.limit = limit;
+
+        self.excess -= self.semaphore.forget_permits(self.excess);
+    }
+}
+
+impl QuotaConcurrency {
+    // Requests over a Quota's limit are rejected immediately instead of being queued. The returned permits are held until the request completes or is cancelled.
+    fn acquire(&self, quotas: &[Quota]) -> Result<Vec<OwnedSemaphorePermit>, ModelError> {
+        let mut semaphores = match self.semaphores.lock() {
+            Ok(semaphores) => semaphores,
+            Err(_) => return Err(ModelError::InternalError),
+        };
+
+        let mut permits = Vec::new();
+        for quota in quotas {
+            let max_concurrent = match quota.max_concurrent {
+                Some(max_concurrent) => max_concurrent,
+                None => {
+                    semaphores.remove(&quota.uuid);
+                    continue;
+                }
+            };
+
+            let semaphore = semaphores
+                .entry(quota.uuid)
+                .or_insert_with(|| QuotaSemaphore::new(max_concurrent));
+            semaphore.resize(max_concurrent);
+
+            match semaphore.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permits.push(permit),
+                Err(_) => {
+                    tracing::debug!(
+                        quota = %quota.uuid,
+                        max_concurrent = max_concurrent,
+                        "Quota concurrency limit reached"
+                    );
+                    return Err(ModelError::UserRateLimit);
+                }
+            }
+        }
+
+        Ok(permits)
+    }
+
+    // Called when a Quota is deleted. Requests which are still in flight keep their permits until they complete.
+    fn remove(&self, quota: &Uuid) {
+        if let Ok(mut semaphores) = self.semaphores.lock() {
+            semaphores.remove(quota);
+        }
+    }
+}
+
+// Counts the requests in flight to each model, so that capability-based routing can prefer the least loaded model.
+#[derive(Debug, Default)]
+pub struct ModelLoad {
+    active: Mutex<HashMap<Uuid, usize>>,
+}
+
+impl ModelLoad {
+    // The request is counted until the returned value is dropped.
+    fn start(self: &Arc<Self>, model: Uuid) -> ActiveModelRequest {
+        if let Ok(mut active) = self.active.lock() {
+            *active.entry(model).or_default() += 1;
+        }
+
+        ActiveModelRequest {
+            load: self.clone(),
+            model,
+        }
+    }
+
+    fn get(&self, model: Uuid) -> usize {
+        self.active
+            .lock()
+            .ok()
+            .and_then(|active| active.get(&model).copied())
+            .unwrap_or_default()
+    }
+}
+
+struct ActiveModelRequest {
+    load: Arc<ModelLoad>,
+    model: Uuid,
+}
+
+impl Drop for ActiveModelRequest {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.load.active.lock() {
+            if let Some(count) = active.get_mut(&self.model) {
+                *count = count.saturating_sub(1);
+
+                if *count == 0 {
+                    active.remove(&self.model);
+                }
+            }
+        }
+    }
+}
+
+// Tracks the tasks which update Quotas once a streamed response ends, so that they can finish before the database is flushed on shutdown.
+#[derive(Debug)]
+pub struct StreamTasks {
+    running: watch::Sender<usize>,
+}
+
+impl Default for StreamTasks {
+    fn default() -> Self {
+        StreamTasks {
+            running: watch::channel(0).0,
+        }
+    }
+}
+
+impl StreamTasks {
+    fn spawn(self: &Arc<Self>, task: impl Future<Output = ()> + Send + 'static) {
+        self.running.send_modify(|running| *running += 1);
+
+        let tasks = self.clone();
+        tokio::spawn(async move {
+            task.await;
+            tasks.running.send_modify(|running| *running -= 1);
+        });
+    }
+
+    pub async fn wait(&self) {
+        let mut running = self.running.subscribe();
+
+        while *running.borrow_and_update() > 0 {
+            if running.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// The result of preparing a request for a specific model, which is used to reserve the request's Quotas.
+#[derive(Debug, Clone, Copy)]
+struct PreparedRequest {
+    convert_to_completion: bool,
+    prompt_tokens: u64,
+    size_tokens: Option<u64>,
+    max_tokens: Option<u64>,
+    count: u64,
+    estimated_tokens: u64,
+}
+
+// Applies the model's settings to the request and checks it against the model's limits. This doesn't send the request or update any Quotas, so it can also be used to validate requests.
+fn prepare_model_request(
+    state: &AppState,
+    auth: &Authenticated,
+    model: &Model,
+    request: &mut ModelRequest,
+) -> Result<PreparedRequest, ModelError> {
+    request.tokenizer = get_model_tokenizer(model, state.fallback_tokenizer);
+
+    let convert_to_completion =
+        request.r#type == RequestType::TextChat && !model.types.contains(&RequestType::TextChat);
+    if convert_to_completion {
+        request.convert_chat_to_completion()?;
+    }
+
+    if let Some(limits) = state.json_schema_limits {
+        if model.json_schema_support == JsonSchemaSupport::Supported {
+            request.validate_json_schema(limits)?;
+        }
+    }
+
+    request.apply_json_schema_support(model.json_schema_support)?;
+    request.apply_token_input_support(model.token_input_support)?;
+    if let Some(range) = model.penalty_range {
+        request.apply_penalty_range(range)?;
+    }
+    if let Some(sizes) = &model.image_sizes {
+        request.apply_image_sizes(sizes)?;
+    }
+    check_split_count(model, request)?;
+
+    if model.proxy_metadata && request.proxy_metadata.is_none() {
+        request.proxy_metadata = Some(ProxyMetadata {
+            deployment: state.deployment_name.clone(),
+        });
+    }
+    request.fingerprint = Some(get_system_fingerprint(model));
+
+    if let Some(name) = &request.param_profile {
+        request.apply_param_profile(get_param_profile(model, name)?);
+    }
+
+    let prompt_tokens = match &model.prompt_template {
+        Some(template) => {
+            let user_uuid = auth.user.uuid.to_string();
+            let prompt = model::render_prompt_template(
+                template,
+                &[
+                    ("user_label", auth.user.label.as_str()),
+                    ("user_uuid", user_uuid.as_str()),
+                ],
+            );
+
+            request.prepend_system_prompt(&prompt)
+        }
+        None => 0,
+    };
+
+    let model_max_tokens = model.api.get_max_tokens();
+    let request_max_tokens = request.get_max_tokens();
+    let request_count = request.get_count() as u64;
+    if request_max_tokens.unwrap_or(model_max_tokens) > model_max_tokens {
+        return Err(ModelError::UserRateLimit);
+    }
+    if model.check_context_length {
+        request.check_context_length(
+            model_max_tokens,
+            model.api.get_default_max_tokens(),
+            request.tokenizer,
+        )?;
+    }
+
+    let size_tokens = model
+        .size_charging
+        .map(|charging| request.get_size_tokens(charging));
+
+    if let Some(pricing) = &model.pricing {
+        let input_tokens = request
+            .get_token_count(request.tokenizer)
+            .unwrap_or(prompt_tokens)
+            .max(size_tokens.unwrap_or_default());
+        let output_tokens = request_max_tokens
+            .unwrap_or(model_max_tokens)
+            .saturating_mul(request_count);
+
+        check_request_cost(auth, pricing, input_tokens, output_tokens)?;
+    }
+
+    let output_token_weight = model.output_token_weight.unwrap_or(1.0);
+    let weighted_max_tokens = (request_max_tokens
+        .or(model.api.get_default_max_tokens())
+        .unwrap_or(model_max_tokens) as f64
+        * output_token_weight.max(1.0))
+    .ceil() as u64;
+
+    Ok(PreparedRequest {
+        convert_to_completion,
+        prompt_tokens,
+        size_tokens,
+        max_tokens: request_max_tokens,
+        count: request_count,
+        estimated_tokens: weighted_max_tokens
+            .saturating_add(prompt_tokens)
+            .saturating_mul(request_count)
+            .max(size_tokens.unwrap_or_default()),
+    })
+}
+
+// A request is limited by its User's Quotas, the Quotas of the User's Roles, and the model's Quotas.
+fn get_request_quotas(auth: &Authenticated, model: &Model) -> Vec<Uuid> {
+    let quotas: HashSet<Uuid> = auth
+        .user
+        .quotas
+        .iter()
+        .chain(auth.roles.iter().flat_map(|role| role.quotas.iter()))
+        .chain(model.quotas.iter())
+        .copied()
+        .collect();
+
+    quotas.into_iter().collect()
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+async fn send_model_request(
+    state: &AppState,
+    auth: &Authenticated,
+    model: &Model,
+    mut request: ModelRequest,
+    deadline: Option<Instant>,
+) -> Result<ModelResponse, ModelError> {
+    if cfg!(debug_assertions) {
+        tracing::debug!(model = ?model);
+    } else {
+        tracing::debug!(model = ?model.uuid);
+    }
+
+    let prepared = prepare_model_request(state, auth, model, &mut request)?;
+    let request_type = request.r#type;
+    let size_tokens = prepared.size_tokens;
+
+    if let Some(max_tokens) = prepared.max_tokens {
+        tracing::debug!(histogram.request.max_tokens = max_tokens, unit = "tokens");
+    }
+    tracing::debug!(histogram.request.count = prepared.count);
+    if let Some(size_tokens) = size_tokens {
+        tracing::debug!(histogram.request.size_tokens = size_tokens, unit = "tokens");
+    }
+
+    let quotas = get_request_quotas(auth, model);
+
+    tracing::debug!(quotas = ?quotas);
+
+    // Permits (and the model's load count) are released when this function returns (or once the response's stream ends), regardless of whether the request succeeded.
+    let permits = match state
+        .database
+        .get_items_skip_missing::<Uuid, Quota>("quotas", &quotas)
+    {
+        DatabaseValueResult::Success(items) => (
+            state.quota_concurrency.acquire(&items)?,
+            state.model_load.start(model.uuid),
+        ),
+        _ => return Err(ModelError::InternalError),
+    };
+
+    let output_token_weight = model.output_token_weight.unwrap_or(1.0);
+    let limiter_request = limiter::Request {
+        arrived_at: auth.timestamp,
+        estimated_tokens: prepared.estimated_tokens,
+    };
+    tracing::debug!(
+        histogram.quota.estimated_tokens = limiter_request.estimated_tokens,
+        unit = "tokens"
+    );
+
+    let limit_request = |quota: &mut Quota| {
+        let mut wait_until = Instant::now();
+        let mut crossings = Vec::new();
+
+        for limit in &mut quota.limits {
+            let usage = limit.get_usage(&state.clock, limiter_request.arrived_at);
+
+            match limit.request(&state.clock, &limiter_request) {
+                LimiterResult::Ready => {}
+                LimiterResult::WaitUntil(timestamp) => wait_until = wait_until.max(timestamp),
+                LimiterResult::Oversized => return Err(ModelError::UserRateLimit),
+            }
+
+            let updated_usage = limit.get_usage(&state.clock, limiter_request.arrived_at);
+            for threshold in
+                get_crossed_thresholds(&quota.notification_thresholds, usage, updated_usage)
+            {
+                crossings.push((quota.uuid, threshold, updated_usage));
+            }
+        }
+
+        Ok((
+            quota.uuid,
+            wait_until,
+            quota.max_wait.map(Duration::from_secs),
+            crossings,
+        ))
+    };
+
+    let mut queue_time = Duration::ZERO;
+
+    let mut reservation =
+        match state
+            .database
+            .modify_items_skip_missing("quotas", &quotas, limit_request)
+        {
+            DatabaseFunctionResult::Success(results) => {
+                // Events are sent after the transaction completes, as the transaction may be retried.
+                for (quota, threshold, usage) in
+                    results.iter().flat_map(|(_, _, _, crossings)| crossings)
+                {
+                    tracing::info!(
+                        quota = %quota,
+                        user = %auth.user.uuid,
+                        threshold = threshold,
+                        usage = usage * 100.0,
+                        "Quota usage crossed notification threshold"
+                    );
+                }
+                let timestamps: Vec<(Instant, Option<Duration>)> = results
+                    .iter()
+                    .map(|(_, wait_until, max_wait, _)| (*wait_until, *max_wait))
+                    .collect();
+
+                let mut reservation = QuotaReservation {
+                    database: &state.database,
+                    clock: &state.clock,
+                    quotas: &quotas,
+                    arrived_at: limiter_request.arrived_at,
+                    estimated_tokens: limiter_request.estimated_tokens,
+                    completed: false,
+                    rejection: None,
+                };
+
+                let wait_until =
+                    match check_max_wait(Instant::now(), &timestamps, state.max_rate_limit_wait) {
+                        Ok(wait_until) => wait_until,
+                        Err(retry_after) => {
+                            reservation.reject("max_wait exceeded");
+                            return Ok(ModelResponse::from(ModelError::UserRateLimit)
+                                .with_retry_after(retry_after));
+                        }
+                    };
+                let now = Instant::now();
+                for (quota, wait_until, _, _) in &results {
+                    tracing::debug!(
+                        histogram.quota.wait.duration =
+                            wait_until.saturating_duration_since(now).as_secs_f64(),
+                        quota = %quota,
+                        unit = "s"
+                    );
+                }
+
+                if let Some(wait_until) = wait_until {
+                    if let Err(error) = check_deadline(wait_until, deadline) {
+                        reservation.reject("deadline exceeded");
+                        return Err(error);
+                    }
+
+                    queue_time += wait_until.saturating_duration_since(Instant::now());
+                    time::sleep_until(time::Instant::from_std(wait_until))
+                        .instrument(tracing::debug_span!("rate_limit_request"))
+                        .await
+                }
+
+                reservation
+            }
+            DatabaseFunctionResult::FunctionError(error) => return Err(error),
+            DatabaseFunctionResult::BackendError => return Err(ModelError::InternalError),
+        };
+
+    let generate = async {
+        match request.split_n().filter(|_| model.split_n) {
+            Some(requests) => send_split_requests(state, model, requests, deadline).await,
+            None => {
+                let coalescing_key = request.get_coalescing_key(model.uuid, &state.coalescing_key);
+                let generate = model.api.generate(
+                    &state.http,
+                    &state.pacer,
+                    &state.retry_budget,
+                    &state.key_cooldowns,
+                    model.uuid,
+                    request,
+                    deadline,
+                    state.log_upstream_requests,
+                );
+
+                match (&state.coalescer, coalescing_key) {
+                    (Some(coalescer), Some(key)) => match coalescer.run(key, generate).await {
+                        (mut response, true) => {
+                            response.set_tag(Uuid::new_v4());
+                            response
+                        }
+                        (response, false) => response,
+                    },
+                    _ => generate.await,
+                }
+            }
+        }
+    };
+    let started = Instant::now();
+    let mut response =
+        get_response_before_timeout(model.request_timeout.map(Duration::from_secs), generate).await;
+    reservation.complete();
+    tracing::debug!(
+        histogram.model.request.duration = started.elapsed().as_secs_f64(),
+        model = %model.uuid,
+        unit = "s"
+    );
+
+    let pricing = model.pricing;
+    let get_usage_record =
+        |status: StatusCode, usage: &TokenUsage, charged_tokens: u64| UsageRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            user: auth.user.uuid,
+            model: model.uuid,
+            r#type: request_type,
+            status: status.as_u16(),
+            input_tokens: usage.input,
+            output_tokens: usage.output,
+            total_tokens: usage.total,
+            charged_tokens,
+            cost: pricing.map(|pricing| pricing.get_usage_cost(usage)),
+        };
+
+    // Streamed responses are sent to the client right away, and their Quotas are updated once the stream ends. Streams which end without reporting usage are charged their estimated tokens.
+    if let Some(usage) = response.take_stream_usage() {
+        let stream_tasks = state.stream_tasks.clone();
+        let state = state.clone();
+        let quotas = quotas.clone();
+        let mut record = get_usage_record(
+            response.status,
+            &TokenUsage::default(),
+            limiter_request.estimated_tokens,
+        );
+
+        stream_tasks.spawn(
+            async move {
+                let usage = usage.await;
+                drop(permits);
+
+                if let Ok(Some(usage)) = usage {
+                    record.input_tokens = usage.input;
+                    record.output_tokens = usage.output;
+                    record.total_tokens = usage.total;
+                    record.charged_tokens = usage.weighted(output_token_weight);
+                    record.cost = pricing.map(|pricing| pricing.get_usage_cost(&usage));
+                }
+
+                if let Err(error) = complete_model_request(&state, &quotas, limiter_request, record)
+                {
+                    tracing::error!("Unable to update quotas after stream: {:?}", error);
+                }
             }
+            .in_current_span(),
+        );
+
+        response.timings.queue = Some(queue_time + response.timings.queue.unwrap_or_default());
+        return Ok(response);
+    }
+
+    // Size-based charging only replaces the flat charge used for responses without token counts.
+    let charged_tokens = match size_tokens {
+        Some(size_tokens)
+            if response.status.is_success()
+                && response.usage.input.is_none()
+                && response.usage.output.is_none() =>
+        {
+            size_tokens
         }
-        DatabaseFunctionResult::FunctionError(error) => return Err(error),
-        DatabaseFunctionResult::BackendError => return Err(ModelError::InternalError),
+        _ => response.usage.weighted(output_token_weight),
+    };
+    let record = get_usage_record(response.status, &response.usage, charged_tokens);
+    if let Some(wait_until) = complete_model_request(state, &quotas, limiter_request, record)? {
+        queue_time += wait_until.saturating_duration_since(Instant::now());
+        time::sleep_until(time::Instant::from_std(wait_until))
+            .instrument(tracing::debug_span!("rate_limit_response"))
+            .await
+    }
+
+    response.timings.queue = Some(queue_time + response.timings.queue.unwrap_or_default());
+
+    if prepared.convert_to_completion {
+        response.convert_completion_to_chat();
     }
 
     Ok(response)
 }
 
+// A model's request timeout fails the attempt as a backend error (rather than as an exceeded deadline), so that the request can be retried using the model's fallbacks. The model's Quotas are still updated as usual.
+async fn get_response_before_timeout(
+    timeout: Option<Duration>,
+    response: impl Future<Output = ModelResponse>,
+) -> ModelResponse {
+    match timeout {
+        Some(timeout) => match time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                tracing::warn!("Model request timed out after {:?}", timeout);
+                ModelResponse::from(ModelError::BackendError)
+            }
+        },
+        None => response.await,
+    }
+}
+
+// Split requests are all sent to the backend at once, so the number of choices they can be split into is limited.
+fn check_split_count(model: &Model, request: &ModelRequest) -> Result<(), ModelError> {
+    if model.split_n
+        && (request.r#type == RequestType::TextChat
+            || request.r#type == RequestType::TextCompletion)
+        && request.get_count() as u64 > model.max_split_n
+    {
+        return Err(ModelError::ParameterOutOfRange {
+            param: "n",
+            min: 1.0,
+            max: model.max_split_n as f64,
+        });
+    }
+
+    Ok(())
+}
+
+// Sends each of the requests split from a request for multiple choices in parallel, merging their responses in order.
+async fn send_split_requests(
+    state: &AppState,
+    model: &Model,
+    requests: Vec<ModelRequest>,
+    deadline: Option<Instant>,
+) -> ModelResponse {
+    let mut tasks = JoinSet::new();
+
+    for (index, request) in requests.into_iter().enumerate() {
+        let http_client = state.http.clone();
+        let pacer = state.pacer.clone();
+        let retry_budget = state.retry_budget.clone();
+        let key_cooldowns = state.key_cooldowns.clone();
+        let api = model.api.clone();
+        let uuid = model.uuid;
+        let log_upstream_requests = state.log_upstream_requests;
+
+        tasks.spawn(
+            async move {
+                let response = api
+                    .generate(
+                        &http_client,
+                        &pacer,
+                        &retry_budget,
+                        &key_cooldowns,
+                        uuid,
+                        request,
+                        deadline,
+                        log_upstream_requests,
+                    )
+                    .await;
+
+                (index, response)
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut responses = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(response) => responses.push(response),
+            Err(_) => return ModelResponse::from(ModelError::InternalError),
+        }
+    }
+    responses.sort_by_key(|(index, _)| *index);
+
+    ModelResponse::merge_choices(
+        responses
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect(),
+    )
+}
+
+// Records the request's actual token usage, returning when the response can be sent without exceeding the request's Quotas.
+fn complete_model_request(
+    state: &AppState,
+    quotas: &[Uuid],
+    request: limiter::Request,
+    record: UsageRecord,
+) -> Result<Option<Instant>, ModelError> {
+    let limiter_response = limiter::Response {
+        request,
+        actual_tokens: record.charged_tokens,
+    };
+
+    if state.record_usage {
+        if let DatabaseActionResult::BackendError =
+            state
+                .database
+                .insert_item("usage", &Uuid::now_v7(), &record)
+        {
+            tracing::warn!("Unable to record usage: {:?}", record);
+        }
+    }
+    tracing::debug!(
+        histogram.quota.actual_tokens = limiter_response.actual_tokens,
+        unit = "tokens"
+    );
+    if let Some(input_tokens) = record.input_tokens {
+        tracing::debug!(
+            histogram.quota.actual_tokens.input = input_tokens,
+            unit = "tokens"
+        );
+    }
+    if let Some(output_tokens) = record.output_tokens {
+        tracing::debug!(
+            histogram.quota.actual_tokens.output = output_tokens,
+            unit = "tokens"
+        );
+    }
+    tracing::debug!(
+        histogram.quota.estimate_offset = limiter_response.request.estimated_tokens as i64
+            - limiter_response.actual_tokens as i64,
+        unit = "tokens"
+    );
+
+    let limit_response =
+        |quota: &mut Quota| limit_quota_response(&state.clock, quota, &limiter_response);
+
+    match state
+        .database
+        .modify_items_skip_missing("quotas", quotas, limit_response)
+    {
+        DatabaseFunctionResult::Success(timestamps) => Ok(timestamps.iter().max().cloned()),
+        DatabaseFunctionResult::FunctionError(error) => Err(error),
+        DatabaseFunctionResult::BackendError => Err(ModelError::InternalError),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 enum StringOrUuid {
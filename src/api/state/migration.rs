@@ -1,27 +1,309 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs,
+    io::ErrorKind,
+    ops::Range,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
 
 use serde::{de::DeserializeOwned, Serialize};
-use sled::{
-    transaction::{ConflictableTransactionError, TransactionError, Transactional},
-    Batch, Db, Mode,
+use sled::{Batch, Db, Mode};
+use tokio::time;
+
+use super::{
+    super::{Model, Quota, Role, User},
+    Database,
 };
 
-use super::Database;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+// sled releases a database's lock in the background after it's closed, so the migrated database may still be locked for a moment after the migration finishes.
+const MIGRATED_LOCK_WAIT: Duration = Duration::from_secs(5);
+const MIGRATED_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// The oldest database version which can be migrated automatically.
+const FIRST_DATABASE_VERSION: usize = 1;
+
+// Each migration upgrades the database by one version, starting from FIRST_DATABASE_VERSION. New migrations must be added to the end of the list, as the current version is derived from its length.
+const MIGRATIONS: [Migration; 1] = [add_record_fields];
+
+const CURRENT_DATABASE_VERSION: usize = FIRST_DATABASE_VERSION + MIGRATIONS.len();
+
+type Migration = fn(&Db) -> Result<(), sled::Error>;
+
+// The layouts of records stored by version 1 of the database. The database's encoding isn't self-describing, so records can only be decoded using the exact layout they were encoded with.
+mod v1 {
+    use std::{
+        collections::HashSet,
+        time::{Duration, SystemTime},
+    };
+
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use crate::{limiter::LimitItem, model::RequestType};
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct User {
+        label: String,
+        uuid: Uuid,
+        admin: bool,
+        api_keys: HashSet<String>,
+        roles: HashSet<Uuid>,
+        models: HashSet<Uuid>,
+        quotas: HashSet<Uuid>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Role {
+        label: String,
+        uuid: Uuid,
+        admin: bool,
+        models: HashSet<Uuid>,
+        quotas: HashSet<Uuid>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Model {
+        label: String,
+        uuid: Uuid,
+        name: String,
+        types: HashSet<RequestType>,
+        api: ModelBackend,
+        quotas: HashSet<Uuid>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum ModelBackend {
+        OpenAI(OpenAIModelBackend),
+        Loopback,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct OpenAIModelBackend {
+        model_string: String,
+        model_context_len: Option<u64>,
+        openai_api_base: String,
+        openai_api_key: String,
+        openai_organization: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub(super) struct Quota {
+        label: String,
+        uuid: Uuid,
+        limits: Vec<Limit>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Limit {
+        count: u64,
+        r#type: LimitItem,
+        period: u64,
+        state: Option<LimiterState>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct LimiterState {
+        uuid: Uuid,
+        epoch: Option<SystemTime>,
+        elasped: Option<Duration>,
+    }
+}
+
+// Version 2 added fields to users, roles, models and quotas (including fields in the middle of records). Records are converted into the current layouts, so a migration which changes them again must first freeze the version 2 layouts (like the version 1 layouts above).
+fn add_record_fields(database: &Db) -> Result<(), sled::Error> {
+    convert_table::<v1::User, User>(database, "users")?;
+    convert_table::<v1::Role, Role>(database, "roles")?;
+    convert_table::<v1::Model, Model>(database, "models")?;
+    convert_table::<v1::Quota, Quota>(database, "quotas")
+}
+
+// Records are converted through JSON, which is self-describing, so that fields are matched by name and new fields are filled in with their defaults. Records which can't be decoded are left as-is, as they were already unreadable.
+fn convert_table<P, C>(database: &Db, table: &str) -> Result<(), sled::Error>
+where
+    P: DeserializeOwned + Serialize,
+    C: DeserializeOwned + Serialize,
+{
+    let tree = database.open_tree(table.as_bytes())?;
+    let mut batch = Batch::default();
+
+    for item in tree.iter() {
+        let (key, value) = item?;
+
+        let converted = postcard::from_bytes::<P>(&value)
+            .ok()
+            .and_then(|record| serde_json::to_value(record).ok())
+            .and_then(|record| serde_json::from_value::<C>(record).ok())
+            .and_then(|record| postcard::to_stdvec(&record).ok());
+
+        match converted {
+            Some(converted) => batch.insert(key, converted),
+            None => tracing::warn!("Unable to migrate unreadable record in {} table", table),
+        }
+    }
+
+    tree.apply_batch(batch)
+}
+
+fn get_database_location(path: &Path, version: usize) -> PathBuf {
+    path.join(PathBuf::from(format!("version-{}", version)))
+}
+
+fn open_database_location(location: PathBuf) -> Result<Db, sled::Error> {
+    sled::Config::default()
+        .path(location)
+        .mode(Mode::HighThroughput)
+        .open()
+}
+
+// Incomplete migrations (and other unrelated files) are ignored.
+fn find_database_version(path: &Path) -> Result<Option<usize>, sled::Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut newest_version = None;
+
+    for entry in fs::read_dir(path)? {
+        let version = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("version-"))
+            .and_then(|version| version.parse::<usize>().ok());
+
+        newest_version = newest_version.max(version);
+    }
+
+    Ok(newest_version)
+}
+
+// Returns the database versions which need to be migrated from, in order.
+fn plan_migrations(path: &Path) -> Result<Range<usize>, sled::Error> {
+    match find_database_version(path)? {
+        Some(version) if version > CURRENT_DATABASE_VERSION => Err(sled::Error::Unsupported(format!(
+            "The database in {} is version {}, but this version of the proxy only supports version {}. Downgrading the database is not supported; use a newer version of the proxy, or a different database folder",
+            path.display(),
+            version,
+            CURRENT_DATABASE_VERSION
+        ))),
+        Some(version) if version < FIRST_DATABASE_VERSION => Err(sled::Error::Unsupported(format!(
+            "The database in {} is version {}, which is too old to be migrated to version {}",
+            path.display(),
+            version,
+            CURRENT_DATABASE_VERSION
+        ))),
+        Some(version) => Ok(version..CURRENT_DATABASE_VERSION),
+        None => Ok(CURRENT_DATABASE_VERSION..CURRENT_DATABASE_VERSION),
+    }
+}
+
+// Migrations are applied to a copy of the database, which only becomes the current version once every migration has succeeded. The older version is left in place as a backup.
+fn migrate(path: &Path, versions: Range<usize>) -> Result<(), sled::Error> {
+    let current_location = get_database_location(path, CURRENT_DATABASE_VERSION);
+    let staging_location = path.join(PathBuf::from(format!(
+        "version-{}.migrating",
+        CURRENT_DATABASE_VERSION
+    )));
+
+    if staging_location.exists() {
+        fs::remove_dir_all(&staging_location)?;
+    }
+
+    {
+        let past_database = open_database_location(get_database_location(path, versions.start))?;
+        let database = open_database_location(staging_location.clone())?;
+        database.import(past_database.export());
+
+        for version in versions {
+            tracing::info!(
+                "Migrating database from version {} to version {}",
+                version,
+                version + 1
+            );
+
+            MIGRATIONS[version - FIRST_DATABASE_VERSION](&database)?;
+        }
+
+        database.flush()?;
+    }
+
+    fs::rename(staging_location, current_location)?;
+
+    Ok(())
+}
 
 impl Database {
     pub fn open(path: &Path) -> Result<Self, sled::Error> {
-        let current_database_location = path.join(PathBuf::from("version-1"));
-        let past_database_location = path.join(PathBuf::from("version-0"));
+        let versions = plan_migrations(path)?;
+
+        let location = get_database_location(path, CURRENT_DATABASE_VERSION);
+        if versions.is_empty() {
+            return Ok(Database {
+                database: open_database_location(location)?,
+            });
+        }
+
+        migrate(path, versions)?;
+
+        let started = Instant::now();
+        loop {
+            match open_database_location(location.clone()) {
+                Err(error)
+                    if Self::is_lock_error(&error) && started.elapsed() < MIGRATED_LOCK_WAIT =>
+                {
+                    thread::sleep(MIGRATED_LOCK_RETRY_INTERVAL);
+                }
+                result => return result.map(|database| Database { database }),
+            }
+        }
+    }
 
-        if past_database_location.exists() && !current_database_location.exists() {
-            todo!()
+    // Logs the migrations that opening the database would run, without applying them.
+    pub fn log_planned_migrations(path: &Path) -> Result<(), sled::Error> {
+        let versions = plan_migrations(path)?;
+
+        if versions.is_empty() {
+            tracing::info!(
+                "Database is up to date (version {}), no migrations are needed",
+                CURRENT_DATABASE_VERSION
+            );
+        }
+
+        for version in versions {
+            tracing::info!(
+                "Database would be migrated from version {} to version {}",
+                version,
+                version + 1
+            );
+        }
+
+        Ok(())
+    }
+
+    // Retries opening the database while it's locked by another process, such as a previous instance of the proxy which hasn't finished shutting down.
+    pub async fn open_with_lock_wait(path: &Path, wait: Duration) -> Result<Self, sled::Error> {
+        let started = Instant::now();
+
+        loop {
+            match Self::open(path) {
+                Err(error) if Self::is_lock_error(&error) && started.elapsed() < wait => {
+                    tracing::debug!("Database is locked, retrying: {}", error);
+                    time::sleep(LOCK_RETRY_INTERVAL).await;
+                }
+                result => return result,
+            }
         }
+    }
 
-        Ok(Database {
-            database: sled::Config::default()
-                .path(current_database_location)
-                .mode(Mode::HighThroughput)
-                .open()?,
-        })
+    pub fn is_lock_error(error: &sled::Error) -> bool {
+        match error {
+            sled::Error::Io(error) => {
+                error.kind() == ErrorKind::WouldBlock
+                    || error.to_string().contains("could not acquire lock")
+            }
+            _ => false,
+        }
     }
 }
@@ -59,6 +59,17 @@ impl Database {
         Ok(())
     }
 
+    // The database is considered ready if its tables can be opened.
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub(super) fn is_ready(&self) -> bool {
+        self.database.open_tree("users".as_bytes()).is_ok()
+    }
+
+    #[tracing::instrument(skip(self), level = "trace")]
+    pub(super) fn get_size_on_disk(&self) -> Option<u64> {
+        self.database.size_on_disk().ok()
+    }
+
     #[tracing::instrument(skip(self), level = "trace")]
     pub fn is_table_empty(&self, table: &str) -> bool {
         match self.database.open_tree(table.as_bytes()) {
@@ -88,6 +99,34 @@ impl Database {
         }
     }
 
+    #[tracing::instrument(skip(self, start, end), level = "debug")]
+    pub(super) fn iter_table_range<K, V>(
+        &self,
+        table: &str,
+        start: &K,
+        end: &K,
+    ) -> DatabaseValueResult<impl Iterator<Item = V> + Send + 'static>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        let range = match (postcard::to_stdvec(start), postcard::to_stdvec(end)) {
+            (Ok(start), Ok(end)) => start..end,
+            _ => return DatabaseValueResult::BackendError,
+        };
+
+        match self.database.open_tree(table.as_bytes()) {
+            Ok(tree) => DatabaseValueResult::Success(tree.range(range).filter_map(|item| {
+                item.ok()
+                    .and_then(|(_, value)| postcard::from_bytes(&value).ok())
+            })),
+            Err(error) => {
+                tracing::error!("Unable to open \"{}\" table: {}", table, error);
+                DatabaseValueResult::BackendError
+            }
+        }
+    }
+
     #[tracing::instrument(skip(self, key), level = "debug")]
     pub(super) fn get_item<K, V>(&self, table: &str, key: &K) -> DatabaseValueResult<V>
     where
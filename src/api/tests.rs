@@ -0,0 +1,1734 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::{oneshot, Semaphore},
+};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::{
+    limiter::{self, LimiterClock, LimiterResult},
+    model::{
+        ApiKeyCooldowns, BodyNormalization, CoalescingKeySettings, RequestPacer, RetryBudget,
+        SystemFingerprint,
+    },
+    AppState,
+};
+
+use super::{
+    api_router, can_capture_requests, capture::CapturedRequest, check_deadline, check_max_wait,
+    check_readiness, check_request_cost, check_split_count, find_conflicting_model,
+    find_invalid_example, get_accessible_models, get_crossed_thresholds, get_deprecation_header,
+    get_enabled_request_capture, get_healthy_models, get_model_tokenizer, get_param_profile,
+    get_region, get_request_quotas, get_response_before_timeout, get_system_fingerprint,
+    get_upstream_timeout, get_usage_key, has_unknown_tokenizer, is_admin, list_model_examples,
+    list_param_profiles, parse_deadline, prefer_healthy_models, select_model,
+    select_model_by_capabilities, state::DatabaseLinkedInsertionResult, suggest_model_names,
+    ActiveRequest, Authenticated, Database, DatabaseFunctionResult, DatabaseValueResult, Model,
+    ModelError, ModelHealth, ModelLoad, ModelRequest, ModelResponse, Pricing, Quota,
+    QuotaConcurrency, QuotaReservation, RequestCapture, RequestType, Role, StreamTasks, Tokenizer,
+    User,
+};
+
+#[test]
+fn model_prompt_template_default() {
+    let model: Model = serde_json::from_value(json!({ "api": "Loopback" })).unwrap();
+
+    assert_eq!(model.prompt_template, None);
+}
+
+#[test]
+fn model_tokenizer_fallback() {
+    let model = |tokenizer: Value| -> Model {
+        serde_json::from_value(json!({ "api": "Loopback", "tokenizer": tokenizer })).unwrap()
+    };
+
+    let fallback = Some(Tokenizer::R50kBase);
+    assert_eq!(
+        get_model_tokenizer(&model(Value::Null), fallback),
+        Some(Tokenizer::Cl100kBase)
+    );
+    assert_eq!(
+        get_model_tokenizer(&model(json!("p50k_base")), fallback),
+        Some(Tokenizer::P50kBase)
+    );
+    assert!(!has_unknown_tokenizer(&model(json!("p50k_base"))));
+
+    // Unknown tokenizers use the fallback tokenizer, unless it has been disabled.
+    let misconfigured = model(json!("llama3"));
+    assert!(has_unknown_tokenizer(&misconfigured));
+    assert_eq!(get_model_tokenizer(&misconfigured, fallback), fallback);
+    assert_eq!(get_model_tokenizer(&misconfigured, None), None);
+}
+
+#[test]
+fn param_profile_listing() {
+    let model = |profiles: Value| -> Model {
+        serde_json::from_value(json!({ "api": "Loopback", "param_profiles": profiles })).unwrap()
+    };
+
+    let models = vec![
+        model(json!({ "precise": { "temperature": 0.1 } })),
+        model(json!({
+            "creative": { "temperature": 1.2 },
+            "precise": { "temperature": 0.5 }
+        })),
+    ];
+    let profiles = list_param_profiles(&models);
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[0]["name"], json!("creative"));
+    assert_eq!(profiles[1]["name"], json!("precise"));
+    assert_eq!(profiles[1]["parameters"]["temperature"], json!(0.1));
+
+    let stored: Model = postcard::from_bytes(&postcard::to_stdvec(&models[1]).unwrap()).unwrap();
+    assert_eq!(stored.param_profiles["creative"]["temperature"], json!(1.2));
+
+    assert_eq!(
+        get_param_profile(&models[1], "creative").unwrap()["temperature"],
+        json!(1.2)
+    );
+    match get_param_profile(&models[1], "balanced") {
+        Err(ModelError::UnsupportedValue { param, supported }) => {
+            assert_eq!(param, "param_profile");
+            assert_eq!(supported, vec!["creative", "precise"]);
+        }
+        _ => panic!("expected an unsupported value error"),
+    }
+}
+
+#[test]
+fn deadline_parsing() {
+    let now = Instant::now();
+
+    assert_eq!(
+        parse_deadline("1500", now),
+        Some(now + Duration::from_millis(1500))
+    );
+    assert_eq!(parse_deadline(" 0 ", now), Some(now));
+    assert!(parse_deadline("Thu, 01 Jan 1970 00:00:00 GMT", now).is_some());
+    assert_eq!(parse_deadline("soon", now), None);
+}
+
+#[test]
+fn deadline_checking() {
+    let now = Instant::now();
+    let deadline = parse_deadline("100", now);
+
+    assert!(check_deadline(now, deadline).is_ok());
+    assert!(check_deadline(now + Duration::from_millis(100), deadline).is_ok());
+    assert!(check_deadline(now + Duration::from_secs(60), deadline).is_err());
+    assert!(check_deadline(now + Duration::from_secs(60), None).is_ok());
+}
+
+#[test]
+fn max_wait_checking() {
+    let now = Instant::now();
+    let default_max_wait = Duration::from_secs(120);
+
+    assert_eq!(check_max_wait(now, &[], default_max_wait), Ok(None));
+    assert_eq!(
+        check_max_wait(
+            now,
+            &[(now, None), (now + Duration::from_secs(60), None)],
+            default_max_wait
+        ),
+        Ok(Some(now + Duration::from_secs(60)))
+    );
+    assert_eq!(
+        check_max_wait(
+            now,
+            &[(now + Duration::from_secs(300), None)],
+            default_max_wait
+        ),
+        Err(Duration::from_secs(300))
+    );
+    assert_eq!(
+        check_max_wait(
+            now,
+            &[(
+                now + Duration::from_secs(300),
+                Some(Duration::from_secs(600))
+            )],
+            default_max_wait
+        ),
+        Ok(Some(now + Duration::from_secs(300)))
+    );
+    assert_eq!(
+        check_max_wait(
+            now,
+            &[(now + Duration::from_secs(60), Some(Duration::from_secs(30)))],
+            default_max_wait
+        ),
+        Err(Duration::from_secs(60))
+    );
+}
+
+fn request_quota(
+    database: &Database,
+    clock: &LimiterClock,
+    quota: Uuid,
+    request: &limiter::Request,
+) -> Vec<LimiterResult> {
+    match database.modify_items_skip_missing("quotas", &[quota], |quota: &mut Quota| {
+        Ok::<_, ModelError>(
+            quota
+                .limits
+                .iter_mut()
+                .map(|limit| limit.request(clock, request))
+                .collect::<Vec<_>>(),
+        )
+    }) {
+        DatabaseFunctionResult::Success(results) => results.into_iter().flatten().collect(),
+        _ => panic!("Unable to modify quota"),
+    }
+}
+
+#[test]
+fn cancelled_request_reverts_quota() {
+    let path = std::env::temp_dir().join(format!("quota-reservation-{}", Uuid::new_v4()));
+    let database = Database::open(&path).unwrap();
+    let clock = LimiterClock::new();
+
+    let quota: Quota = serde_json::from_value(json!({
+        "uuid": Uuid::new_v4(),
+        "limits": [{ "count": 100, "type": "Token", "period": 60 }]
+    }))
+    .unwrap();
+    database.insert_item("quotas", &quota.uuid, &quota);
+
+    let request = limiter::Request {
+        arrived_at: Instant::now(),
+        estimated_tokens: 100,
+    };
+    let quotas = [quota.uuid];
+
+    // The client disconnects while the request is in progress, dropping the reservation.
+    assert_eq!(
+        request_quota(&database, &clock, quota.uuid, &request),
+        vec![LimiterResult::Ready]
+    );
+    drop(QuotaReservation {
+        database: &database,
+        clock: &clock,
+        quotas: &quotas,
+        arrived_at: request.arrived_at,
+        estimated_tokens: request.estimated_tokens,
+        completed: false,
+        rejection: None,
+    });
+
+    // Requests rejected while waiting for their quotas are reverted like cancelled requests.
+    assert_eq!(
+        request_quota(&database, &clock, quota.uuid, &request),
+        vec![LimiterResult::Ready]
+    );
+    let mut reservation = QuotaReservation {
+        database: &database,
+        clock: &clock,
+        quotas: &quotas,
+        arrived_at: request.arrived_at,
+        estimated_tokens: request.estimated_tokens,
+        completed: false,
+        rejection: None,
+    };
+    reservation.reject("deadline exceeded");
+    drop(reservation);
+
+    assert_eq!(
+        request_quota(&database, &clock, quota.uuid, &request),
+        vec![LimiterResult::Ready]
+    );
+    let mut reservation = QuotaReservation {
+        database: &database,
+        clock: &clock,
+        quotas: &quotas,
+        arrived_at: request.arrived_at,
+        estimated_tokens: request.estimated_tokens,
+        completed: false,
+        rejection: None,
+    };
+    reservation.complete();
+    drop(reservation);
+
+    assert!(matches!(
+        request_quota(&database, &clock, quota.uuid, &request)[..],
+        [LimiterResult::WaitUntil(_)]
+    ));
+
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn denied_models_take_precedence() {
+    let allowed = Uuid::new_v4();
+    let denied = Uuid::new_v4();
+    let role_model = Uuid::new_v4();
+
+    let auth = Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User {
+            models: [allowed, denied].into(),
+            denied_models: [denied, role_model].into(),
+            ..Default::default()
+        },
+        roles: vec![Role {
+            models: [role_model, denied].into(),
+            ..Default::default()
+        }],
+    };
+
+    assert_eq!(get_accessible_models(&auth), vec![allowed]);
+}
+
+// An AppState with the server's default settings, using a new database in the temporary directory.
+fn test_state(name: &str) -> AppState {
+    let path = std::env::temp_dir().join(format!("{}-{}", name, Uuid::new_v4()));
+
+    AppState {
+        http: reqwest::Client::new(),
+        database: Database::open(&path).unwrap(),
+        clock: Arc::new(LimiterClock::new()),
+        proxy_warnings: false,
+        timing_headers: false,
+        record_usage: false,
+        served_model_header: false,
+        deployment_name: None,
+        max_rate_limit_wait: Duration::from_secs(30),
+        upstream_timeout: None,
+        max_upstream_timeout: Duration::from_secs(600),
+        role_admin: true,
+        model_suggestions: true,
+        log_upstream_requests: false,
+        external_auth: None,
+        coalescer: None,
+        coalescing_key: CoalescingKeySettings::default(),
+        concurrency_limit: None,
+        quota_concurrency: Arc::new(QuotaConcurrency::default()),
+        model_load: Arc::new(ModelLoad::default()),
+        stream_tasks: Arc::new(StreamTasks::default()),
+        pacer: Arc::new(RequestPacer::default()),
+        retry_budget: Arc::new(RetryBudget::default()),
+        key_cooldowns: Arc::new(ApiKeyCooldowns::default()),
+        prometheus_metrics: None,
+        health: Arc::new(ModelHealth::new(Duration::from_secs(60), None, 10)),
+        json_schema_limits: None,
+        body_normalization: BodyNormalization::default(),
+        repair_json: false,
+        fallback_tokenizer: Some(Tokenizer::Cl100kBase),
+        request_capture: None,
+    }
+}
+
+fn insert_test_user(state: &AppState, user: &User) {
+    let related_items: Vec<_> = user.api_keys.iter().map(|item| (item, user.uuid)).collect();
+
+    assert!(matches!(
+        state.database.insert_related_items(
+            ("users", "api_keys"),
+            (&user.uuid, user),
+            &related_items
+        ),
+        DatabaseLinkedInsertionResult::Success
+    ));
+}
+
+// Sends a request through the API router, returning its status and JSON body. Debug builds of the router need more stack than test threads have, so the request is sent from a separate thread.
+async fn send_test_request(
+    state: &AppState,
+    api_key: &str,
+    path: &str,
+    body: Value,
+) -> (StatusCode, Value) {
+    let router = api_router(state.clone());
+    let request = http::Request::post(path)
+        .header("authorization", format!("Bearer {}", api_key))
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+
+    let (sender, receiver) = oneshot::channel();
+    std::thread::Builder::new()
+        .stack_size(8_388_608)
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            let _ = sender.send(runtime.block_on(async move {
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+
+                (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+            }));
+        })
+        .unwrap();
+
+    receiver.await.unwrap()
+}
+
+#[tokio::test]
+async fn denied_models_are_not_served() {
+    let state = test_state("denied-models");
+
+    let model: Model = serde_json::from_value(json!({
+        "api": "Loopback",
+        "uuid": Uuid::new_v4(),
+        "name": "test",
+        "types": ["TextChat"]
+    }))
+    .unwrap();
+    state.database.insert_item("models", &model.uuid, &model);
+    let role = Role {
+        uuid: Uuid::new_v4(),
+        models: [model.uuid].into(),
+        ..Default::default()
+    };
+    state.database.insert_item("roles", &role.uuid, &role);
+
+    insert_test_user(
+        &state,
+        &User {
+            uuid: Uuid::new_v4(),
+            api_keys: ["allowed".to_string()].into(),
+            roles: [role.uuid].into(),
+            ..Default::default()
+        },
+    );
+    insert_test_user(
+        &state,
+        &User {
+            uuid: Uuid::new_v4(),
+            api_keys: ["denied".to_string()].into(),
+            roles: [role.uuid].into(),
+            denied_models: [model.uuid].into(),
+            ..Default::default()
+        },
+    );
+
+    let body = json!({ "model": "test", "messages": [{ "role": "user", "content": "Hi" }] });
+    let (status, _) =
+        send_test_request(&state, "allowed", "/v1/chat/completions", body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Models which are denied to the User are handled as if they don't exist, even if one of the User's Roles grants access to them.
+    let (status, response) =
+        send_test_request(&state, "denied", "/v1/chat/completions", body).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(response["error"]["code"], json!("model_not_found"));
+}
+
+#[tokio::test]
+async fn upstream_errors_use_fallbacks() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // The backend answers every request with a server error.
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.read(&mut [0; 4096]).await;
+
+            let body = r#"{"error":{"message":"Internal error"}}"#;
+            let _ = stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .await;
+        }
+    });
+
+    let state = test_state("upstream-fallbacks");
+
+    let fallback: Model = serde_json::from_value(json!({
+        "api": "Loopback",
+        "uuid": Uuid::new_v4(),
+        "name": "fallback",
+        "types": ["TextChat"]
+    }))
+    .unwrap();
+    let model: Model = serde_json::from_value(json!({
+        "api": {
+            "OpenAI": {
+                "model_string": "test",
+                "model_context_len": null,
+                "openai_api_base": format!("http://{}", address),
+                "openai_api_key": ""
+            }
+        },
+        "uuid": Uuid::new_v4(),
+        "name": "test",
+        "types": ["TextChat"],
+        "fallbacks": [fallback.uuid]
+    }))
+    .unwrap();
+    state.database.insert_item("models", &model.uuid, &model);
+    state
+        .database
+        .insert_item("models", &fallback.uuid, &fallback);
+
+    let quota: Quota = serde_json::from_value(json!({
+        "uuid": Uuid::new_v4(),
+        "limits": [{ "count": 2, "type": "Request", "period": 60 }],
+        "max_wait": 0
+    }))
+    .unwrap();
+    state.database.insert_item("quotas", &quota.uuid, &quota);
+
+    insert_test_user(
+        &state,
+        &User {
+            uuid: Uuid::new_v4(),
+            api_keys: ["user".to_string()].into(),
+            models: [model.uuid, fallback.uuid].into(),
+            ..Default::default()
+        },
+    );
+    insert_test_user(
+        &state,
+        &User {
+            uuid: Uuid::new_v4(),
+            api_keys: ["limited".to_string()].into(),
+            models: [model.uuid, fallback.uuid].into(),
+            quotas: [quota.uuid].into(),
+            ..Default::default()
+        },
+    );
+
+    // The backend's server error is retried with the fallback model.
+    let body = json!({ "model": "test", "messages": [{ "role": "user", "content": "Hi" }] });
+    let (status, _) = send_test_request(&state, "user", "/v1/chat/completions", body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Requests rejected by the User's own rate limits aren't retried, and aren't counted towards the model's health. The User's first request is counted twice, as the fallback model's request uses the same Quota.
+    let (status, _) =
+        send_test_request(&state, "limited", "/v1/chat/completions", body.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send_test_request(&state, "limited", "/v1/chat/completions", body).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    let statuses = serde_json::to_value(state.health.get_statuses()).unwrap();
+    assert_eq!(statuses[model.uuid.to_string()]["requests"], json!(2));
+    assert_eq!(statuses[model.uuid.to_string()]["errors"], json!(2));
+}
+
+#[tokio::test]
+async fn stream_first_chunk_latency() {
+    use http_body::Body as _;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (release, released) = oneshot::channel::<()>();
+
+    // The backend sends its first chunk, and then waits until the test has received it before finishing the stream.
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let _ = stream.read(&mut [0; 4096]).await;
+
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\ndata: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n")
+            .await
+            .unwrap();
+        let _ = released.await;
+        let _ = stream
+            .write_all(b"data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" world\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n")
+            .await;
+    });
+
+    let state = test_state("stream-first-chunk");
+
+    let model: Model = serde_json::from_value(json!({
+        "api": {
+            "OpenAI": {
+                "model_string": "test",
+                "model_context_len": null,
+                "openai_api_base": format!("http://{}", address),
+                "openai_api_key": ""
+            }
+        },
+        "uuid": Uuid::new_v4(),
+        "name": "test",
+        "types": ["TextChat"]
+    }))
+    .unwrap();
+    state.database.insert_item("models", &model.uuid, &model);
+
+    let quota: Quota = serde_json::from_value(json!({
+        "uuid": Uuid::new_v4(),
+        "limits": [{ "count": 1, "type": "Request", "period": 60 }],
+        "max_wait": 0
+    }))
+    .unwrap();
+    state.database.insert_item("quotas", &quota.uuid, &quota);
+
+    insert_test_user(
+        &state,
+        &User {
+            uuid: Uuid::new_v4(),
+            api_keys: ["user".to_string()].into(),
+            models: [model.uuid].into(),
+            quotas: [quota.uuid].into(),
+            ..Default::default()
+        },
+    );
+
+    let body = json!({
+        "model": "test",
+        "messages": [{ "role": "user", "content": "Hi" }],
+        "stream": true
+    });
+    let router = api_router(state.clone());
+    let request = http::Request::post("/v1/chat/completions")
+        .header("authorization", "Bearer user")
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap();
+
+    let (first_sender, first_receiver) = oneshot::channel();
+    let (sender, receiver) = oneshot::channel();
+    std::thread::Builder::new()
+        .stack_size(8_388_608)
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            let _ = sender.send(runtime.block_on(async move {
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let mut body = response.into_body();
+
+                let mut first_sender = Some(first_sender);
+                let mut events = String::new();
+                while let Some(frame) =
+                    std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await
+                {
+                    if let Ok(data) = frame.unwrap().into_data() {
+                        let data = String::from_utf8(data.to_vec()).unwrap();
+                        if let Some(first_sender) = first_sender.take() {
+                            let _ = first_sender.send(data.clone());
+                        }
+                        events.push_str(&data);
+                    }
+                }
+
+                (status, events)
+            }));
+        })
+        .unwrap();
+
+    // The first chunk reaches the client while the backend is still generating the rest of the response.
+    let first = tokio::time::timeout(Duration::from_secs(10), first_receiver)
+        .await
+        .expect("first chunk wasn't forwarded before the response finished")
+        .unwrap();
+    assert!(first.contains("Hello"));
+
+    let _ = release.send(());
+    let (status, events) = receiver.await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert!(events.contains(" world"));
+    assert!(events.ends_with("data: [DONE]\n\n"));
+
+    // The request was still admitted by the User's Quota before the stream began.
+    let (status, _) = send_test_request(&state, "user", "/v1/chat/completions", body).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn admin_precedence() {
+    let admin_role = Role {
+        admin: true,
+        ..Default::default()
+    };
+    let role = Role::default();
+
+    let user = User::default();
+    assert!(!is_admin(&user, std::slice::from_ref(&role), true));
+    assert!(is_admin(&user, &[role.clone(), admin_role.clone()], true));
+    assert!(!is_admin(&user, std::slice::from_ref(&admin_role), false));
+
+    let user = User {
+        admin: true,
+        ..Default::default()
+    };
+    assert!(is_admin(&user, std::slice::from_ref(&role), true));
+    assert!(is_admin(&user, &[], false));
+
+    let user = User {
+        admin: true,
+        admin_override: Some(false),
+        ..Default::default()
+    };
+    assert!(!is_admin(&user, std::slice::from_ref(&admin_role), true));
+
+    let user = User {
+        admin_override: Some(true),
+        ..Default::default()
+    };
+    assert!(is_admin(&user, &[role], false));
+}
+
+#[test]
+fn region_preferred_selection() {
+    let model = |name: &str, region: Option<&str>| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": name,
+            "types": ["TextChat"],
+            "region": region
+        }))
+        .unwrap()
+    };
+
+    let models = vec![
+        model("test", Some("us")),
+        model("test", Some("eu")),
+        model("test", None),
+        model("other", Some("ap")),
+    ];
+
+    let selected = select_model(&models, RequestType::TextChat, "test", Some("eu"), None).unwrap();
+    assert_eq!(selected.uuid, models[1].uuid);
+
+    let selected = select_model(&models, RequestType::TextChat, "test", Some("ap"), None).unwrap();
+    assert_eq!(selected.uuid, models[0].uuid);
+
+    let selected = select_model(&models, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[0].uuid);
+
+    assert!(select_model(&models, RequestType::TextCompletion, "test", None, None).is_none());
+
+    let auth = Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User::default(),
+        roles: vec![Role {
+            region: Some("eu".to_string()),
+            ..Default::default()
+        }],
+    };
+    assert_eq!(get_region(&auth), Some("eu"));
+
+    let auth = Authenticated {
+        user: User {
+            region: Some("us".to_string()),
+            ..Default::default()
+        },
+        ..auth
+    };
+    assert_eq!(get_region(&auth), Some("us"));
+}
+
+#[test]
+fn prompt_length_routing() {
+    let model = |max_prompt_tokens: Option<u64>| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": "test",
+            "types": ["TextChat"],
+            "max_prompt_tokens": max_prompt_tokens
+        }))
+        .unwrap()
+    };
+
+    let models = vec![model(None), model(Some(8000)), model(Some(1000))];
+    let select = |prompt_tokens: Option<u64>| {
+        select_model(&models, RequestType::TextChat, "test", None, prompt_tokens)
+            .unwrap()
+            .uuid
+    };
+
+    // Limits are inclusive, and the smallest limit which fits the prompt is used.
+    assert_eq!(select(Some(20)), models[2].uuid);
+    assert_eq!(select(Some(1000)), models[2].uuid);
+    assert_eq!(select(Some(1001)), models[1].uuid);
+    assert_eq!(select(Some(8000)), models[1].uuid);
+    assert_eq!(select(Some(100_000)), models[0].uuid);
+    assert_eq!(select(None), models[0].uuid);
+
+    // Without an unlimited model, prompts which are too long use the largest limit.
+    let limited = &models[1..];
+    let selected = select_model(limited, RequestType::TextChat, "test", None, Some(100_000));
+    assert_eq!(selected.unwrap().uuid, models[1].uuid);
+
+    assert!(find_conflicting_model(&models, &model(Some(1000))).is_some());
+    assert!(find_conflicting_model(&models, &model(Some(4000))).is_none());
+}
+
+#[test]
+fn usage_key_ordering() {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let key = Uuid::now_v7();
+
+    let serialize = |uuid: &Uuid| postcard::to_stdvec(uuid).unwrap();
+    assert!(serialize(&get_usage_key(now - 1000)) < serialize(&key));
+    assert!(serialize(&key) < serialize(&get_usage_key(now + 1000)));
+    assert!(serialize(&key) < serialize(&Uuid::max()));
+}
+
+#[test]
+fn model_conflict_detection() {
+    let model = |name: &str, types: Value, region: Option<&str>| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": name,
+            "types": types,
+            "region": region
+        }))
+        .unwrap()
+    };
+
+    let models = vec![
+        model("test", json!(["TextChat", "TextCompletion"]), None),
+        model("test", json!(["TextChat"]), Some("eu")),
+        model("other", json!(["TextEmbedding"]), None),
+    ];
+
+    let (conflict, types) = find_conflicting_model(
+        &models,
+        &model("test", json!(["TextCompletion", "TextEdit"]), None),
+    )
+    .unwrap();
+    assert_eq!(conflict.uuid, models[0].uuid);
+    assert_eq!(types, vec![RequestType::TextCompletion]);
+
+    assert!(find_conflicting_model(&models, &model("test", json!(["TextEdit"]), None)).is_none());
+    assert!(
+        find_conflicting_model(&models, &model("test", json!(["TextChat"]), Some("us"))).is_none()
+    );
+    assert!(find_conflicting_model(&models, &model("new", json!(["TextChat"]), None)).is_none());
+
+    let (conflict, _) =
+        find_conflicting_model(&models, &model("test", json!(["TextChat"]), Some("eu"))).unwrap();
+    assert_eq!(conflict.uuid, models[1].uuid);
+
+    // Updating a model shouldn't conflict with its own previous version.
+    assert!(find_conflicting_model(&models, &models[0]).is_none());
+}
+
+#[test]
+fn chat_to_completion_selection() {
+    let model = |types: Value, allow_chat_to_completion: bool| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": "test",
+            "types": types,
+            "allow_chat_to_completion": allow_chat_to_completion
+        }))
+        .unwrap()
+    };
+
+    let models = vec![model(json!(["TextCompletion"]), false)];
+    assert!(select_model(&models, RequestType::TextChat, "test", None, None).is_none());
+
+    let models = vec![model(json!(["TextCompletion"]), true)];
+    let selected = select_model(&models, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[0].uuid);
+
+    let models = vec![
+        model(json!(["TextCompletion"]), true),
+        model(json!(["TextChat"]), false),
+    ];
+    let selected = select_model(&models, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[1].uuid);
+}
+
+#[test]
+fn model_example_validation() {
+    let model = |types: Value, examples: Value| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "types": types,
+            "examples": examples
+        }))
+        .unwrap()
+    };
+
+    let chat_example = json!({ "messages": [{ "role": "user", "content": "Hello" }] });
+    let completion_example = json!({ "prompt": "Hello" });
+
+    let valid = model(
+        json!(["TextChat", "TextCompletion"]),
+        json!([chat_example, completion_example]),
+    );
+    assert_eq!(find_invalid_example(&valid), None);
+
+    let invalid = model(
+        json!(["TextChat"]),
+        json!([chat_example, completion_example]),
+    );
+    assert_eq!(find_invalid_example(&invalid), Some(1));
+
+    let invalid = model(json!(["TextChat"]), json!(["Hello"]));
+    assert_eq!(find_invalid_example(&invalid), Some(0));
+
+    let other = model(
+        json!(["TextChat"]),
+        json!([chat_example, { "messages": [] }]),
+    );
+    assert_eq!(
+        list_model_examples(&[valid, other]),
+        vec![chat_example, completion_example, json!({ "messages": [] })]
+    );
+}
+
+#[test]
+fn model_name_suggestions() {
+    let model = |name: &str, types: Value| -> Model {
+        serde_json::from_value(json!({ "api": "Loopback", "name": name, "types": types })).unwrap()
+    };
+
+    let models = vec![
+        model("gpt-4o", json!(["TextChat"])),
+        model("gpt-4o-mini", json!(["TextChat"])),
+        model("gpt-4", json!(["TextChat"])),
+        model("gpt-3.5-turbo", json!(["TextChat"])),
+        model("gpt-4o", json!(["TextChat"])),
+        model("gpt-40", json!(["TextEmbedding"])),
+    ];
+
+    assert_eq!(
+        suggest_model_names(&models, RequestType::TextChat, "gpt4o"),
+        vec!["gpt-4o".to_string(), "gpt-4".to_string()]
+    );
+    assert_eq!(
+        suggest_model_names(&models, RequestType::TextChat, "GPT-4o-mnii"),
+        vec!["gpt-4o-mini".to_string()]
+    );
+    assert!(suggest_model_names(&models, RequestType::TextChat, "llama-3").is_empty());
+    assert!(suggest_model_names(&[], RequestType::TextChat, "gpt-4o").is_empty());
+}
+
+#[test]
+fn quota_threshold_crossing() {
+    let thresholds = [50, 80, 100];
+
+    assert_eq!(
+        get_crossed_thresholds(&thresholds, 0.0, 0.4),
+        Vec::<u8>::new()
+    );
+    assert_eq!(get_crossed_thresholds(&thresholds, 0.4, 0.5), vec![50]);
+    assert_eq!(get_crossed_thresholds(&thresholds, 0.4, 0.85), vec![50, 80]);
+    assert_eq!(get_crossed_thresholds(&thresholds, 0.85, 1.2), vec![100]);
+
+    // Requests made after a threshold has been crossed don't cross it again.
+    assert_eq!(
+        get_crossed_thresholds(&thresholds, 0.85, 0.9),
+        Vec::<u8>::new()
+    );
+    assert_eq!(
+        get_crossed_thresholds(&thresholds, 0.6, 0.55),
+        Vec::<u8>::new()
+    );
+    assert_eq!(get_crossed_thresholds(&[], 0.0, 1.0), Vec::<u8>::new());
+
+    let clock = LimiterClock::new();
+    let mut limit: limiter::Limit =
+        serde_json::from_value(json!({ "count": 10, "type": "Request", "period": 60 })).unwrap();
+    let now = Instant::now();
+    assert_eq!(limit.get_usage(&clock, now), 0.0);
+
+    let mut crossed = Vec::new();
+    for _ in 0..10 {
+        let usage = limit.get_usage(&clock, now);
+        limit.request(
+            &clock,
+            &limiter::Request {
+                arrived_at: now,
+                estimated_tokens: 1,
+            },
+        );
+        crossed.extend(get_crossed_thresholds(
+            &thresholds,
+            usage,
+            limit.get_usage(&clock, now),
+        ));
+    }
+    assert_eq!(crossed, vec![50, 80, 100]);
+}
+
+#[test]
+fn upstream_timeout_overrides() {
+    let auth = |user: Option<u64>, roles: &[Option<u64>]| Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User {
+            upstream_timeout: user,
+            ..Default::default()
+        },
+        roles: roles
+            .iter()
+            .map(|timeout| Role {
+                upstream_timeout: *timeout,
+                ..Default::default()
+            })
+            .collect(),
+    };
+    let default = Some(Duration::from_secs(60));
+    let max = Duration::from_secs(600);
+
+    assert_eq!(
+        get_upstream_timeout(&auth(None, &[]), default, max),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        get_upstream_timeout(&auth(Some(300), &[Some(120)]), default, max),
+        Some(Duration::from_secs(300))
+    );
+    assert_eq!(
+        get_upstream_timeout(&auth(None, &[Some(120), None, Some(240)]), default, max),
+        Some(Duration::from_secs(240))
+    );
+
+    // Overrides can't lower the timeout, or raise it past the maximum.
+    assert_eq!(
+        get_upstream_timeout(&auth(Some(10), &[]), default, max),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        get_upstream_timeout(&auth(Some(3600), &[]), default, max),
+        Some(Duration::from_secs(600))
+    );
+    assert_eq!(
+        get_upstream_timeout(&auth(Some(3600), &[]), default, Duration::from_secs(30)),
+        Some(Duration::from_secs(60))
+    );
+
+    assert_eq!(get_upstream_timeout(&auth(Some(300), &[]), None, max), None);
+}
+
+#[test]
+fn request_cost_ceiling() {
+    let auth = |user: Option<f64>, roles: &[Option<f64>]| Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User {
+            max_request_cost: user,
+            ..Default::default()
+        },
+        roles: roles
+            .iter()
+            .map(|limit| Role {
+                max_request_cost: *limit,
+                ..Default::default()
+            })
+            .collect(),
+    };
+    let pricing = |max_request_cost: Value| -> Pricing {
+        serde_json::from_value(
+            json!({ "input": 2.5, "output": 10, "max_request_cost": max_request_cost }),
+        )
+        .unwrap()
+    };
+
+    // 100,000 input tokens and 25,000 output tokens cost 0.25 + 0.25.
+    assert!(check_request_cost(&auth(None, &[]), &pricing(json!(0.5)), 100_000, 25_000).is_ok());
+    assert!(check_request_cost(&auth(None, &[]), &pricing(json!(0.5)), 100_000, 20_000).is_ok());
+    match check_request_cost(&auth(None, &[]), &pricing(json!(0.5)), 100_000, 30_000) {
+        Err(ModelError::CostLimitExceeded { estimated, max }) => {
+            assert_eq!(estimated, 0.55);
+            assert_eq!(max, 0.5);
+        }
+        _ => panic!(),
+    }
+    assert!(check_request_cost(&auth(None, &[]), &pricing(Value::Null), 100_000, 30_000).is_ok());
+
+    // A User's limit takes precedence over their Roles', but can't raise the model's limit.
+    let unlimited = pricing(Value::Null);
+    assert!(check_request_cost(
+        &auth(None, &[Some(0.1), Some(1.0)]),
+        &unlimited,
+        100_000,
+        30_000
+    )
+    .is_ok());
+    assert!(
+        check_request_cost(&auth(Some(0.1), &[Some(1.0)]), &unlimited, 100_000, 30_000).is_err()
+    );
+    assert!(
+        check_request_cost(&auth(Some(1.0), &[]), &pricing(json!(0.5)), 100_000, 30_000).is_err()
+    );
+
+    let response = ModelResponse::from(ModelError::CostLimitExceeded {
+        estimated: 0.55,
+        max: 0.5,
+    });
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn request_capture_permission() {
+    let auth = |user: bool, roles: &[bool]| Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User {
+            allow_request_capture: user,
+            ..Default::default()
+        },
+        roles: roles
+            .iter()
+            .map(|allowed| Role {
+                allow_request_capture: *allowed,
+                ..Default::default()
+            })
+            .collect(),
+    };
+
+    assert!(!can_capture_requests(&auth(false, &[])));
+    assert!(!can_capture_requests(&auth(false, &[false, false])));
+    assert!(can_capture_requests(&auth(true, &[])));
+    assert!(can_capture_requests(&auth(false, &[false, true])));
+
+    // Revoking permission opts the user out, removing their existing captures.
+    let capture = RequestCapture::new(Duration::from_secs(60));
+    let allowed = auth(true, &[]);
+    capture.set_enabled(allowed.user.uuid, true);
+    capture.record(
+        allowed.user.uuid,
+        CapturedRequest::new(None, None, 200, json!({}), json!({})),
+    );
+    assert!(get_enabled_request_capture(Some(&capture), &allowed).is_some());
+
+    let revoked = auth(false, &[]);
+    assert!(get_enabled_request_capture(Some(&capture), &revoked).is_none());
+    assert!(!capture.is_enabled(revoked.user.uuid));
+    assert!(capture.get_captures(revoked.user.uuid).is_empty());
+}
+
+#[tokio::test]
+async fn model_request_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // The backend accepts connections, but never responds.
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let model: Model = serde_json::from_value(json!({
+        "api": {
+            "OpenAI": {
+                "model_string": "gpt-4",
+                "model_context_len": null,
+                "openai_api_base": format!("http://{}", address),
+                "openai_api_key": ""
+            }
+        },
+        "request_timeout": 1
+    }))
+    .unwrap();
+    let request = ModelRequest::from_batch_item(
+        "POST",
+        "/v1/chat/completions",
+        json!({ "model": "gpt-4", "messages": [] })
+            .as_object()
+            .unwrap()
+            .clone(),
+    )
+    .unwrap();
+
+    let http_client = reqwest::Client::new();
+    let pacer = RequestPacer::default();
+    let started = Instant::now();
+    let response = get_response_before_timeout(
+        Some(Duration::from_millis(100)),
+        model.api.generate(
+            &http_client,
+            &pacer,
+            &RetryBudget::default(),
+            &ApiKeyCooldowns::default(),
+            model.uuid,
+            request,
+            None,
+            false,
+        ),
+    )
+    .await;
+
+    assert_eq!(response.status, StatusCode::BAD_GATEWAY);
+    assert!(response.is_fallback_eligible());
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(model.request_timeout, Some(1));
+}
+
+#[tokio::test]
+async fn database_lock_conflict() {
+    let path = std::env::temp_dir().join(format!("database-lock-{}", Uuid::new_v4()));
+    let database = Database::open(&path).unwrap();
+
+    match Database::open(&path) {
+        Err(error) => assert!(Database::is_lock_error(&error)),
+        Ok(_) => panic!("Database was opened while locked"),
+    }
+
+    let started = Instant::now();
+    match Database::open_with_lock_wait(&path, Duration::from_millis(500)).await {
+        Err(error) => assert!(Database::is_lock_error(&error)),
+        Ok(_) => panic!("Database was opened while locked"),
+    }
+    assert!(started.elapsed() >= Duration::from_millis(500));
+
+    drop(database);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn database_version_checks() {
+    let path = std::env::temp_dir().join(format!("database-version-{}", Uuid::new_v4()));
+
+    // Opening an up to date database doesn't run any migrations.
+    drop(Database::open(&path).unwrap());
+    assert!(Database::log_planned_migrations(&path).is_ok());
+    drop(Database::open(&path).unwrap());
+
+    // Newer databases can't be downgraded, and databases older than the first migration can't be upgraded.
+    std::fs::create_dir(path.join("version-999")).unwrap();
+    assert!(matches!(
+        Database::open(&path),
+        Err(sled::Error::Unsupported(_))
+    ));
+    assert!(Database::log_planned_migrations(&path).is_err());
+    std::fs::remove_dir_all(path.join("version-999")).unwrap();
+    std::fs::remove_dir_all(path.join("version-2")).unwrap();
+
+    std::fs::create_dir(path.join("version-0")).unwrap();
+    assert!(matches!(
+        Database::open(&path),
+        Err(sled::Error::Unsupported(_))
+    ));
+
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn split_count_limit() {
+    let model = |split_n: bool| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "split_n": split_n,
+            "max_split_n": 4
+        }))
+        .unwrap()
+    };
+    let request = |n: u64| {
+        ModelRequest::from_batch_item(
+            "POST",
+            "/v1/chat/completions",
+            json!({ "model": "test", "messages": [], "n": n })
+                .as_object()
+                .unwrap()
+                .clone(),
+        )
+        .unwrap()
+    };
+
+    assert!(check_split_count(&model(true), &request(4)).is_ok());
+    assert!(matches!(
+        check_split_count(&model(true), &request(5)),
+        Err(ModelError::ParameterOutOfRange { param: "n", .. })
+    ));
+    assert!(check_split_count(&model(false), &request(5)).is_ok());
+
+    let default: Model =
+        serde_json::from_value(json!({ "api": "Loopback", "split_n": true })).unwrap();
+    assert!(check_split_count(&default, &request(16)).is_ok());
+    assert!(check_split_count(&default, &request(17)).is_err());
+}
+
+#[test]
+fn database_v1_migration() {
+    #[derive(serde::Serialize)]
+    enum ModelBackendV1 {
+        OpenAI((String, Option<u64>, String, String, Option<String>)),
+    }
+
+    let path = std::env::temp_dir().join(format!("database-migration-{}", Uuid::new_v4()));
+    let (user, model, quota) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+    {
+        let database = sled::open(path.join("version-1")).unwrap();
+        let insert = |table: &str, key: &Uuid, value: Vec<u8>| {
+            database
+                .open_tree(table)
+                .unwrap()
+                .insert(postcard::to_stdvec(key).unwrap(), value)
+                .unwrap();
+        };
+
+        insert(
+            "users",
+            &user,
+            postcard::to_stdvec(&(
+                "Test user",
+                user,
+                true,
+                vec!["key"],
+                Vec::<Uuid>::new(),
+                vec![model],
+                vec![quota],
+            ))
+            .unwrap(),
+        );
+        insert(
+            "models",
+            &model,
+            postcard::to_stdvec(&(
+                "Test model",
+                model,
+                "gpt-4",
+                vec![RequestType::TextChat],
+                ModelBackendV1::OpenAI((
+                    "gpt-4".to_string(),
+                    Some(8192),
+                    "https://api.openai.com".to_string(),
+                    "key".to_string(),
+                    None,
+                )),
+                vec![quota],
+            ))
+            .unwrap(),
+        );
+        insert(
+            "quotas",
+            &quota,
+            postcard::to_stdvec(&("Test quota", quota, vec![(10u64, 0u8, 60u64, None::<()>)]))
+                .unwrap(),
+        );
+        database.flush().unwrap();
+    }
+
+    let database = Database::open(&path).unwrap();
+
+    match database.get_item::<_, User>("users", &user) {
+        DatabaseValueResult::Success(migrated) => {
+            assert_eq!(migrated.label, "Test user");
+            assert!(migrated.admin);
+            assert!(migrated.api_keys.contains("key"));
+            assert!(migrated.models.contains(&model));
+            assert!(migrated.denied_models.is_empty());
+            assert!(migrated.quotas.contains(&quota));
+            assert!(!migrated.allow_request_capture);
+        }
+        _ => panic!("User wasn't migrated"),
+    }
+    match database.get_item::<_, Model>("models", &model) {
+        DatabaseValueResult::Success(migrated) => {
+            assert_eq!(migrated.name, "gpt-4");
+            assert!(migrated.types.contains(&RequestType::TextChat));
+            assert_eq!(migrated.api.get_max_tokens(), 8192);
+            assert!(migrated.quotas.contains(&quota));
+            assert!(migrated.fallbacks.is_empty());
+        }
+        _ => panic!("Model wasn't migrated"),
+    }
+    match database.get_item::<_, Quota>("quotas", &quota) {
+        DatabaseValueResult::Success(migrated) => {
+            assert_eq!(migrated.limits.len(), 1);
+            assert_eq!(migrated.limits[0].count, 10);
+            assert_eq!(migrated.limits[0].max_borrow, 0);
+            assert_eq!(migrated.max_concurrent, None);
+        }
+        _ => panic!("Quota wasn't migrated"),
+    }
+
+    // The previous version is kept as a backup.
+    assert!(path.join("version-1").exists());
+
+    drop(database);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn readiness_check() {
+    let path = std::env::temp_dir().join(format!("database-readiness-{}", Uuid::new_v4()));
+    let database = Database::open(&path).unwrap();
+
+    let (status, body) = check_readiness(&database, &LimiterClock::new());
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], json!("ok"));
+    assert_eq!(body["database"], json!(true));
+    assert_eq!(body["clock"], json!(true));
+    // The database's size is only reported to admins.
+    assert!(body.get("database_size").is_none());
+
+    drop(database);
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[test]
+fn concurrency_limit_saturation() {
+    let limit = Arc::new(Semaphore::new(2));
+
+    let first = ActiveRequest::start(Some(&limit)).ok().unwrap();
+    let second = ActiveRequest::start(Some(&limit)).ok().unwrap();
+
+    match ActiveRequest::start(Some(&limit)) {
+        Err(response) => {
+            assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.retry_after, Some(Duration::from_secs(1)));
+        }
+        Ok(_) => panic!("Request was accepted over the concurrency limit"),
+    }
+
+    drop(first);
+    assert!(ActiveRequest::start(Some(&limit)).is_ok());
+
+    drop(second);
+    assert_eq!(limit.available_permits(), 2);
+
+    assert!(ActiveRequest::start(None).is_ok());
+}
+
+#[test]
+fn quota_concurrency_limit() {
+    let quota = |uuid: Uuid, max_concurrent: Option<u32>| -> Quota {
+        serde_json::from_value(json!({
+            "label": "test",
+            "uuid": uuid,
+            "max_concurrent": max_concurrent
+        }))
+        .unwrap()
+    };
+    let limited = Uuid::new_v4();
+    let concurrency = QuotaConcurrency::default();
+
+    let first = concurrency
+        .acquire(&[quota(limited, Some(2)), quota(Uuid::new_v4(), None)])
+        .unwrap();
+    assert_eq!(first.len(), 1);
+    let second = concurrency.acquire(&[quota(limited, Some(2))]).unwrap();
+
+    match concurrency.acquire(&[quota(limited, Some(2))]) {
+        Err(ModelError::UserRateLimit) => {}
+        _ => panic!("Request was accepted over the Quota's concurrency limit"),
+    }
+
+    // Permits are released when they're dropped, including when the request fails.
+    drop(first);
+    let third = concurrency.acquire(&[quota(limited, Some(2))]).unwrap();
+    assert!(concurrency.acquire(&[quota(limited, Some(2))]).is_err());
+
+    // Raising the limit takes effect immediately.
+    let fourth = concurrency.acquire(&[quota(limited, Some(3))]).unwrap();
+
+    // Lowering the limit keeps counting the requests which are already in flight.
+    assert!(concurrency.acquire(&[quota(limited, Some(1))]).is_err());
+    drop((second, third));
+    assert!(concurrency.acquire(&[quota(limited, Some(1))]).is_err());
+    drop(fourth);
+    let fifth = concurrency.acquire(&[quota(limited, Some(1))]).unwrap();
+    assert!(concurrency.acquire(&[quota(limited, Some(1))]).is_err());
+
+    // Raising the limit again cancels out permits which haven't been removed yet.
+    let sixth = concurrency.acquire(&[quota(limited, Some(2))]).unwrap();
+    assert!(concurrency.acquire(&[quota(limited, Some(2))]).is_err());
+    drop((fifth, sixth));
+
+    // Deleted Quotas are forgotten.
+    concurrency.remove(&limited);
+    assert!(concurrency.semaphores.lock().unwrap().is_empty());
+
+    assert!(quota(limited, Some(0)).has_invalid_concurrency());
+    assert!(!quota(limited, None).has_invalid_concurrency());
+
+    assert!(concurrency
+        .acquire(&[quota(Uuid::new_v4(), None)])
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn stream_task_tracking() {
+    let tasks = Arc::new(StreamTasks::default());
+    tasks.wait().await;
+
+    let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+    let finished = Arc::new(Semaphore::new(0));
+    let task_finished = finished.clone();
+    tasks.spawn(async move {
+        let _ = receiver.await;
+        task_finished.add_permits(1);
+    });
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), tasks.wait())
+            .await
+            .is_err()
+    );
+
+    let _ = sender.send(());
+    tasks.wait().await;
+    assert_eq!(finished.available_permits(), 1);
+}
+
+#[test]
+fn capability_model_selection() {
+    let model = |name: &str, capabilities: Value, weight: f64, region: Option<&str>| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": name,
+            "types": ["TextChat"],
+            "capabilities": capabilities,
+            "output_token_weight": weight,
+            "region": region
+        }))
+        .unwrap()
+    };
+
+    let models = vec![
+        model("text-only", json!(["tools"]), 1.0, None),
+        model("vision-expensive", json!(["vision", "tools"]), 4.0, None),
+        model("vision-eu", json!(["vision"]), 2.0, Some("eu")),
+        model("vision-us", json!(["vision"]), 2.0, Some("us")),
+    ];
+
+    let load = Arc::new(ModelLoad::default());
+
+    let selected =
+        select_model_by_capabilities(&models, RequestType::TextChat, &["vision"], None, &load)
+            .unwrap();
+    assert_eq!(selected.name, "vision-eu");
+
+    let selected = select_model_by_capabilities(
+        &models,
+        RequestType::TextChat,
+        &["vision"],
+        Some("us"),
+        &load,
+    )
+    .unwrap();
+    assert_eq!(selected.name, "vision-us");
+
+    // The cheapest model lacks the vision capability, so it can't be chosen.
+    let selected = select_model_by_capabilities(
+        &models,
+        RequestType::TextChat,
+        &["vision", "tools"],
+        Some("us"),
+        &load,
+    )
+    .unwrap();
+    assert_eq!(selected.name, "vision-expensive");
+
+    // Among equally priced models, the one with fewer requests in flight is preferred.
+    let active = load.start(models[2].uuid);
+    let selected =
+        select_model_by_capabilities(&models, RequestType::TextChat, &["vision"], None, &load)
+            .unwrap();
+    assert_eq!(selected.name, "vision-us");
+
+    drop(active);
+    assert_eq!(load.get(models[2].uuid), 0);
+    let selected =
+        select_model_by_capabilities(&models, RequestType::TextChat, &["vision"], None, &load)
+            .unwrap();
+    assert_eq!(selected.name, "vision-eu");
+
+    assert!(
+        select_model_by_capabilities(&models, RequestType::TextChat, &["audio"], None, &load)
+            .is_none()
+    );
+    assert!(select_model_by_capabilities(
+        &models,
+        RequestType::TextCompletion,
+        &["vision"],
+        None,
+        &load
+    )
+    .is_none());
+    assert!(
+        select_model_by_capabilities(&models, RequestType::TextChat, &[], None, &load).is_none()
+    );
+
+    // Models are ranked by their price when they have one, rather than by their output token weight.
+    let priced = |name: &str, weight: f64, input: f64, output: f64| -> Model {
+        let mut model = model(name, json!(["vision"]), weight, None);
+        model.pricing = Some(Pricing {
+            input,
+            output,
+            max_request_cost: None,
+        });
+        model
+    };
+    let models = vec![
+        model("unpriced", json!(["vision"]), 0.1, None),
+        priced("cheap-output", 0.5, 5.0, 10.0),
+        priced("cheap-total", 4.0, 1.0, 12.0),
+    ];
+    let selected =
+        select_model_by_capabilities(&models, RequestType::TextChat, &["vision"], None, &load)
+            .unwrap();
+    assert_eq!(selected.name, "cheap-total");
+}
+
+#[test]
+fn model_deprecation_header() {
+    let model = |deprecated: Value| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "name": "test",
+            "deprecated": deprecated
+        }))
+        .unwrap()
+    };
+
+    assert!(get_deprecation_header(&model(Value::Null)).is_none());
+    assert_eq!(
+        get_deprecation_header(&model(json!("Use test-2 instead"))).unwrap(),
+        "Use test-2 instead"
+    );
+    assert_eq!(get_deprecation_header(&model(json!(""))).unwrap(), "true");
+    assert_eq!(
+        get_deprecation_header(&model(json!("Line\nbreak"))).unwrap(),
+        "true"
+    );
+}
+
+#[test]
+fn unhealthy_model_routing() {
+    let model = |name: &str| -> Model {
+        serde_json::from_value(json!({
+            "api": "Loopback",
+            "uuid": Uuid::new_v4(),
+            "name": name,
+            "types": ["TextChat"]
+        }))
+        .unwrap()
+    };
+
+    let models = vec![model("test"), model("test"), model("fallback")];
+    let health = ModelHealth::new(Duration::from_millis(200), Some(0.5), 2);
+
+    let selected = select_model(&models, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[0].uuid);
+
+    health.record(models[0].uuid, false);
+    health.record(models[0].uuid, false);
+
+    // Equivalent models with the same name are preferred over unhealthy ones.
+    let healthy = get_healthy_models(&models, &health);
+    let selected = select_model(&healthy, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[1].uuid);
+
+    health.record(models[1].uuid, false);
+    health.record(models[1].uuid, false);
+
+    let healthy = get_healthy_models(&models, &health);
+    assert!(select_model(&healthy, RequestType::TextChat, "test", None, None).is_none());
+
+    let chain = prefer_healthy_models(models.clone(), &health);
+    assert_eq!(chain[0].name, "fallback");
+    assert_eq!(chain[1].uuid, models[0].uuid);
+
+    std::thread::sleep(Duration::from_millis(250));
+
+    let healthy = get_healthy_models(&models, &health);
+    let selected = select_model(&healthy, RequestType::TextChat, "test", None, None).unwrap();
+    assert_eq!(selected.uuid, models[0].uuid);
+}
+
+#[test]
+fn request_validation_quotas() {
+    let shared = Uuid::new_v4();
+    let auth = Authenticated {
+        timestamp: Instant::now(),
+        admin: false,
+        user: User {
+            quotas: [shared].into_iter().collect(),
+            ..Default::default()
+        },
+        roles: vec![Role {
+            quotas: [shared, Uuid::new_v4()].into_iter().collect(),
+            ..Default::default()
+        }],
+    };
+    let model: Model =
+        serde_json::from_value(json!({ "api": "Loopback", "quotas": [Uuid::new_v4()] })).unwrap();
+
+    // Quotas shared by the User, their Roles, and the model are only applied once.
+    assert_eq!(get_request_quotas(&auth, &model).len(), 3);
+
+    // Validation checks limits using a copy of their state, which doesn't count the request.
+    let clock = LimiterClock::new();
+    let limit: limiter::Limit =
+        serde_json::from_value(json!({ "count": 1, "type": "Request", "period": 60 })).unwrap();
+    let request = limiter::Request {
+        arrived_at: auth.timestamp,
+        estimated_tokens: 1,
+    };
+    for _ in 0..2 {
+        assert_eq!(
+            limit.clone().request(&clock, &request),
+            LimiterResult::Ready
+        );
+    }
+    assert_eq!(limit.get_usage(&clock, auth.timestamp), 0.0);
+}
+
+#[test]
+fn config_system_fingerprint() {
+    let model = |model_string: &str, api_key: &str, strategy: &str| -> Model {
+        serde_json::from_value(json!({
+            "api": {
+                "OpenAI": {
+                    "model_string": model_string,
+                    "model_context_len": null,
+                    "openai_api_base": "https://api.openai.com",
+                    "openai_api_key": api_key
+                }
+            },
+            "system_fingerprint": strategy
+        }))
+        .unwrap()
+    };
+
+    let fingerprint = get_system_fingerprint(&model("gpt-4", "sk-a", "Config"));
+    assert!(matches!(fingerprint, SystemFingerprint::Override(_)));
+
+    // Rotating a model's API key doesn't change its fingerprint, but changing its backend configuration does.
+    assert_eq!(
+        get_system_fingerprint(&model("gpt-4", "sk-b", "Config")),
+        fingerprint
+    );
+    assert_ne!(
+        get_system_fingerprint(&model("gpt-4o", "sk-a", "Config")),
+        fingerprint
+    );
+
+    assert_eq!(
+        get_system_fingerprint(&model("gpt-4", "sk-a", "Static")),
+        SystemFingerprint::from_model(Uuid::default())
+    );
+    assert_eq!(
+        get_system_fingerprint(&model("gpt-4", "sk-a", "Passthrough")),
+        SystemFingerprint::Passthrough
+    );
+}
@@ -24,6 +24,13 @@ impl LimiterClock {
             epoch: Instant::now(),
         }
     }
+
+    // The clock is only usable if its epoch can be converted into a system time, which is needed to store limiter states.
+    pub(super) fn is_ready(&self) -> bool {
+        SystemTime::now()
+            .checked_sub(self.epoch.elapsed())
+            .is_some()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -44,7 +51,7 @@ impl LimiterState {
     }
 
     #[tracing::instrument(skip(clock), level = "trace", ret)]
-    fn to_monotonic(&self, clock: &LimiterClock) -> Option<Instant> {
+    fn to_monotonic(self, clock: &LimiterClock) -> Option<Instant> {
         self.elasped
             .and_then(|elapsed| match self.uuid == clock.uuid {
                 true => clock.epoch.checked_add(elapsed),
@@ -92,10 +99,23 @@ pub(super) struct Limit {
     pub(super) count: u64,
     pub(super) r#type: LimitItem,
     pub(super) period: u64,
+    #[serde(default)]
+    pub(super) max_borrow: u64,
     state: Option<LimiterState>,
 }
 
 impl Limit {
+    // Returns the fraction of the limit's capacity which is in use at the given time, without modifying the limit's state.
+    #[tracing::instrument(skip(clock), level = "trace", ret)]
+    pub(super) fn get_usage(&self, clock: &LimiterClock, now: Instant) -> f64 {
+        match self.state.and_then(|state| state.to_monotonic(clock)) {
+            Some(tat) if self.period > 0 => {
+                tat.saturating_duration_since(now).as_secs_f64() / self.period as f64
+            }
+            _ => 0.0,
+        }
+    }
+
     #[tracing::instrument(skip(clock), level = "trace", ret)]
     pub(super) fn request(&mut self, clock: &LimiterClock, request: &Request) -> LimiterResult {
         let mut state = GcraState {
@@ -117,10 +137,22 @@ impl Limit {
 
                 LimiterResult::WaitUntil(next_allowed_at)
             }
-            Err(GcraError::DeniedIndefinitely {
-                cost: _,
-                rate_limit: _,
-            }) => return LimiterResult::Oversized,
+            Err(GcraError::DeniedIndefinitely { cost, rate_limit }) => {
+                if cost as u64 > self.count.saturating_add(self.max_borrow) {
+                    return LimiterResult::Oversized;
+                }
+
+                // Oversized requests are only allowed once all previous usage has expired, and borrow the excess from future periods.
+                let allowed_at = state
+                    .tat
+                    .map_or(request.arrived_at, |tat| tat.max(request.arrived_at));
+                state.tat = Some(allowed_at + rate_limit.increment_interval(cost));
+
+                match allowed_at > request.arrived_at {
+                    true => LimiterResult::WaitUntil(allowed_at),
+                    false => LimiterResult::Ready,
+                }
+            }
         };
 
         self.state = state
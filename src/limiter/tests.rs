@@ -193,6 +193,7 @@ fn limit_requests_without_tokens() {
         count,
         r#type: super::LimitItem::Request,
         period: count * get_random_unsigned(3, 128),
+        max_borrow: 0,
         state: None,
     };
 
@@ -231,6 +232,7 @@ fn limit_requests_with_tokens_equal_passes() {
         count,
         r#type: super::LimitItem::Token,
         period: count * get_random_unsigned(3, 128),
+        max_borrow: 0,
         state: None,
     };
 
@@ -318,3 +320,62 @@ fn limit_requests_with_tokens_greater_first_pass() {}
 
 #[test]
 fn limit_requests_with_tokens_greater_second_pass() {}
+
+#[test]
+fn limit_requests_with_tokens_borrowed() {
+    let clock = LimiterClock::new();
+    let request_time = clock.epoch;
+    let count = get_random_unsigned(3, 128);
+    let mut limit = Limit {
+        count,
+        r#type: super::LimitItem::Token,
+        period: count * get_random_unsigned(3, 128),
+        max_borrow: count,
+        state: None,
+    };
+    let interval = Duration::from_secs(limit.period) / limit.count as u32;
+
+    let request = Request {
+        arrived_at: request_time,
+        estimated_tokens: count * 2,
+    };
+    assert_eq!(limit.request(&clock, &request), LimiterResult::Ready);
+
+    let request = Request {
+        arrived_at: request_time,
+        estimated_tokens: 1,
+    };
+    assert_eq!(
+        limit.request(&clock, &request),
+        LimiterResult::WaitUntil(request_time + interval * (count + 1) as u32)
+    );
+
+    let request = Request {
+        arrived_at: request_time,
+        estimated_tokens: count + 1,
+    };
+    assert_eq!(
+        limit.request(&clock, &request),
+        LimiterResult::WaitUntil(request_time + interval * (count * 2 + 1) as u32)
+    );
+}
+
+#[test]
+fn limit_requests_with_tokens_borrow_exceeded() {
+    let clock = LimiterClock::new();
+    let count = get_random_unsigned(3, 128);
+    let mut limit = Limit {
+        count,
+        r#type: super::LimitItem::Token,
+        period: count * get_random_unsigned(3, 128),
+        max_borrow: count,
+        state: None,
+    };
+
+    let request = Request {
+        arrived_at: clock.epoch,
+        estimated_tokens: count * 2 + 1,
+    };
+    assert_eq!(limit.request(&clock, &request), LimiterResult::Oversized);
+    assert!(limit.state.is_none());
+}
@@ -1,13 +1,17 @@
 use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use http::uri::{Authority, Parts, PathAndQuery, Scheme, Uri};
-use opentelemetry::KeyValue;
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::{runtime, trace, Resource};
-use reqwest::{Client, ClientBuilder};
-use tokio::{fs, net::TcpListener, signal};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::{
+    runtime,
+    trace::{self, BatchConfigBuilder, BatchSpanProcessor, TracerProvider},
+    Resource,
+};
+use reqwest::{Client, ClientBuilder, Url};
+use tokio::{fs, net::TcpListener, signal, sync::Semaphore, time};
 use tracing::Level;
 use tracing_opentelemetry::MetricsLayer;
 use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
@@ -15,9 +19,19 @@ use tracing_subscriber::{filter, layer::SubscriberExt, util::SubscriberInitExt};
 mod api;
 mod limiter;
 mod model;
+mod server;
+mod telemetry;
 
-use api::Database;
+use api::{
+    Database, ExternalAuth, ModelHealth, ModelLoad, QuotaConcurrency, RequestCapture,
+    RequestCoalescer, StreamTasks,
+};
 use limiter::LimiterClock;
+use model::{
+    ApiKeyCooldowns, BodyNormalization, CoalescingKeySettings, JsonSchemaLimits, ModelResponse,
+    RequestPacer, RetryBudget, Tokenizer,
+};
+use server::ServerSettings;
 
 /// A multi-user proxy server for major generative model APIs
 #[derive(Parser, Debug)]
@@ -31,9 +45,177 @@ struct Args {
     #[arg(short, long, default_value = "./database")]
     database_folder: PathBuf,
 
+    /// The number of seconds to wait for the database to be unlocked on startup, if it's being used by another process (such as a previous instance of the proxy that is still shutting down).
+    #[arg(long, default_value_t = 0)]
+    wait_for_lock: u64,
+
+    /// Log the database migrations that would be run on startup, then exit without applying them.
+    #[arg(long)]
+    migration_dry_run: bool,
+
     /// The OpenTelemetry-compatible collector used for logging.
     #[arg(short, long)]
     opentelemetry_endpoint: Option<String>,
+
+    /// Continue starting the server without OpenTelemetry if the collector can't be reached on startup, instead of exiting.
+    #[arg(long)]
+    otel_fail_open: bool,
+
+    /// The maximum number of spans buffered for export to the OpenTelemetry collector. Spans are dropped when the buffer is full, instead of delaying requests.
+    #[arg(long, default_value_t = 2048)]
+    otel_max_queue_size: usize,
+
+    /// The fraction of successful requests which are traced to the OpenTelemetry collector, between 0 and 1. Requests which return an error (including rate-limited requests) are always traced. When sampling, up to --otel-max-queue-size spans are held until their request finishes. Requests which take longer than 5 minutes, or whose spans don't fit, are sampled early.
+    #[arg(long, default_value_t = 1.0)]
+    otel_sample_rate: f64,
+
+    /// The maximum number of concurrent requests a single HTTP/2 client connection can make. Higher values allow clients to multiplex more requests over one connection, at the cost of making it easier for a single client to monopolize the server.
+    #[arg(long, default_value_t = 200)]
+    http2_max_concurrent_streams: u32,
+
+    /// The initial HTTP/2 flow control window size of each request, in bytes. Larger windows improve throughput for large request bodies, at the cost of higher memory usage per request.
+    #[arg(long, default_value_t = 2_097_152)]
+    http2_initial_stream_window_size: u32,
+
+    /// The initial HTTP/2 flow control window size of each client connection, in bytes. This should be at least as large as the stream window size.
+    #[arg(long, default_value_t = 5_242_880)]
+    http2_initial_connection_window_size: u32,
+
+    /// Dynamically adjust HTTP/2 flow control windows based on measured connection throughput. Overrides the configured window sizes.
+    #[arg(long)]
+    http2_adaptive_window: bool,
+
+    /// The maximum number of seconds to wait for in-flight requests to finish when shutting down. Requests which are still running afterwards are aborted with a 503 error. By default, the proxy waits for all in-flight requests to finish.
+    #[arg(long)]
+    shutdown_grace: Option<u64>,
+
+    /// The maximum number of in-flight requests to wait for when shutting down. The oldest requests are allowed to finish, and the rest are immediately aborted with a 503 error.
+    #[arg(long)]
+    shutdown_max_requests: Option<usize>,
+
+    /// An HTTP endpoint used to validate API keys, instead of the database. The endpoint receives each API key as a bearer token, and should respond with a User object for valid keys, or a 401 error for invalid keys.
+    #[arg(long)]
+    external_auth_endpoint: Option<Url>,
+
+    /// The number of seconds that valid API keys are cached for when using an external authentication endpoint. Invalid API keys are cached for 5 seconds, or for this long if it's shorter.
+    #[arg(long, default_value_t = 60)]
+    external_auth_cache_ttl: u64,
+
+    /// The number of milliseconds after an identical TextEmbedding or TextModeration request from the same user is sent during which new requests will share its backend request, as long as it's still in flight. Finished responses are never reused. Set to 0 to disable coalescing.
+    #[arg(long, default_value_t = 0)]
+    coalescing_window_ms: u64,
+
+    /// Coalesce requests which only differ in the order of their JSON object keys. Other differences (such as whitespace within strings) are never ignored, as they can change the response.
+    #[arg(long)]
+    semantic_coalescing: bool,
+
+    /// A comma-separated list of the request fields which must be identical for requests to be coalesced. Other fields are ignored. If not specified, all fields must be identical.
+    #[arg(long, value_delimiter = ',')]
+    coalescing_fields: Vec<String>,
+
+    /// Check the structure of json_schema response formats before sending requests to models which support structured outputs, instead of relying on the model's backend to reject invalid schemas.
+    #[arg(long)]
+    validate_json_schemas: bool,
+
+    /// The maximum nesting depth of json_schema response formats, when validating schemas.
+    #[arg(long, default_value_t = 32)]
+    max_json_schema_depth: usize,
+
+    /// The maximum size of json_schema response formats, in bytes, when validating schemas.
+    #[arg(long, default_value_t = 65_536)]
+    max_json_schema_size: usize,
+
+    /// How to handle request bodies with common mistakes, such as messages being an object instead of an array, or values no model accepts (such as n being 0, a negative max_tokens, or a temperature above 2). Lenient fixes these mistakes before the request is processed, while strict rejects them with an error.
+    #[arg(long, value_enum, default_value_t = BodyNormalization::Disabled)]
+    body_normalization: BodyNormalization,
+
+    /// Repair request bodies containing malformed JSON (trailing commas, single-quoted strings, or unquoted object keys) instead of rejecting them. Repairs only change the body's quoting and whitespace, and are logged and listed in the response's proxy warnings. Bodies which can't be repaired this way are still rejected.
+    #[arg(long)]
+    repair_json: bool,
+
+    /// Add an X-Proxy-Warnings header to model responses, listing any changes the proxy made to the request before sending it to the model.
+    #[arg(long)]
+    proxy_warnings: bool,
+
+    /// Add X-Upstream-Latency-Ms and X-Proxy-Queue-Ms headers to model responses, reporting the time spent waiting on the model's backend and on rate limits. This may leak timing information about other users' requests.
+    #[arg(long)]
+    timing_headers: bool,
+
+    /// Save a record of each model request's token usage to the database, which can be exported using the /admin/usage/export endpoint.
+    #[arg(long)]
+    record_usage: bool,
+
+    /// Log the body of each request sent to a model's backend (after conversion) at the trace level, with the backend's API key redacted. This will log the contents of users' requests.
+    #[arg(long)]
+    log_upstream_requests: bool,
+
+    /// Prevent Roles from granting administrative status to their Users. Users with admin set to true (or admin_override set to true) will still be administrators.
+    #[arg(long)]
+    no_role_admin: bool,
+
+    /// Don't suggest similarly named models when a user requests a model that doesn't exist. Suggestions only include models the user can access.
+    #[arg(long)]
+    no_model_suggestions: bool,
+
+    /// A deployment name included in the _proxy metadata object added to model responses, for identifying which deployment served a response.
+    #[arg(long)]
+    deployment_name: Option<String>,
+
+    /// Add an X-Served-Model header to model responses, reporting the name of the model that served the request after any fallbacks. This may leak information about internal routing.
+    #[arg(long)]
+    served_model_header: bool,
+
+    /// The maximum number of model requests (including batch requests) the proxy will handle at once. Requests over this limit are immediately rejected with a 503 error, instead of being queued. Requests are only counted once they're authenticated, and admin API requests aren't counted.
+    #[arg(long)]
+    max_concurrent_requests: Option<usize>,
+
+    /// The maximum number of seconds a request can wait for a Quota before being rejected, unless overridden by the Quota.
+    #[arg(long, default_value_t = 120)]
+    max_rate_limit_wait: u64,
+
+    /// The maximum number of seconds to wait for a response from a model's backend, unless raised by the User or their Roles. Requests which time out fail with a 504 error with the code upstream_timeout. If not specified, requests to model backends don't time out.
+    #[arg(long)]
+    upstream_timeout: Option<u64>,
+
+    /// The maximum number of seconds that Users and Roles can raise the upstream timeout to.
+    #[arg(long, default_value_t = 3600)]
+    max_upstream_timeout: u64,
+
+    /// The fraction of a model's recent requests (between 0 and 1) which can fail with a server error before the model is considered unhealthy. Unhealthy models are only used if no healthy model with the same name (or healthy fallback) is available. If not specified, models are never considered unhealthy.
+    #[arg(long)]
+    health_error_threshold: Option<f64>,
+
+    /// The number of seconds of requests used to calculate each model's error rate. Unhealthy models recover once their errors are older than this.
+    #[arg(long, default_value_t = 60)]
+    health_window: u64,
+
+    /// The minimum number of requests a model must have handled within the health window before it can be considered unhealthy.
+    #[arg(long, default_value_t = 10)]
+    health_min_requests: usize,
+
+    /// The tokenizer used to count tokens for models whose configured tokenizer is unknown, so that context length checks keep working if a model is misconfigured.
+    #[arg(long, value_enum, default_value_t = Tokenizer::Cl100kBase)]
+    fallback_tokenizer: Tokenizer,
+
+    /// Disable the fallback tokenizer. Models with an unknown tokenizer can't be added using the admin API, and existing models with an unknown tokenizer won't have their requests' tokens counted.
+    #[arg(long)]
+    strict_tokenizers: bool,
+
+    /// Allow Users to capture their own recent requests and responses for debugging, keeping them in memory for this many seconds. Users can only opt in if they (or one of their Roles) have allow_request_capture set to true. If not specified, request capture is disabled.
+    #[arg(long)]
+    request_capture_retention: Option<u64>,
+
+    /// The number of times each User's requests can be retried (after transient backend errors) within the retry budget window. Once a User's retries are used up, their requests fail without being retried until the budget refills. If not specified, retries are only limited by each model's max_retries.
+    #[arg(long)]
+    retry_budget: Option<u32>,
+
+    /// The number of seconds over which each User's retry budget is refilled.
+    #[arg(long, default_value_t = 60)]
+    retry_budget_window: u64,
+
+    /// Serve request counts, latency histograms, and other metrics in Prometheus' text format at an unauthenticated /metrics endpoint. This doesn't require an OpenTelemetry collector.
+    #[arg(long)]
+    prometheus_metrics: bool,
 }
 
 #[derive(Clone)]
@@ -41,12 +223,44 @@ struct AppState {
     http: Client,
     database: Database,
     clock: Arc<LimiterClock>,
+    proxy_warnings: bool,
+    timing_headers: bool,
+    record_usage: bool,
+    served_model_header: bool,
+    deployment_name: Option<String>,
+    max_rate_limit_wait: Duration,
+    upstream_timeout: Option<Duration>,
+    max_upstream_timeout: Duration,
+    role_admin: bool,
+    model_suggestions: bool,
+    log_upstream_requests: bool,
+    external_auth: Option<Arc<ExternalAuth>>,
+    coalescer: Option<Arc<RequestCoalescer<ModelResponse>>>,
+    coalescing_key: CoalescingKeySettings,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    quota_concurrency: Arc<QuotaConcurrency>,
+    model_load: Arc<ModelLoad>,
+    stream_tasks: Arc<StreamTasks>,
+    pacer: Arc<RequestPacer>,
+    retry_budget: Arc<RetryBudget>,
+    key_cooldowns: Arc<ApiKeyCooldowns>,
+    prometheus_metrics: Option<telemetry::PrometheusMetrics>,
+    health: Arc<ModelHealth>,
+    json_schema_limits: Option<JsonSchemaLimits>,
+    body_normalization: BodyNormalization,
+    repair_json: bool,
+    fallback_tokenizer: Option<Tokenizer>,
+    request_capture: Option<Arc<RequestCapture>>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    let prometheus_metrics = args
+        .prometheus_metrics
+        .then(telemetry::PrometheusMetrics::default);
+
     let registry = tracing_subscriber::registry()
         .with(
             filter::Targets::new()
@@ -64,22 +278,53 @@ async fn main() -> Result<()> {
                     ("sled", Level::INFO),
                 ]),
         )
-        .with(tracing_subscriber::fmt::layer().pretty());
+        .with(tracing_subscriber::fmt::layer().pretty())
+        .with(prometheus_metrics.clone());
+
+    let collector_error = match &args.opentelemetry_endpoint {
+        Some(endpoint) => match telemetry::check_collector(endpoint).await {
+            Err(error) if !args.otel_fail_open => {
+                return Err(error.context("Unable to connect to OpenTelemetry collector"))
+            }
+            result => result.err(),
+        },
+        None => None,
+    };
 
-    match args.opentelemetry_endpoint {
+    match args
+        .opentelemetry_endpoint
+        .filter(|_| collector_error.is_none())
+    {
         Some(endpoint) => {
-            let tracer = opentelemetry_otlp::new_pipeline()
-                .tracing()
-                .with_exporter(
-                    opentelemetry_otlp::new_exporter()
-                        .tonic()
-                        .with_endpoint(endpoint.clone()),
+            let exporter = SpanExporterBuilder::from(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint.clone()),
+            )
+            .build_span_exporter()
+            .context("Failed to start OpenTelemetry tracing pipeline")?;
+            let processor = BatchSpanProcessor::builder(exporter, runtime::Tokio)
+                .with_batch_config(
+                    BatchConfigBuilder::default()
+                        .with_max_queue_size(args.otel_max_queue_size)
+                        .build(),
+                )
+                .build();
+            let provider = TracerProvider::builder()
+                .with_span_processor(telemetry::SamplingSpanProcessor::new(
+                    processor,
+                    args.otel_sample_rate,
+                    args.otel_max_queue_size,
+                ))
+                .with_config(
+                    trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        "generative-model-proxy-server",
+                    )])),
                 )
-                .with_trace_config(trace::config().with_resource(Resource::new(vec![
-                    KeyValue::new("service.name", "generative-model-proxy-server"),
-                ])))
-                .install_batch(runtime::Tokio)
-                .context("Failed to start OpenTelemetry tracing pipeline")?;
+                .build();
+            let tracer = provider.tracer("generative-model-proxy-server");
+            opentelemetry::global::set_tracer_provider(provider);
             let meter = opentelemetry_otlp::new_pipeline()
                 .metrics(runtime::Tokio)
                 .with_exporter(
@@ -97,6 +342,7 @@ async fn main() -> Result<()> {
             let metrics = MetricsLayer::new(meter);
 
             registry.with(metrics).with(telemetry).init();
+            telemetry::install_error_handler();
 
             if cfg!(debug_assertions) {
                 tracing::warn!(
@@ -107,10 +353,38 @@ async fn main() -> Result<()> {
         None => registry.init(),
     }
 
+    if let Some(error) = collector_error {
+        tracing::warn!(
+            "Unable to connect to OpenTelemetry collector, continuing without it: {:#}",
+            error
+        );
+    }
+
     fs::create_dir_all(&args.database_folder)
         .await
         .context("Unable to create database directory!")?;
 
+    if args.migration_dry_run {
+        return Database::log_planned_migrations(&args.database_folder)
+            .context("Unable to migrate database");
+    }
+
+    let database = match Database::open_with_lock_wait(
+        &args.database_folder,
+        Duration::from_secs(args.wait_for_lock),
+    )
+    .await
+    {
+        Ok(database) => database,
+        Err(error) if Database::is_lock_error(&error) => {
+            return Err(anyhow!(error).context(format!(
+                "Unable to initalize database, as {} is locked. Another instance of the proxy is likely running with the same database folder; stop it, use a different --database-folder, or use --wait-for-lock to wait for it to exit",
+                args.database_folder.display()
+            )))
+        }
+        Err(error) => return Err(error).context("Unable to initalize database"),
+    };
+
     let state = AppState {
         http: ClientBuilder::new()
             .user_agent("generative-model-proxy-server")
@@ -120,15 +394,72 @@ async fn main() -> Result<()> {
             .http2_keep_alive_while_idle(true)
             .build()
             .context("Unable to initalize HTTP client")?,
-        database: Database::open(&args.database_folder).context("Unable to initalize database")?,
+        database,
         clock: Arc::new(LimiterClock::new()),
+        proxy_warnings: args.proxy_warnings,
+        timing_headers: args.timing_headers,
+        record_usage: args.record_usage,
+        served_model_header: args.served_model_header,
+        deployment_name: args.deployment_name,
+        max_rate_limit_wait: Duration::from_secs(args.max_rate_limit_wait),
+        upstream_timeout: args.upstream_timeout.map(Duration::from_secs),
+        max_upstream_timeout: Duration::from_secs(args.max_upstream_timeout),
+        role_admin: !args.no_role_admin,
+        model_suggestions: !args.no_model_suggestions,
+        log_upstream_requests: args.log_upstream_requests,
+        external_auth: args.external_auth_endpoint.map(|endpoint| {
+            Arc::new(ExternalAuth::new(
+                endpoint,
+                Duration::from_secs(args.external_auth_cache_ttl),
+            ))
+        }),
+        coalescer: match args.coalescing_window_ms {
+            0 => None,
+            window => Some(Arc::new(RequestCoalescer::new(Duration::from_millis(
+                window,
+            )))),
+        },
+        coalescing_key: CoalescingKeySettings {
+            semantic: args.semantic_coalescing,
+            fields: args.coalescing_fields,
+        },
+        concurrency_limit: args
+            .max_concurrent_requests
+            .map(|limit| Arc::new(Semaphore::new(limit))),
+        quota_concurrency: Arc::new(QuotaConcurrency::default()),
+        model_load: Arc::new(ModelLoad::default()),
+        stream_tasks: Arc::new(StreamTasks::default()),
+        pacer: Arc::new(RequestPacer::default()),
+        retry_budget: Arc::new(match args.retry_budget {
+            Some(retries) => {
+                RetryBudget::new(retries, Duration::from_secs(args.retry_budget_window))
+            }
+            None => RetryBudget::default(),
+        }),
+        key_cooldowns: Arc::new(ApiKeyCooldowns::default()),
+        prometheus_metrics,
+        health: Arc::new(ModelHealth::new(
+            Duration::from_secs(args.health_window),
+            args.health_error_threshold,
+            args.health_min_requests,
+        )),
+        json_schema_limits: args.validate_json_schemas.then_some(JsonSchemaLimits {
+            max_depth: args.max_json_schema_depth,
+            max_size: args.max_json_schema_size,
+        }),
+        body_normalization: args.body_normalization,
+        repair_json: args.repair_json,
+        fallback_tokenizer: (!args.strict_tokenizers).then_some(args.fallback_tokenizer),
+        request_capture: args
+            .request_capture_retention
+            .map(|retention| Arc::new(RequestCapture::new(Duration::from_secs(retention)))),
     };
 
     let listener = TcpListener::bind(&args.bind_to)
         .await
         .with_context(|| format!("Failed to bind HTTP server to {}", &args.bind_to))?;
 
-    if state.database.is_table_empty("users") {
+    if state.external_auth.is_none() && state.database.is_table_empty("users") {
         let addr = listener.local_addr().unwrap_or(args.bind_to);
         let mut parts = Parts::default();
         parts.scheme = Some(Scheme::HTTP);
@@ -143,14 +474,41 @@ async fn main() -> Result<()> {
         tracing::warn!("It looks like you don't have any users added to your database. Please see {} (login with a blank username and \"setup-key\" as the password) for more information.", uri)
     }
 
-    axum::serve(listener, api::api_router(state.clone()))
-        .with_graceful_shutdown(async move {
+    api::check_model_tokenizers(&state);
+    tokio::spawn(api::warm_model_connections(state.clone()));
+
+    let settings = ServerSettings {
+        http2_max_concurrent_streams: args.http2_max_concurrent_streams,
+        http2_initial_stream_window_size: args.http2_initial_stream_window_size,
+        http2_initial_connection_window_size: args.http2_initial_connection_window_size,
+        http2_adaptive_window: args.http2_adaptive_window,
+        shutdown_grace: args.shutdown_grace.map(Duration::from_secs),
+        shutdown_max_requests: args.shutdown_max_requests,
+    };
+
+    server::serve(
+        listener,
+        api::api_router(state.clone()),
+        settings,
+        async move {
             if let Err(error) = signal::ctrl_c().await {
                 tracing::error!("Unable to run signal handler task: {}", error)
             }
-        })
-        .await
-        .context("Failed to start HTTP server")?;
+        },
+    )
+    .await
+    .context("Failed to start HTTP server")?;
+
+    // The database is only flushed once in-flight requests (and the Quota updates of streamed responses) have finished or been aborted, so that their usage is included.
+    let stream_tasks = state.stream_tasks.wait();
+    match args.shutdown_grace.map(Duration::from_secs) {
+        Some(grace) => {
+            if time::timeout(grace, stream_tasks).await.is_err() {
+                tracing::warn!("Timed out waiting for streamed responses to update quotas");
+            }
+        }
+        None => stream_tasks.await,
+    }
 
     tracing::debug!("flushing database to disk");
     if let Err(error) = state.database.close().await {
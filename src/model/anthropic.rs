@@ -0,0 +1,487 @@
+use std::time::Instant;
+
+use base32::Alphabet;
+use reqwest::{header::HeaderMap, Client, Url};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::Value, Map};
+use uuid::Uuid;
+
+use super::{
+    get_backend_headers, get_backend_url, get_message_text, get_openai_finish_reason,
+    send_converted_request, ConvertedRequestTarget, ModelError, ModelRequest, ModelRequestData,
+    ModelResponse, ModelResponseData, RequestType, STREAMING_UNSUPPORTED_WARNING,
+};
+
+const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+
+// The Messages API requires max_tokens to be set, so this is used when the client doesn't specify it.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+const SAMPLING_PARAMETERS: [&str; 3] = ["temperature", "top_p", "top_k"];
+
+const REQUIRED_PARAMETERS: [&str; 3] = ["model", "max_tokens", "messages"];
+
+fn default_anthropic_version() -> String {
+    DEFAULT_ANTHROPIC_VERSION.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct AnthropicModelBackend {
+    model_string: String,
+    pub(super) model_context_len: Option<u64>,
+    anthropic_api_base: String,
+    pub(super) anthropic_api_key: String,
+    #[serde(default = "default_anthropic_version")]
+    anthropic_version: String,
+    #[serde(default)]
+    max_output_tokens: Option<u64>,
+}
+
+impl AnthropicModelBackend {
+    // Anthropic authenticates using an x-api-key header instead of a bearer token.
+    fn get_headers(&self) -> Option<HeaderMap> {
+        get_backend_headers(&[
+            ("x-api-key", &self.anthropic_api_key),
+            ("anthropic-version", &self.anthropic_version),
+        ])
+    }
+
+    pub(super) fn get_default_max_tokens(&self) -> u64 {
+        self.max_output_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    pub(super) fn get_probe_parameters(&self) -> Option<(Url, HeaderMap)> {
+        get_backend_url(&self.anthropic_api_base, "/v1/models").zip(self.get_headers())
+    }
+
+    pub(super) async fn generate(
+        &self,
+        http_client: &Client,
+        tag: Uuid,
+        model: Uuid,
+        request: ModelRequest,
+        deadline: Option<Instant>,
+        log_upstream_requests: bool,
+    ) -> ModelResponse {
+        // Both chat and completion requests are sent to the Messages API, as the Text Completions API is deprecated.
+        match request.r#type {
+            RequestType::TextChat | RequestType::TextCompletion => {}
+            _ => return ModelResponse::from(ModelError::UnknownEndpoint),
+        }
+
+        let (url, headers) = match get_backend_url(&self.anthropic_api_base, "/v1/messages")
+            .zip(self.get_headers())
+        {
+            Some(parameters) => parameters,
+            None => return ModelResponse::from(ModelError::InternalError),
+        };
+        let request_type = request.r#type;
+        let user = request.user;
+
+        send_converted_request(
+            http_client,
+            tag,
+            model,
+            request,
+            deadline,
+            log_upstream_requests,
+            ConvertedRequestTarget {
+                url,
+                headers,
+                api_key: &self.anthropic_api_key,
+                required_parameters: &REQUIRED_PARAMETERS,
+            },
+            |request, warnings| {
+                request.into_anthropic(
+                    request_type,
+                    self.model_string.clone(),
+                    self.get_default_max_tokens(),
+                    user,
+                    warnings,
+                )
+            },
+            |response| response.insert_anthropic_choices(request_type),
+        )
+        .await
+    }
+}
+
+fn get_user_id(user: Uuid) -> String {
+    base32::encode(
+        Alphabet::Crockford,
+        digest::digest(&digest::SHA256, user.as_bytes()).as_ref(),
+    )
+}
+
+// Image URLs are sent as URL sources, except for data URLs, which are sent as base64 sources.
+fn get_image_block(url: &str) -> Value {
+    let data = url
+        .strip_prefix("data:")
+        .and_then(|data| data.split_once(";base64,"));
+
+    match data {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": media_type,
+                "data": data,
+            },
+        }),
+        None => json!({
+            "type": "image",
+            "source": {
+                "type": "url",
+                "url": url,
+            },
+        }),
+    }
+}
+
+// Returns the message's content blocks, and whether any content had to be removed.
+fn get_content_blocks(content: Option<&Value>) -> (Vec<Value>, bool) {
+    match content {
+        Some(Value::String(text)) if !text.is_empty() => {
+            (vec![json!({ "type": "text", "text": text })], false)
+        }
+        Some(Value::Array(parts)) => {
+            let mut removed_content = false;
+            let blocks = parts
+                .iter()
+                .filter_map(|part| {
+                    let block = match part.get("type").and_then(|r#type| r#type.as_str()) {
+                        Some("text") => part
+                            .get("text")
+                            .and_then(|text| text.as_str())
+                            .map(|text| json!({ "type": "text", "text": text })),
+                        Some("image_url") => part
+                            .get("image_url")
+                            .and_then(|image| image.get("url").or(Some(image)))
+                            .and_then(|url| url.as_str())
+                            .map(get_image_block),
+                        _ => None,
+                    };
+                    removed_content |= block.is_none();
+
+                    block
+                })
+                .collect();
+
+            (blocks, removed_content)
+        }
+        _ => (Vec::new(), false),
+    }
+}
+
+fn get_tool_use_block(tool_call: &Value) -> Option<Value> {
+    let function = tool_call.get("function")?;
+    let input = match function.get("arguments") {
+        Some(Value::String(arguments)) => {
+            serde_json::from_str(arguments).unwrap_or_else(|_| json!({}))
+        }
+        Some(Value::Object(arguments)) => Value::Object(arguments.clone()),
+        _ => json!({}),
+    };
+
+    Some(json!({
+        "type": "tool_use",
+        "id": tool_call.get("id").cloned().unwrap_or(Value::Null),
+        "name": function.get("name").cloned().unwrap_or(Value::Null),
+        "input": input,
+    }))
+}
+
+fn get_anthropic_tool(tool: &Value) -> Option<Value> {
+    let function = tool.get("function")?;
+    let mut converted = Map::new();
+
+    converted.insert("name".to_string(), function.get("name")?.clone());
+    if let Some(description) = function.get("description") {
+        converted.insert("description".to_string(), description.clone());
+    }
+    converted.insert(
+        "input_schema".to_string(),
+        function
+            .get("parameters")
+            .cloned()
+            .unwrap_or_else(|| json!({ "type": "object" })),
+    );
+
+    Some(Value::Object(converted))
+}
+
+fn get_anthropic_tool_choice(tool_choice: &Value) -> Option<Value> {
+    match tool_choice {
+        Value::String(choice) => match choice.as_str() {
+            "auto" => Some(json!({ "type": "auto" })),
+            "required" => Some(json!({ "type": "any" })),
+            "none" => Some(json!({ "type": "none" })),
+            _ => None,
+        },
+        Value::Object(choice) => choice
+            .get("function")
+            .and_then(|function| function.get("name"))
+            .map(|name| json!({ "type": "tool", "name": name })),
+        _ => None,
+    }
+}
+
+// Appends the content blocks to the conversation, merging them into the previous message if it has the same role, as the Messages API requires roles to alternate.
+fn push_message(messages: &mut Vec<Value>, role: &str, blocks: Vec<Value>) {
+    if blocks.is_empty() {
+        return;
+    }
+
+    if let Some(Value::Object(message)) = messages.last_mut() {
+        if message.get("role").and_then(|role| role.as_str()) == Some(role) {
+            if let Some(Value::Array(content)) = message.get_mut("content") {
+                content.extend(blocks);
+                return;
+            }
+        }
+    }
+
+    messages.push(json!({ "role": role, "content": blocks }));
+}
+
+impl ModelRequestData {
+    // Converts an OpenAI-style request into a Messages API request. Parameters which don't have an Anthropic equivalent are removed.
+    #[tracing::instrument(level = "trace", ret)]
+    pub(super) fn into_anthropic(
+        self,
+        r#type: RequestType,
+        model: String,
+        max_tokens: u64,
+        user: Option<Uuid>,
+        warnings: &mut Vec<String>,
+    ) -> Result<Self, ModelError> {
+        let mut json = match self {
+            Self::Json(json) => json,
+            Self::Form(_) => return Err(ModelError::BadRequest),
+        };
+
+        if let Some(Value::Bool(true)) = json.remove("stream") {
+            warnings.push(STREAMING_UNSUPPORTED_WARNING.to_string());
+        }
+        json.remove("stream_options");
+        json.remove("model");
+        json.remove("user");
+
+        let mut system = Vec::new();
+        let mut messages = Vec::new();
+        let mut removed_content = false;
+
+        match r#type {
+            RequestType::TextChat => {
+                let chat = match json.remove("messages") {
+                    Some(Value::Array(messages)) => messages,
+                    _ => {
+                        return Err(ModelError::InvalidParameterType {
+                            param: "messages",
+                            expected: "array",
+                        })
+                    }
+                };
+
+                for message in &chat {
+                    match message.get("role").and_then(|role| role.as_str()) {
+                        Some("system") | Some("developer") => {
+                            system.push(get_message_text(message));
+                        }
+                        Some("assistant") => {
+                            let (mut blocks, removed) = get_content_blocks(message.get("content"));
+                            removed_content |= removed;
+
+                            if let Some(Value::Array(tool_calls)) = message.get("tool_calls") {
+                                blocks.extend(tool_calls.iter().filter_map(get_tool_use_block));
+                            }
+
+                            push_message(&mut messages, "assistant", blocks);
+                        }
+                        Some("tool") => {
+                            let tool_use_id =
+                                message.get("tool_call_id").cloned().unwrap_or(Value::Null);
+
+                            push_message(
+                                &mut messages,
+                                "user",
+                                vec![json!({
+                                    "type": "tool_result",
+                                    "tool_use_id": tool_use_id,
+                                    "content": get_message_text(message),
+                                })],
+                            );
+                        }
+                        _ => {
+                            let (blocks, removed) = get_content_blocks(message.get("content"));
+                            removed_content |= removed;
+
+                            push_message(&mut messages, "user", blocks);
+                        }
+                    }
+                }
+            }
+            RequestType::TextCompletion => match json.remove("prompt") {
+                Some(Value::String(prompt)) => push_message(
+                    &mut messages,
+                    "user",
+                    vec![json!({ "type": "text", "text": prompt })],
+                ),
+                _ => {
+                    return Err(ModelError::InvalidParameterType {
+                        param: "prompt",
+                        expected: "string",
+                    })
+                }
+            },
+            _ => return Err(ModelError::UnknownEndpoint),
+        }
+
+        let mut request = Map::new();
+        request.insert("model".to_string(), Value::String(model));
+        if !system.is_empty() {
+            request.insert("system".to_string(), Value::String(system.join("\n\n")));
+        }
+        request.insert("messages".to_string(), Value::Array(messages));
+
+        let max_tokens = match (
+            json.remove("max_completion_tokens"),
+            json.remove("max_tokens"),
+        ) {
+            (Some(Value::Number(tokens)), _) | (_, Some(Value::Number(tokens))) => {
+                tokens.as_u64().unwrap_or(max_tokens)
+            }
+            _ => max_tokens,
+        };
+        request.insert("max_tokens".to_string(), Value::Number(max_tokens.into()));
+
+        for key in SAMPLING_PARAMETERS {
+            if let Some(value) = json.remove(key) {
+                request.insert(key.to_string(), value);
+            }
+        }
+
+        match json.remove("stop") {
+            Some(Value::String(sequence)) => {
+                request.insert("stop_sequences".to_string(), json!([sequence]));
+            }
+            Some(Value::Array(sequences)) => {
+                request.insert("stop_sequences".to_string(), Value::Array(sequences));
+            }
+            _ => {}
+        }
+
+        if let Some(Value::Array(tools)) = json.remove("tools") {
+            let tools: Vec<Value> = tools.iter().filter_map(get_anthropic_tool).collect();
+
+            if !tools.is_empty() {
+                request.insert("tools".to_string(), Value::Array(tools));
+            }
+        }
+        if let Some(tool_choice) = json
+            .remove("tool_choice")
+            .as_ref()
+            .and_then(get_anthropic_tool_choice)
+        {
+            request.insert("tool_choice".to_string(), tool_choice);
+        }
+
+        if let Some(user) = user {
+            request.insert(
+                "metadata".to_string(),
+                json!({ "user_id": get_user_id(user) }),
+            );
+        }
+
+        let mut removed_parameters: Vec<&str> = json
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, _)| key.as_str())
+            .collect();
+        if !removed_parameters.is_empty() {
+            removed_parameters.sort_unstable();
+            warnings.push(format!(
+                "The following parameters are not supported by this model and were removed: {}.",
+                removed_parameters.join(", ")
+            ));
+        }
+        if removed_content {
+            warnings.push(
+                "Some message content is not supported by this model and was removed.".to_string(),
+            );
+        }
+
+        Ok(Self::Json(request))
+    }
+}
+
+impl ModelResponseData {
+    // Adds OpenAI-style choices to a Messages API response, so that into_hybrid_api can fill in the rest of the hybrid response.
+    #[tracing::instrument(level = "trace")]
+    pub(super) fn insert_anthropic_choices(&mut self, r#type: RequestType) {
+        let json = match self {
+            Self::Json(json) if json.get("type") == Some(&json!("message")) => json,
+            _ => return,
+        };
+
+        let blocks = match json.get("content") {
+            Some(Value::Array(blocks)) => blocks.as_slice(),
+            _ => &[],
+        };
+
+        let text: String = blocks
+            .iter()
+            .filter(|block| block.get("type") == Some(&json!("text")))
+            .filter_map(|block| block.get("text").and_then(|text| text.as_str()))
+            .collect();
+        let tool_calls: Vec<Value> = blocks
+            .iter()
+            .filter(|block| block.get("type") == Some(&json!("tool_use")))
+            .map(|block| {
+                json!({
+                    "id": block.get("id").cloned().unwrap_or(Value::Null),
+                    "type": "function",
+                    "function": {
+                        "name": block.get("name").cloned().unwrap_or(Value::Null),
+                        "arguments": block.get("input").unwrap_or(&json!({})).to_string(),
+                    },
+                })
+            })
+            .collect();
+        let finish_reason = json
+            .get("stop_reason")
+            .and_then(|reason| reason.as_str())
+            .map(|reason| Value::String(get_openai_finish_reason(reason).to_string()))
+            .unwrap_or(Value::Null);
+
+        let choice = match r#type {
+            RequestType::TextCompletion => json!({
+                "index": 0,
+                "text": text,
+                "finish_reason": finish_reason,
+            }),
+            _ => {
+                let mut message = Map::new();
+                message.insert("role".to_string(), json!("assistant"));
+                message.insert(
+                    "content".to_string(),
+                    match text.is_empty() && !tool_calls.is_empty() {
+                        true => Value::Null,
+                        false => Value::String(text),
+                    },
+                );
+                if !tool_calls.is_empty() {
+                    message.insert("tool_calls".to_string(), Value::Array(tool_calls));
+                }
+
+                json!({
+                    "index": 0,
+                    "message": message,
+                    "finish_reason": finish_reason,
+                })
+            }
+        };
+        json.insert("choices".to_string(), json!([choice]));
+    }
+}
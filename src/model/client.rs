@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use http::status::StatusCode;
 use reqwest::{
@@ -9,13 +9,45 @@ use reqwest::{
 use serde_json::{value::Value, Map};
 
 use super::{
+    stream::{ModelStream, StreamSettings},
     ModelError, ModelFormItem, ModelRequest, ModelRequestData, ModelResponse, ModelResponseData,
-    TokenUsage,
+    ModelTimings, TokenUsage,
 };
 
+// Returned alongside the responses to failed requests which may succeed if they're sent again, with the delay requested by the backend's Retry-After header (if any).
+#[derive(Debug)]
+pub(super) struct Retryable {
+    pub(super) retry_after: Option<Duration>,
+    // Server errors may mean that the request was partially processed, so they're only retried if the model allows it.
+    pub(super) server_error: bool,
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+fn is_server_error_status(status: StatusCode) -> bool {
+    status == StatusCode::INTERNAL_SERVER_ERROR
+        || status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("retry-after")?.to_str().ok()?.trim();
+
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value).ok().map(|timestamp| {
+            timestamp
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        }),
+    }
+}
+
 impl ModelRequest {
     #[tracing::instrument(name = "serialize_model_request", level = "debug", skip_all)]
-    fn to_http_body(self, base: RequestBuilder) -> reqwest::Result<Request> {
+    fn into_http_body(self, base: RequestBuilder) -> reqwest::Result<Request> {
         match self.request {
             ModelRequestData::Json(json) => base.json(&json),
             ModelRequestData::Form(formdata) => {
@@ -97,6 +129,9 @@ impl ModelResponse {
                 ModelResponse {
                     status,
                     usage: TokenUsage::default(),
+                    warnings: Vec::new(),
+                    timings: ModelTimings::default(),
+                    retry_after: None,
                     response,
                 }
             }
@@ -107,6 +142,9 @@ impl ModelResponse {
                     ModelResponse {
                         status,
                         usage: TokenUsage::default(),
+                        warnings: Vec::new(),
+                        timings: ModelTimings::default(),
+                        retry_after: None,
                         response,
                     }
                 } else {
@@ -118,6 +156,27 @@ impl ModelResponse {
     }
 }
 
+#[tracing::instrument(level = "debug", skip_all)]
+pub(super) async fn send_probe_request(
+    client: &Client,
+    url: Url,
+    headers: HeaderMap,
+) -> Result<(), (StatusCode, String)> {
+    match client.get(url).headers(headers).send().await {
+        Ok(http_response) if http_response.status().is_success() => Ok(()),
+        Ok(http_response) => {
+            let status = StatusCode::from_u16(http_response.status().as_u16()).unwrap();
+
+            Err((status, http_response.text().await.unwrap_or_default()))
+        }
+        Err(error) => {
+            tracing::warn!("Error sending probe request: {:?}", error);
+
+            Err((StatusCode::BAD_GATEWAY, error.to_string()))
+        }
+    }
+}
+
 #[tracing::instrument(level = "debug", fields(otel.name = format!("{} {}", method, url.as_str()), otel.kind = "Client", network.protocol.name = "http", network.protocol.version, server.address = url.authority(), server.port = url.port_or_known_default(), url.full = url.as_str(), url.scheme = url.scheme(), user_agent.original = "generative-model-proxy-server", http.request.method = method.as_str(), http.request.header.content_type, http.response.status_code, http.response.header.content_type), skip_all)]
 pub(super) async fn send_http_request(
     client: &Client,
@@ -126,10 +185,11 @@ pub(super) async fn send_http_request(
     headers: HeaderMap,
     request: ModelRequest,
     binary: bool,
-) -> ModelResponse {
+    stream: Option<StreamSettings>,
+) -> (ModelResponse, Option<Retryable>) {
     let span = tracing::Span::current();
 
-    match request.to_http_body(client.request(method, url).headers(headers)) {
+    match request.into_http_body(client.request(method, url).headers(headers)) {
         Ok(http_request) => {
             if let Some(content_type) = http_request
                 .headers()
@@ -171,6 +231,38 @@ pub(super) async fn send_http_request(
                     }
 
                     let status = StatusCode::from_u16(http_response.status().as_u16()).unwrap();
+
+                    // Backends which don't support streaming may return a regular response instead.
+                    let is_event_stream = http_response
+                        .headers()
+                        .get("content-type")
+                        .and_then(|value| value.to_str().ok())
+                        .is_some_and(|value| value.starts_with("text/event-stream"));
+                    if let Some(settings) =
+                        stream.filter(|_| status.is_success() && is_event_stream)
+                    {
+                        let response = ModelResponse {
+                            status,
+                            usage: TokenUsage::default(),
+                            warnings: Vec::new(),
+                            timings: ModelTimings::default(),
+                            retry_after: None,
+                            response: ModelResponseData::Stream(ModelStream::new(
+                                http_response,
+                                settings,
+                            )),
+                        };
+
+                        return (response, None);
+                    }
+
+                    let server_error = is_server_error_status(status);
+                    let retryable =
+                        (is_retryable_status(status) || server_error).then(|| Retryable {
+                            retry_after: get_retry_after(http_response.headers()),
+                            server_error,
+                        });
+
                     let body = http_response.bytes().await;
 
                     tracing::debug!(
@@ -185,33 +277,46 @@ pub(super) async fn send_http_request(
                                 unit = "By"
                             );
 
-                            ModelResponse::from_http_body(status, &body.to_vec(), binary)
+                            (
+                                ModelResponse::from_http_body(status, &body.to_vec(), binary),
+                                retryable,
+                            )
                         }
                         Err(error) => {
                             tracing::error!("Error receiving response: {:?}", error);
 
-                            ModelResponse::from(ModelError::BackendError)
+                            (ModelResponse::from(ModelError::BackendError), None)
                         }
                     }
                 }
                 Err(error) => {
                     tracing::error!("Error sending request: {:?}", error);
 
-                    if error.is_connect() | error.is_redirect() | error.is_decode() {
-                        return ModelResponse::from(ModelError::BackendError);
+                    if error.is_connect() {
+                        return (
+                            ModelResponse::from(ModelError::BackendError),
+                            Some(Retryable {
+                                retry_after: None,
+                                server_error: false,
+                            }),
+                        );
+                    }
+
+                    if error.is_redirect() | error.is_decode() {
+                        return (ModelResponse::from(ModelError::BackendError), None);
                     }
 
                     if error.is_timeout() {
-                        return ModelResponse::from(ModelError::ModelRateLimit);
+                        return (ModelResponse::from(ModelError::ModelRateLimit), None);
                     }
 
-                    ModelResponse::from(ModelError::InternalError)
+                    (ModelResponse::from(ModelError::InternalError), None)
                 }
             }
         }
         Err(error) => {
             tracing::error!("Error building request: {:?}", error);
-            ModelResponse::from(ModelError::InternalError)
+            (ModelResponse::from(ModelError::InternalError), None)
         }
     }
 }
@@ -0,0 +1,519 @@
+use std::{collections::HashMap, time::Instant};
+
+use reqwest::{header::HeaderMap, Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::Value, Map};
+use uuid::Uuid;
+
+use super::{
+    get_backend_headers, get_backend_url, get_message_text, send_converted_request,
+    ConvertedRequestTarget, ModelError, ModelRequest, ModelRequestData, ModelResponse,
+    ModelResponseData, RequestType, STREAMING_UNSUPPORTED_WARNING,
+};
+
+// Parameters which are sent in Gemini's generationConfig, keyed by their OpenAI name.
+const GENERATION_PARAMETERS: [(&str, &str); 7] = [
+    ("temperature", "temperature"),
+    ("top_p", "topP"),
+    ("top_k", "topK"),
+    ("n", "candidateCount"),
+    ("presence_penalty", "presencePenalty"),
+    ("frequency_penalty", "frequencyPenalty"),
+    ("seed", "seed"),
+];
+
+const REQUIRED_PARAMETERS: [&str; 1] = ["contents"];
+
+const FINISH_REASONS: [(&str, &str); 8] = [
+    ("STOP", "stop"),
+    ("MAX_TOKENS", "length"),
+    ("SAFETY", "content_filter"),
+    ("RECITATION", "content_filter"),
+    ("BLOCKLIST", "content_filter"),
+    ("PROHIBITED_CONTENT", "content_filter"),
+    ("SPII", "content_filter"),
+    ("IMAGE_SAFETY", "content_filter"),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct GeminiModelBackend {
+    model_string: String,
+    pub(super) model_context_len: Option<u64>,
+    gemini_api_base: String,
+    pub(super) gemini_api_key: String,
+}
+
+impl GeminiModelBackend {
+    // Gemini authenticates using an x-goog-api-key header instead of a bearer token.
+    fn get_headers(&self) -> Option<HeaderMap> {
+        get_backend_headers(&[("x-goog-api-key", &self.gemini_api_key)])
+    }
+
+    pub(super) fn get_probe_parameters(&self) -> Option<(Url, HeaderMap)> {
+        get_backend_url(&self.gemini_api_base, "/v1beta/models").zip(self.get_headers())
+    }
+
+    pub(super) async fn generate(
+        &self,
+        http_client: &Client,
+        tag: Uuid,
+        model: Uuid,
+        request: ModelRequest,
+        deadline: Option<Instant>,
+        log_upstream_requests: bool,
+    ) -> ModelResponse {
+        if request.r#type != RequestType::TextChat {
+            return ModelResponse::from(ModelError::UnknownEndpoint);
+        }
+
+        let path = format!("/v1beta/models/{}:generateContent", self.model_string);
+        let (url, headers) =
+            match get_backend_url(&self.gemini_api_base, &path).zip(self.get_headers()) {
+                Some(parameters) => parameters,
+                None => return ModelResponse::from(ModelError::InternalError),
+            };
+
+        send_converted_request(
+            http_client,
+            tag,
+            model,
+            request,
+            deadline,
+            log_upstream_requests,
+            ConvertedRequestTarget {
+                url,
+                headers,
+                api_key: &self.gemini_api_key,
+                required_parameters: &REQUIRED_PARAMETERS,
+            },
+            ModelRequestData::into_gemini,
+            ModelResponseData::insert_gemini_choices,
+        )
+        .await
+    }
+}
+
+// Unknown finish reasons aren't reported as "stop", as they may be new reasons for blocking or truncating the output.
+fn get_finish_reason(reason: &str) -> Option<&str> {
+    FINISH_REASONS
+        .iter()
+        .find(|(gemini, _)| *gemini == reason)
+        .map(|(_, openai)| *openai)
+}
+
+fn get_function_call_part(tool_call: &Value) -> Option<Value> {
+    let function = tool_call.get("function")?;
+    let args = match function.get("arguments") {
+        Some(Value::String(arguments)) => {
+            serde_json::from_str(arguments).unwrap_or_else(|_| json!({}))
+        }
+        Some(Value::Object(arguments)) => Value::Object(arguments.clone()),
+        _ => json!({}),
+    };
+
+    Some(json!({
+        "functionCall": {
+            "name": function.get("name")?,
+            "args": args,
+        }
+    }))
+}
+
+fn get_function_declaration(tool: &Value) -> Option<Value> {
+    let function = tool.get("function")?;
+    let mut converted = Map::new();
+
+    converted.insert("name".to_string(), function.get("name")?.clone());
+    if let Some(description) = function.get("description") {
+        converted.insert("description".to_string(), description.clone());
+    }
+    if let Some(parameters) = function.get("parameters") {
+        converted.insert("parameters".to_string(), parameters.clone());
+    }
+
+    Some(Value::Object(converted))
+}
+
+fn get_function_calling_config(tool_choice: &Value) -> Option<Value> {
+    match tool_choice {
+        Value::String(choice) => match choice.as_str() {
+            "auto" => Some(json!({ "mode": "AUTO" })),
+            "required" => Some(json!({ "mode": "ANY" })),
+            "none" => Some(json!({ "mode": "NONE" })),
+            _ => None,
+        },
+        Value::Object(choice) => choice
+            .get("function")
+            .and_then(|function| function.get("name"))
+            .map(|name| json!({ "mode": "ANY", "allowedFunctionNames": [name] })),
+        _ => None,
+    }
+}
+
+// Inline images are sent as inline data, as Gemini can't fetch images from arbitrary URLs.
+fn get_image_part(url: &str) -> Option<Value> {
+    let (mime_type, data) = url.strip_prefix("data:")?.split_once(";base64,")?;
+
+    Some(json!({
+        "inlineData": {
+            "mimeType": mime_type,
+            "data": data,
+        },
+    }))
+}
+
+// Returns the message's parts, and whether any content had to be removed.
+fn get_parts(content: Option<&Value>) -> (Vec<Value>, bool) {
+    match content {
+        Some(Value::String(text)) if !text.is_empty() => (vec![json!({ "text": text })], false),
+        Some(Value::Array(parts)) => {
+            let mut removed_content = false;
+            let converted = parts
+                .iter()
+                .filter_map(|part| {
+                    let converted = match part.get("type").and_then(|r#type| r#type.as_str()) {
+                        Some("text") => part
+                            .get("text")
+                            .and_then(|text| text.as_str())
+                            .map(|text| json!({ "text": text })),
+                        Some("image_url") => part
+                            .get("image_url")
+                            .and_then(|image| image.get("url").or(Some(image)))
+                            .and_then(|url| url.as_str())
+                            .and_then(get_image_part),
+                        _ => None,
+                    };
+                    removed_content |= converted.is_none();
+
+                    converted
+                })
+                .collect();
+
+            (converted, removed_content)
+        }
+        _ => (Vec::new(), false),
+    }
+}
+
+// Appends the parts to the conversation, merging them into the previous content if it has the same role, as Gemini expects roles to alternate.
+fn push_content(contents: &mut Vec<Value>, role: &str, parts: Vec<Value>) {
+    if parts.is_empty() {
+        return;
+    }
+
+    if let Some(Value::Object(content)) = contents.last_mut() {
+        if content.get("role").and_then(|role| role.as_str()) == Some(role) {
+            if let Some(Value::Array(existing)) = content.get_mut("parts") {
+                existing.extend(parts);
+                return;
+            }
+        }
+    }
+
+    contents.push(json!({ "role": role, "parts": parts }));
+}
+
+impl ModelRequestData {
+    // Converts an OpenAI-style chat request into a generateContent request. Parameters which don't have a Gemini equivalent are removed.
+    #[tracing::instrument(level = "trace", ret)]
+    pub(super) fn into_gemini(self, warnings: &mut Vec<String>) -> Result<Self, ModelError> {
+        let mut json = match self {
+            Self::Json(json) => json,
+            Self::Form(_) => return Err(ModelError::BadRequest),
+        };
+
+        if let Some(Value::Bool(true)) = json.remove("stream") {
+            warnings.push(STREAMING_UNSUPPORTED_WARNING.to_string());
+        }
+        json.remove("stream_options");
+        json.remove("model");
+        json.remove("user");
+
+        let chat = match json.remove("messages") {
+            Some(Value::Array(messages)) => messages,
+            _ => {
+                return Err(ModelError::InvalidParameterType {
+                    param: "messages",
+                    expected: "array",
+                })
+            }
+        };
+
+        let mut system = Vec::new();
+        let mut contents = Vec::new();
+        let mut removed_content = false;
+        // Gemini matches function responses to their calls by name rather than by ID.
+        let mut tool_call_names = HashMap::new();
+
+        for message in &chat {
+            match message.get("role").and_then(|role| role.as_str()) {
+                Some("system") | Some("developer") => {
+                    system.push(get_message_text(message));
+                }
+                Some("assistant") => {
+                    let (mut parts, removed) = get_parts(message.get("content"));
+                    removed_content |= removed;
+
+                    if let Some(Value::Array(tool_calls)) = message.get("tool_calls") {
+                        for tool_call in tool_calls {
+                            if let Some((id, name)) = tool_call
+                                .get("id")
+                                .and_then(|id| id.as_str())
+                                .zip(tool_call.pointer("/function/name"))
+                            {
+                                tool_call_names.insert(id, name.clone());
+                            }
+                        }
+                        parts.extend(tool_calls.iter().filter_map(get_function_call_part));
+                    }
+
+                    push_content(&mut contents, "model", parts);
+                }
+                Some("tool") => {
+                    match message
+                        .get("tool_call_id")
+                        .and_then(|id| id.as_str())
+                        .and_then(|id| tool_call_names.get(id))
+                    {
+                        Some(name) => push_content(
+                            &mut contents,
+                            "user",
+                            vec![json!({
+                                "functionResponse": {
+                                    "name": name,
+                                    "response": { "content": get_message_text(message) },
+                                }
+                            })],
+                        ),
+                        None => removed_content = true,
+                    }
+                }
+                _ => {
+                    let (parts, removed) = get_parts(message.get("content"));
+                    removed_content |= removed;
+
+                    push_content(&mut contents, "user", parts);
+                }
+            }
+        }
+
+        let mut request = Map::new();
+        if !system.is_empty() {
+            request.insert(
+                "systemInstruction".to_string(),
+                json!({ "parts": [{ "text": system.join("\n\n") }] }),
+            );
+        }
+        // Requests without any user or model messages are left without contents, as Gemini rejects empty contents.
+        if !contents.is_empty() {
+            request.insert("contents".to_string(), Value::Array(contents));
+        }
+
+        let mut generation_config = Map::new();
+        for (openai, gemini) in GENERATION_PARAMETERS {
+            if let Some(value) = json.remove(openai) {
+                generation_config.insert(gemini.to_string(), value);
+            }
+        }
+        if let Some(max_tokens) = json
+            .remove("max_completion_tokens")
+            .or_else(|| json.remove("max_tokens"))
+        {
+            generation_config.insert("maxOutputTokens".to_string(), max_tokens);
+        }
+        json.remove("max_tokens");
+        match json.remove("stop") {
+            Some(Value::String(sequence)) => {
+                generation_config.insert("stopSequences".to_string(), json!([sequence]));
+            }
+            Some(Value::Array(sequences)) => {
+                generation_config.insert("stopSequences".to_string(), Value::Array(sequences));
+            }
+            _ => {}
+        }
+        if let Some(Value::Array(tools)) = json.remove("tools") {
+            let declarations: Vec<Value> =
+                tools.iter().filter_map(get_function_declaration).collect();
+
+            if !declarations.is_empty() {
+                request.insert(
+                    "tools".to_string(),
+                    json!([{ "functionDeclarations": declarations }]),
+                );
+            }
+        }
+        if let Some(config) = json
+            .remove("tool_choice")
+            .as_ref()
+            .and_then(get_function_calling_config)
+        {
+            request.insert(
+                "toolConfig".to_string(),
+                json!({ "functionCallingConfig": config }),
+            );
+        }
+        if json
+            .get("response_format")
+            .and_then(|format| format.get("type"))
+            == Some(&json!("json_object"))
+        {
+            json.remove("response_format");
+            generation_config.insert("responseMimeType".to_string(), json!("application/json"));
+        }
+        if !generation_config.is_empty() {
+            request.insert(
+                "generationConfig".to_string(),
+                Value::Object(generation_config),
+            );
+        }
+
+        let mut removed_parameters: Vec<&str> = json
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, _)| key.as_str())
+            .collect();
+        if !removed_parameters.is_empty() {
+            removed_parameters.sort_unstable();
+            warnings.push(format!(
+                "The following parameters are not supported by this model and were removed: {}.",
+                removed_parameters.join(", ")
+            ));
+        }
+        if removed_content {
+            warnings.push(
+                "Some message content is not supported by this model and was removed.".to_string(),
+            );
+        }
+
+        Ok(Self::Json(request))
+    }
+}
+
+impl ModelResponseData {
+    // Adds OpenAI-style choices and usage to a generateContent response, so that into_hybrid_api can fill in the rest of the hybrid response.
+    #[tracing::instrument(level = "trace")]
+    pub(super) fn insert_gemini_choices(&mut self) {
+        let json = match self {
+            Self::Json(json)
+                if json.contains_key("candidates") || json.contains_key("promptFeedback") =>
+            {
+                json
+            }
+            _ => return,
+        };
+
+        let candidates = match json.get("candidates") {
+            Some(Value::Array(candidates)) => candidates.as_slice(),
+            _ => &[],
+        };
+
+        let mut choices: Vec<Value> = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| {
+                let parts = candidate
+                    .pointer("/content/parts")
+                    .and_then(|parts| parts.as_array())
+                    .map(|parts| parts.as_slice())
+                    .unwrap_or_default();
+                let index = candidate
+                    .get("index")
+                    .and_then(|index| index.as_u64())
+                    .unwrap_or(index as u64);
+
+                // Thought summaries aren't part of the model's response.
+                let text: String = parts
+                    .iter()
+                    .filter(|part| part.get("thought") != Some(&Value::Bool(true)))
+                    .filter_map(|part| part.get("text").and_then(|text| text.as_str()))
+                    .collect();
+                let tool_calls: Vec<Value> = parts
+                    .iter()
+                    .filter_map(|part| part.get("functionCall"))
+                    .enumerate()
+                    .map(|(call, function_call)| {
+                        json!({
+                            "id": function_call
+                                .get("id")
+                                .cloned()
+                                .unwrap_or_else(|| json!(format!("call_{}_{}", index, call))),
+                            "type": "function",
+                            "function": {
+                                "name": function_call.get("name").cloned().unwrap_or(Value::Null),
+                                "arguments": function_call.get("args").unwrap_or(&json!({})).to_string(),
+                            },
+                        })
+                    })
+                    .collect();
+                // Gemini finishes with STOP after calling a function, which OpenAI reports as tool_calls.
+                let finish_reason = match candidate
+                    .get("finishReason")
+                    .and_then(|reason| reason.as_str())
+                    .and_then(get_finish_reason)
+                {
+                    Some("stop") if !tool_calls.is_empty() => json!("tool_calls"),
+                    Some(reason) => json!(reason),
+                    None => Value::Null,
+                };
+
+                let mut message = Map::new();
+                message.insert("role".to_string(), json!("assistant"));
+                message.insert(
+                    "content".to_string(),
+                    match text.is_empty() && !tool_calls.is_empty() {
+                        true => Value::Null,
+                        false => Value::String(text),
+                    },
+                );
+                if !tool_calls.is_empty() {
+                    message.insert("tool_calls".to_string(), Value::Array(tool_calls));
+                }
+
+                json!({
+                    "index": index,
+                    "message": message,
+                    "finish_reason": finish_reason,
+                })
+            })
+            .collect();
+
+        // Prompts which were blocked don't have any candidates.
+        if choices.is_empty()
+            && json
+                .get("promptFeedback")
+                .and_then(|feedback| feedback.get("blockReason"))
+                .is_some()
+        {
+            choices.push(json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": "" },
+                "finish_reason": "content_filter",
+            }));
+        }
+        json.insert("choices".to_string(), Value::Array(choices));
+
+        if let Some(Value::Object(metadata)) = json.get("usageMetadata") {
+            let get_count = |field: &str| {
+                metadata
+                    .get(field)
+                    .and_then(|count| count.as_u64())
+                    .unwrap_or_default()
+            };
+            let input_tokens = get_count("promptTokenCount");
+            let output_tokens = get_count("candidatesTokenCount") + get_count("thoughtsTokenCount");
+            let total_tokens = match get_count("totalTokenCount") {
+                0 => input_tokens + output_tokens,
+                total => total,
+            };
+
+            json.insert(
+                "usage".to_string(),
+                json!({
+                    "prompt_tokens": input_tokens,
+                    "completion_tokens": output_tokens,
+                    "total_tokens": total_tokens,
+                }),
+            );
+        }
+    }
+}
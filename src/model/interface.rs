@@ -1,19 +1,67 @@
-use std::{clone::Clone, collections::HashMap};
+use std::{
+    clone::Clone,
+    collections::HashMap,
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 use axum::{
     async_trait,
-    body::{self, Bytes},
+    body::{self, Body, Bytes},
     extract::{FromRequest, Multipart, Request},
     response::IntoResponse,
     Form, Json,
 };
-use http::{header::CONTENT_TYPE, Method};
+use http::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER, TRAILER},
+    Method,
+};
+use http_body::Frame;
+use serde_json::{value::Value, Map};
 
 use super::{
-    ModelError, ModelFormFile, ModelFormItem, ModelRequest, ModelRequestData, ModelResponse,
-    ModelResponseData, RequestType,
+    get_event_stream, json_repair, JsonRepair, ModelError, ModelFormFile, ModelFormItem,
+    ModelRequest, ModelRequestData, ModelResponse, ModelResponseData, ModelWarnings, RequestType,
+    TokenUsage, Tokenizer,
 };
 
+// Bodies which fail to parse are repaired (if enabled) and parsed again, with each repair being logged and added to the request's warnings.
+fn parse_json_body(
+    body: &[u8],
+    repair: bool,
+    warnings: &mut Vec<String>,
+) -> Option<Map<String, Value>> {
+    if let Ok(Json(json)) = Json::from_bytes(body) {
+        return Some(json);
+    }
+    if !repair {
+        return None;
+    }
+
+    let (repaired, repairs) = json_repair::repair_json(std::str::from_utf8(body).ok()?)?;
+    let Json(json) = Json::from_bytes(repaired.as_bytes()).ok()?;
+
+    let mut descriptions = Vec::new();
+    for (kind, offset) in repairs {
+        tracing::info!(
+            repair = kind.get_description(),
+            offset = offset,
+            "Repaired malformed JSON in request body"
+        );
+
+        if !descriptions.contains(&kind.get_description()) {
+            descriptions.push(kind.get_description());
+        }
+    }
+    warnings.push(format!(
+        "The request body contained malformed JSON, which was repaired: {}.",
+        descriptions.join(", ")
+    ));
+
+    Some(json)
+}
+
 #[async_trait]
 impl<S> FromRequest<S> for ModelRequest
 where
@@ -28,6 +76,11 @@ where
             Ok(r#type) => r#type,
             Err(_) => return Err(ModelError::UnknownEndpoint),
         };
+        let repair = req
+            .extensions()
+            .get::<JsonRepair>()
+            .is_some_and(|repair| repair.0);
+        let mut warnings = Vec::new();
 
         if req.method() != Method::GET
             && req.method() != Method::HEAD
@@ -36,14 +89,16 @@ where
             return Err(ModelError::BadEndpointMethod);
         }
 
+        // Content types can have parameters (such as multipart/form-data's boundary), which are ignored when choosing how to parse the body.
         match req
             .headers()
             .get(CONTENT_TYPE)
             .and_then(|header_value| {
                 header_value
                     .to_str()
-                    .map(|header_string| header_string.to_ascii_lowercase())
                     .ok()
+                    .and_then(|header_string| header_string.split(';').next())
+                    .map(|mime_type| mime_type.trim().to_ascii_lowercase())
             })
             .as_deref()
         {
@@ -99,15 +154,15 @@ where
                 }
                 Err(_) => None,
             },
-            Some("application/json") => Json::from_request(req, state)
+            Some("application/json") => Bytes::from_request(req, state)
                 .await
-                .map(|value| value.0)
                 .ok()
+                .and_then(|body| parse_json_body(body.as_ref(), repair, &mut warnings))
                 .map(ModelRequestData::Json),
             Some(_) => body::to_bytes(req.into_body(), usize::MAX)
                 .await
                 .ok()
-                .and_then(|body| Json::from_bytes(body.as_ref()).map(|value| value.0).ok())
+                .and_then(|body| parse_json_body(body.as_ref(), repair, &mut warnings))
                 .map(ModelRequestData::Json),
             None => if req.method() == Method::HEAD || req.method() == Method::GET {
                 Form::from_request(req, state)
@@ -118,13 +173,21 @@ where
                 body::to_bytes(req.into_body(), usize::MAX)
                     .await
                     .ok()
-                    .and_then(|body| Json::from_bytes(body.as_ref()).map(|value| value.0).ok())
+                    .and_then(|body| parse_json_body(body.as_ref(), repair, &mut warnings))
             }
             .map(ModelRequestData::Json),
         }
-        .map(|request| ModelRequest {
+        .map(|mut request| ModelRequest {
             user: None,
             r#type,
+            warnings,
+            request_id: None,
+            param_profile: request.take_param_profile(),
+            proxy_metadata: None,
+            fingerprint: None,
+            timeout: None,
+            tokenizer: Some(Tokenizer::default()),
+            stream: None,
             request,
         })
         .ok_or(ModelError::BadRequest)
@@ -134,10 +197,116 @@ where
 impl IntoResponse for ModelResponse {
     #[tracing::instrument(name = "serialize_model_response", level = "debug", skip_all)]
     fn into_response(self) -> axum::response::Response {
-        match self.response {
+        let mut response = match self.response {
             ModelResponseData::Json(json) => (self.status, Json(json)).into_response(),
             ModelResponseData::Binary(binary) => (self.status, binary).into_response(),
+            ModelResponseData::Stream(stream) => {
+                let mut response = (self.status, stream.into_body()).into_response();
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+                response
+                    .headers_mut()
+                    .insert(TRAILER, HeaderValue::from_static(USAGE_TRAILERS));
+
+                response
+            }
+        };
+
+        if !self.warnings.is_empty() {
+            response
+                .extensions_mut()
+                .insert(ModelWarnings(self.warnings));
+        }
+
+        if let Some(retry_after) = self.retry_after {
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        if self.timings.upstream.is_some() || self.timings.queue.is_some() {
+            response.extensions_mut().insert(self.timings);
+        }
+
+        response
+    }
+}
+
+// Event stream body followed by token usage trailers, for clients which can't read the usage chunk.
+struct EventStreamBody {
+    stream: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl http_body::Body for EventStreamBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if let Some(stream) = self.stream.take() {
+            return Poll::Ready(Some(Ok(Frame::data(stream))));
         }
+
+        Poll::Ready(
+            self.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+}
+
+const USAGE_TRAILERS: &str = "X-Total-Tokens, X-Input-Tokens, X-Output-Tokens";
+
+pub(super) fn get_usage_trailers(usage: &TokenUsage) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+
+    trailers.insert("X-Total-Tokens", HeaderValue::from(usage.total));
+    if let Some(input) = usage.input {
+        trailers.insert("X-Input-Tokens", HeaderValue::from(input));
+    }
+    if let Some(output) = usage.output {
+        trailers.insert("X-Output-Tokens", HeaderValue::from(output));
+    }
+
+    trailers
+}
+
+impl ModelResponse {
+    // Clients which requested streaming receive the buffered response as a single-chunk event stream.
+    pub(crate) fn into_event_stream(
+        self,
+        r#type: RequestType,
+        include_usage: bool,
+    ) -> axum::response::Response {
+        let stream = match &self.response {
+            ModelResponseData::Json(json) if self.status.is_success() => {
+                get_event_stream(r#type, json, include_usage)
+            }
+            _ => None,
+        };
+        let trailers = get_usage_trailers(&self.usage);
+
+        let mut response = self.into_response();
+
+        if let Some(stream) = stream {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+            response
+                .headers_mut()
+                .insert(TRAILER, HeaderValue::from_static(USAGE_TRAILERS));
+            *response.body_mut() = Body::new(EventStreamBody {
+                stream: Some(Bytes::from(stream)),
+                trailers: Some(trailers),
+            });
+        }
+
+        response
     }
 }
 
@@ -0,0 +1,29 @@
+use serde::{de::DeserializeOwned, de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+// Non-self-describing formats (such as the database's) can't represent arbitrary JSON values, so they're stored as JSON strings instead.
+
+pub(crate) fn serialize<T, S>(map: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    match serializer.is_human_readable() {
+        true => map.serialize(serializer),
+        false => serde_json::to_string(map)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer),
+    }
+}
+
+pub(crate) fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: DeserializeOwned,
+    D: Deserializer<'de>,
+{
+    match deserializer.is_human_readable() {
+        true => T::deserialize(deserializer),
+        false => {
+            serde_json::from_str(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Repairs common syntax mistakes made by buggy JSON serializers. Repairs only change the body's quoting and whitespace (along with removing trailing commas), so that they can't change the meaning of a request; anything else is left for the JSON parser to reject.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum JsonRepairKind {
+    TrailingComma,
+    SingleQuotedString,
+    UnquotedKey,
+}
+
+impl JsonRepairKind {
+    pub(super) fn get_description(&self) -> &'static str {
+        match self {
+            Self::TrailingComma => "trailing commas",
+            Self::SingleQuotedString => "single-quoted strings",
+            Self::UnquotedKey => "unquoted object keys",
+        }
+    }
+}
+
+// Returns the repaired JSON and the byte offset of each repair, or None if the JSON didn't need any repairs (or couldn't be repaired).
+pub(super) fn repair_json(input: &str) -> Option<(String, Vec<(JsonRepairKind, usize)>)> {
+    let mut output = String::with_capacity(input.len());
+    let mut repairs = Vec::new();
+
+    // Objects are tracked so that keys can be told apart from values.
+    let mut objects = Vec::new();
+    let mut expecting_key = false;
+
+    let mut chars = input.char_indices().peekable();
+    while let Some((offset, char)) = chars.next() {
+        match char {
+            '"' => {
+                output.push(char);
+
+                let mut escaped = false;
+                loop {
+                    let (_, char) = chars.next()?;
+                    output.push(char);
+
+                    match char {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                expecting_key = false;
+            }
+            '\'' => {
+                output.push('"');
+
+                loop {
+                    match chars.next()? {
+                        (_, '\\') => match chars.next()? {
+                            (_, '\'') => output.push('\''),
+                            (_, char) => {
+                                output.push('\\');
+                                output.push(char);
+                            }
+                        },
+                        (_, '\'') => break,
+                        (_, '"') => output.push_str("\\\""),
+                        (_, char) => output.push(char),
+                    }
+                }
+                output.push('"');
+
+                repairs.push((JsonRepairKind::SingleQuotedString, offset));
+                expecting_key = false;
+            }
+            '{' | '[' => {
+                output.push(char);
+
+                objects.push(char == '{');
+                expecting_key = char == '{';
+            }
+            '}' | ']' => {
+                let trimmed_length = output.trim_end().len();
+                if output[..trimmed_length].ends_with(',') {
+                    output.truncate(trimmed_length - 1);
+                    repairs.push((JsonRepairKind::TrailingComma, offset));
+                }
+                output.push(char);
+
+                objects.pop();
+                expecting_key = false;
+            }
+            ',' => {
+                output.push(char);
+
+                expecting_key = objects.last() == Some(&true);
+            }
+            char if expecting_key && (char.is_ascii_alphabetic() || char == '_' || char == '$') => {
+                let mut key = String::from(char);
+                while let Some((_, char)) = chars.next_if(|(_, char)| {
+                    char.is_ascii_alphanumeric() || *char == '_' || *char == '$' || *char == '-'
+                }) {
+                    key.push(char);
+                }
+
+                // Only identifiers followed by a colon are treated as keys.
+                let mut whitespace = String::new();
+                while let Some((_, char)) = chars.next_if(|(_, char)| char.is_whitespace()) {
+                    whitespace.push(char);
+                }
+                if chars.peek().map(|(_, char)| *char) != Some(':') {
+                    return None;
+                }
+
+                output.push('"');
+                output.push_str(&key);
+                output.push('"');
+                output.push_str(&whitespace);
+
+                repairs.push((JsonRepairKind::UnquotedKey, offset));
+                expecting_key = false;
+            }
+            _ => {
+                output.push(char);
+
+                if !char.is_whitespace() {
+                    expecting_key = false;
+                }
+            }
+        }
+    }
+
+    match repairs.is_empty() {
+        true => None,
+        false => Some((output, repairs)),
+    }
+}
@@ -1,25 +1,137 @@
 use std::{
     cmp::Ordering,
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap},
     fmt::Debug,
-    time::{SystemTime, UNIX_EPOCH},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use fast32::base32::{CROCKFORD, RFC4648};
+use base32::Alphabet;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
 use http::{status::StatusCode, Uri};
 use reqwest::{
-    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
     Client, Method, Url,
 };
 use ring::digest;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, value::Value, Map};
+use tokio::{sync::oneshot, task::JoinSet, time};
 use uuid::Uuid;
 
+mod anthropic;
 mod client;
+mod gemini;
 mod interface;
+pub(super) mod json_map;
+mod json_repair;
+mod stream;
 mod tokenizer;
 
+#[cfg(test)]
+mod tests;
+
+use anthropic::AnthropicModelBackend;
+use gemini::GeminiModelBackend;
+use stream::{ModelStream, StreamSettings};
+pub(super) use tokenizer::Tokenizer;
+use tokenizer::{TokenizerMessage, TokenizerSettings};
+
+const MAX_TEMPLATE_VALUE_LEN: usize = 256;
+
+const CHAT_ONLY_PARAMETERS: [&str; 8] = [
+    "tools",
+    "tool_choice",
+    "functions",
+    "function_call",
+    "parallel_tool_calls",
+    "response_format",
+    "logprobs",
+    "top_logprobs",
+];
+
+const PENALTY_PARAMETERS: [&str; 2] = ["frequency_penalty", "presence_penalty"];
+
+const MAX_TOKENS_PARAMETERS: [&str; 2] = ["max_tokens", "max_completion_tokens"];
+
+const TEMPERATURE_RANGE: (f64, f64) = (0.0, 2.0);
+
+const CHAT_COMPLETION_STOP_SEQUENCE: &str = "\n\nUser:";
+
+pub(super) const STREAMING_UNSUPPORTED_WARNING: &str =
+    "Streaming is not supported by this model; the response was not streamed.";
+
+const DEFAULT_REFUSAL_MESSAGE: &str = "I'm sorry, but I can't help with that.";
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// How long an API key is avoided after the backend rejects it.
+const API_KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+// Error codes and types which show that a 403 response was caused by the API key, rather than by the request's contents.
+const API_KEY_ERROR_CODES: [&str; 6] = [
+    "invalid_api_key",
+    "invalid_organization",
+    "invalid_project",
+    "authentication_error",
+    "permission_error",
+    "insufficient_permissions",
+];
+
+const CITATION_FIELDS: [&str; 2] = ["citations", "search_results"];
+
+const STORE_PARAMETERS: [&str; 2] = ["metadata", "store"];
+
+const STREAM_CHUNK_FIELDS: [&str; 5] = ["id", "created", "model", "system_fingerprint", "_proxy"];
+
+#[tracing::instrument(level = "trace", ret)]
+pub(super) fn render_prompt_template(template: &str, variables: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut remaining = template;
+
+    while let Some(start) = remaining.find("{{") {
+        output.push_str(&remaining[..start]);
+        remaining = &remaining[start..];
+
+        let value = remaining.find("}}").and_then(|end| {
+            let name = remaining[2..end].trim();
+
+            variables
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (end, value))
+        });
+
+        match value {
+            Some((end, value)) => {
+                let mut escaped = String::with_capacity(value.len());
+
+                for character in value.chars().take(MAX_TEMPLATE_VALUE_LEN) {
+                    if character.is_control() || character.is_whitespace() {
+                        if !escaped.ends_with(' ') {
+                            escaped.push(' ');
+                        }
+                    } else {
+                        escaped.push(character);
+                    }
+                }
+
+                output.push_str(escaped.trim());
+                remaining = &remaining[end + 2..];
+            }
+            None => {
+                output.push_str("{{");
+                remaining = &remaining[2..];
+            }
+        }
+    }
+
+    output.push_str(remaining);
+    output
+}
+
 #[tracing::instrument(level = "trace", ret)]
 fn get_prompt_count(prompt: &Value) -> usize {
     match prompt {
@@ -39,15 +151,62 @@ fn get_prompt_count(prompt: &Value) -> usize {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(super) struct ModelRequest {
     pub(super) user: Option<Uuid>,
     pub(super) r#type: RequestType,
+    pub(super) warnings: Vec<String>,
+    pub(super) request_id: Option<String>,
+    pub(super) param_profile: Option<String>,
+    pub(super) proxy_metadata: Option<ProxyMetadata>,
+    pub(super) fingerprint: Option<SystemFingerprint>,
+    pub(super) timeout: Option<Duration>,
+    // The tokenizer of the model the request is sent to, or None if the model's tokenizer is unknown.
+    pub(super) tokenizer: Option<Tokenizer>,
+    // Whether usage should be included in the stream, if the client requested streaming.
+    pub(super) stream: Option<bool>,
 
     request: ModelRequestData,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+pub(super) struct ProxyMetadata {
+    pub(super) deployment: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) enum FingerprintStrategy {
+    #[default]
+    Static,
+    Passthrough,
+    Config,
+}
+
+// The system_fingerprint added to TextChat and TextCompletion responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum SystemFingerprint {
+    // Only used if the backend's response doesn't have a fingerprint.
+    Fallback(String),
+    // Replaces the backend's fingerprint.
+    Override(String),
+    // Only the backend's fingerprint is returned.
+    Passthrough,
+}
+
+impl SystemFingerprint {
+    pub(super) fn from_model(model: Uuid) -> Self {
+        Self::Fallback(get_fingerprint(model.as_bytes()))
+    }
+}
+
+pub(super) fn get_fingerprint(data: &[u8]) -> String {
+    base32::encode(
+        Alphabet::Crockford,
+        digest::digest(&digest::SHA256, data).as_ref(),
+    )
+}
+
+#[derive(Debug, Clone)]
 enum ModelRequestData {
     Json(Map<String, Value>),
     Form(HashMap<String, ModelFormItem>),
@@ -55,14 +214,19 @@ enum ModelRequestData {
 
 impl ModelRequestData {
     #[tracing::instrument(level = "trace", ret)]
-    fn into_openai(self, model: String, user: Option<Uuid>) -> Self {
+    fn into_openai(self, model: String, user: Option<Uuid>, warnings: &mut Vec<String>) -> Self {
         let user = user.map(|user| {
-            CROCKFORD.encode(digest::digest(&digest::SHA256, user.as_bytes()).as_ref())
+            base32::encode(
+                Alphabet::Crockford,
+                digest::digest(&digest::SHA256, user.as_bytes()).as_ref(),
+            )
         });
 
         match self {
             Self::Json(mut json) => {
-                json.remove("stream");
+                if let Some(Value::Bool(true)) = json.remove("stream") {
+                    warnings.push(STREAMING_UNSUPPORTED_WARNING.to_string());
+                }
                 json.insert("model".to_string(), Value::String(model));
                 match user {
                     Some(user) => {
@@ -117,7 +281,10 @@ impl ModelRequestData {
 
                             file_json.insert(
                                 "data".to_string(),
-                                Value::String(RFC4648.encode(&file.data)),
+                                Value::String(base32::encode(
+                                    Alphabet::Rfc4648 { padding: true },
+                                    &file.data,
+                                )),
                             );
 
                             json.insert(key, Value::Object(file_json));
@@ -136,6 +303,9 @@ impl ModelRequestData {
                 input: None,
                 output: None,
             },
+            warnings: Vec::new(),
+            timings: ModelTimings::default(),
+            retry_after: None,
             response: ModelResponseData::Json(json),
         }
     }
@@ -190,6 +360,95 @@ impl ModelRequestData {
         }
     }
 
+    // Returns the number of tokens in each of the request's prompts.
+    fn get_prompt_token_counts(
+        &self,
+        r#type: RequestType,
+        tokenizer: &TokenizerSettings,
+    ) -> Option<Vec<usize>> {
+        let json = match self {
+            Self::Json(json) => json,
+            Self::Form(_) => return None,
+        };
+
+        let count_prompts = |value: &Value| match value {
+            Value::String(text) => Some(vec![tokenizer.tokenize_text(text).len()]),
+            Value::Array(array) if array.iter().all(|value| value.is_number()) => {
+                Some(vec![array.len()])
+            }
+            Value::Array(array) => Some(
+                array
+                    .iter()
+                    .map(|value| match value {
+                        Value::String(text) => tokenizer.tokenize_text(text).len(),
+                        Value::Array(tokens) => tokens.len(),
+                        _ => 0,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+
+        match r#type {
+            RequestType::TextChat => match json.get("messages") {
+                Some(Value::Array(messages)) => {
+                    let contents: Vec<(&str, String, Option<&str>)> = messages
+                        .iter()
+                        .map(|message| {
+                            (
+                                message
+                                    .get("role")
+                                    .and_then(|role| role.as_str())
+                                    .unwrap_or_default(),
+                                get_message_text(message),
+                                message.get("name").and_then(|name| name.as_str()),
+                            )
+                        })
+                        .collect();
+                    let messages: Vec<TokenizerMessage> = contents
+                        .iter()
+                        .map(|(role, content, name)| TokenizerMessage {
+                            role,
+                            content: Some(content.as_str()),
+                            name: *name,
+                        })
+                        .collect();
+
+                    Some(vec![tokenizer.get_message_token_count(&messages)])
+                }
+                _ => None,
+            },
+            RequestType::TextCompletion => json.get("prompt").and_then(count_prompts),
+            RequestType::TextEdit => json.get("input").and_then(count_prompts).map(|inputs| {
+                let instruction: usize = json
+                    .get("instruction")
+                    .and_then(count_prompts)
+                    .map(|counts| counts.iter().sum())
+                    .unwrap_or_default();
+
+                inputs.iter().map(|input| input + instruction).collect()
+            }),
+            RequestType::TextEmbedding => json.get("input").and_then(count_prompts),
+            _ => None,
+        }
+    }
+
+    // Returns the number of tokens in the request's largest prompt, as each prompt is processed separately.
+    fn get_token_count(&self, r#type: RequestType, tokenizer: &TokenizerSettings) -> Option<u64> {
+        self.get_prompt_token_counts(r#type, tokenizer)
+            .and_then(|counts| counts.into_iter().max())
+            .map(|count| count as u64)
+    }
+
+    fn get_input_token_count(
+        &self,
+        r#type: RequestType,
+        tokenizer: &TokenizerSettings,
+    ) -> Option<u64> {
+        self.get_prompt_token_counts(r#type, tokenizer)
+            .map(|counts| counts.into_iter().sum::<usize>() as u64)
+    }
+
     #[tracing::instrument(level = "trace", ret)]
     fn get_max_tokens(&self) -> Option<u64> {
         match self {
@@ -199,206 +458,2124 @@ impl ModelRequestData {
             Self::Form(_) => None,
         }
     }
-}
 
-#[derive(Debug)]
-enum ModelFormItem {
-    Text(String),
-    File(ModelFormFile),
-}
+    #[tracing::instrument(level = "trace", ret)]
+    fn prepend_system_prompt(&mut self, r#type: RequestType, prompt: &str) -> bool {
+        match (self, r#type) {
+            (Self::Json(json), RequestType::TextChat) => {
+                if let Some(Value::Array(messages)) = json.get_mut("messages") {
+                    messages.insert(0, json!({ "role": "system", "content": prompt }));
 
-#[derive(Debug)]
-struct ModelFormFile {
-    file_name: Option<String>,
-    content_type: Option<String>,
-    data: Vec<u8>,
-}
+                    return true;
+                }
+            }
+            (Self::Json(json), RequestType::TextCompletion) => match json.get_mut("prompt") {
+                Some(Value::String(text)) => {
+                    *text = format!("{}\n\n{}", prompt, text);
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(super) enum RequestType {
-    TextChat,
-    TextCompletion,
-    TextEdit,
-    TextEmbedding,
-    TextModeration,
-    ImageGeneration,
-    ImageEdit,
-    ImageVariation,
-    AudioTTS,
-    AudioTranscription,
-    AudioTranslation,
-}
+                    return true;
+                }
+                Some(Value::Array(prompts)) => {
+                    let mut modified = false;
 
-impl TryFrom<&Uri> for RequestType {
-    type Error = &'static str;
+                    for value in prompts {
+                        if let Value::String(text) = value {
+                            *text = format!("{}\n\n{}", prompt, text);
+                            modified = true;
+                        }
+                    }
 
-    fn try_from(value: &Uri) -> Result<Self, Self::Error> {
-        match value.path() {
-            "/v1/chat/completions" => Ok(RequestType::TextChat),
-            "/v1/completions" => Ok(RequestType::TextCompletion),
-            "/v1/edits" => Ok(RequestType::TextEdit),
-            "/v1/embeddings" => Ok(RequestType::TextEmbedding),
-            "/v1/moderations" => Ok(RequestType::TextModeration),
-            "/v1/images/generations" => Ok(RequestType::ImageGeneration),
-            "/v1/images/edits" => Ok(RequestType::ImageEdit),
-            "/v1/images/variations" => Ok(RequestType::ImageVariation),
-            "/v1/audio/speech" => Ok(RequestType::AudioTTS),
-            "/v1/audio/transcriptions" => Ok(RequestType::AudioTranscription),
-            "/v1/audio/translations" => Ok(RequestType::AudioTranslation),
-            _ => Err("Invalid URI"),
+                    return modified;
+                }
+                _ => {}
+            },
+            _ => {}
         }
-    }
-}
 
-impl ModelRequest {
-    pub(super) fn get_model(&self) -> Option<&str> {
-        self.request.get_model()
+        false
     }
 
-    pub(super) fn get_count(&self) -> usize {
-        self.request.get_count()
+    #[tracing::instrument(level = "trace")]
+    fn split_embedding_input(&self, max_inputs: usize) -> Option<Vec<Self>> {
+        if let Self::Json(json) = self {
+            if let Some(Value::Array(inputs)) = json.get("input") {
+                // An array of integers is a single pre-tokenized input, rather than multiple inputs.
+                if inputs.len() > max_inputs.max(1) && !inputs.iter().all(|input| input.is_number())
+                {
+                    return Some(
+                        inputs
+                            .chunks(max_inputs.max(1))
+                            .map(|chunk| {
+                                let mut json = json.clone();
+                                json.insert("input".to_string(), Value::Array(chunk.to_vec()));
+
+                                Self::Json(json)
+                            })
+                            .collect(),
+                    );
+                }
+            }
+        }
+
+        None
     }
 
-    pub(super) fn get_max_tokens(&self) -> Option<u64> {
-        self.request.get_max_tokens()
+    #[tracing::instrument(level = "trace", ret)]
+    fn apply_json_schema_support(
+        &mut self,
+        support: JsonSchemaSupport,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), ModelError> {
+        if let Self::Json(json) = self {
+            let is_json_schema = json
+                .get("response_format")
+                .and_then(|format| format.get("type"))
+                .and_then(|value| value.as_str())
+                == Some("json_schema");
+
+            if is_json_schema {
+                match support {
+                    JsonSchemaSupport::Supported => {}
+                    JsonSchemaSupport::Downgrade => {
+                        json.insert(
+                            "response_format".to_string(),
+                            json!({ "type": "json_object" }),
+                        );
+                        warnings.push("This model does not support structured outputs; the json_schema response_format was replaced with json_object.".to_string());
+                    }
+                    JsonSchemaSupport::Strip => {
+                        json.remove("response_format");
+                        warnings.push("This model does not support structured outputs; the json_schema response_format was removed.".to_string());
+                    }
+                    JsonSchemaSupport::Reject => return Err(ModelError::UnsupportedResponseFormat),
+                }
+            }
+        }
+
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub(super) struct ModelResponse {
-    pub(super) status: StatusCode,
-    pub(super) usage: TokenUsage,
-    response: ModelResponseData,
-}
+    // Removes the echo parameter from TextCompletion requests, returning the prompts to be echoed and the number of choices per prompt. Prompts which aren't text are left for the backend to echo.
+    #[tracing::instrument(level = "trace", ret)]
+    fn take_echo_prompts(&mut self, warnings: &mut Vec<String>) -> Option<(Vec<String>, u64)> {
+        let json = match self {
+            Self::Json(json) if json.get("echo").and_then(|echo| echo.as_bool()) == Some(true) => {
+                json
+            }
+            _ => return None,
+        };
 
-#[derive(Debug)]
-enum ModelResponseData {
-    Json(Map<String, Value>),
-    Binary(Vec<u8>),
-}
+        let prompts = match json.get("prompt") {
+            Some(Value::String(prompt)) => vec![prompt.clone()],
+            Some(Value::Array(prompts)) => prompts
+                .iter()
+                .map(|prompt| prompt.as_str().map(|prompt| prompt.to_string()))
+                .collect::<Option<Vec<String>>>()?,
+            _ => return None,
+        };
+        let n = json.get("n").and_then(|n| n.as_u64()).unwrap_or(1).max(1);
+
+        json.remove("echo");
+        if json
+            .get("logprobs")
+            .is_some_and(|logprobs| !logprobs.is_null())
+        {
+            warnings.push("This model does not support echo; the prompt was added to each choice's text, but is not included in its logprobs.".to_string());
+        }
 
-/*
+        Some((prompts, n))
+    }
 
-TODO: Need to add token counting as a fallback when usage information is not available
+    // Removes the suffix parameter from TextCompletion requests, returning the suffix to be appended to each choice.
+    fn take_suffix(&mut self) -> Option<String> {
+        match self {
+            Self::Json(json) => match json.remove("suffix") {
+                Some(Value::String(suffix)) => Some(suffix),
+                Some(suffix) => {
+                    json.insert("suffix".to_string(), suffix);
+                    None
+                }
+                None => None,
+            },
+            Self::Form(_) => None,
+        }
+    }
 
-*/
+    // Removes OpenAI's store and metadata parameters (used for stored completions), for backends which reject them.
+    #[tracing::instrument(level = "trace")]
+    fn strip_store_parameters(&mut self, warnings: &mut Vec<String>) {
+        let json = match self {
+            Self::Json(json) => json,
+            Self::Form(_) => return,
+        };
+
+        let removed: Vec<&str> = STORE_PARAMETERS
+            .into_iter()
+            .filter(|param| json.remove(*param).is_some_and(|value| !value.is_null()))
+            .collect();
+
+        if !removed.is_empty() {
+            warnings.push(format!(
+                "The following parameters are not supported by this model and were removed: {}.",
+                removed.join(", ")
+            ));
+        }
+    }
 
-impl ModelResponseData {
     #[tracing::instrument(level = "trace", ret)]
-    fn into_hybrid_api(
-        self,
-        model: Option<String>,
+    fn apply_token_input_support(
+        &mut self,
         r#type: RequestType,
-        tag: Uuid,
-        fingerprint: Uuid,
-        is_error: bool,
-    ) -> (Self, TokenUsage) {
-        match self {
-            Self::Json(mut json) => {
-                match is_error {
-                    true => {
-                        if let Some(Value::Object(_)) = json.get("error") {
-                            json.insert("type".to_string(), Value::String("error".to_string()));
-                        }
-                    }
-                    false => {
-                        if (r#type == RequestType::TextChat
-                            || r#type == RequestType::TextCompletion)
-                            && !json.contains_key("system_fingerprint")
-                        {
-                            json.insert(
-                                "system_fingerprint".to_string(),
-                                Value::String(
-                                    CROCKFORD.encode(
-                                        digest::digest(&digest::SHA256, fingerprint.as_bytes())
-                                            .as_ref(),
-                                    ),
-                                ),
-                            );
-                        }
+        support: TokenInputSupport,
+        tokenizer: &TokenizerSettings,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), ModelError> {
+        let json = match (self, r#type) {
+            (Self::Json(json), RequestType::TextEmbedding)
+                if support != TokenInputSupport::Supported =>
+            {
+                json
+            }
+            _ => return Ok(()),
+        };
 
-                        if r#type == RequestType::TextChat
-                            || r#type == RequestType::TextCompletion
-                            || r#type == RequestType::TextEdit
-                        {
-                            let mut completion = None;
-                            let mut stop_reason = None;
+        let is_tokens = |value: &Value| match value {
+            Value::Array(tokens) => !tokens.is_empty() && tokens.iter().all(Value::is_number),
+            _ => false,
+        };
+        // An array of integers is a single pre-tokenized input, rather than multiple inputs.
+        let single_input = match json.get("input") {
+            Some(input) if is_tokens(input) => true,
+            Some(Value::Array(inputs)) if inputs.iter().any(is_tokens) => false,
+            _ => return Ok(()),
+        };
 
-                            if let Some(Value::Array(choices)) = json.get_mut("choices") {
-                                for (index, value) in choices.iter_mut().enumerate() {
-                                    if let Value::Object(choice) = value {
-                                        if !choice.contains_key("index") {
-                                            choice.insert(
-                                                "index".to_string(),
-                                                Value::Number(index.into()),
-                                            );
-                                        }
+        let error = ModelError::InvalidParameterType {
+            param: "input",
+            expected: "a string or an array of strings, as this model does not accept token arrays",
+        };
 
-                                        if !choice.contains_key("logprobs") {
-                                            choice.insert("logprobs".to_string(), Value::Null);
-                                        }
+        if support == TokenInputSupport::Reject {
+            return Err(error);
+        }
 
-                                        if (r#type == RequestType::TextCompletion
-                                            || r#type == RequestType::TextEdit)
-                                            && !choice.contains_key("text")
-                                        {
-                                            if let Some(Value::Object(message)) =
-                                                choice.get("message")
-                                            {
-                                                if let Some(Value::String(content)) =
-                                                    message.get("content")
-                                                {
-                                                    choice.insert(
-                                                        "text".to_string(),
-                                                        Value::String(content.clone()),
-                                                    );
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
+        let decode = |value: &Value| -> Option<Value> {
+            match value {
+                Value::String(_) => Some(value.clone()),
+                Value::Array(tokens) => tokens
+                    .iter()
+                    .map(|token| token.as_u64().and_then(|token| usize::try_from(token).ok()))
+                    .collect::<Option<Vec<usize>>>()
+                    .and_then(|tokens| tokenizer.detokenize(tokens))
+                    .map(Value::String),
+                _ => None,
+            }
+        };
 
-                                choices.sort_by(|a, b| {
-                                    if let (Some(a), Some(b)) = (
-                                        a.get("index").and_then(|v| v.as_u64()),
-                                        b.get("index").and_then(|v| v.as_u64()),
-                                    ) {
-                                        a.cmp(&b)
-                                    } else {
-                                        Ordering::Equal
-                                    }
-                                });
+        let input = match (json.get("input"), single_input) {
+            (Some(input), true) => decode(input),
+            (Some(Value::Array(inputs)), false) => inputs
+                .iter()
+                .map(decode)
+                .collect::<Option<Vec<Value>>>()
+                .map(Value::Array),
+            _ => None,
+        };
 
-                                if choices.len() == 1 {
+        match input {
+            Some(input) => {
+                json.insert("input".to_string(), input);
+                warnings.push("This model does not support token inputs; the input tokens were decoded to text using the cl100k_base tokenizer.".to_string());
+
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    // Fixes common client mistakes, returning the name of each parameter that was changed.
+    #[tracing::instrument(level = "trace", ret)]
+    fn normalize_body(
+        &mut self,
+        r#type: RequestType,
+        normalization: BodyNormalization,
+    ) -> Result<Vec<&'static str>, ModelError> {
+        let json = match (self, normalization) {
+            (_, BodyNormalization::Disabled) | (Self::Form(_), _) => return Ok(Vec::new()),
+            (Self::Json(json), _) => json,
+        };
+        let mut coerced = Vec::new();
+
+        if r#type == RequestType::TextChat {
+            if let Some(Value::Object(message)) = json.get("messages") {
+                if normalization == BodyNormalization::Strict {
+                    return Err(ModelError::InvalidParameterType {
+                        param: "messages",
+                        expected: "an array of messages",
+                    });
+                }
+
+                let messages = Value::Array(vec![Value::Object(message.clone())]);
+                json.insert("messages".to_string(), messages);
+                coerced.push("messages");
+            }
+        }
+
+        // Values which no backend accepts are corrected: n is raised to 1, and non-positive token limits are removed so that the backend's default is used.
+        if json
+            .get("n")
+            .and_then(|value| value.as_f64())
+            .is_some_and(|n| n < 1.0)
+        {
+            if normalization == BodyNormalization::Strict {
+                return Err(ModelError::InvalidParameterType {
+                    param: "n",
+                    expected: "a positive integer",
+                });
+            }
+
+            json.insert("n".to_string(), Value::from(1));
+            coerced.push("n");
+        }
+
+        if r#type == RequestType::TextChat || r#type == RequestType::TextCompletion {
+            for param in MAX_TOKENS_PARAMETERS {
+                if json
+                    .get(param)
+                    .and_then(|value| value.as_f64())
+                    .is_some_and(|max_tokens| max_tokens < 1.0)
+                {
+                    if normalization == BodyNormalization::Strict {
+                        return Err(ModelError::InvalidParameterType {
+                            param,
+                            expected: "a positive integer",
+                        });
+                    }
+
+                    json.remove(param);
+                    coerced.push(param);
+                }
+            }
+
+            let (min, max) = TEMPERATURE_RANGE;
+            if let Some(temperature) = json
+                .get("temperature")
+                .and_then(|value| value.as_f64())
+                .filter(|temperature| !(min..=max).contains(temperature))
+            {
+                if normalization == BodyNormalization::Strict {
+                    return Err(ModelError::ParameterOutOfRange {
+                        param: "temperature",
+                        min,
+                        max,
+                    });
+                }
+
+                json.insert(
+                    "temperature".to_string(),
+                    Value::from(temperature.clamp(min, max)),
+                );
+                coerced.push("temperature");
+            }
+        }
+
+        Ok(coerced)
+    }
+
+    #[tracing::instrument(level = "trace", ret)]
+    fn apply_image_sizes(
+        &mut self,
+        r#type: RequestType,
+        sizes: &ImageSizes,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), ModelError> {
+        if r#type != RequestType::ImageGeneration
+            && r#type != RequestType::ImageEdit
+            && r#type != RequestType::ImageVariation
+        {
+            return Ok(());
+        }
+
+        let size = match self {
+            Self::Json(json) => json.get("size").and_then(|value| value.as_str()),
+            Self::Form(form) => match form.get("size") {
+                Some(ModelFormItem::Text(size)) => Some(size.as_str()),
+                _ => None,
+            },
+        };
+        let size = match size {
+            Some(size) if !sizes.sizes.iter().any(|supported| supported == size) => size,
+            _ => return Ok(()),
+        };
+
+        let snapped = match sizes.strict {
+            true => None,
+            false => get_nearest_image_size(size, &sizes.sizes),
+        };
+        let snapped = match snapped {
+            Some(snapped) => snapped.to_string(),
+            None => {
+                return Err(ModelError::UnsupportedValue {
+                    param: "size",
+                    supported: sizes.sizes.clone(),
+                })
+            }
+        };
+
+        warnings.push(format!(
+            "This model does not support images with a size of {}; the size was changed to {}.",
+            size, snapped
+        ));
+        match self {
+            Self::Json(json) => {
+                json.insert("size".to_string(), Value::String(snapped));
+            }
+            Self::Form(form) => {
+                form.insert("size".to_string(), ModelFormItem::Text(snapped));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", ret)]
+    fn apply_penalty_range(
+        &mut self,
+        range: PenaltyRange,
+        warnings: &mut Vec<String>,
+    ) -> Result<(), ModelError> {
+        if let Self::Json(json) = self {
+            for param in PENALTY_PARAMETERS {
+                let penalty = match json.get(param).and_then(|value| value.as_f64()) {
+                    Some(penalty) => penalty,
+                    None => continue,
+                };
+
+                if penalty < range.min || penalty > range.max {
+                    if range.strict {
+                        return Err(ModelError::ParameterOutOfRange {
+                            param,
+                            min: range.min,
+                            max: range.max,
+                        });
+                    }
+
+                    let clamped = penalty.clamp(range.min, range.max);
+                    json.insert(param.to_string(), Value::from(clamped));
+                    warnings.push(format!(
+                        "This model only supports a {} between {} and {}; the {} was changed from {} to {}.",
+                        param, range.min, range.max, param, penalty, clamped
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", ret)]
+    fn validate_json_schema(&self, limits: JsonSchemaLimits) -> Result<(), ModelError> {
+        let json_schema = match self {
+            Self::Json(json) => match json.get("response_format") {
+                Some(format) if format.get("type") == Some(&json!("json_schema")) => {
+                    format.get("json_schema")
+                }
+                _ => return Ok(()),
+            },
+            Self::Form(_) => return Ok(()),
+        };
+
+        let json_schema = match json_schema {
+            Some(Value::Object(json_schema)) => json_schema,
+            Some(_) => {
+                return Err(ModelError::InvalidJsonSchema(
+                    "json_schema must be an object".to_string(),
+                ))
+            }
+            None => {
+                return Err(ModelError::InvalidJsonSchema(
+                    "json_schema is missing".to_string(),
+                ))
+            }
+        };
+
+        if !matches!(json_schema.get("name"), Some(Value::String(_))) {
+            return Err(ModelError::InvalidJsonSchema(
+                "json_schema.name must be a string".to_string(),
+            ));
+        }
+
+        if let Some(schema) = json_schema.get("schema") {
+            let size = schema.to_string().len();
+            if size > limits.max_size {
+                return Err(ModelError::InvalidJsonSchema(format!(
+                    "the schema is {} bytes long, which is more than the maximum of {} bytes",
+                    size, limits.max_size
+                )));
+            }
+
+            check_json_schema(schema, "schema", 1, limits.max_depth)
+                .map_err(ModelError::InvalidJsonSchema)?;
+        }
+
+        Ok(())
+    }
+
+    fn to_log_string(&self) -> String {
+        self.to_log_value().to_string()
+    }
+
+    // File contents are replaced with their size, as they're usually too large to be useful in logs.
+    fn to_log_value(&self) -> Value {
+        match self {
+            Self::Json(json) => Value::Object(json.clone()),
+            Self::Form(form) => {
+                let form: Map<String, Value> = form
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = match value {
+                            ModelFormItem::Text(text) => Value::String(text.clone()),
+                            ModelFormItem::File(file) => json!({
+                                "file_name": file.file_name,
+                                "content_type": file.content_type,
+                                "size": file.data.len(),
+                            }),
+                        };
+
+                        (key.clone(), value)
+                    })
+                    .collect();
+
+                Value::Object(form)
+            }
+        }
+    }
+
+    fn get_size(&self) -> u64 {
+        match self {
+            Self::Json(json) => Value::Object(json.clone()).to_string().len() as u64,
+            Self::Form(form) => form
+                .iter()
+                .map(|(key, value)| {
+                    key.len()
+                        + match value {
+                            ModelFormItem::Text(text) => text.len(),
+                            ModelFormItem::File(file) => file.data.len(),
+                        }
+                })
+                .sum::<usize>() as u64,
+        }
+    }
+
+    fn get_audio_duration(&self) -> Option<f64> {
+        match self {
+            Self::Form(form) => form.values().find_map(|value| match value {
+                ModelFormItem::File(file) => get_wav_duration(&file.data),
+                ModelFormItem::Text(_) => None,
+            }),
+            Self::Json(_) => None,
+        }
+    }
+
+    // Flattens a chat request's messages into a single prompt, for models which only support completions.
+    #[tracing::instrument(level = "trace", ret)]
+    fn convert_chat_to_completion(&mut self, warnings: &mut Vec<String>) -> bool {
+        let json = match self {
+            Self::Json(json) => json,
+            Self::Form(_) => return false,
+        };
+
+        let messages = match json.get("messages") {
+            Some(Value::Array(messages)) => messages,
+            _ => return false,
+        };
+
+        let mut prompt = String::new();
+        for message in messages {
+            let role = match message.get("role").and_then(|role| role.as_str()) {
+                Some("system") | Some("developer") => "System",
+                Some("assistant") => "Assistant",
+                Some("tool") | Some("function") => "Tool",
+                _ => "User",
+            };
+
+            prompt.push_str(&format!("{}: {}\n\n", role, get_message_text(message)));
+        }
+        prompt.push_str("Assistant:");
+
+        json.remove("messages");
+        json.insert("prompt".to_string(), Value::String(prompt));
+
+        let mut removed_parameters = false;
+        for key in CHAT_ONLY_PARAMETERS {
+            removed_parameters |= json.remove(key).is_some();
+        }
+
+        let mut stop = match json.remove("stop") {
+            Some(Value::String(sequence)) => vec![Value::String(sequence)],
+            Some(Value::Array(sequences)) => sequences,
+            _ => Vec::new(),
+        };
+        if stop.len() < 4 {
+            stop.push(Value::String(CHAT_COMPLETION_STOP_SEQUENCE.to_string()));
+        }
+        json.insert("stop".to_string(), Value::Array(stop));
+
+        warnings.push(
+            "This model only supports completions; the chat messages were converted into a prompt."
+                .to_string(),
+        );
+        if removed_parameters {
+            warnings.push(
+                "Chat-only parameters (such as tools) are not supported by this model and were removed."
+                    .to_string(),
+            );
+        }
+
+        true
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn take_param_profile(&mut self) -> Option<String> {
+        match self {
+            Self::Json(json) => match json.remove("param_profile") {
+                Some(Value::String(profile)) => Some(profile),
+                _ => None,
+            },
+            Self::Form(form) => match form.remove("param_profile") {
+                Some(ModelFormItem::Text(profile)) => Some(profile),
+                _ => None,
+            },
+        }
+    }
+
+    // Returns whether usage should be included in the stream, if streaming was requested.
+    fn take_stream(&mut self) -> Option<bool> {
+        match self {
+            Self::Json(json) => {
+                let stream_options = json.remove("stream_options");
+
+                match json.remove("stream") {
+                    Some(Value::Bool(true)) => Some(
+                        stream_options
+                            .and_then(|options| options.get("include_usage").cloned())
+                            .and_then(|value| value.as_bool())
+                            .unwrap_or_default(),
+                    ),
+                    _ => None,
+                }
+            }
+            Self::Form(_) => None,
+        }
+    }
+
+    // Usage is always requested (unless the backend doesn't support stream_options), as it's needed to update the request's Quotas.
+    fn insert_stream(&mut self, include_usage: bool) {
+        if let Self::Json(json) = self {
+            json.insert("stream".to_string(), Value::Bool(true));
+            if include_usage {
+                json.insert(
+                    "stream_options".to_string(),
+                    json!({ "include_usage": true }),
+                );
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn merge_extra_body(&mut self, extra_body: &Map<String, Value>) {
+        match self {
+            Self::Json(json) => {
+                for (key, value) in extra_body {
+                    if !json.contains_key(key) {
+                        json.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            Self::Form(form) => {
+                for (key, value) in extra_body {
+                    if !form.contains_key(key) {
+                        let text = match value {
+                            Value::String(string) => string.clone(),
+                            value => value.to_string(),
+                        };
+
+                        form.insert(key.clone(), ModelFormItem::Text(text));
+                    }
+                }
+            }
+        }
+    }
+
+    // Checks that the converted request contains all of the parameters which the backend requires, so that conversion gaps are caught before the request is sent upstream.
+    fn check_required_parameters<'a>(
+        &self,
+        required: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ModelError> {
+        let missing = required.into_iter().find(|param| match self {
+            Self::Json(json) => json.get(*param).is_none_or(|value| value.is_null()),
+            Self::Form(form) => !form.contains_key(*param),
+        });
+
+        match missing {
+            Some(param) => Err(ModelError::MissingParameter(param.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ModelFormItem {
+    Text(String),
+    File(ModelFormFile),
+}
+
+#[derive(Debug, Clone)]
+struct ModelFormFile {
+    file_name: Option<String>,
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum RequestType {
+    TextChat,
+    TextCompletion,
+    TextEdit,
+    TextEmbedding,
+    TextModeration,
+    ImageGeneration,
+    ImageEdit,
+    ImageVariation,
+    AudioTTS,
+    AudioTranscription,
+    AudioTranslation,
+}
+
+impl RequestType {
+    // The fields which a request of this type can't be processed without.
+    pub(super) fn get_required_fields(&self) -> &'static [&'static str] {
+        match self {
+            RequestType::TextChat => &["messages"],
+            RequestType::TextCompletion => &["prompt"],
+            RequestType::TextEdit => &["instruction"],
+            RequestType::TextEmbedding => &["input"],
+            RequestType::TextModeration => &["input"],
+            RequestType::ImageGeneration => &["prompt"],
+            RequestType::ImageEdit => &["image", "prompt"],
+            RequestType::ImageVariation => &["image"],
+            RequestType::AudioTTS => &["input", "voice"],
+            RequestType::AudioTranscription => &["file"],
+            RequestType::AudioTranslation => &["file"],
+        }
+    }
+}
+
+impl TryFrom<&Uri> for RequestType {
+    type Error = &'static str;
+
+    fn try_from(value: &Uri) -> Result<Self, Self::Error> {
+        match value.path() {
+            "/v1/chat/completions" => Ok(RequestType::TextChat),
+            "/v1/completions" => Ok(RequestType::TextCompletion),
+            "/v1/edits" => Ok(RequestType::TextEdit),
+            "/v1/embeddings" => Ok(RequestType::TextEmbedding),
+            "/v1/moderations" => Ok(RequestType::TextModeration),
+            "/v1/images/generations" => Ok(RequestType::ImageGeneration),
+            "/v1/images/edits" => Ok(RequestType::ImageEdit),
+            "/v1/images/variations" => Ok(RequestType::ImageVariation),
+            "/v1/audio/speech" => Ok(RequestType::AudioTTS),
+            "/v1/audio/transcriptions" => Ok(RequestType::AudioTranscription),
+            "/v1/audio/translations" => Ok(RequestType::AudioTranslation),
+            _ => Err("Invalid URI"),
+        }
+    }
+}
+
+impl ModelRequest {
+    // Identical requests to the same model from the same user will have the same key. Only request types which are expected to always return the same response can be coalesced.
+    pub(super) fn get_coalescing_key(
+        &self,
+        model: Uuid,
+        settings: &CoalescingKeySettings,
+    ) -> Option<Vec<u8>> {
+        match (self.r#type, &self.request) {
+            (
+                RequestType::TextEmbedding | RequestType::TextModeration,
+                ModelRequestData::Json(json),
+            ) => {
+                let json = Value::Object(
+                    json.iter()
+                        .filter(|(key, _)| {
+                            settings.fields.is_empty() || settings.fields.contains(key)
+                        })
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect(),
+                );
+                let json = match settings.semantic {
+                    true => get_canonical_value(&json),
+                    false => json,
+                };
+
+                let mut context = digest::Context::new(&digest::SHA256);
+                context.update(self.user.unwrap_or_default().as_bytes());
+                context.update(model.as_bytes());
+                context.update(&[self.proxy_metadata.is_some() as u8]);
+                context.update(json.to_string().as_bytes());
+
+                Some(context.finish().as_ref().to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn from_batch_item(
+        method: &str,
+        path: &str,
+        body: Map<String, Value>,
+    ) -> Result<ModelRequest, ModelError> {
+        let r#type = path
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| RequestType::try_from(&uri).ok())
+            .ok_or(ModelError::UnknownEndpoint)?;
+
+        let mut request = ModelRequestData::Json(body);
+
+        match method.to_ascii_uppercase().as_str() {
+            "GET" | "HEAD" | "POST" => Ok(ModelRequest {
+                user: None,
+                r#type,
+                warnings: Vec::new(),
+                request_id: None,
+                param_profile: request.take_param_profile(),
+                proxy_metadata: None,
+                fingerprint: None,
+                timeout: None,
+                tokenizer: Some(Tokenizer::default()),
+                stream: None,
+                request,
+            }),
+            _ => Err(ModelError::BadEndpointMethod),
+        }
+    }
+
+    pub(super) fn get_model(&self) -> Option<&str> {
+        self.request.get_model()
+    }
+
+    pub(super) fn get_count(&self) -> usize {
+        self.request.get_count()
+    }
+
+    pub(super) fn to_log_value(&self) -> Value {
+        self.request.to_log_value()
+    }
+
+    pub(super) fn get_max_tokens(&self) -> Option<u64> {
+        self.request.get_max_tokens()
+    }
+
+    pub(super) fn apply_json_schema_support(
+        &mut self,
+        support: JsonSchemaSupport,
+    ) -> Result<(), ModelError> {
+        self.request
+            .apply_json_schema_support(support, &mut self.warnings)
+    }
+
+    // Token arrays can't be decoded if the model's tokenizer is unknown, so they're rejected instead.
+    pub(super) fn apply_token_input_support(
+        &mut self,
+        support: TokenInputSupport,
+    ) -> Result<(), ModelError> {
+        let support = match (support, self.tokenizer) {
+            (TokenInputSupport::Decode, None) => TokenInputSupport::Reject,
+            (support, _) => support,
+        };
+
+        self.request.apply_token_input_support(
+            self.r#type,
+            support,
+            &TokenizerSettings::new(self.tokenizer.unwrap_or_default()),
+            &mut self.warnings,
+        )
+    }
+
+    pub(super) fn normalize_body(
+        &mut self,
+        normalization: BodyNormalization,
+    ) -> Result<(), ModelError> {
+        for param in self.request.normalize_body(self.r#type, normalization)? {
+            tracing::debug!("Coerced malformed {} parameter", param);
+            self.warnings.push(format!(
+                "The {} parameter was malformed, and was converted into the expected format.",
+                param
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn apply_image_sizes(&mut self, sizes: &ImageSizes) -> Result<(), ModelError> {
+        self.request
+            .apply_image_sizes(self.r#type, sizes, &mut self.warnings)
+    }
+
+    pub(super) fn apply_penalty_range(&mut self, range: PenaltyRange) -> Result<(), ModelError> {
+        self.request.apply_penalty_range(range, &mut self.warnings)
+    }
+
+    pub(super) fn validate_json_schema(&self, limits: JsonSchemaLimits) -> Result<(), ModelError> {
+        self.request.validate_json_schema(limits)
+    }
+
+    // Returns None if the request's tokens can't be counted, such as when the model's tokenizer is unknown.
+    pub(super) fn get_token_count(&self, tokenizer: Option<Tokenizer>) -> Option<u64> {
+        tokenizer.and_then(|tokenizer| {
+            self.request
+                .get_token_count(self.r#type, &TokenizerSettings::new(tokenizer))
+        })
+    }
+
+    // Splits a request for multiple choices into a request for each choice, for backends which don't support n. Requests with a seed have it incremented for each choice, so that the choices aren't identical.
+    pub(super) fn split_n(&self) -> Option<Vec<ModelRequest>> {
+        if self.r#type != RequestType::TextChat && self.r#type != RequestType::TextCompletion {
+            return None;
+        }
+
+        let json = match &self.request {
+            ModelRequestData::Json(json) => json,
+            ModelRequestData::Form(_) => return None,
+        };
+        let n = json.get("n").and_then(|n| n.as_u64()).filter(|n| *n > 1)?;
+        let seed = json.get("seed").and_then(|seed| seed.as_i64());
+
+        Some(
+            (0..n)
+                .map(|index| {
+                    let mut json = json.clone();
+                    json.remove("n");
+                    if let Some(seed) = seed {
+                        json.insert(
+                            "seed".to_string(),
+                            Value::from(seed.wrapping_add(index as i64)),
+                        );
+                    }
+
+                    ModelRequest {
+                        request: ModelRequestData::Json(json),
+                        stream: None,
+                        ..self.clone()
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    // Returns the number of tokens the request is charged based on its size, using the duration of uploaded audio where possible.
+    pub(super) fn get_size_tokens(&self, charging: SizeCharging) -> u64 {
+        let tokens = match charging
+            .tokens_per_second
+            .zip(self.request.get_audio_duration())
+        {
+            Some((tokens_per_second, duration)) => (duration * tokens_per_second).ceil() as u64,
+            None => self
+                .request
+                .get_size()
+                .div_ceil(charging.bytes_per_token.max(1)),
+        };
+
+        tokens.max(1)
+    }
+
+    // Requests without max_tokens are checked using the backend's default max_tokens, if it sends one.
+    pub(super) fn check_context_length(
+        &self,
+        context_len: u64,
+        default_max_tokens: Option<u64>,
+        tokenizer: Option<Tokenizer>,
+    ) -> Result<(), ModelError> {
+        let tokens = self
+            .get_token_count(tokenizer)
+            .unwrap_or_default()
+            .saturating_add(
+                self.get_max_tokens()
+                    .or(default_max_tokens)
+                    .unwrap_or_default(),
+            );
+
+        match tokens > context_len {
+            true => Err(ModelError::RequestTooLarge {
+                max: context_len,
+                overflow: tokens - context_len,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    pub(super) fn convert_chat_to_completion(&mut self) -> Result<(), ModelError> {
+        if self.r#type != RequestType::TextChat {
+            return Ok(());
+        }
+
+        match self.request.convert_chat_to_completion(&mut self.warnings) {
+            true => {
+                self.r#type = RequestType::TextCompletion;
+                Ok(())
+            }
+            false => Err(ModelError::BadRequest),
+        }
+    }
+
+    pub(super) fn take_stream(&mut self) -> Option<bool> {
+        match self.r#type {
+            RequestType::TextChat | RequestType::TextCompletion => self.request.take_stream(),
+            _ => None,
+        }
+    }
+
+    pub(super) fn apply_param_profile(&mut self, parameters: &Map<String, Value>) {
+        self.request.merge_extra_body(parameters)
+    }
+
+    pub(super) fn prepend_system_prompt(&mut self, prompt: &str) -> u64 {
+        match self.request.prepend_system_prompt(self.r#type, prompt) {
+            true => self.tokenizer.map_or(0, |tokenizer| {
+                TokenizerSettings::new(tokenizer)
+                    .tokenize_text(prompt)
+                    .len() as u64
+            }),
+            false => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct ModelResponse {
+    pub(super) status: StatusCode,
+    pub(super) usage: TokenUsage,
+    pub(super) warnings: Vec<String>,
+    pub(super) timings: ModelTimings,
+    pub(super) retry_after: Option<Duration>,
+    response: ModelResponseData,
+}
+
+impl ModelResponse {
+    pub(super) fn with_retry_after(self, retry_after: Duration) -> Self {
+        ModelResponse {
+            retry_after: Some(retry_after),
+            ..self
+        }
+    }
+
+    // Gives a response shared by coalesced requests its own tag, which is returned as the response's ID.
+    pub(super) fn set_tag(&mut self, tag: Uuid) {
+        tracing::debug!(tag = ?tag);
+
+        if let ModelResponseData::Json(json) = &mut self.response {
+            if let Some(value) = json.get_mut("id") {
+                *value = Value::String(format!("{}", tag));
+            }
+        }
+    }
+
+    // Returns true if the backend rejected the API key used for the request. Every 401 response is caused by the key, but a 403 response may also be a refusal of the request's contents, so its error code is checked.
+    fn is_api_key_error(&self) -> bool {
+        match self.status {
+            StatusCode::UNAUTHORIZED => true,
+            StatusCode::FORBIDDEN => match &self.response {
+                ModelResponseData::Json(json) => json
+                    .get("error")
+                    .and_then(|error| error.as_object())
+                    .is_some_and(|error| {
+                        ["code", "type"].iter().any(|field| {
+                            error
+                                .get(*field)
+                                .and_then(|value| value.as_str())
+                                .is_some_and(|value| API_KEY_ERROR_CODES.contains(&value))
+                        })
+                    }),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Reverses ModelRequest::convert_chat_to_completion, so that the client receives the chat response it asked for.
+    pub(super) fn convert_completion_to_chat(&mut self) {
+        if self.status.is_success() {
+            self.response.convert_completion_to_chat();
+        }
+    }
+
+    // Returns the text of each of the response's choices (which is empty for choices without text), in the same order as the choices.
+    pub(super) fn get_output_texts(&self) -> Vec<String> {
+        match &self.response {
+            ModelResponseData::Json(json) if self.status.is_success() => {
+                match json.get("choices") {
+                    Some(Value::Array(choices)) => choices
+                        .iter()
+                        .map(|choice| {
+                            choice
+                                .get("message")
+                                .and_then(|message| message.get("content"))
+                                .or(choice.get("text"))
+                                .and_then(|text| text.as_str())
+                                .unwrap_or_default()
+                                .to_string()
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Returns whether each input of a TextModeration response was flagged, or None if the response doesn't have a result for each input.
+    pub(super) fn get_moderation_flags(&self, inputs: usize) -> Option<Vec<bool>> {
+        let results = match &self.response {
+            ModelResponseData::Json(json) if self.status.is_success() => json.get("results")?,
+            _ => return None,
+        };
+
+        let flags: Vec<bool> = results
+            .as_array()?
+            .iter()
+            .map(|result| result.get("flagged").and_then(|flagged| flagged.as_bool()))
+            .collect::<Option<_>>()?;
+
+        (flags.len() == inputs).then_some(flags)
+    }
+
+    // Replaces (or annotates) the choices whose output was flagged by the moderation model.
+    pub(super) fn apply_output_moderation(
+        &mut self,
+        flags: &[bool],
+        moderation: &OutputModeration,
+    ) {
+        let json = match &mut self.response {
+            ModelResponseData::Json(json) => json,
+            _ => return,
+        };
+        let refusal = moderation
+            .refusal_message
+            .as_deref()
+            .unwrap_or(DEFAULT_REFUSAL_MESSAGE);
+
+        let mut flagged = 0;
+        if let Some(Value::Array(choices)) = json.get_mut("choices") {
+            for (choice, _) in choices
+                .iter_mut()
+                .zip(flags)
+                .filter(|(_, flagged)| **flagged)
+            {
+                let choice = match choice {
+                    Value::Object(choice) => choice,
+                    _ => continue,
+                };
+                flagged += 1;
+
+                match moderation.action {
+                    ModerationAction::Block => {
+                        if let Some(Value::Object(message)) = choice.get_mut("message") {
+                            message.insert("content".to_string(), Value::from(refusal));
+                            message.remove("tool_calls");
+                            message.remove("function_call");
+                        }
+                        if choice.contains_key("text") {
+                            choice.insert("text".to_string(), Value::from(refusal));
+                        }
+                        choice.insert("finish_reason".to_string(), Value::from("content_filter"));
+                    }
+                    ModerationAction::Annotate => {
+                        choice.insert(
+                            "content_filter_results".to_string(),
+                            json!({ "flagged": true }),
+                        );
+                    }
+                }
+            }
+        }
+
+        if flagged == 0 {
+            return;
+        }
+
+        match moderation.action {
+            ModerationAction::Block => {
+                // The Anthropic-style fields are only present on responses with a single choice.
+                if json.contains_key("completion") {
+                    json.insert("completion".to_string(), Value::from(refusal));
+                }
+                if json.contains_key("stop_reason") {
+                    json.insert("stop_reason".to_string(), Value::from("refusal"));
+                }
+                if let Some(Value::Array(_)) = json.get("content") {
+                    json.insert(
+                        "content".to_string(),
+                        json!([{ "type": "text", "text": refusal }]),
+                    );
+                }
+
+                self.warnings.push(
+                    "The model's output was flagged by the moderation model, and was replaced."
+                        .to_string(),
+                );
+            }
+            ModerationAction::Annotate => {
+                self.warnings
+                    .push("The model's output was flagged by the moderation model.".to_string());
+            }
+        }
+    }
+
+    pub(super) fn is_fallback_eligible(&self) -> bool {
+        match self.status {
+            StatusCode::SERVICE_UNAVAILABLE | StatusCode::BAD_GATEWAY => true,
+            // Rate limits and errors are only retried if they came from the backend, as the User's rate limits and the proxy's own errors would also apply to the fallback.
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::INTERNAL_SERVER_ERROR => {
+                self.timings.upstream.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn merge_embedding_chunks(responses: Vec<ModelResponse>) -> ModelResponse {
+        Self::merge_chunks(responses, "data")
+    }
+
+    // Merges the responses to requests split by ModelRequest::split_n, with the choices of each response re-indexed to follow the previous response's choices.
+    pub(super) fn merge_choices(responses: Vec<ModelResponse>) -> ModelResponse {
+        let mut merged = Self::merge_chunks(responses, "choices");
+
+        if let ModelResponseData::Json(json) = &mut merged.response {
+            let choices = json
+                .get("choices")
+                .and_then(|choices| choices.as_array())
+                .map_or(0, |choices| choices.len());
+
+            // The Anthropic-style fields only describe the first response's choice.
+            if choices > 1 {
+                json.remove("completion");
+                json.remove("stop_reason");
+                if let Some(Value::Array(_)) = json.get("content") {
+                    json.remove("content");
+                }
+            }
+        }
+
+        merged
+    }
+
+    // If any chunk fails, the first failed response is returned, with the usage of every chunk so that the tokens used by the other chunks are still charged.
+    #[tracing::instrument(level = "trace", ret)]
+    fn merge_chunks(responses: Vec<ModelResponse>, field: &str) -> ModelResponse {
+        let mut merged: Option<ModelResponse> = None;
+        let mut failed: Option<ModelResponse> = None;
+        let mut data = Vec::new();
+        let mut usage = Map::new();
+        let mut token_usage = Vec::new();
+
+        for response in responses {
+            let mut json = match response.response {
+                ModelResponseData::Json(json) if response.status.is_success() => json,
+                _ => {
+                    // Failed responses without any usage would otherwise hide the other chunks' input and output tokens.
+                    if response.usage.total > 0 {
+                        token_usage.push(response.usage.clone());
+                    }
+                    failed.get_or_insert(response);
+                    continue;
+                }
+            };
+            token_usage.push(response.usage);
+
+            if let Some(Value::Array(mut objects)) = json.remove(field) {
+                objects.sort_by_key(|object| object.get("index").and_then(|index| index.as_u64()));
+
+                let offset = data.len();
+                for (index, mut object) in objects.into_iter().enumerate() {
+                    if let Value::Object(object) = &mut object {
+                        object.insert("index".to_string(), Value::from(offset + index));
+                    }
+
+                    data.push(object);
+                }
+            }
+
+            if let Some(Value::Object(chunk_usage)) = json.remove("usage") {
+                merge_usage_objects(&mut usage, chunk_usage);
+            }
+
+            if merged.is_none() {
+                merged = Some(ModelResponse {
+                    response: ModelResponseData::Json(json),
+                    usage: TokenUsage::default(),
+                    ..response
+                });
+            }
+        }
+
+        if let Some(mut failed) = failed {
+            failed.usage = TokenUsage::merge(token_usage);
+            return failed;
+        }
+
+        match merged {
+            Some(mut merged) => {
+                merged.usage = TokenUsage::merge(token_usage);
+
+                if let ModelResponseData::Json(json) = &mut merged.response {
+                    json.insert(field.to_string(), Value::Array(data));
+                    if !usage.is_empty() {
+                        json.insert("usage".to_string(), Value::Object(usage));
+                    }
+                }
+
+                merged
+            }
+            None => ModelResponse::from(ModelError::BackendError),
+        }
+    }
+
+    pub(super) fn is_stream(&self) -> bool {
+        matches!(self.response, ModelResponseData::Stream(_))
+    }
+
+    // Binary responses are replaced with their size, and streamed responses can't be read without consuming them.
+    pub(super) fn to_log_value(&self) -> Value {
+        match &self.response {
+            ModelResponseData::Json(json) => Value::Object(json.clone()),
+            ModelResponseData::Binary(data) => json!({ "size": data.len() }),
+            ModelResponseData::Stream(_) => json!({ "stream": true }),
+        }
+    }
+
+    // The usage of a streamed response is only known once the stream ends.
+    pub(super) fn take_stream_usage(&self) -> Option<oneshot::Receiver<Option<TokenUsage>>> {
+        match &self.response {
+            ModelResponseData::Stream(stream) => stream.take_usage(),
+            _ => None,
+        }
+    }
+
+    pub(super) fn into_batch_item(self, include_warnings: bool) -> Value {
+        let mut item = Map::new();
+
+        item.insert("status".to_string(), Value::from(self.status.as_u16()));
+        item.insert(
+            "body".to_string(),
+            match self.response {
+                ModelResponseData::Json(json) => Value::Object(json),
+                ModelResponseData::Binary(binary) => Value::String(STANDARD.encode(&binary)),
+                ModelResponseData::Stream(_) => Value::Null,
+            },
+        );
+        if include_warnings && !self.warnings.is_empty() {
+            item.insert("warnings".to_string(), json!(self.warnings));
+        }
+
+        Value::Object(item)
+    }
+}
+
+// OpenAI finish reason, Anthropic Messages stop reason, Anthropic Text Completions stop reason
+const STOP_REASONS: [(&str, &str, &str); 6] = [
+    ("stop", "end_turn", "stop_sequence"),
+    ("stop", "stop_sequence", "stop_sequence"),
+    ("length", "max_tokens", "max_tokens"),
+    ("tool_calls", "tool_use", "tool_use"),
+    ("function_call", "tool_use", "tool_use"),
+    ("content_filter", "refusal", "refusal"),
+];
+
+const JSON_SCHEMA_TYPES: [&str; 7] = [
+    "string", "number", "integer", "boolean", "object", "array", "null",
+];
+
+// Checks the structure of a schema and its subschemas, without checking that the keywords used are supported by the model.
+fn check_json_schema(
+    schema: &Value,
+    path: &str,
+    depth: usize,
+    max_depth: usize,
+) -> Result<(), String> {
+    if depth > max_depth {
+        return Err(format!(
+            "{} is nested more than {} levels deep",
+            path, max_depth
+        ));
+    }
+
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(_) => return Ok(()),
+        _ => return Err(format!("{} must be an object or a boolean", path)),
+    };
+
+    let check_subschema = |value: &Value, key: &str| {
+        check_json_schema(value, &format!("{}.{}", path, key), depth + 1, max_depth)
+    };
+
+    for (key, value) in schema {
+        match key.as_str() {
+            "type" => {
+                let is_valid_type = |value: &Value| {
+                    value
+                        .as_str()
+                        .is_some_and(|name| JSON_SCHEMA_TYPES.contains(&name))
+                };
+
+                let is_valid = match value {
+                    Value::Array(types) => !types.is_empty() && types.iter().all(is_valid_type),
+                    value => is_valid_type(value),
+                };
+
+                if !is_valid {
+                    return Err(format!("{}.type must be a valid JSON Schema type", path));
+                }
+            }
+            "properties" | "patternProperties" | "$defs" | "definitions" => match value {
+                Value::Object(properties) => {
+                    for (name, property) in properties {
+                        check_subschema(property, &format!("{}.{}", key, name))?;
+                    }
+                }
+                _ => return Err(format!("{}.{} must be an object", path, key)),
+            },
+            "items" | "additionalProperties" | "not" | "contains" | "propertyNames" => {
+                match value {
+                    Value::Array(items) if key == "items" => {
+                        for (index, item) in items.iter().enumerate() {
+                            check_subschema(item, &format!("{}[{}]", key, index))?;
+                        }
+                    }
+                    value => check_subschema(value, key)?,
+                }
+            }
+            "anyOf" | "allOf" | "oneOf" | "prefixItems" => match value {
+                Value::Array(schemas) if !schemas.is_empty() => {
+                    for (index, schema) in schemas.iter().enumerate() {
+                        check_subschema(schema, &format!("{}[{}]", key, index))?;
+                    }
+                }
+                _ => return Err(format!("{}.{} must be a non-empty array", path, key)),
+            },
+            "required" => {
+                let is_valid = match value {
+                    Value::Array(names) => names.iter().all(|name| name.is_string()),
+                    _ => false,
+                };
+
+                if !is_valid {
+                    return Err(format!("{}.required must be an array of strings", path));
+                }
+            }
+            "enum" if !value.is_array() => {
+                return Err(format!("{}.enum must be an array", path));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Only WAV files are supported, as other audio formats have to be decoded to find their duration.
+fn get_wav_duration(data: &[u8]) -> Option<f64> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+
+    let mut byte_rate = None;
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let body = offset + 8;
+
+        match &header[0..4] {
+            b"fmt " => {
+                byte_rate = Some(u32::from_le_bytes(
+                    data.get(body + 8..body + 12)?.try_into().ok()?,
+                ));
+            }
+            b"data" => {
+                // Streamed WAV files may not know the size of their data ahead of time.
+                let size = size.min(data.len() - body);
+
+                return match byte_rate? {
+                    0 => None,
+                    byte_rate => Some(size as f64 / byte_rate as f64),
+                };
+            }
+            _ => {}
+        }
+
+        offset = body.checked_add(size)?.checked_add(size % 2)?;
+    }
+
+    None
+}
+
+fn parse_image_size(size: &str) -> Option<(u64, u64)> {
+    let (width, height) = size.split_once('x')?;
+
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+// The nearest size is the one with the smallest total difference in width and height.
+fn get_nearest_image_size<'a>(size: &str, sizes: &'a [String]) -> Option<&'a str> {
+    let (width, height) = parse_image_size(size)?;
+
+    sizes
+        .iter()
+        .filter_map(|supported| {
+            parse_image_size(supported).map(|(supported_width, supported_height)| {
+                (
+                    width.abs_diff(supported_width) + height.abs_diff(supported_height),
+                    supported.as_str(),
+                )
+            })
+        })
+        .min_by_key(|(difference, _)| *difference)
+        .map(|(_, supported)| supported)
+}
+
+fn get_message_text(message: &Value) -> String {
+    match message.get("content") {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| part.get("text"))
+            .filter_map(|text| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn get_anthropic_stop_reason(reason: &str, r#type: RequestType) -> &str {
+    STOP_REASONS
+        .iter()
+        .find(|(openai, _, _)| *openai == reason)
+        .map(|(_, messages, completion)| match r#type {
+            RequestType::TextCompletion => *completion,
+            _ => *messages,
+        })
+        .unwrap_or(reason)
+}
+
+fn get_openai_finish_reason(reason: &str) -> &str {
+    STOP_REASONS
+        .iter()
+        .find(|(openai, messages, completion)| {
+            *openai == reason || *messages == reason || *completion == reason
+        })
+        .map(|(openai, _, _)| *openai)
+        .unwrap_or(reason)
+}
+
+// Converts a buffered response into the equivalent event stream, with all of the response's content sent in a single chunk.
+fn get_event_stream(
+    r#type: RequestType,
+    json: &Map<String, Value>,
+    include_usage: bool,
+) -> Option<String> {
+    let object = match r#type {
+        RequestType::TextChat => "chat.completion.chunk",
+        RequestType::TextCompletion => "text_completion",
+        _ => return None,
+    };
+
+    let mut chunk = Map::new();
+    for field in STREAM_CHUNK_FIELDS {
+        if let Some(value) = json.get(field) {
+            chunk.insert(field.to_string(), value.clone());
+        }
+    }
+    chunk.insert("object".to_string(), Value::String(object.to_string()));
+
+    let choices: Vec<Value> = match json.get("choices") {
+        Some(Value::Array(choices)) => choices
+            .iter()
+            .filter_map(|choice| choice.as_object())
+            .map(|choice| {
+                let mut choice = choice.clone();
+
+                if r#type == RequestType::TextChat {
+                    let mut delta = match choice.remove("message") {
+                        Some(Value::Object(message)) => message,
+                        _ => Map::new(),
+                    };
+
+                    if let Some(Value::Array(tool_calls)) = delta.get_mut("tool_calls") {
+                        for (index, tool_call) in tool_calls.iter_mut().enumerate() {
+                            if let Value::Object(tool_call) = tool_call {
+                                tool_call
+                                    .entry("index")
+                                    .or_insert(Value::Number(index.into()));
+                            }
+                        }
+                    }
+
+                    choice.insert("delta".to_string(), Value::Object(delta));
+                }
+
+                Value::Object(choice)
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut events = Vec::new();
+
+    let mut content_chunk = chunk.clone();
+    content_chunk.insert("choices".to_string(), Value::Array(choices));
+    events.push(Value::Object(content_chunk));
+
+    if include_usage {
+        chunk.insert("choices".to_string(), Value::Array(Vec::new()));
+        chunk.insert(
+            "usage".to_string(),
+            json.get("usage").cloned().unwrap_or(Value::Null),
+        );
+        events.push(Value::Object(chunk));
+    }
+
+    let mut stream = String::new();
+    for event in events {
+        stream.push_str(&format!("data: {}\n\n", event));
+    }
+    stream.push_str("data: [DONE]\n\n");
+
+    Some(stream)
+}
+
+// Only the order of object keys is made consistent, as any other difference (such as whitespace within strings, or 1 instead of 1.0) can change how a backend handles the request.
+fn get_canonical_value(value: &Value) -> Value {
+    match value {
+        Value::Array(array) => Value::Array(array.iter().map(get_canonical_value).collect()),
+        Value::Object(object) => {
+            let mut fields: Vec<_> = object.iter().collect();
+            fields.sort_by_key(|(key, _)| *key);
+
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), get_canonical_value(value)))
+                    .collect(),
+            )
+        }
+        _ => value.clone(),
+    }
+}
+
+// Embeddings can either be an array of numbers, or an object containing one (such as Gemini's {"values": [...]}).
+fn get_embedding_vector(embedding: &Value) -> Option<Value> {
+    match embedding {
+        Value::Array(_) => Some(embedding.clone()),
+        Value::Object(embedding) => ["values", "embedding"]
+            .iter()
+            .filter_map(|field| embedding.get(*field))
+            .find(|vector| vector.is_array())
+            .cloned(),
+        _ => None,
+    }
+}
+
+// Citations can either be a URL, or an object describing the cited source.
+fn get_citation_annotation(citation: &Value) -> Option<Value> {
+    let (url, title, start_index, end_index) = match citation {
+        Value::String(url) => (Value::String(url.clone()), None, None, None),
+        Value::Object(citation) => (
+            ["url", "uri", "filepath", "source"]
+                .iter()
+                .find_map(|field| citation.get(*field).filter(|value| value.is_string()))
+                .cloned()?,
+            citation.get("title"),
+            citation.get("start_index").or(citation.get("start")),
+            citation.get("end_index").or(citation.get("end")),
+        ),
+        _ => return None,
+    };
+
+    Some(json!({
+        "type": "url_citation",
+        "url_citation": {
+            "url": url,
+            "title": title.cloned().unwrap_or(Value::Null),
+            "start_index": start_index.cloned().unwrap_or(Value::Null),
+            "end_index": end_index.cloned().unwrap_or(Value::Null),
+        },
+    }))
+}
+
+#[tracing::instrument(level = "trace", ret)]
+// Converts a request of the from type in the same way as requests sent to a model which only supports the to type, or returns None if the proxy can't convert between the types.
+pub(super) fn preview_request_conversion(
+    from: RequestType,
+    to: RequestType,
+    json: Map<String, Value>,
+) -> Option<Value> {
+    let model = json
+        .get("model")
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let mut warnings = Vec::new();
+    let mut request = ModelRequestData::Json(json);
+
+    match (from, to) {
+        _ if from == to => {}
+        (RequestType::TextChat, RequestType::TextCompletion) => {
+            if !request.convert_chat_to_completion(&mut warnings) {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+
+    Some(match request.into_openai(model, None, &mut warnings) {
+        ModelRequestData::Json(json) => json!({ "body": json, "warnings": warnings }),
+        ModelRequestData::Form(_) => json!({ "body": null, "warnings": warnings }),
+    })
+}
+
+// Converts a response to a request of the from type before it's returned to a client which sent a request of the to type, or returns None if the proxy can't convert between the types.
+#[tracing::instrument(level = "trace", ret)]
+pub(super) fn preview_response_conversion(
+    from: RequestType,
+    to: RequestType,
+    json: Map<String, Value>,
+) -> Option<Value> {
+    if from != to && (from, to) != (RequestType::TextCompletion, RequestType::TextChat) {
+        return None;
+    }
+
+    let label = json
+        .get("model")
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string());
+    let is_error = json.contains_key("error");
+
+    let (mut response, usage) = ModelResponseData::Json(json).into_hybrid_api(
+        label,
+        from,
+        Uuid::nil(),
+        &SystemFingerprint::from_model(Uuid::nil()),
+        is_error,
+        None,
+    );
+    if from != to && !is_error {
+        response.convert_completion_to_chat();
+    }
+
+    Some(json!({
+        "body": match response {
+            ModelResponseData::Json(json) => Value::Object(json),
+            ModelResponseData::Binary(_) | ModelResponseData::Stream(_) => Value::Null,
+        },
+        "usage": {
+            "total": usage.total,
+            "input": usage.input,
+            "output": usage.output,
+        },
+    }))
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct ModelWarnings(pub(super) Vec<String>);
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct ModelTimings {
+    pub(super) upstream: Option<Duration>,
+    pub(super) queue: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+enum ModelResponseData {
+    Json(Map<String, Value>),
+    Binary(Vec<u8>),
+    Stream(ModelStream),
+}
+
+impl ModelResponseData {
+    // Prepends each choice's prompt to its text, for backends which don't support echo. Choices are assumed to be ordered by prompt, with n choices per prompt.
+    fn insert_echo(&mut self, prompts: &[String], n: u64) {
+        let choices = match self {
+            Self::Json(json) => match json.get_mut("choices") {
+                Some(Value::Array(choices)) => choices,
+                _ => return,
+            },
+            _ => return,
+        };
+
+        for (position, choice) in choices.iter_mut().enumerate() {
+            let index = choice
+                .get("index")
+                .and_then(|index| index.as_u64())
+                .unwrap_or(position as u64);
+
+            if let (Some(prompt), Some(Value::String(text))) =
+                (prompts.get((index / n) as usize), choice.get_mut("text"))
+            {
+                text.insert_str(0, prompt);
+            }
+        }
+    }
+
+    // Appends the suffix to each choice's text, for backends which don't support suffix.
+    fn insert_suffix(&mut self, suffix: &str) {
+        if let Self::Json(json) = self {
+            if let Some(Value::Array(choices)) = json.get_mut("choices") {
+                for choice in choices {
+                    if let Some(Value::String(text)) = choice.get_mut("text") {
+                        text.push_str(suffix);
+                    }
+                }
+            }
+        }
+    }
+
+    // Some backends (such as local model servers) don't return usage information, so it has to be estimated using a tokenizer.
+    #[tracing::instrument(level = "trace")]
+    fn synthesize_usage(
+        &mut self,
+        r#type: RequestType,
+        input_tokens: u64,
+        tokenizer: &TokenizerSettings,
+    ) {
+        let json = match self {
+            Self::Json(json) if !json.contains_key("usage") => json,
+            _ => return,
+        };
+
+        let usage = match r#type {
+            RequestType::TextChat | RequestType::TextCompletion | RequestType::TextEdit => {
+                let output_tokens: u64 = match json.get("choices") {
+                    Some(Value::Array(choices)) => choices
+                        .iter()
+                        .filter_map(|choice| {
+                            choice
+                                .get("message")
+                                .and_then(|message| message.get("content"))
+                                .or(choice.get("text"))
+                                .and_then(|text| text.as_str())
+                        })
+                        .map(|text| tokenizer.tokenize_text(text).len() as u64)
+                        .sum(),
+                    _ => 0,
+                };
+
+                json!({
+                    "prompt_tokens": input_tokens,
+                    "completion_tokens": output_tokens,
+                    "total_tokens": input_tokens + output_tokens,
+                })
+            }
+            RequestType::TextEmbedding => json!({
+                "prompt_tokens": input_tokens,
+                "total_tokens": input_tokens,
+            }),
+            _ => return,
+        };
+
+        json.insert("usage".to_string(), usage);
+    }
+
+    // Embedding backends which don't implement OpenAI's API (such as Cohere, Gemini, and Ollama) return embeddings in several different formats, which are converted into an OpenAI-style list. The original fields are removed, so that embeddings aren't sent to the client twice.
+    #[tracing::instrument(level = "trace")]
+    fn normalize_embeddings(&mut self) {
+        let json = match self {
+            Self::Json(json) if !json.contains_key("data") => json,
+            _ => return,
+        };
+
+        let embeddings: Option<Vec<Value>> = match (json.get("embeddings"), json.get("embedding")) {
+            (Some(Value::Array(embeddings)), _) => {
+                embeddings.iter().map(get_embedding_vector).collect()
+            }
+            // Cohere's v2 API groups embeddings by their type.
+            (Some(Value::Object(embeddings)), _) => match embeddings.get("float") {
+                Some(Value::Array(embeddings)) => {
+                    embeddings.iter().map(get_embedding_vector).collect()
+                }
+                _ => None,
+            },
+            (None, Some(embedding)) => get_embedding_vector(embedding).map(|vector| vec![vector]),
+            _ => None,
+        };
+        let embeddings = match embeddings {
+            Some(embeddings) => embeddings,
+            None => return,
+        };
+        json.remove("embeddings");
+        json.remove("embedding");
+
+        json.insert("object".to_string(), Value::String("list".to_string()));
+        json.insert(
+            "data".to_string(),
+            Value::Array(
+                embeddings
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, embedding)| {
+                        json!({
+                            "object": "embedding",
+                            "index": index,
+                            "embedding": embedding,
+                        })
+                    })
+                    .collect(),
+            ),
+        );
+
+        if !json.contains_key("usage") {
+            let input_tokens = json
+                .get("meta")
+                .and_then(|meta| meta.pointer("/billed_units/input_tokens"))
+                .or_else(|| json.get("prompt_eval_count"))
+                .and_then(|tokens| tokens.as_u64());
+
+            if let Some(input_tokens) = input_tokens {
+                json.insert(
+                    "usage".to_string(),
+                    json!({
+                        "prompt_tokens": input_tokens,
+                        "total_tokens": input_tokens,
+                    }),
+                );
+            }
+        }
+    }
+
+    // Retrieval-augmented backends return citations in several different formats, which are converted into OpenAI-style url_citation annotations. The original fields are left in place.
+    #[tracing::instrument(level = "trace")]
+    fn normalize_citations(&mut self, r#type: RequestType) {
+        let json = match self {
+            Self::Json(json) => json,
+            _ => return,
+        };
+
+        let shared_citations: Vec<Value> = CITATION_FIELDS
+            .iter()
+            .filter_map(|field| json.get(*field))
+            .find_map(|citations| citations.as_array())
+            .map(|citations| {
+                citations
+                    .iter()
+                    .filter_map(get_citation_annotation)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(Value::Array(choices)) = json.get_mut("choices") {
+            for choice in choices {
+                let target = match (r#type, choice) {
+                    (RequestType::TextChat, Value::Object(choice)) => {
+                        match choice.get_mut("message") {
+                            Some(Value::Object(message)) => message,
+                            _ => continue,
+                        }
+                    }
+                    (
+                        RequestType::TextCompletion | RequestType::TextEdit,
+                        Value::Object(choice),
+                    ) => choice,
+                    _ => continue,
+                };
+
+                let citations = CITATION_FIELDS
+                    .iter()
+                    .filter_map(|field| target.get(*field))
+                    .chain(
+                        target
+                            .get("context")
+                            .and_then(|context| context.get("citations")),
+                    )
+                    .find_map(|citations| citations.as_array())
+                    .map(|citations| {
+                        citations
+                            .iter()
+                            .filter_map(get_citation_annotation)
+                            .collect()
+                    })
+                    .unwrap_or_else(|| shared_citations.clone());
+
+                // The response body is only changed for choices which have citations.
+                if citations.is_empty() {
+                    continue;
+                }
+
+                match target.get_mut("annotations") {
+                    Some(Value::Array(annotations)) if annotations.is_empty() => {
+                        *annotations = citations;
+                    }
+                    None => {
+                        target.insert("annotations".to_string(), Value::Array(citations));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn convert_completion_to_chat(&mut self) {
+        if let Self::Json(json) = self {
+            if let Some(Value::Array(choices)) = json.get_mut("choices") {
+                for choice in choices {
+                    if let Value::Object(choice) = choice {
+                        if let Some(text) = choice.remove("text") {
+                            choice.insert(
+                                "message".to_string(),
+                                json!({ "role": "assistant", "content": text }),
+                            );
+                        }
+                    }
+                }
+            }
+
+            if let Some(Value::String(reason)) = json.get_mut("stop_reason") {
+                *reason = get_anthropic_stop_reason(
+                    get_openai_finish_reason(reason),
+                    RequestType::TextChat,
+                )
+                .to_string();
+            }
+
+            json.remove("completion");
+            json.insert(
+                "object".to_string(),
+                Value::String("chat.completion".to_string()),
+            );
+            json.insert("type".to_string(), Value::String("message".to_string()));
+        }
+    }
+
+    #[tracing::instrument(level = "trace", ret)]
+    fn into_hybrid_api(
+        self,
+        model: Option<String>,
+        r#type: RequestType,
+        tag: Uuid,
+        fingerprint: &SystemFingerprint,
+        is_error: bool,
+        proxy_metadata: Option<&ProxyMetadata>,
+    ) -> (Self, TokenUsage) {
+        match self {
+            Self::Json(mut json) => {
+                match is_error {
+                    true => {
+                        if let Some(Value::Object(_)) = json.get("error") {
+                            json.insert("type".to_string(), Value::String("error".to_string()));
+                        }
+                    }
+                    false => {
+                        if r#type == RequestType::TextChat || r#type == RequestType::TextCompletion
+                        {
+                            match fingerprint {
+                                SystemFingerprint::Fallback(fingerprint)
+                                    if !json.contains_key("system_fingerprint") =>
+                                {
+                                    json.insert(
+                                        "system_fingerprint".to_string(),
+                                        Value::String(fingerprint.clone()),
+                                    );
+                                }
+                                SystemFingerprint::Override(fingerprint) => {
+                                    json.insert(
+                                        "system_fingerprint".to_string(),
+                                        Value::String(fingerprint.clone()),
+                                    );
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        if r#type == RequestType::TextChat
+                            || r#type == RequestType::TextCompletion
+                            || r#type == RequestType::TextEdit
+                        {
+                            let mut completion = None;
+                            let mut stop_reason = None;
+
+                            if let Some(Value::Array(choices)) = json.get_mut("choices") {
+                                for (index, value) in choices.iter_mut().enumerate() {
+                                    if let Value::Object(choice) = value {
+                                        if !choice.contains_key("index") {
+                                            choice.insert(
+                                                "index".to_string(),
+                                                Value::Number(index.into()),
+                                            );
+                                        }
+
+                                        if !choice.contains_key("logprobs") {
+                                            choice.insert("logprobs".to_string(), Value::Null);
+                                        }
+
+                                        // Each choice keeps its own finish reason, as choices can finish for different reasons.
+                                        match choice.get_mut("finish_reason") {
+                                            Some(Value::String(reason)) => {
+                                                *reason =
+                                                    get_openai_finish_reason(reason).to_string();
+                                            }
+                                            Some(_) => {}
+                                            None => {
+                                                choice.insert(
+                                                    "finish_reason".to_string(),
+                                                    Value::Null,
+                                                );
+                                            }
+                                        }
+
+                                        if (r#type == RequestType::TextCompletion
+                                            || r#type == RequestType::TextEdit)
+                                            && !choice.contains_key("text")
+                                        {
+                                            if let Some(Value::Object(message)) =
+                                                choice.get("message")
+                                            {
+                                                if let Some(Value::String(content)) =
+                                                    message.get("content")
+                                                {
+                                                    choice.insert(
+                                                        "text".to_string(),
+                                                        Value::String(content.clone()),
+                                                    );
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+
+                                choices.sort_by(|a, b| {
+                                    if let (Some(a), Some(b)) = (
+                                        a.get("index").and_then(|v| v.as_u64()),
+                                        b.get("index").and_then(|v| v.as_u64()),
+                                    ) {
+                                        a.cmp(&b)
+                                    } else {
+                                        Ordering::Equal
+                                    }
+                                });
+
+                                if choices.len() == 1 {
                                     if let Some(Value::Object(choice)) = choices.first() {
                                         if r#type == RequestType::TextCompletion {
                                             completion = choice.get("text").cloned();
-                                            stop_reason = choice
-                                                .get("finish_reason")
-                                                .and_then(|value| value.as_str())
-                                                .map(|reason| {
-                                                    match reason {
-                                                        "stop" => "stop_sequence",
-                                                        "length" => "max_tokens",
-                                                        _ => reason,
-                                                    }
-                                                    .to_string()
-                                                })
-                                                .map(Value::String);
-                                        } else {
-                                            stop_reason = choice
-                                                .get("finish_reason")
-                                                .and_then(|value| value.as_str())
-                                                .map(|reason| {
-                                                    match reason {
-                                                        "stop" => "end_turn",
-                                                        "length" => "max_tokens",
-                                                        _ => reason,
-                                                    }
-                                                    .to_string()
-                                                })
-                                                .map(Value::String);
                                         }
+
+                                        stop_reason = choice
+                                            .get("finish_reason")
+                                            .and_then(|value| value.as_str())
+                                            .map(|reason| {
+                                                Value::String(
+                                                    get_anthropic_stop_reason(reason, r#type)
+                                                        .to_string(),
+                                                )
+                                            });
                                     }
                                 }
                             }
@@ -586,6 +2763,17 @@ impl ModelResponseData {
                     }
                 };
 
+                if let Some(metadata) = proxy_metadata {
+                    json.insert(
+                        "_proxy".to_string(),
+                        json!({
+                            "version": env!("CARGO_PKG_VERSION"),
+                            "deployment": metadata.deployment,
+                            "request_tag": tag.to_string(),
+                        }),
+                    );
+                }
+
                 (Self::Json(json), usage)
             }
             Self::Binary(binary) => match is_error {
@@ -615,6 +2803,7 @@ impl ModelResponseData {
                     },
                 ),
             },
+            Self::Stream(stream) => (Self::Stream(stream), TokenUsage::default()),
         }
     }
 }
@@ -623,6 +2812,7 @@ impl From<ModelError> for ModelResponse {
     fn from(value: ModelError) -> Self {
         let mut json = Map::new();
 
+        let formatted_message;
         let message = match value {
             ModelError::BadRequest => "We could not parse the JSON body of your request. (HINT: This likely means you aren't using your HTTP library correctly. The API expects a JSON payload, but what was sent was not valid JSON. If you have trouble figuring out how to fix this, contact the proxy's administrator.)",
             ModelError::AuthMissing => "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY), or as the password field (with blank username) if you're accessing the API from your browser and are prompted for a username and password. You can obtain an API key from the proxy's administrator.",
@@ -632,8 +2822,50 @@ impl From<ModelError> for ModelResponse {
             ModelError::UnknownEndpoint => "Unknown request URL. Please check the URL for typos, or contact the proxy's administrator for information regarding available endpoints.",
             ModelError::BadEndpointMethod => "Invalid request method. Please check the URL for typos, or contact the proxy's administrator for information regarding available endpoints.",
             ModelError::UnknownModel => "The requested model does not exist. Contact the proxy's administrator for more information.",
+            ModelError::UnknownModelSuggestions(ref suggestions) => {
+                formatted_message = format!("The requested model does not exist. Did you mean {}? Contact the proxy's administrator for more information.", suggestions.join(", "));
+                &formatted_message
+            }
             ModelError::InternalError => "The proxy server had an error processing your request. Sorry about that! You can retry your request, or contact the proxy's administrator if the error persists.",
             ModelError::BackendError => "The model had an error processing your request. Sorry about that! Contact the proxy's administrator for more information.",
+            ModelError::DeadlineExceeded => "The model did not finish processing your request before the deadline set in your request's X-Request-Deadline header. You can retry your request with a longer deadline.",
+            ModelError::UpstreamTimeout => "The model's backend did not respond before the proxy's upstream timeout. You can retry your request, or contact the proxy's administrator if the error persists.",
+            ModelError::UnsupportedResponseFormat => "This model does not support structured outputs. Please use a response_format of json_object instead, or contact the proxy's administrator for more information.",
+            ModelError::BatchTooLarge => "Your batch contains too many requests, or requests too many tokens in total. You can split your batch into multiple smaller batches and retry.",
+            ModelError::ServerOverloaded => "The proxy is currently overloaded with other requests. You can retry your request, or contact the proxy's administrator if the error persists.",
+            ModelError::RequestTooLarge { max, overflow } => {
+                formatted_message = format!("This model's maximum context length is {} tokens. However, you requested {} tokens (including max_tokens), which exceeds it by {} tokens. Please reduce the length of your prompt or max_tokens.", max, max.saturating_add(overflow), overflow);
+                &formatted_message
+            }
+            ModelError::InvalidJsonSchema(ref reason) => {
+                formatted_message = format!("Invalid schema for response_format: {}. Please fix your schema and retry your request.", reason);
+                &formatted_message
+            }
+            ModelError::ParameterOutOfRange { param, min, max } => {
+                formatted_message = format!("Invalid {}: this model only supports values between {} and {}.", param, min, max);
+                &formatted_message
+            }
+            ModelError::InvalidParameterType { param, expected } => {
+                formatted_message = format!("Invalid type for '{}': expected {}.", param, expected);
+                &formatted_message
+            }
+            ModelError::UnsupportedValue {
+                param,
+                ref supported,
+            } => {
+                formatted_message = format!("Invalid {}: this model only supports the following values: {}.", param, supported.join(", "));
+                &formatted_message
+            }
+            ModelError::PermissionDenied => "You don't have permission to use this feature. Contact the proxy's administrator for more information.",
+            ModelError::ShuttingDown => "The proxy is shutting down, and was unable to finish processing your request. You can retry your request.",
+            ModelError::MissingParameter(ref param) => {
+                formatted_message = format!("Missing required parameter: '{}'. This model's backend requires it, but it wasn't provided and couldn't be filled in by the proxy.", param);
+                &formatted_message
+            }
+            ModelError::CostLimitExceeded { estimated, max } => {
+                formatted_message = format!("This request's estimated cost of {} exceeds the maximum cost of {} per request. Please reduce the length of your prompt, max_tokens, or n.", estimated, max);
+                &formatted_message
+            }
         };
         let error_type = match value {
             ModelError::BadRequest => "invalid_request_error",
@@ -644,8 +2876,23 @@ impl From<ModelError> for ModelResponse {
             ModelError::UnknownEndpoint => "invalid_request_error",
             ModelError::BadEndpointMethod => "invalid_request_error",
             ModelError::UnknownModel => "invalid_request_error",
+            ModelError::UnknownModelSuggestions(_) => "invalid_request_error",
             ModelError::InternalError => "server_error",
             ModelError::BackendError => "server_error",
+            ModelError::DeadlineExceeded => "server_error",
+            ModelError::UpstreamTimeout => "server_error",
+            ModelError::UnsupportedResponseFormat => "invalid_request_error",
+            ModelError::BatchTooLarge => "invalid_request_error",
+            ModelError::ServerOverloaded => "server_error",
+            ModelError::RequestTooLarge { .. } => "invalid_request_error",
+            ModelError::InvalidJsonSchema(_) => "invalid_request_error",
+            ModelError::ParameterOutOfRange { .. } => "invalid_request_error",
+            ModelError::InvalidParameterType { .. } => "invalid_request_error",
+            ModelError::UnsupportedValue { .. } => "invalid_request_error",
+            ModelError::CostLimitExceeded { .. } => "invalid_request_error",
+            ModelError::PermissionDenied => "invalid_request_error",
+            ModelError::ShuttingDown => "server_error",
+            ModelError::MissingParameter(_) => "invalid_request_error",
         };
         let error_code = match value {
             ModelError::BadRequest => Value::Null,
@@ -656,87 +2903,955 @@ impl From<ModelError> for ModelResponse {
             ModelError::UnknownEndpoint => Value::String("unknown_url".to_string()),
             ModelError::BadEndpointMethod => Value::Null,
             ModelError::UnknownModel => Value::String("model_not_found".to_string()),
+            ModelError::UnknownModelSuggestions(_) => Value::String("model_not_found".to_string()),
             ModelError::InternalError => Value::Null,
             ModelError::BackendError => Value::Null,
+            ModelError::DeadlineExceeded => Value::String("deadline_exceeded".to_string()),
+            ModelError::UpstreamTimeout => Value::String("upstream_timeout".to_string()),
+            ModelError::UnsupportedResponseFormat => {
+                Value::String("unsupported_response_format".to_string())
+            }
+            ModelError::BatchTooLarge => Value::String("batch_too_large".to_string()),
+            ModelError::ServerOverloaded => Value::Null,
+            ModelError::RequestTooLarge { .. } => {
+                Value::String("context_length_exceeded".to_string())
+            }
+            ModelError::InvalidJsonSchema(_) => Value::String("invalid_json_schema".to_string()),
+            ModelError::ParameterOutOfRange { .. } => Value::String("invalid_value".to_string()),
+            ModelError::InvalidParameterType { .. } => Value::String("invalid_type".to_string()),
+            ModelError::UnsupportedValue { .. } => Value::String("invalid_value".to_string()),
+            ModelError::CostLimitExceeded { .. } => {
+                Value::String("cost_limit_exceeded".to_string())
+            }
+            ModelError::PermissionDenied => Value::String("permission_denied".to_string()),
+            ModelError::ShuttingDown => Value::Null,
+            ModelError::MissingParameter(_) => {
+                Value::String("missing_required_parameter".to_string())
+            }
+        };
+        let error_param = match value {
+            ModelError::UnknownModel => Value::String("model".to_string()),
+            ModelError::UnknownModelSuggestions(_) => Value::String("model".to_string()),
+            ModelError::UnsupportedResponseFormat => Value::String("response_format".to_string()),
+            ModelError::InvalidJsonSchema(_) => Value::String("response_format".to_string()),
+            ModelError::ParameterOutOfRange { param, .. } => Value::String(param.to_string()),
+            ModelError::InvalidParameterType { param, .. } => Value::String(param.to_string()),
+            ModelError::UnsupportedValue { param, .. } => Value::String(param.to_string()),
+            ModelError::MissingParameter(ref param) => Value::String(param.clone()),
+            _ => Value::Null,
+        };
+
+        json.insert("message".to_string(), Value::String(message.to_string()));
+        json.insert("type".to_string(), Value::String(error_type.to_string()));
+        json.insert("param".to_string(), error_param);
+        json.insert("code".to_string(), error_code);
+
+        if let ModelError::RequestTooLarge { max, overflow } = value {
+            json.insert("overflow_tokens".to_string(), Value::from(overflow));
+            json.insert("max_context_tokens".to_string(), Value::from(max));
+            json.insert(
+                "requested_tokens".to_string(),
+                Value::from(max.saturating_add(overflow)),
+            );
+        }
+
+        if let ModelError::CostLimitExceeded { estimated, max } = value {
+            json.insert("estimated_cost".to_string(), Value::from(estimated));
+            json.insert("max_cost".to_string(), Value::from(max));
+        }
+
+        if let ModelError::UnknownModelSuggestions(ref suggestions) = value {
+            json.insert("suggestions".to_string(), Value::from(suggestions.clone()));
+        }
+
+        let status = match value {
+            ModelError::BadRequest => StatusCode::BAD_REQUEST,
+            ModelError::AuthMissing => StatusCode::UNAUTHORIZED,
+            ModelError::AuthInvalid => StatusCode::UNAUTHORIZED,
+            ModelError::UserRateLimit => StatusCode::TOO_MANY_REQUESTS,
+            ModelError::ModelRateLimit => StatusCode::SERVICE_UNAVAILABLE,
+            ModelError::UnknownEndpoint => StatusCode::NOT_FOUND,
+            ModelError::BadEndpointMethod => StatusCode::METHOD_NOT_ALLOWED,
+            ModelError::UnknownModel => StatusCode::NOT_FOUND,
+            ModelError::UnknownModelSuggestions(_) => StatusCode::NOT_FOUND,
+            ModelError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ModelError::BackendError => StatusCode::BAD_GATEWAY,
+            ModelError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            ModelError::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ModelError::UnsupportedResponseFormat => StatusCode::BAD_REQUEST,
+            ModelError::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ModelError::ServerOverloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ModelError::RequestTooLarge { .. } => StatusCode::BAD_REQUEST,
+            ModelError::InvalidJsonSchema(_) => StatusCode::BAD_REQUEST,
+            ModelError::ParameterOutOfRange { .. } => StatusCode::BAD_REQUEST,
+            ModelError::InvalidParameterType { .. } => StatusCode::BAD_REQUEST,
+            ModelError::UnsupportedValue { .. } => StatusCode::BAD_REQUEST,
+            ModelError::CostLimitExceeded { .. } => StatusCode::BAD_REQUEST,
+            ModelError::PermissionDenied => StatusCode::FORBIDDEN,
+            ModelError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            ModelError::MissingParameter(_) => StatusCode::BAD_REQUEST,
+        };
+
+        let mut error_object = Map::new();
+        error_object.insert("type".to_string(), Value::String("error".to_string()));
+        error_object.insert("error".to_string(), Value::Object(json));
+
+        ModelResponse {
+            usage: TokenUsage::default(),
+            status,
+            warnings: Vec::new(),
+            timings: ModelTimings::default(),
+            retry_after: None,
+            response: ModelResponseData::Json(error_object),
+        }
+    }
+}
+
+// Sums the token counts of usage objects, including nested objects such as completion_tokens_details.
+fn merge_usage_objects(total: &mut Map<String, Value>, usage: Map<String, Value>) {
+    for (key, value) in usage {
+        match value {
+            Value::Object(value) => {
+                if let Value::Object(total) = total
+                    .entry(key)
+                    .or_insert_with(|| Value::Object(Map::new()))
+                {
+                    merge_usage_objects(total, value);
+                }
+            }
+            value => {
+                if let Some(value) = value.as_u64() {
+                    let sum = total.get(&key).and_then(|total| total.as_u64());
+
+                    total.insert(key, Value::from(sum.unwrap_or_default() + value));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+#[allow(dead_code)]
+pub(super) struct TokenUsage {
+    pub(super) total: u64,
+    pub(super) input: Option<u64>,
+    pub(super) output: Option<u64>,
+}
+
+impl TokenUsage {
+    pub(super) fn weighted(&self, output_weight: f64) -> u64 {
+        match (self.input, self.output) {
+            (Some(input), Some(output)) => {
+                input.saturating_add((output as f64 * output_weight.max(0.0)).ceil() as u64)
+            }
+            _ => self.total,
+        }
+    }
+
+    // Breakdowns are only kept if every merged value has one, as a partial breakdown would undercount.
+    pub(super) fn merge(usages: impl IntoIterator<Item = TokenUsage>) -> Self {
+        let mut merged: Option<TokenUsage> = None;
+
+        for usage in usages {
+            merged = Some(match merged {
+                Some(merged) => TokenUsage {
+                    total: merged.total.saturating_add(usage.total),
+                    input: merged
+                        .input
+                        .zip(usage.input)
+                        .map(|(a, b)| a.saturating_add(b)),
+                    output: merged
+                        .output
+                        .zip(usage.output)
+                        .map(|(a, b)| a.saturating_add(b)),
+                },
+                None => usage,
+            });
+        }
+
+        merged.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum ModelError {
+    BadRequest,
+    AuthMissing,
+    AuthInvalid,
+    UserRateLimit,
+    ModelRateLimit,
+    UnknownEndpoint,
+    BadEndpointMethod,
+    UnknownModel,
+    UnknownModelSuggestions(Vec<String>),
+    InternalError,
+    BackendError,
+    DeadlineExceeded,
+    UpstreamTimeout,
+    UnsupportedResponseFormat,
+    BatchTooLarge,
+    ServerOverloaded,
+    RequestTooLarge {
+        max: u64,
+        overflow: u64,
+    },
+    InvalidJsonSchema(String),
+    ParameterOutOfRange {
+        param: &'static str,
+        min: f64,
+        max: f64,
+    },
+    InvalidParameterType {
+        param: &'static str,
+        expected: &'static str,
+    },
+    UnsupportedValue {
+        param: &'static str,
+        supported: Vec<String>,
+    },
+    CostLimitExceeded {
+        estimated: f64,
+        max: f64,
+    },
+    PermissionDenied,
+    ShuttingDown,
+    MissingParameter(String),
+}
+
+// Added to requests as an extension when malformed JSON request bodies should be repaired.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct JsonRepair(pub(super) bool);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(super) enum BodyNormalization {
+    #[default]
+    Disabled,
+    Lenient,
+    Strict,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(super) struct PenaltyRange {
+    min: f64,
+    max: f64,
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(super) struct ImageSizes {
+    sizes: Vec<String>,
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) enum ModerationAction {
+    #[default]
+    Block,
+    Annotate,
+}
+
+// Checks each response's output using a TextModeration model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(super) struct OutputModeration {
+    pub(super) model: Uuid,
+    #[serde(default)]
+    pub(super) action: ModerationAction,
+    #[serde(default)]
+    refusal_message: Option<String>,
+}
+
+// Charges Quotas based on the size of the request, for request types (such as audio and images) where the backend doesn't report token usage.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(super) struct SizeCharging {
+    bytes_per_token: u64,
+    #[serde(default)]
+    tokens_per_second: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct CoalescingKeySettings {
+    pub(super) semantic: bool,
+    pub(super) fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct JsonSchemaLimits {
+    pub(super) max_depth: usize,
+    pub(super) max_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) enum JsonSchemaSupport {
+    #[default]
+    Supported,
+    Downgrade,
+    Strip,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) enum TokenInputSupport {
+    #[default]
+    Supported,
+    Decode,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(private_interfaces, clippy::large_enum_variant)]
+pub(super) enum ModelBackend {
+    OpenAI(OpenAIModelBackend),
+    Anthropic(AnthropicModelBackend),
+    Gemini(GeminiModelBackend),
+    Loopback,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct OpenAIModelBackend {
+    model_string: String,
+    model_context_len: Option<u64>,
+    openai_api_base: String,
+    #[serde(default)]
+    openai_api_key: String,
+    #[serde(default)]
+    openai_api_keys: Vec<WeightedApiKey>,
+    openai_organization: Option<String>,
+    #[serde(default)]
+    openai_project: Option<String>,
+    #[serde(default, with = "json_map")]
+    extra_body: Map<String, Value>,
+    #[serde(default)]
+    max_embedding_inputs: Option<usize>,
+    #[serde(default)]
+    request_id_header: Option<String>,
+    #[serde(default)]
+    synthesize_usage: bool,
+    #[serde(default)]
+    warm_connections: usize,
+    #[serde(default)]
+    normalize_citations: bool,
+    #[serde(default)]
+    normalize_embeddings: bool,
+    #[serde(default)]
+    max_requests_per_second: Option<f64>,
+    #[serde(default)]
+    model_prefix: Option<ModelPrefix>,
+    #[serde(default)]
+    emulate_echo: bool,
+    #[serde(default)]
+    emulate_suffix: bool,
+    #[serde(default = "default_supports_store")]
+    supports_store: bool,
+    #[serde(default = "default_supports_stream_options")]
+    supports_stream_options: bool,
+    #[serde(default)]
+    allow_unterminated_streams: bool,
+    #[serde(default)]
+    keep_alive_interval_ms: Option<u64>,
+    #[serde(default)]
+    stream_batch_size: Option<usize>,
+    #[serde(default)]
+    stream_batch_window_ms: Option<u64>,
+    #[serde(default)]
+    max_retries: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    retry_base_delay_ms: u64,
+    #[serde(default)]
+    retry_server_errors: Vec<RequestType>,
+    #[serde(default)]
+    required_parameters: Vec<String>,
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_supports_store() -> bool {
+    true
+}
+
+fn default_supports_stream_options() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct WeightedApiKey {
+    key: String,
+    #[serde(default = "default_api_key_weight")]
+    weight: f64,
+}
+
+fn default_api_key_weight() -> f64 {
+    1.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum ModelPrefix {
+    Add(String),
+    Strip(String),
+}
+
+// Spaces out requests sent to each model's backend, so that bursts of incoming requests don't trip the backend's own rate limits.
+#[derive(Debug, Default)]
+pub(super) struct RequestPacer {
+    next_requests: Mutex<HashMap<Uuid, Instant>>,
+}
+
+impl RequestPacer {
+    // Reserves the next free slot for the model, unless it would be after the deadline.
+    fn reserve(
+        &self,
+        model: Uuid,
+        interval: Duration,
+        deadline: Option<Instant>,
+    ) -> Result<PacerReservation<'_>, ModelError> {
+        let now = Instant::now();
+
+        let slot = match self.next_requests.lock() {
+            Ok(mut next_requests) => {
+                let next_request = next_requests.entry(model).or_insert(now);
+                let slot = (*next_request).max(now);
+                if deadline.is_some_and(|deadline| slot > deadline) {
+                    return Err(ModelError::DeadlineExceeded);
+                }
+
+                *next_request = slot + interval;
+                slot
+            }
+            Err(_) => now,
+        };
+
+        Ok(PacerReservation {
+            pacer: self,
+            model,
+            slot,
+            interval,
+            used: false,
+        })
+    }
+
+    #[tracing::instrument(skip(self), level = "debug")]
+    async fn wait(
+        &self,
+        model: Uuid,
+        interval: Option<Duration>,
+        deadline: Option<Instant>,
+    ) -> Result<Duration, ModelError> {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return Ok(Duration::ZERO),
+        };
+
+        let mut reservation = self.reserve(model, interval, deadline)?;
+
+        let waited = reservation.slot.saturating_duration_since(Instant::now());
+        time::sleep_until(time::Instant::from_std(reservation.slot)).await;
+        reservation.used = true;
+
+        Ok(waited)
+    }
+}
+
+// Returns the slot to the pacer if the request is cancelled while waiting for it. Slots can only be returned if no later requests have been queued behind them.
+struct PacerReservation<'a> {
+    pacer: &'a RequestPacer,
+    model: Uuid,
+    slot: Instant,
+    interval: Duration,
+    used: bool,
+}
+
+impl Drop for PacerReservation<'_> {
+    fn drop(&mut self) {
+        if self.used {
+            return;
+        }
+
+        if let Ok(mut next_requests) = self.pacer.next_requests.lock() {
+            if let Some(next_request) = next_requests.get_mut(&self.model) {
+                if *next_request == self.slot + self.interval {
+                    *next_request = self.slot;
+                }
+            }
+        }
+    }
+}
+
+// Limits the number of retries each user's requests can cause, so that a single user can't multiply their load on a backend during an outage. Each user's budget is refilled evenly over the window.
+#[derive(Debug, Default)]
+pub(super) struct RetryBudget {
+    limit: Option<(u32, Duration)>,
+    budgets: Mutex<HashMap<Uuid, (f64, Instant)>>,
+}
+
+impl RetryBudget {
+    pub(super) fn new(retries: u32, window: Duration) -> Self {
+        RetryBudget {
+            limit: Some((retries, window)),
+            budgets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_available(retries: u32, window: Duration, budget: (f64, Instant), now: Instant) -> f64 {
+        let (available, updated_at) = budget;
+        let refilled = match window.as_secs_f64() {
+            window if window > 0.0 => {
+                now.saturating_duration_since(updated_at).as_secs_f64() / window * retries as f64
+            }
+            _ => retries as f64,
+        };
+
+        (available + refilled).min(retries as f64)
+    }
+
+    // Nothing is spent if the user's budget has been used up.
+    fn try_spend(&self, user: Uuid) -> bool {
+        let (retries, window) = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let now = Instant::now();
+
+        match self.budgets.lock() {
+            Ok(mut budgets) => {
+                let budget = budgets.entry(user).or_insert((retries as f64, now));
+                let available = Self::get_available(retries, window, *budget, now);
+
+                match available >= 1.0 {
+                    true => {
+                        *budget = (available - 1.0, now);
+                        true
+                    }
+                    false => {
+                        *budget = (available, now);
+                        false
+                    }
+                }
+            }
+            Err(_) => true,
+        }
+    }
+
+    // Returns the remaining retries of each user who has used part of their budget. Users with a full budget are forgotten.
+    pub(super) fn get_remaining(&self) -> HashMap<Uuid, u32> {
+        let (retries, window) = match self.limit {
+            Some(limit) => limit,
+            None => return HashMap::new(),
+        };
+        let now = Instant::now();
+
+        match self.budgets.lock() {
+            Ok(mut budgets) => {
+                budgets.retain(|_, budget| {
+                    Self::get_available(retries, window, *budget, now) < retries as f64
+                });
+
+                budgets
+                    .iter()
+                    .map(|(user, budget)| {
+                        (
+                            *user,
+                            Self::get_available(retries, window, *budget, now) as u32,
+                        )
+                    })
+                    .collect()
+            }
+            Err(_) => HashMap::new(),
+        }
+    }
+}
+
+// Keeps track of API keys which were recently rejected by their backend, so that requests can be sent using the model's other keys. Key health is only kept in memory, and is forgotten when the proxy restarts.
+#[derive(Debug, Default)]
+pub(super) struct ApiKeyCooldowns {
+    cooldowns: Mutex<HashMap<(Uuid, u64), Instant>>,
+}
+
+impl ApiKeyCooldowns {
+    // Cooldowns are keyed by a hash of the key rather than its position, so that they stay with the right key when a model's key list is edited.
+    fn get_key_hash(api_key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        api_key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn is_cooling_down(&self, model: Uuid, api_key: &str) -> bool {
+        self.cooldowns.lock().is_ok_and(|cooldowns| {
+            cooldowns
+                .get(&(model, Self::get_key_hash(api_key)))
+                .is_some_and(|until| *until > Instant::now())
+        })
+    }
+
+    fn start_cooldown(&self, model: Uuid, api_key: &str) {
+        if let Ok(mut cooldowns) = self.cooldowns.lock() {
+            let now = Instant::now();
+
+            cooldowns.retain(|_, until| *until > now);
+            cooldowns.insert((model, Self::get_key_hash(api_key)), now + API_KEY_COOLDOWN);
+        }
+    }
+}
+
+// Returns a random number between 0 and 1.
+fn get_random_fraction() -> f64 {
+    (Uuid::new_v4().as_u128() >> 64) as f64 / u64::MAX as f64
+}
+
+// Returns the index of the chosen weight, using a random number between 0 and 1. Weights which aren't positive are never chosen.
+fn choose_weighted(weights: &[f64], random: f64) -> Option<usize> {
+    let total: f64 = weights.iter().filter(|weight| **weight > 0.0).sum();
+    let mut target = random * total;
+
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0.0)
+        .find(|(_, weight)| {
+            target -= **weight;
+            target < 0.0
+        })
+        .or_else(|| {
+            weights
+                .iter()
+                .enumerate()
+                .rfind(|(_, weight)| **weight > 0.0)
+        })
+        .map(|(index, _)| index)
+}
+
+// Returns the time by which the backend must respond, along with the error returned if it doesn't, so that clients can tell whether their own deadline or the proxy's upstream timeout was exceeded.
+fn get_upstream_expiry(
+    deadline: Option<Instant>,
+    timeout: Option<Instant>,
+) -> Option<(Instant, ModelError)> {
+    match (deadline, timeout) {
+        (Some(deadline), Some(timeout)) if timeout < deadline => {
+            Some((timeout, ModelError::UpstreamTimeout))
+        }
+        (Some(deadline), _) => Some((deadline, ModelError::DeadlineExceeded)),
+        (None, Some(timeout)) => Some((timeout, ModelError::UpstreamTimeout)),
+        (None, None) => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RetrySettings<'a> {
+    max_retries: u32,
+    base_delay: Duration,
+    server_errors: bool,
+    budget: Option<(&'a RetryBudget, Uuid)>,
+}
+
+impl RetrySettings<'_> {
+    // The delay doubles with each retry, and is randomly shortened by up to half so that requests which failed together aren't retried together.
+    fn get_backoff(&self, retry: u32) -> Duration {
+        let jitter = get_random_fraction();
+
+        self.base_delay
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(MAX_RETRY_DELAY)
+            .mul_f64(1.0 - jitter / 2.0)
+    }
+}
+
+// Transient errors are retried until the request's retries are used up, unless waiting for the next retry would pass the deadline.
+#[allow(clippy::too_many_arguments)]
+async fn send_request_before_deadline(
+    http_client: &Client,
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+    request: ModelRequest,
+    binary: bool,
+    stream: Option<StreamSettings>,
+    deadline: Option<Instant>,
+    timeout: Option<Duration>,
+    retries: RetrySettings<'_>,
+) -> ModelResponse {
+    let mut request = Some(request);
+
+    for retry in 0..=retries.max_retries {
+        // The request is only cloned if it may need to be sent again.
+        let attempt = match retry < retries.max_retries {
+            true => request.clone(),
+            false => request.take(),
         };
-        let error_param = match value {
-            ModelError::UnknownModel => Value::String("model".to_string()),
-            _ => Value::Null,
+        let attempt = match attempt {
+            Some(attempt) => attempt,
+            None => break,
         };
 
-        json.insert("message".to_string(), Value::String(message.to_string()));
-        json.insert("type".to_string(), Value::String(error_type.to_string()));
-        json.insert("param".to_string(), error_param);
-        json.insert("code".to_string(), error_code);
+        // Unlike the client's deadline, the upstream timeout is restarted for each attempt, so that it doesn't include time spent waiting on rate limits or retries.
+        let timeout = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
 
-        let status = match value {
-            ModelError::BadRequest => StatusCode::BAD_REQUEST,
-            ModelError::AuthMissing => StatusCode::UNAUTHORIZED,
-            ModelError::AuthInvalid => StatusCode::UNAUTHORIZED,
-            ModelError::UserRateLimit => StatusCode::TOO_MANY_REQUESTS,
-            ModelError::ModelRateLimit => StatusCode::SERVICE_UNAVAILABLE,
-            ModelError::UnknownEndpoint => StatusCode::NOT_FOUND,
-            ModelError::BadEndpointMethod => StatusCode::METHOD_NOT_ALLOWED,
-            ModelError::UnknownModel => StatusCode::NOT_FOUND,
-            ModelError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            ModelError::BackendError => StatusCode::BAD_GATEWAY,
+        let response = client::send_http_request(
+            http_client,
+            method.clone(),
+            url.clone(),
+            headers.clone(),
+            attempt,
+            binary,
+            stream
+                .clone()
+                .map(|stream| StreamSettings { timeout, ..stream }),
+        );
+        let (response, retryable) = match get_upstream_expiry(deadline, timeout) {
+            Some((expiry, error)) => {
+                match time::timeout_at(time::Instant::from_std(expiry), response).await {
+                    Ok(response) => response,
+                    Err(_) => return ModelResponse::from(error),
+                }
+            }
+            None => response.await,
         };
 
-        let mut error_object = Map::new();
-        error_object.insert("type".to_string(), Value::String("error".to_string()));
-        error_object.insert("error".to_string(), Value::Object(json));
+        let delay = match retryable {
+            Some(retryable)
+                if retry < retries.max_retries
+                    && (!retryable.server_error || retries.server_errors) =>
+            {
+                retryable
+                    .retry_after
+                    .unwrap_or_else(|| retries.get_backoff(retry))
+            }
+            _ => return response,
+        };
+        // Backends which ask for a long delay are treated as unavailable, so that fallbacks can be used instead.
+        if delay > MAX_RETRY_DELAY
+            || deadline.is_some_and(|deadline| {
+                Instant::now()
+                    .checked_add(delay)
+                    .is_none_or(|retry_at| retry_at > deadline)
+            })
+        {
+            return response;
+        }
+        if let Some((budget, user)) = retries.budget {
+            if !budget.try_spend(user) {
+                tracing::warn!("Not retrying transient backend error, as the user's retry budget has been used up");
+                tracing::debug!(monotonic_counter.upstream.suppressed_retries = 1_u64);
 
-        ModelResponse {
-            usage: TokenUsage::default(),
-            status,
-            response: ModelResponseData::Json(error_object),
+                return response;
+            }
         }
+
+        tracing::warn!(
+            "Retrying request in {:?} after transient backend error ({} of {} retries)",
+            delay,
+            retry + 1,
+            retries.max_retries
+        );
+        tracing::debug!(monotonic_counter.upstream.retries = 1_u64);
+        time::sleep(delay).await;
     }
+
+    ModelResponse::from(ModelError::InternalError)
 }
 
-#[derive(Debug, Default)]
-#[allow(dead_code)]
-pub(super) struct TokenUsage {
-    pub(super) total: u64,
-    pub(super) input: Option<u64>,
-    pub(super) output: Option<u64>,
+#[tracing::instrument(level = "trace")]
+fn get_backend_url(api_base: &str, path: &str) -> Option<Url> {
+    match Url::parse(api_base).and_then(|base_url| base_url.join(path)) {
+        Ok(url) => Some(url),
+        Err(error) => {
+            tracing::warn!("Unable to parse model URL: {:?}", error);
+            None
+        }
+    }
 }
 
-#[derive(Debug)]
-pub(super) enum ModelError {
-    BadRequest,
-    AuthMissing,
-    AuthInvalid,
-    UserRateLimit,
-    ModelRateLimit,
-    UnknownEndpoint,
-    BadEndpointMethod,
-    UnknownModel,
-    InternalError,
-    BackendError,
+// Used by backends which authenticate using their own headers instead of a bearer token.
+fn get_backend_headers(headers: &[(&str, &str)]) -> Option<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+
+    for (name, value) in headers {
+        match (HeaderName::from_bytes(name.as_bytes()), value.parse()) {
+            (Ok(name), Ok(value)) => {
+                header_map.insert(name, value);
+            }
+            _ => {
+                tracing::warn!("Unable to parse {} header", name);
+                return None;
+            }
+        }
+    }
+
+    Some(header_map)
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[allow(private_interfaces)]
-pub(super) enum ModelBackend {
-    OpenAI(OpenAIModelBackend),
-    Loopback,
+// Where a request to a backend which doesn't use OpenAI's API format is sent, along with what's needed to check and log it.
+struct ConvertedRequestTarget<'a> {
+    url: Url,
+    headers: HeaderMap,
+    api_key: &'a str,
+    required_parameters: &'a [&'a str],
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct OpenAIModelBackend {
-    model_string: String,
-    model_context_len: Option<u64>,
-    openai_api_base: String,
-    openai_api_key: String,
-    openai_organization: Option<String>,
+// Sends a request to a backend which doesn't use OpenAI's API format. Only the conversion of the request body and the addition of OpenAI-style choices to the response differ between these backends, so the rest of the request is handled here.
+#[allow(clippy::too_many_arguments)]
+async fn send_converted_request(
+    http_client: &Client,
+    tag: Uuid,
+    model: Uuid,
+    mut request: ModelRequest,
+    deadline: Option<Instant>,
+    log_upstream_requests: bool,
+    target: ConvertedRequestTarget<'_>,
+    convert_request: impl FnOnce(
+        ModelRequestData,
+        &mut Vec<String>,
+    ) -> Result<ModelRequestData, ModelError>,
+    convert_response: impl FnOnce(&mut ModelResponseData),
+) -> ModelResponse {
+    let timeout = request.timeout;
+    let request_type = request.r#type;
+    let label = request.get_model().map(|value| value.to_string());
+    let proxy_metadata = request.proxy_metadata.take();
+    let fingerprint = request
+        .fingerprint
+        .take()
+        .unwrap_or_else(|| SystemFingerprint::from_model(model));
+
+    request.request = match convert_request(request.request, &mut request.warnings) {
+        Ok(request) => request,
+        Err(error) => return ModelResponse::from(error),
+    };
+    if let Err(error) = request
+        .request
+        .check_required_parameters(target.required_parameters.iter().copied())
+    {
+        return ModelResponse::from(error);
+    }
+    if log_upstream_requests {
+        tracing::trace!(
+            tag = ?tag,
+            upstream_request = redact_secret(&request.request.to_log_string(), target.api_key)
+        );
+    }
+    let warnings = std::mem::take(&mut request.warnings);
+
+    let started = Instant::now();
+    let mut response = send_request_before_deadline(
+        http_client,
+        Method::POST,
+        target.url,
+        target.headers,
+        request,
+        false,
+        None,
+        deadline,
+        timeout,
+        RetrySettings::default(),
+    )
+    .await;
+    response.timings.upstream = Some(started.elapsed());
+
+    if response.status.is_success() {
+        convert_response(&mut response.response);
+    }
+    (response.response, response.usage) = response.response.into_hybrid_api(
+        label,
+        request_type,
+        tag,
+        &fingerprint,
+        !response.status.is_success(),
+        proxy_metadata.as_ref(),
+    );
+    response.warnings = warnings;
+
+    response
 }
 
 impl OpenAIModelBackend {
+    // Returns None if requests to the backend aren't paced, or if the rate is invalid.
+    fn get_pacing_interval(&self) -> Option<Duration> {
+        self.max_requests_per_second
+            .filter(|rate| *rate > 0.0)
+            .and_then(|rate| Duration::try_from_secs_f64(1.0 / rate).ok())
+    }
+
+    // Picks one of the backend's API keys using weighted random selection, avoiding keys which are cooling down unless all of them are.
+    fn choose_api_key(&self, model: Uuid, cooldowns: &ApiKeyCooldowns) -> (usize, &str) {
+        let random = get_random_fraction();
+        let weights: Vec<f64> = self.openai_api_keys.iter().map(|key| key.weight).collect();
+        let healthy_weights: Vec<f64> = weights
+            .iter()
+            .zip(&self.openai_api_keys)
+            .map(
+                |(weight, api_key)| match cooldowns.is_cooling_down(model, &api_key.key) {
+                    true => 0.0,
+                    false => *weight,
+                },
+            )
+            .collect();
+
+        match choose_weighted(&healthy_weights, random)
+            .or_else(|| choose_weighted(&weights, random))
+        {
+            Some(index) => (index, &self.openai_api_keys[index].key),
+            None => (0, self.get_primary_api_key()),
+        }
+    }
+
+    // Backends with a list of keys don't use the openai_api_key field.
+    fn get_primary_api_key(&self) -> &str {
+        match self.openai_api_keys.first() {
+            Some(key) => &key.key,
+            None => &self.openai_api_key,
+        }
+    }
+
+    fn get_retry_settings<'a>(
+        &self,
+        r#type: RequestType,
+        budget: Option<(&'a RetryBudget, Uuid)>,
+    ) -> RetrySettings<'a> {
+        RetrySettings {
+            max_retries: self.max_retries,
+            base_delay: Duration::from_millis(self.retry_base_delay_ms),
+            server_errors: self.retry_server_errors.contains(&r#type),
+            budget,
+        }
+    }
+
+    // Aggregator backends (such as OpenRouter or LiteLLM) expect model names to be prefixed with the model's provider, while other backends reject prefixed names.
+    #[tracing::instrument(level = "trace", ret)]
+    fn get_upstream_model_string(&self) -> String {
+        match &self.model_prefix {
+            Some(ModelPrefix::Add(prefix)) if !self.model_string.starts_with(prefix.as_str()) => {
+                format!("{}{}", prefix, self.model_string)
+            }
+            Some(ModelPrefix::Strip(prefix)) => self
+                .model_string
+                .strip_prefix(prefix.as_str())
+                .unwrap_or(&self.model_string)
+                .to_string(),
+            _ => self.model_string.clone(),
+        }
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn insert_request_id(&self, headers: &mut HeaderMap, request_id: &str) {
+        if let Some(name) = &self.request_id_header {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(request_id),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => tracing::warn!("Unable to add {} header: {:?}", name, request_id),
+            }
+        }
+    }
+
     #[tracing::instrument(level = "trace")]
     fn get_request_parameters(
         &self,
         r#type: RequestType,
+        api_key: &str,
     ) -> Option<(Method, Url, HeaderMap, bool)> {
         match Url::parse(&self.openai_api_base).and_then(|base_url| {
             base_url.join(match r#type {
@@ -753,17 +3868,26 @@ impl OpenAIModelBackend {
                 RequestType::AudioTranslation => "/v1/audio/translations",
             })
         }) {
-            Ok(url) => match HeaderValue::from_str(&format!("Bearer {}", self.openai_api_key)) {
+            Ok(url) => match HeaderValue::from_str(&format!("Bearer {}", api_key)) {
                 Ok(auth_header) => {
                     let mut headers = HeaderMap::new();
                     headers.insert(AUTHORIZATION, auth_header);
 
-                    if let Some(organization) = self
-                        .openai_organization
-                        .as_ref()
-                        .and_then(|value| value.parse::<HeaderValue>().ok())
-                    {
-                        headers.insert("OpenAI-Organization", organization);
+                    for (name, value) in [
+                        ("OpenAI-Organization", &self.openai_organization),
+                        ("OpenAI-Project", &self.openai_project),
+                    ] {
+                        if let Some(value) = value {
+                            match value.parse::<HeaderValue>() {
+                                Ok(header) if !value.trim().is_empty() => {
+                                    headers.insert(name, header);
+                                }
+                                _ => {
+                                    tracing::warn!("Unable to parse {} header: {:?}", name, value);
+                                    return None;
+                                }
+                            }
+                        }
                     }
 
                     Some((Method::POST, url, headers, r#type == RequestType::AudioTTS))
@@ -781,57 +3905,513 @@ impl OpenAIModelBackend {
     }
 }
 
+fn redact_secret(text: &str, secret: &str) -> String {
+    match secret.is_empty() {
+        true => text.to_string(),
+        false => text.replace(secret, "[REDACTED]"),
+    }
+}
+
 impl ModelBackend {
+    // Pacing rates must be positive, so that they can be converted into the interval between requests.
+    pub(super) fn has_invalid_pacing(&self) -> bool {
+        match self {
+            Self::OpenAI(config) => {
+                config.max_requests_per_second.is_some() && config.get_pacing_interval().is_none()
+            }
+            _ => false,
+        }
+    }
+
     pub(super) fn get_max_tokens(&self) -> u64 {
         match &self {
             Self::OpenAI(backend) => backend.model_context_len.unwrap_or(1),
+            Self::Anthropic(backend) => backend.model_context_len.unwrap_or(1),
+            Self::Gemini(backend) => backend.model_context_len.unwrap_or(1),
             Self::Loopback => 1,
         }
     }
 
-    #[tracing::instrument(skip(self, http_client), level = "debug", ret)]
+    // The max_tokens sent to the backend when the client doesn't specify it.
+    pub(super) fn get_default_max_tokens(&self) -> Option<u64> {
+        match &self {
+            Self::Anthropic(backend) => Some(backend.get_default_max_tokens()),
+            _ => None,
+        }
+    }
+
+    // Opens connections to the backend's host ahead of time, so that they can be reused by later requests.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub(super) async fn warm_connections(&self, http_client: &Client) {
+        let config = match &self {
+            Self::OpenAI(config) if config.warm_connections > 0 => config,
+            _ => return,
+        };
+
+        let url = match Url::parse(&config.openai_api_base) {
+            Ok(url) => url,
+            Err(error) => {
+                tracing::warn!("Unable to parse model URL: {:?}", error);
+                return;
+            }
+        };
+
+        let mut tasks = JoinSet::new();
+        for _ in 0..config.warm_connections {
+            tasks.spawn(http_client.head(url.clone()).send());
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            if let Ok(Err(error)) = result {
+                tracing::debug!("Unable to warm connection: {:?}", error);
+            }
+        }
+    }
+
+    // Backend configurations are compared without their API keys, as rotating a key doesn't change the model's outputs.
+    pub(super) fn get_config_value(&self) -> Value {
+        let mut backend = self.clone();
+        let _ = backend.set_api_key(String::new());
+
+        serde_json::to_value(backend).unwrap_or_default()
+    }
+
+    // Replaces one of the backend's keys, keeping its weight. Backends with multiple keys need to be told which key is being replaced.
+    pub(super) fn rotate_api_key(
+        &mut self,
+        api_key: String,
+        replaces: Option<&str>,
+    ) -> Result<(), ModelError> {
+        match self {
+            Self::OpenAI(backend) if !backend.openai_api_keys.is_empty() => {
+                let index = match replaces {
+                    Some(replaces) => backend
+                        .openai_api_keys
+                        .iter()
+                        .position(|key| key.key == replaces),
+                    None if backend.openai_api_keys.len() == 1 => Some(0),
+                    None => None,
+                };
+
+                match index {
+                    Some(index) => {
+                        backend.openai_api_keys[index].key = api_key;
+                        Ok(())
+                    }
+                    None => Err(ModelError::BadRequest),
+                }
+            }
+            _ => self.set_api_key(api_key),
+        }
+    }
+
+    pub(super) fn set_api_key(&mut self, api_key: String) -> Result<(), ModelError> {
+        match self {
+            // Setting the key replaces all of the backend's keys.
+            Self::OpenAI(backend) => {
+                backend.openai_api_key = api_key;
+                backend.openai_api_keys.clear();
+                Ok(())
+            }
+            Self::Anthropic(backend) => {
+                backend.anthropic_api_key = api_key;
+                Ok(())
+            }
+            Self::Gemini(backend) => {
+                backend.gemini_api_key = api_key;
+                Ok(())
+            }
+            Self::Loopback => Err(ModelError::BadRequest),
+        }
+    }
+
+    // Sends an inexpensive request to the backend, returning the backend's error (with the API key redacted) if it fails.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub(super) async fn probe(&self, http_client: &Client) -> Result<(), Value> {
+        match &self {
+            Self::OpenAI(config) => {
+                let api_key = config.get_primary_api_key();
+                let parameters = config
+                    .get_request_parameters(RequestType::TextChat, api_key)
+                    .zip(
+                        Url::parse(&config.openai_api_base)
+                            .and_then(|base_url| base_url.join("/v1/models"))
+                            .ok(),
+                    );
+
+                match parameters {
+                    Some(((_, _, headers, _), url)) => {
+                        client::send_probe_request(http_client, url, headers)
+                            .await
+                            .map_err(|(status, body)| {
+                                let body = redact_secret(&body, api_key);
+
+                                json!({
+                                    "status": status.as_u16(),
+                                    "error": serde_json::from_str::<Value>(&body)
+                                        .unwrap_or(Value::String(body)),
+                                })
+                            })
+                    }
+                    None => Err(json!({
+                        "error": "Unable to parse backend configuration",
+                    })),
+                }
+            }
+            Self::Anthropic(config) => match config.get_probe_parameters() {
+                Some((url, headers)) => client::send_probe_request(http_client, url, headers)
+                    .await
+                    .map_err(|(status, body)| {
+                        let body = redact_secret(&body, &config.anthropic_api_key);
+
+                        json!({
+                            "status": status.as_u16(),
+                            "error": serde_json::from_str::<Value>(&body)
+                                .unwrap_or(Value::String(body)),
+                        })
+                    }),
+                None => Err(json!({
+                    "error": "Unable to parse backend configuration",
+                })),
+            },
+            Self::Gemini(config) => match config.get_probe_parameters() {
+                Some((url, headers)) => client::send_probe_request(http_client, url, headers)
+                    .await
+                    .map_err(|(status, body)| {
+                        let body = redact_secret(&body, &config.gemini_api_key);
+
+                        json!({
+                            "status": status.as_u16(),
+                            "error": serde_json::from_str::<Value>(&body)
+                                .unwrap_or(Value::String(body)),
+                        })
+                    }),
+                None => Err(json!({
+                    "error": "Unable to parse backend configuration",
+                })),
+            },
+            Self::Loopback => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(
+        skip(self, http_client, pacer, retry_budget, key_cooldowns),
+        level = "debug",
+        ret
+    )]
+    #[allow(clippy::too_many_arguments)]
     pub(super) async fn generate(
         &self,
         http_client: &Client,
+        pacer: &RequestPacer,
+        retry_budget: &RetryBudget,
+        key_cooldowns: &ApiKeyCooldowns,
         model: Uuid,
         mut request: ModelRequest,
+        deadline: Option<Instant>,
+        log_upstream_requests: bool,
     ) -> ModelResponse {
         let tag = Uuid::new_v4();
         tracing::debug!(tag = ?tag);
 
-        match &self {
-            Self::OpenAI(config) => match config.get_request_parameters(request.r#type) {
-                Some((method, url, headers, binary)) => {
+        // Only OpenAI backends support multiple API keys, so the key is chosen before the request is sent.
+        let api_key = match &self {
+            Self::OpenAI(config) => {
+                let (key_index, api_key) = config.choose_api_key(model, key_cooldowns);
+                tracing::debug!(api_key_index = key_index);
+
+                Some((key_index, api_key))
+            }
+            _ => None,
+        };
+
+        let response = match &self {
+            Self::OpenAI(config) => match api_key.and_then(|(_, api_key)| {
+                config
+                    .get_request_parameters(request.r#type, api_key)
+                    .map(|parameters| (api_key, parameters))
+            }) {
+                Some((api_key, (method, url, mut headers, binary))) => {
+                    let request_id = request
+                        .request_id
+                        .clone()
+                        .unwrap_or_else(|| tag.to_string());
+                    config.insert_request_id(&mut headers, &request_id);
+
+                    let timeout = request.timeout;
+                    let tokenizer = request.tokenizer.map(TokenizerSettings::new);
                     let request_type = request.r#type;
                     let label = request.get_model().map(|value| value.to_string());
+                    let proxy_metadata = request.proxy_metadata.take();
+                    let fingerprint = request
+                        .fingerprint
+                        .take()
+                        .unwrap_or_else(|| SystemFingerprint::from_model(model));
+                    let retry_budget = request.user.map(|user| (retry_budget, user));
+                    let stream = match request_type {
+                        RequestType::TextChat => {
+                            request.stream.map(|include_usage| StreamSettings {
+                                label: label.clone(),
+                                tag,
+                                include_usage,
+                                deadline,
+                                timeout: None,
+                                tokenizer: request.tokenizer,
+                                input: request.tokenizer.map(|_| Arc::new(request.request.clone())),
+                                allow_unterminated: config.allow_unterminated_streams,
+                                keep_alive: config
+                                    .keep_alive_interval_ms
+                                    .map(Duration::from_millis),
+                                batch_size: config.stream_batch_size,
+                                batch_window: config
+                                    .stream_batch_window_ms
+                                    .map(Duration::from_millis),
+                            })
+                        }
+                        _ => None,
+                    };
 
-                    request.request = request
-                        .request
-                        .into_openai(config.model_string.clone(), request.user);
-
-                    let mut response = client::send_http_request(
-                        http_client,
-                        method,
-                        url,
-                        headers,
-                        request,
-                        binary,
-                    )
-                    .await;
+                    let echo = match request_type {
+                        RequestType::TextCompletion if config.emulate_echo => {
+                            request.request.take_echo_prompts(&mut request.warnings)
+                        }
+                        _ => None,
+                    };
+                    let suffix = match request_type {
+                        RequestType::TextCompletion if config.emulate_suffix => {
+                            request.request.take_suffix()
+                        }
+                        _ => None,
+                    };
+                    if !config.supports_store {
+                        request
+                            .request
+                            .strip_store_parameters(&mut request.warnings);
+                    }
 
-                    (response.response, response.usage) = response.response.into_hybrid_api(
-                        label,
-                        request_type,
-                        tag,
-                        model,
-                        !response.status.is_success(),
+                    request.request = request.request.into_openai(
+                        config.get_upstream_model_string(),
+                        request.user,
+                        &mut request.warnings,
                     );
+                    request.request.merge_extra_body(&config.extra_body);
+                    if stream.is_some() {
+                        request
+                            .request
+                            .insert_stream(config.supports_stream_options);
+                    }
+                    if let Err(error) = request.request.check_required_parameters(
+                        config.required_parameters.iter().map(String::as_str),
+                    ) {
+                        return ModelResponse::from(error);
+                    }
+                    if log_upstream_requests {
+                        tracing::trace!(
+                            tag = ?tag,
+                            upstream_request = redact_secret(
+                                &request.request.to_log_string(),
+                                api_key
+                            )
+                        );
+                    }
+                    let warnings = std::mem::take(&mut request.warnings);
+
+                    let chunks = match request_type {
+                        RequestType::TextEmbedding => {
+                            config.max_embedding_inputs.and_then(|max_inputs| {
+                                request.request.split_embedding_input(max_inputs)
+                            })
+                        }
+                        _ => None,
+                    };
+
+                    let convert_response =
+                        |mut response: ModelResponse, input_tokens: Option<u64>| {
+                            if config.normalize_embeddings
+                                && request_type == RequestType::TextEmbedding
+                                && response.status.is_success()
+                            {
+                                response.response.normalize_embeddings();
+                            }
+                            if let Some((input_tokens, tokenizer)) =
+                                input_tokens.zip(tokenizer.as_ref())
+                            {
+                                if response.status.is_success() {
+                                    response.response.synthesize_usage(
+                                        request_type,
+                                        input_tokens,
+                                        tokenizer,
+                                    );
+                                }
+                            }
+
+                            if config.normalize_citations && response.status.is_success() {
+                                response.response.normalize_citations(request_type);
+                            }
+                            if let Some((prompts, n)) = &echo {
+                                if response.status.is_success() {
+                                    response.response.insert_echo(prompts, *n);
+                                }
+                            }
+                            if let Some(suffix) = &suffix {
+                                if response.status.is_success() {
+                                    response.response.insert_suffix(suffix);
+                                }
+                            }
+
+                            (response.response, response.usage) =
+                                response.response.into_hybrid_api(
+                                    label.clone(),
+                                    request_type,
+                                    tag,
+                                    &fingerprint,
+                                    !response.status.is_success(),
+                                    proxy_metadata.as_ref(),
+                                );
+
+                            response
+                        };
+
+                    let interval = config.get_pacing_interval();
+                    let mut paced = Duration::ZERO;
+
+                    let started = Instant::now();
+                    let mut response = match chunks {
+                        Some(chunks) => {
+                            tracing::debug!(histogram.request.chunks = chunks.len());
+
+                            let mut responses = Vec::with_capacity(chunks.len());
+                            for chunk in chunks {
+                                let input_tokens = match config.synthesize_usage {
+                                    true => tokenizer.as_ref().and_then(|tokenizer| {
+                                        chunk.get_input_token_count(request_type, tokenizer)
+                                    }),
+                                    false => None,
+                                };
+                                let chunk = ModelRequest {
+                                    user: request.user,
+                                    r#type: request_type,
+                                    warnings: Vec::new(),
+                                    request_id: request.request_id.clone(),
+                                    param_profile: None,
+                                    proxy_metadata: None,
+                                    fingerprint: None,
+                                    timeout: None,
+                                    tokenizer: request.tokenizer,
+                                    stream: None,
+                                    request: chunk,
+                                };
+                                let response = match pacer.wait(model, interval, deadline).await {
+                                    Ok(waited) => {
+                                        paced += waited;
+
+                                        send_request_before_deadline(
+                                            http_client,
+                                            method.clone(),
+                                            url.clone(),
+                                            headers.clone(),
+                                            chunk,
+                                            binary,
+                                            None,
+                                            deadline,
+                                            timeout,
+                                            config.get_retry_settings(request_type, retry_budget),
+                                        )
+                                        .await
+                                    }
+                                    Err(error) => ModelResponse::from(error),
+                                };
+                                let is_error = !response.status.is_success();
+
+                                responses.push(convert_response(response, input_tokens));
+                                if is_error {
+                                    break;
+                                }
+                            }
+
+                            ModelResponse::merge_embedding_chunks(responses)
+                        }
+                        None => {
+                            let input_tokens = match config.synthesize_usage {
+                                true => tokenizer.as_ref().and_then(|tokenizer| {
+                                    request
+                                        .request
+                                        .get_input_token_count(request_type, tokenizer)
+                                }),
+                                false => None,
+                            };
+
+                            let response = match pacer.wait(model, interval, deadline).await {
+                                Ok(waited) => {
+                                    paced += waited;
+
+                                    send_request_before_deadline(
+                                        http_client,
+                                        method,
+                                        url,
+                                        headers,
+                                        request,
+                                        binary,
+                                        stream,
+                                        deadline,
+                                        timeout,
+                                        config.get_retry_settings(request_type, retry_budget),
+                                    )
+                                    .await
+                                }
+                                Err(error) => ModelResponse::from(error),
+                            };
+
+                            convert_response(response, input_tokens)
+                        }
+                    };
+                    response.timings.upstream = Some(started.elapsed().saturating_sub(paced));
+                    if !paced.is_zero() {
+                        response.timings.queue = Some(paced);
+                    }
+                    response.warnings = warnings;
 
                     response
                 }
                 None => ModelResponse::from(ModelError::InternalError),
             },
+            Self::Anthropic(config) => {
+                config
+                    .generate(
+                        http_client,
+                        tag,
+                        model,
+                        request,
+                        deadline,
+                        log_upstream_requests,
+                    )
+                    .await
+            }
+            Self::Gemini(config) => {
+                config
+                    .generate(
+                        http_client,
+                        tag,
+                        model,
+                        request,
+                        deadline,
+                        log_upstream_requests,
+                    )
+                    .await
+            }
             Self::Loopback => request.request.into_loopback(),
+        };
+
+        if let Some((key_index, api_key)) = api_key.filter(|_| response.is_api_key_error()) {
+            tracing::warn!(
+                "API key {} of model {} was rejected by the backend, avoiding it for {:?}",
+                key_index,
+                model,
+                API_KEY_COOLDOWN
+            );
+            key_cooldowns.start_cooldown(model, api_key);
         }
+
+        response
     }
 }
@@ -0,0 +1,536 @@
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::body::{Body, Bytes};
+use http_body::Frame;
+use serde_json::{json, value::Value, Map};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::{
+    get_upstream_expiry, interface::get_usage_trailers, tokenizer::TokenizerSettings, ModelError,
+    ModelRequestData, RequestType, TokenUsage, Tokenizer,
+};
+
+// The number of events buffered before reading from the backend is paused to wait for the client.
+const STREAM_BUFFER_SIZE: usize = 32;
+
+// Sent when the backend hasn't sent a chunk within the keep-alive interval. Comments are ignored by SSE clients, and carry no content to be charged for.
+const KEEP_ALIVE_COMMENT: &str = ": keep-alive\n\n";
+
+const DEADLINE_TRUNCATED_STREAM_MESSAGE: &str = "The model's response was cut off before it finished, as it passed the deadline set in your request's X-Request-Deadline header. You can retry your request with a longer deadline.";
+
+const BACKEND_TRUNCATED_STREAM_MESSAGE: &str = "The model's response was cut off before it finished, as the model's backend stopped responding or the connection to it was lost. You can retry your request, or contact the proxy's administrator if the error persists.";
+
+const BACKEND_ERROR_STREAM_MESSAGE: &str = "The model had an error while streaming its response. Sorry about that! Contact the proxy's administrator for more information.";
+
+#[derive(Debug, Clone)]
+pub(super) struct StreamSettings {
+    pub(super) label: Option<String>,
+    pub(super) tag: Uuid,
+    pub(super) include_usage: bool,
+    pub(super) deadline: Option<Instant>,
+    // The upstream timeout of the attempt which started the stream.
+    pub(super) timeout: Option<Instant>,
+    // Used to estimate the usage of truncated streams. The request is only tokenized if the stream is cut off, so that counting its tokens doesn't delay the first chunk.
+    pub(super) tokenizer: Option<Tokenizer>,
+    pub(super) input: Option<Arc<ModelRequestData>>,
+    // Whether streams which end without a [DONE] event are complete, for backends which don't send one.
+    pub(super) allow_unterminated: bool,
+    pub(super) keep_alive: Option<Duration>,
+    // Consecutive content deltas are merged until either limit is reached, so that fewer events are sent to clients on high-latency links.
+    pub(super) batch_size: Option<usize>,
+    pub(super) batch_window: Option<Duration>,
+}
+
+// Content deltas which haven't been sent yet, which are sent as a single chunk when the batch is flushed.
+#[derive(Debug)]
+struct DeltaBatch {
+    chunk: Map<String, Value>,
+    index: Value,
+    content: String,
+    deltas: usize,
+    started: Instant,
+}
+
+// Converts the backend's event stream as it arrives, keeping track of the usage reported in the final chunk.
+#[derive(Debug)]
+struct StreamConverter {
+    settings: StreamSettings,
+    buffer: Vec<u8>,
+    usage: Option<TokenUsage>,
+    done: bool,
+    failed: bool,
+    delivered: String,
+    batch: Option<DeltaBatch>,
+}
+
+impl StreamConverter {
+    fn new(settings: StreamSettings) -> Self {
+        StreamConverter {
+            settings,
+            buffer: Vec::new(),
+            usage: None,
+            done: false,
+            failed: false,
+            delivered: String::new(),
+            batch: None,
+        }
+    }
+
+    // Returns the converted events which were completed by the chunk.
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer
+            .extend(chunk.iter().filter(|byte| **byte != b'\r'));
+
+        let mut events = Vec::new();
+        while let Some(position) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..position + 2).collect();
+
+            events.extend(self.convert_event(&String::from_utf8_lossy(&event[..position])));
+
+            // Nothing after an error event is sent to the client.
+            if self.failed {
+                break;
+            }
+        }
+
+        events
+    }
+
+    // Returns the final event (if the stream didn't end with a blank line), and any batched deltas.
+    fn finish(&mut self) -> Vec<String> {
+        let event = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).to_string();
+
+        let mut events = match event.trim().is_empty() {
+            true => Vec::new(),
+            false => self.convert_event(event.trim_end()),
+        };
+        events.extend(self.flush());
+        events
+    }
+
+    // Batches are flushed once their time window passes, even if the backend hasn't sent another chunk.
+    fn get_flush_time(&self) -> Option<Instant> {
+        self.batch
+            .as_ref()
+            .zip(self.settings.batch_window)
+            .map(|(batch, window)| batch.started + window)
+    }
+
+    fn flush(&mut self) -> Vec<String> {
+        match self.batch.take() {
+            Some(mut batch) => {
+                if let Some(delta) = batch
+                    .chunk
+                    .get_mut("choices")
+                    .and_then(|choices| choices.get_mut(0))
+                    .and_then(|choice| choice.get_mut("delta"))
+                    .and_then(|delta| delta.as_object_mut())
+                {
+                    delta.insert("content".to_string(), Value::String(batch.content));
+                }
+
+                vec![format!("data: {}\n\n", Value::Object(batch.chunk))]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Sends any batched deltas before the event, so that the order of events is preserved.
+    fn emit(&mut self, event: String) -> Vec<String> {
+        let mut events = self.flush();
+        events.push(event);
+        events
+    }
+
+    fn batch(&mut self, chunk: Map<String, Value>) -> Vec<String> {
+        if self.settings.batch_size.is_none() && self.settings.batch_window.is_none() {
+            return vec![format!("data: {}\n\n", Value::Object(chunk))];
+        }
+
+        let (index, content) = match get_delta_content(&chunk) {
+            Some(delta) => delta,
+            None => return self.emit(format!("data: {}\n\n", Value::Object(chunk))),
+        };
+
+        let merged = match &mut self.batch {
+            Some(batch) if batch.index == index => {
+                batch.content.push_str(&content);
+                batch.deltas += 1;
+                true
+            }
+            _ => false,
+        };
+
+        let mut events = Vec::new();
+        if !merged {
+            events = self.flush();
+            self.batch = Some(DeltaBatch {
+                chunk,
+                index,
+                content,
+                deltas: 1,
+                started: Instant::now(),
+            });
+        }
+
+        if let (Some(batch), Some(size)) = (&self.batch, self.settings.batch_size) {
+            if batch.deltas >= size {
+                events.extend(self.flush());
+            }
+        }
+
+        events
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done || self.settings.allow_unterminated
+    }
+
+    // Streams which end early rarely report usage, so it's estimated from the text which was delivered.
+    fn estimate_usage(&mut self) {
+        if self.usage.is_some() {
+            return;
+        }
+
+        let tokenizer = self.settings.tokenizer.map(TokenizerSettings::new);
+        let output = tokenizer
+            .as_ref()
+            .map(|tokenizer| tokenizer.tokenize_text(&self.delivered).len() as u64);
+        let input = tokenizer
+            .as_ref()
+            .zip(self.settings.input.as_ref())
+            .and_then(|(tokenizer, input)| {
+                input.get_input_token_count(RequestType::TextChat, tokenizer)
+            });
+
+        self.usage = Some(TokenUsage {
+            total: input.unwrap_or_default() + output.unwrap_or_default(),
+            input,
+            output,
+        });
+    }
+
+    // Returns an error event telling the client that the stream was cut off, after any batched deltas.
+    fn truncate(&mut self, message: &str) -> Vec<String> {
+        self.estimate_usage();
+
+        self.emit(get_error_event(json!({
+            "message": message,
+            "type": "server_error",
+            "param": null,
+            "code": "stream_truncated",
+        })))
+    }
+
+    // Error events from the backend (such as content filter errors) end the stream, and are sent to the client as an error chunk.
+    fn fail(&mut self, error: Option<&Value>) -> String {
+        self.failed = true;
+        self.estimate_usage();
+
+        let field = |key: &str| {
+            error
+                .and_then(|error| error.get(key))
+                .filter(|value| !value.is_null())
+                .cloned()
+        };
+
+        get_error_event(json!({
+            "message": field("message").unwrap_or(Value::from(BACKEND_ERROR_STREAM_MESSAGE)),
+            "type": field("type").unwrap_or(Value::from("server_error")),
+            "param": field("param").unwrap_or(Value::Null),
+            "code": field("code").unwrap_or(Value::from("upstream_error")),
+        }))
+    }
+
+    fn convert_event(&mut self, event: &str) -> Vec<String> {
+        let data: Vec<&str> = event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.strip_prefix(' ').unwrap_or(data))
+            .collect();
+
+        // Comments (such as keep-alive messages) are passed through unmodified.
+        if data.is_empty() {
+            return self.emit(format!("{}\n\n", event));
+        }
+
+        let is_error = event
+            .lines()
+            .filter_map(|line| line.strip_prefix("event:"))
+            .any(|name| name.trim() == "error");
+
+        let data = data.join("\n");
+        if data.trim() == "[DONE]" {
+            self.done = true;
+        }
+        let mut chunk = match serde_json::from_str::<Map<String, Value>>(&data) {
+            Ok(chunk) => chunk,
+            Err(_) if is_error => {
+                let event = self.fail(None);
+                return self.emit(event);
+            }
+            Err(_) => return self.emit(format!("data: {}\n\n", data)),
+        };
+
+        if is_error || chunk.get("error").is_some_and(|error| error.is_object()) {
+            let event = self.fail(chunk.get("error"));
+            return self.emit(event);
+        }
+
+        // Usage is always requested from the backend for Quotas, but is only sent to clients which asked for it.
+        if let Some(Value::Object(usage)) = chunk.get("usage") {
+            self.usage = Some(get_stream_usage(usage));
+        }
+        if let Some(Value::Array(choices)) = chunk.get("choices") {
+            for delta in choices.iter().filter_map(|choice| choice.get("delta")) {
+                if let Some(content) = delta.get("content").and_then(|content| content.as_str()) {
+                    self.delivered.push_str(content);
+                }
+            }
+        }
+        if !self.settings.include_usage
+            && chunk.remove("usage").is_some_and(|usage| usage.is_object())
+            && chunk
+                .get("choices")
+                .and_then(|choices| choices.as_array())
+                .is_none_or(|choices| choices.is_empty())
+        {
+            return Vec::new();
+        }
+
+        if let Some(value) = chunk.get_mut("model") {
+            *value = self
+                .settings
+                .label
+                .clone()
+                .map(Value::String)
+                .unwrap_or(Value::Null);
+        }
+
+        if let Some(value) = chunk.get_mut("id") {
+            *value = Value::String(format!("{}", self.settings.tag));
+        }
+
+        self.batch(chunk)
+    }
+}
+
+// Returns the choice index and content of chunks which only contain a content delta, as other chunks can't be merged.
+fn get_delta_content(chunk: &Map<String, Value>) -> Option<(Value, String)> {
+    if chunk.get("usage").is_some_and(|usage| !usage.is_null()) {
+        return None;
+    }
+
+    let choice = match chunk.get("choices")?.as_array()?.as_slice() {
+        [choice] => choice.as_object()?,
+        _ => return None,
+    };
+    if choice
+        .iter()
+        .any(|(key, value)| key != "index" && key != "delta" && !value.is_null())
+    {
+        return None;
+    }
+
+    let delta = choice.get("delta")?.as_object()?;
+    if delta
+        .iter()
+        .any(|(key, value)| key != "content" && !value.is_null())
+    {
+        return None;
+    }
+
+    Some((
+        choice.get("index").cloned().unwrap_or(Value::Null),
+        delta.get("content")?.as_str()?.to_string(),
+    ))
+}
+
+fn get_error_event(error: Value) -> String {
+    format!("data: {}\n\n", json!({ "error": error }))
+}
+
+fn get_stream_usage(usage: &Map<String, Value>) -> TokenUsage {
+    let input = usage
+        .get("prompt_tokens")
+        .or(usage.get("input_tokens"))
+        .and_then(|num| num.as_u64());
+    let output = usage
+        .get("completion_tokens")
+        .or(usage.get("output_tokens"))
+        .and_then(|num| num.as_u64());
+
+    TokenUsage {
+        total: usage
+            .get("total_tokens")
+            .and_then(|num| num.as_u64())
+            .unwrap_or(input.unwrap_or_default() + output.unwrap_or_default()),
+        input,
+        output,
+    }
+}
+
+// A backend's event stream, which is sent to the client as it arrives. Streams can only be sent to a single client, so clones share the same stream.
+#[derive(Debug, Clone)]
+pub(super) struct ModelStream {
+    frames: Arc<Mutex<Option<mpsc::Receiver<Frame<Bytes>>>>>,
+    usage: Arc<Mutex<Option<oneshot::Receiver<Option<TokenUsage>>>>>,
+}
+
+impl ModelStream {
+    pub(super) fn new(mut response: reqwest::Response, settings: StreamSettings) -> Self {
+        let (frame_sender, frame_receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let (usage_sender, usage_receiver) = oneshot::channel();
+
+        tokio::spawn(
+            async move {
+                let expiry = get_upstream_expiry(settings.deadline, settings.timeout);
+                let expires_at = expiry
+                    .as_ref()
+                    .map(|(expiry, _)| time::Instant::from_std(*expiry));
+                let tag = settings.tag;
+                let keep_alive = settings.keep_alive;
+                let mut converter = StreamConverter::new(settings);
+                let mut truncated = None;
+                let mut last_chunk = time::Instant::now();
+
+                loop {
+                    // Nothing is sent after [DONE], even if the backend is slow to close the stream.
+                    let keep_alive_at = keep_alive
+                        .filter(|_| !converter.done)
+                        .map(|interval| last_chunk + interval);
+                    let flush_at = converter.get_flush_time().map(time::Instant::from_std);
+                    let wake_at = [expires_at, keep_alive_at, flush_at]
+                        .into_iter()
+                        .flatten()
+                        .min();
+
+                    let chunk = match wake_at {
+                        Some(wake_at) => time::timeout_at(wake_at, response.chunk()).await.ok(),
+                        None => Some(response.chunk().await),
+                    };
+
+                    let (events, finished) = match chunk {
+                        Some(Ok(Some(chunk))) => {
+                            last_chunk = time::Instant::now();
+                            (converter.push(&chunk), false)
+                        }
+                        Some(Ok(None)) => (converter.finish(), true),
+                        Some(Err(error)) => {
+                            tracing::error!("Error receiving streamed response: {:?}", error);
+                            truncated = Some(BACKEND_TRUNCATED_STREAM_MESSAGE);
+                            break;
+                        }
+                        None if wake_at == expires_at => {
+                            let error = expiry.as_ref().map(|(_, error)| error);
+                            tracing::warn!("{:?} while streaming response", error);
+                            truncated = Some(match error {
+                                Some(ModelError::DeadlineExceeded) => {
+                                    DEADLINE_TRUNCATED_STREAM_MESSAGE
+                                }
+                                _ => BACKEND_TRUNCATED_STREAM_MESSAGE,
+                            });
+                            break;
+                        }
+                        None if wake_at == flush_at => (converter.flush(), false),
+                        None => {
+                            last_chunk = time::Instant::now();
+                            (vec![KEEP_ALIVE_COMMENT.to_string()], false)
+                        }
+                    };
+
+                    let mut disconnected = false;
+                    for event in events {
+                        if frame_sender
+                            .send(Frame::data(Bytes::from(event)))
+                            .await
+                            .is_err()
+                        {
+                            disconnected = true;
+                            break;
+                        }
+                    }
+
+                    if disconnected {
+                        tracing::debug!("Client disconnected while streaming response");
+                        break;
+                    }
+                    if converter.failed {
+                        tracing::warn!(tag = ?tag, "Upstream stream returned an error");
+                        tracing::debug!(monotonic_counter.stream.errors = 1_u64);
+                        break;
+                    }
+                    if finished {
+                        if !converter.is_complete() {
+                            truncated = Some(BACKEND_TRUNCATED_STREAM_MESSAGE);
+                        }
+                        break;
+                    }
+                }
+
+                if let Some(message) = truncated {
+                    tracing::warn!(tag = ?tag, "Upstream stream was truncated");
+                    tracing::debug!(monotonic_counter.stream.truncated = 1_u64);
+
+                    for event in converter.truncate(message) {
+                        let _ = frame_sender.send(Frame::data(Bytes::from(event))).await;
+                    }
+                }
+
+                if let Some(usage) = &converter.usage {
+                    let _ = frame_sender
+                        .send(Frame::trailers(get_usage_trailers(usage)))
+                        .await;
+                }
+                let _ = usage_sender.send(converter.usage);
+            }
+            .in_current_span(),
+        );
+
+        ModelStream {
+            frames: Arc::new(Mutex::new(Some(frame_receiver))),
+            usage: Arc::new(Mutex::new(Some(usage_receiver))),
+        }
+    }
+
+    // Resolves once the stream ends, with the usage reported by the backend (if any).
+    pub(super) fn take_usage(&self) -> Option<oneshot::Receiver<Option<TokenUsage>>> {
+        self.usage.lock().ok().and_then(|mut usage| usage.take())
+    }
+
+    pub(super) fn into_body(self) -> Body {
+        Body::new(ModelStreamBody {
+            frames: self.frames.lock().ok().and_then(|mut frames| frames.take()),
+        })
+    }
+}
+
+struct ModelStreamBody {
+    frames: Option<mpsc::Receiver<Frame<Bytes>>>,
+}
+
+impl http_body::Body for ModelStreamBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        match &mut self.frames {
+            Some(frames) => frames.poll_recv(cx).map(|frame| frame.map(Ok)),
+            None => Poll::Ready(None),
+        }
+    }
+}
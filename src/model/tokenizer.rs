@@ -1,11 +1,28 @@
-pub(super) enum Tokenizer {
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Tokenizer {
+    #[default]
+    #[value(name = "cl100k_base")]
     Cl100kBase,
+    #[value(name = "p50k_base")]
     P50kBase,
+    #[value(name = "p50k_edit")]
     P50kEdit,
+    #[value(name = "r50k_base")]
     R50kBase,
+    #[value(name = "gpt2")]
     Gpt2,
 }
 
+impl Tokenizer {
+    // Returns None if the tokenizer isn't supported.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        <Self as ValueEnum>::from_str(name.trim(), true).ok()
+    }
+}
+
+#[derive(Debug)]
 pub(super) struct TokenizerSettings {
     tokenizer: Tokenizer,
     starting_tokens: Option<i64>,
@@ -19,7 +36,22 @@ pub(super) struct TokenizerMessage<'a> {
     pub(super) name: Option<&'a str>,
 }
 
+impl Default for TokenizerSettings {
+    fn default() -> Self {
+        TokenizerSettings::new(Tokenizer::default())
+    }
+}
+
 impl TokenizerSettings {
+    pub(super) fn new(tokenizer: Tokenizer) -> Self {
+        TokenizerSettings {
+            tokenizer,
+            starting_tokens: None,
+            tokens_per_message: None,
+            tokens_per_name: None,
+        }
+    }
+
     pub(super) fn tokenize_text(&self, text: &str) -> Vec<usize> {
         let bpe_arc = match self.tokenizer {
             Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
@@ -32,6 +64,36 @@ impl TokenizerSettings {
         bpe.encode_with_special_tokens(text)
     }
 
+    // Returns None if any of the tokens are outside of the tokenizer's vocabulary.
+    pub(super) fn detokenize(&self, tokens: Vec<usize>) -> Option<String> {
+        let bpe_arc = match self.tokenizer {
+            Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Tokenizer::P50kBase => tiktoken_rs::p50k_base_singleton(),
+            Tokenizer::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+            Tokenizer::R50kBase | Tokenizer::Gpt2 => tiktoken_rs::r50k_base_singleton(),
+        };
+
+        // tiktoken panics when decoding unknown tokens, instead of returning an error.
+        if !tokens.iter().all(|token| self.is_known_token(*token)) {
+            return None;
+        }
+
+        let bpe = bpe_arc.lock();
+        bpe.decode(tokens).ok()
+    }
+
+    // Each vocabulary's regular tokens are numbered contiguously from zero, followed by its special tokens.
+    fn is_known_token(&self, token: usize) -> bool {
+        match self.tokenizer {
+            Tokenizer::Cl100kBase => {
+                matches!(token, 0..=100255 | 100257..=100260 | 100276)
+            }
+            Tokenizer::P50kBase => token <= 50280,
+            Tokenizer::P50kEdit => token <= 50283,
+            Tokenizer::R50kBase | Tokenizer::Gpt2 => token <= 50256,
+        }
+    }
+
     pub(super) fn get_message_token_count(&self, messages: &[TokenizerMessage]) -> usize {
         let bpe_arc = match self.tokenizer {
             Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeSet,
+    convert::Infallible,
+    future::{self, Future},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    response::{IntoResponse, Response},
+    Router,
+};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time,
+};
+use tower::Service;
+
+use crate::model::ModelError;
+
+#[cfg(test)]
+mod tests;
+
+// How long aborted requests are given to send their responses before the server stops waiting for their connections to close.
+const SHUTDOWN_ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub(super) struct ServerSettings {
+    pub(super) http2_max_concurrent_streams: u32,
+    pub(super) http2_initial_stream_window_size: u32,
+    pub(super) http2_initial_connection_window_size: u32,
+    pub(super) http2_adaptive_window: bool,
+    pub(super) shutdown_grace: Option<Duration>,
+    pub(super) shutdown_max_requests: Option<usize>,
+}
+
+impl ServerSettings {
+    fn builder(&self) -> Builder<TokioExecutor> {
+        let mut builder = Builder::new(TokioExecutor::new());
+
+        builder
+            .http2()
+            .max_concurrent_streams(self.http2_max_concurrent_streams)
+            .initial_stream_window_size(self.http2_initial_stream_window_size)
+            .initial_connection_window_size(self.http2_initial_connection_window_size)
+            .adaptive_window(self.http2_adaptive_window);
+
+        builder
+    }
+}
+
+// Requests are numbered in the order they arrive, so that the oldest requests can be allowed to finish during shutdown.
+#[derive(Default)]
+struct RequestTracker {
+    next: AtomicU64,
+    active: Mutex<BTreeSet<u64>>,
+}
+
+impl RequestTracker {
+    fn start(self: &Arc<Self>) -> TrackedRequest {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+
+        if let Ok(mut active) = self.active.lock() {
+            active.insert(id);
+        }
+
+        TrackedRequest {
+            tracker: self.clone(),
+            id,
+        }
+    }
+
+    fn get_active_count(&self) -> usize {
+        self.active.lock().map(|active| active.len()).unwrap_or(0)
+    }
+
+    // Requests numbered at or above the returned threshold are aborted. Once shutdown begins, only the oldest in-flight requests (up to the limit) are allowed to finish, and any requests which arrive afterwards are rejected.
+    fn get_abort_threshold(&self, max_requests: Option<usize>) -> u64 {
+        match max_requests {
+            Some(max_requests) => self
+                .active
+                .lock()
+                .ok()
+                .and_then(|active| active.iter().nth(max_requests).copied())
+                .unwrap_or_else(|| self.next.load(Ordering::Relaxed)),
+            None => u64::MAX,
+        }
+    }
+}
+
+struct TrackedRequest {
+    tracker: Arc<RequestTracker>,
+    id: u64,
+}
+
+impl Drop for TrackedRequest {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.tracker.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+// Runs the request until it completes, or until it's aborted by the server shutting down.
+async fn call_until_aborted(
+    mut router: Router,
+    request: Request<Incoming>,
+    request_tracker: Arc<RequestTracker>,
+    mut abort_rx: watch::Receiver<u64>,
+) -> Response {
+    let tracked = request_tracker.start();
+
+    let aborted = async {
+        if abort_rx
+            .wait_for(|threshold| *threshold <= tracked.id)
+            .await
+            .is_err()
+        {
+            future::pending::<()>().await
+        }
+    };
+
+    tokio::select! {
+        response = router.call(request) => match response {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        },
+        _ = aborted => {
+            tracing::debug!("Aborting request {} due to server shutdown", tracked.id);
+            ModelError::ShuttingDown.into_response()
+        }
+    }
+}
+
+async fn accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
+    match listener.accept().await {
+        Ok(connection) => Some(connection),
+        Err(error) => {
+            if !matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionReset
+            ) {
+                tracing::error!("Unable to accept connection: {}", error);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+
+            None
+        }
+    }
+}
+
+#[tracing::instrument(name = "serve", level = "debug", skip_all)]
+pub(super) async fn serve<F>(
+    listener: TcpListener,
+    router: Router,
+    settings: ServerSettings,
+    signal: F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tracing::debug!(settings = ?settings);
+
+    let builder = Arc::new(settings.builder());
+
+    let (signal_tx, signal_rx) = watch::channel(());
+    let signal_tx = Arc::new(signal_tx);
+    tokio::spawn(async move {
+        signal.await;
+        drop(signal_rx);
+    });
+
+    let (close_tx, close_rx) = watch::channel(());
+    let (abort_tx, abort_rx) = watch::channel(u64::MAX);
+    let request_tracker = Arc::new(RequestTracker::default());
+
+    loop {
+        let (stream, address) = tokio::select! {
+            connection = accept(&listener) => match connection {
+                Some(connection) => connection,
+                None => continue,
+            },
+            _ = signal_tx.closed() => break,
+        };
+
+        let router = router.clone();
+        let request_tracker = request_tracker.clone();
+        let abort_rx = abort_rx.clone();
+        let service = service_fn(move |request: Request<Incoming>| {
+            let response = call_until_aborted(
+                router.clone(),
+                request,
+                request_tracker.clone(),
+                abort_rx.clone(),
+            );
+            async move { Ok::<_, Infallible>(response.await) }
+        });
+
+        let builder = builder.clone();
+        let signal_tx = signal_tx.clone();
+        let close_rx = close_rx.clone();
+
+        tokio::spawn(async move {
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            let signal_closed = signal_tx.closed();
+            tokio::pin!(signal_closed);
+
+            let mut shutting_down = false;
+
+            loop {
+                tokio::select! {
+                    result = connection.as_mut() => {
+                        if let Err(error) = result {
+                            tracing::trace!("Connection to {} closed with error: {}", address, error);
+                        }
+                        break;
+                    }
+                    _ = &mut signal_closed, if !shutting_down => {
+                        connection.as_mut().graceful_shutdown();
+                        shutting_down = true;
+                    }
+                }
+            }
+
+            drop(close_rx);
+        });
+    }
+
+    drop(close_rx);
+    drop(listener);
+
+    abort_tx.send_replace(request_tracker.get_abort_threshold(settings.shutdown_max_requests));
+    tracing::info!(
+        "Waiting for {} in-flight requests to finish",
+        request_tracker.get_active_count()
+    );
+
+    // Requests which are still running after the shutdown grace period are aborted, so that shutdown takes a predictable amount of time.
+    let grace = match settings.shutdown_grace {
+        Some(grace) => grace,
+        None => {
+            close_tx.closed().await;
+            return Ok(());
+        }
+    };
+
+    if time::timeout(grace, close_tx.closed()).await.is_err() {
+        tracing::warn!(
+            "Aborting {} requests which were still running after the shutdown grace period",
+            request_tracker.get_active_count()
+        );
+        abort_tx.send_replace(0);
+
+        if time::timeout(SHUTDOWN_ABORT_TIMEOUT, close_tx.closed())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Closing server without waiting for remaining connections");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use axum::{routing::get, Router};
+use reqwest::{Client, StatusCode, Version};
+use tokio::{net::TcpListener, sync::oneshot, time};
+
+use super::{serve, ServerSettings};
+
+#[tokio::test]
+async fn serve_http1_and_http2_clients() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+
+    let settings = ServerSettings {
+        http2_max_concurrent_streams: 16,
+        http2_initial_stream_window_size: 65_535,
+        http2_initial_connection_window_size: 131_070,
+        http2_adaptive_window: false,
+        shutdown_grace: None,
+        shutdown_max_requests: None,
+    };
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let server = tokio::spawn(serve(
+        listener,
+        Router::new().route("/", get(|| async { "ok" })),
+        settings,
+        async move {
+            let _ = shutdown_rx.await;
+        },
+    ));
+
+    let http1 = Client::builder().http1_only().build().unwrap();
+    let response = http1.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), Version::HTTP_11);
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    let http2 = Client::builder().http2_prior_knowledge().build().unwrap();
+    let response = http2.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    drop(http1);
+    drop(http2);
+
+    shutdown_tx.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_with_in_flight_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let settings = ServerSettings {
+        http2_max_concurrent_streams: 16,
+        http2_initial_stream_window_size: 65_535,
+        http2_initial_connection_window_size: 131_070,
+        http2_adaptive_window: false,
+        shutdown_grace: Some(Duration::from_millis(500)),
+        shutdown_max_requests: Some(2),
+    };
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let router = Router::new()
+        .route(
+            "/fast",
+            get(|| async {
+                time::sleep(Duration::from_millis(200)).await;
+                "ok"
+            }),
+        )
+        .route(
+            "/slow",
+            get(|| async {
+                time::sleep(Duration::from_secs(60)).await;
+                "ok"
+            }),
+        );
+    let server = tokio::spawn(serve(listener, router, settings, async move {
+        let _ = shutdown_rx.await;
+    }));
+
+    let client = Client::new();
+    let mut requests = Vec::new();
+    for path in ["/fast", "/slow", "/slow"] {
+        let request = client.get(format!("{}{}", url, path)).send();
+        requests.push(tokio::spawn(async move {
+            let response = request.await.unwrap();
+            (response.status(), Instant::now())
+        }));
+        time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let shutdown_at = Instant::now();
+    shutdown_tx.send(()).unwrap();
+
+    // The oldest requests are allowed to finish within the grace period, and the rest are aborted right away.
+    let (status, _) = requests.remove(0).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, finished_at) = requests.remove(1).await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(finished_at.duration_since(shutdown_at) < Duration::from_millis(400));
+
+    // Requests which are still running after the grace period are aborted.
+    let (status, finished_at) = requests.remove(0).await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(finished_at.duration_since(shutdown_at) >= Duration::from_millis(500));
+
+    drop(client);
+    server.await.unwrap().unwrap();
+}
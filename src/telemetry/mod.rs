@@ -0,0 +1,529 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use http::{uri::Scheme, Uri};
+use opentelemetry::{
+    trace::{SpanId, Status, TraceId, TraceResult},
+    Value,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    trace::{Span, SpanProcessor},
+};
+use tokio::{net::TcpStream, time};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer, Layer};
+
+#[cfg(test)]
+mod tests;
+
+const ERROR_LOG_INTERVAL: u64 = 60;
+
+// Only these event fields are used as Prometheus labels, as other fields can have unbounded values.
+const METRIC_LABELS: [&str; 2] = ["model", "quota"];
+
+// Further label values are combined into a single "other" label value.
+const MAX_LABEL_VALUES: usize = 256;
+
+const DURATION_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+const SIZE_BUCKETS: [f64; 11] = [
+    1.0, 4.0, 16.0, 64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+];
+
+static LAST_ERROR_LOGGED: AtomicU64 = AtomicU64::new(0);
+static SUPPRESSED_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub(super) async fn check_collector(endpoint: &str) -> Result<()> {
+    let uri: Uri = endpoint.parse().context("Invalid collector URL")?;
+    let host = uri
+        .host()
+        .context("Collector URL does not contain a host")?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTPS => 443,
+        _ => 80,
+    });
+
+    time::timeout(Duration::from_secs(5), TcpStream::connect((host, port)))
+        .await
+        .context("Timed out connecting to collector")?
+        .context("Unable to connect to collector")?;
+
+    Ok(())
+}
+
+pub(super) fn install_error_handler() {
+    let result = opentelemetry::global::set_error_handler(|error| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let last = LAST_ERROR_LOGGED.load(Ordering::Relaxed);
+
+        if now.saturating_sub(last) >= ERROR_LOG_INTERVAL
+            && LAST_ERROR_LOGGED
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            tracing::warn!(
+                suppressed = SUPPRESSED_ERRORS.swap(0, Ordering::Relaxed),
+                "Unable to export OpenTelemetry signals: {}",
+                error
+            );
+        } else {
+            SUPPRESSED_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    if let Err(error) = result {
+        tracing::warn!("Unable to install OpenTelemetry error handler: {}", error);
+    }
+}
+
+// Buffers each trace's spans until its root span ends, so that traces containing errors (including rate-limited requests) are always exported, while other traces are sampled by their ID. Traces are sampled early using the spans which have ended so far if the buffer is full, or if their root span hasn't ended within MAX_TRACE_AGE (such as for abandoned streams).
+#[derive(Debug)]
+pub(super) struct SamplingSpanProcessor<P: SpanProcessor> {
+    processor: P,
+    sample_rate: f64,
+    max_spans: usize,
+    buffer: Mutex<TraceBuffer>,
+}
+
+impl<P: SpanProcessor> SamplingSpanProcessor<P> {
+    pub(super) fn new(processor: P, sample_rate: f64, max_spans: usize) -> Self {
+        SamplingSpanProcessor {
+            processor,
+            sample_rate,
+            max_spans,
+            buffer: Mutex::new(TraceBuffer::default()),
+        }
+    }
+
+    fn is_sampled(&self, trace_id: TraceId, spans: &[SpanData]) -> bool {
+        if spans.iter().any(is_error_span) {
+            return true;
+        }
+
+        let bytes = trace_id.to_bytes();
+        let mut random = [0; 8];
+        random.copy_from_slice(&bytes[8..]);
+
+        ((u64::from_be_bytes(random) >> 1) as f64) < self.sample_rate * (1u64 << 63) as f64
+    }
+
+    fn export(&self, traces: impl IntoIterator<Item = (TraceId, Vec<SpanData>)>) {
+        for (trace_id, spans) in traces {
+            if self.is_sampled(trace_id, &spans) {
+                for span in spans {
+                    self.processor.on_end(span);
+                }
+            }
+        }
+    }
+}
+
+const MAX_TRACE_AGE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default)]
+struct TraceBuffer {
+    traces: HashMap<TraceId, Vec<SpanData>>,
+    // Traces in the order they were first buffered. Traces which were removed when their root span ended are skipped once they reach the front.
+    order: VecDeque<(Instant, TraceId)>,
+    spans: usize,
+}
+
+impl TraceBuffer {
+    fn insert(&mut self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+
+        self.traces
+            .entry(trace_id)
+            .or_insert_with(|| {
+                self.order.push_back((Instant::now(), trace_id));
+                Vec::new()
+            })
+            .push(span);
+        self.spans += 1;
+    }
+
+    fn remove(&mut self, trace_id: TraceId) -> Vec<SpanData> {
+        let spans = self.traces.remove(&trace_id).unwrap_or_default();
+        self.spans -= spans.len();
+
+        spans
+    }
+
+    // Removes the oldest traces until the buffer is within its limits.
+    fn evict(&mut self, max_spans: usize) -> Vec<(TraceId, Vec<SpanData>)> {
+        let now = Instant::now();
+        let mut evicted = Vec::new();
+
+        while let Some((buffered_at, trace_id)) = self.order.front().copied() {
+            if self.traces.contains_key(&trace_id)
+                && self.spans <= max_spans
+                && now.saturating_duration_since(buffered_at) < MAX_TRACE_AGE
+            {
+                break;
+            }
+
+            self.order.pop_front();
+            if self.traces.contains_key(&trace_id) {
+                evicted.push((trace_id, self.remove(trace_id)));
+            }
+        }
+
+        evicted
+    }
+
+    fn drain(&mut self) -> Vec<(TraceId, Vec<SpanData>)> {
+        self.order.clear();
+        self.spans = 0;
+
+        self.traces.drain().collect()
+    }
+}
+
+fn is_error_span(span: &SpanData) -> bool {
+    matches!(span.status, Status::Error { .. })
+        || span.attributes.iter().any(|attribute| {
+            match (attribute.key.as_str(), &attribute.value) {
+                ("http.response.status_code", Value::I64(status)) => *status >= 400,
+                ("error.type", _) => true,
+                _ => false,
+            }
+        })
+}
+
+impl<P: SpanProcessor> SpanProcessor for SamplingSpanProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &opentelemetry::Context) {
+        self.processor.on_start(span, cx)
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if self.sample_rate >= 1.0 {
+            return self.processor.on_end(span);
+        }
+
+        let trace_id = span.span_context.trace_id();
+        let is_root = span.parent_span_id == SpanId::INVALID;
+
+        let traces = match self.buffer.lock() {
+            Ok(mut buffer) => {
+                let mut traces = Vec::new();
+                if is_root {
+                    let mut spans = buffer.remove(trace_id);
+                    spans.push(span);
+                    traces.push((trace_id, spans));
+                } else {
+                    buffer.insert(span);
+                }
+
+                traces.extend(buffer.evict(self.max_spans));
+                traces
+            }
+            Err(_) => vec![(trace_id, vec![span])],
+        };
+
+        self.export(traces);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.processor.force_flush()
+    }
+
+    // Traces which are still buffered are sampled using the spans which have ended so far.
+    fn shutdown(&mut self) -> TraceResult<()> {
+        let traces = match self.buffer.lock() {
+            Ok(mut buffer) => buffer.drain(),
+            Err(_) => Vec::new(),
+        };
+        self.export(traces);
+
+        self.processor.shutdown()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    MonotonicCounter,
+    Histogram,
+}
+
+#[derive(Debug)]
+enum Metric {
+    Counter(f64),
+    MonotonicCounter(f64),
+    Histogram {
+        buckets: &'static [f64],
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Metric {
+    fn new(kind: MetricKind, name: &str) -> Self {
+        match kind {
+            MetricKind::Counter => Metric::Counter(0.0),
+            MetricKind::MonotonicCounter => Metric::MonotonicCounter(0.0),
+            MetricKind::Histogram => {
+                let buckets: &'static [f64] = match name.ends_with("duration") {
+                    true => &DURATION_BUCKETS,
+                    false => &SIZE_BUCKETS,
+                };
+
+                Metric::Histogram {
+                    buckets,
+                    counts: vec![0; buckets.len()],
+                    sum: 0.0,
+                    count: 0,
+                }
+            }
+        }
+    }
+
+    fn get_kind(&self) -> MetricKind {
+        match self {
+            Metric::Counter(_) => MetricKind::Counter,
+            Metric::MonotonicCounter(_) => MetricKind::MonotonicCounter,
+            Metric::Histogram { .. } => MetricKind::Histogram,
+        }
+    }
+
+    fn record(&mut self, value: f64) {
+        match self {
+            Metric::Counter(total) | Metric::MonotonicCounter(total) => *total += value,
+            Metric::Histogram {
+                buckets,
+                counts,
+                sum,
+                count,
+            } => {
+                if let Some(index) = buckets.iter().position(|bucket| value <= *bucket) {
+                    counts[index] += 1;
+                }
+                *sum += value;
+                *count += 1;
+            }
+        }
+    }
+}
+
+type MetricKey = (&'static str, Option<(&'static str, String)>);
+
+#[derive(Debug, Default)]
+struct MetricStore {
+    metrics: BTreeMap<MetricKey, Metric>,
+    label_values: HashMap<&'static str, usize>,
+}
+
+// Collects the values of metric event fields (using the same field prefixes as tracing-opentelemetry's MetricsLayer), so that they can be scraped by Prometheus without running an OpenTelemetry collector.
+#[derive(Debug, Clone, Default)]
+pub(super) struct PrometheusMetrics {
+    store: Arc<Mutex<MetricStore>>,
+}
+
+#[derive(Default)]
+struct MetricVisitor {
+    values: Vec<(MetricKind, &'static str, f64)>,
+    label: Option<(&'static str, String)>,
+}
+
+impl MetricVisitor {
+    fn record_value(&mut self, field: &Field, value: f64) {
+        let name = field.name();
+
+        let metric = [
+            ("monotonic_counter.", MetricKind::MonotonicCounter),
+            ("counter.", MetricKind::Counter),
+            ("histogram.", MetricKind::Histogram),
+        ]
+        .into_iter()
+        .find_map(|(prefix, kind)| name.strip_prefix(prefix).map(|name| (kind, name, value)));
+
+        if let Some(metric) = metric {
+            self.values.push(metric);
+        }
+    }
+}
+
+impl Visit for MetricVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record_value(field, value)
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_value(field, value as f64)
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_value(field, value as f64)
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if METRIC_LABELS.contains(&field.name()) {
+            self.label = Some((field.name(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if METRIC_LABELS.contains(&field.name()) {
+            self.label = Some((field.name(), format!("{:?}", value)));
+        }
+    }
+}
+
+impl PrometheusMetrics {
+    fn record(&self, visitor: MetricVisitor) {
+        let mut store = match self.store.lock() {
+            Ok(store) => store,
+            Err(_) => return,
+        };
+
+        for (kind, name, value) in visitor.values {
+            let label = match &visitor.label {
+                Some((field, label_value)) => {
+                    let key = (name, Some((*field, label_value.clone())));
+
+                    if store.metrics.contains_key(&key) {
+                        key.1
+                    } else {
+                        let label_values = store.label_values.entry(name).or_default();
+
+                        match *label_values < MAX_LABEL_VALUES {
+                            true => {
+                                *label_values += 1;
+                                key.1
+                            }
+                            false => Some((*field, "other".to_string())),
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            let metric = store
+                .metrics
+                .entry((name, label))
+                .or_insert_with(|| Metric::new(kind, name));
+
+            if metric.get_kind() == kind {
+                metric.record(value);
+            }
+        }
+    }
+
+    // Renders all collected metrics in Prometheus' text exposition format.
+    pub(super) fn render(&self) -> String {
+        let store = match self.store.lock() {
+            Ok(store) => store,
+            Err(_) => return String::new(),
+        };
+
+        let mut output = String::new();
+        let mut family = None;
+
+        for ((name, label), metric) in &store.metrics {
+            let name = get_prometheus_name(name);
+            let labels = |extra: Option<String>| -> String {
+                let labels: Vec<String> = label
+                    .iter()
+                    .map(|(field, value)| format!("{}=\"{}\"", field, escape_label_value(value)))
+                    .chain(extra)
+                    .collect();
+
+                match labels.is_empty() {
+                    true => String::new(),
+                    false => format!("{{{}}}", labels.join(",")),
+                }
+            };
+
+            if family.as_ref() != Some(&name) {
+                let r#type = match metric {
+                    Metric::Counter(_) => "gauge",
+                    Metric::MonotonicCounter(_) => "counter",
+                    Metric::Histogram { .. } => "histogram",
+                };
+                let _ = writeln!(output, "# TYPE {} {}", name, r#type);
+                family = Some(name.clone());
+            }
+
+            let _ = match metric {
+                Metric::Counter(total) => writeln!(output, "{}{} {}", name, labels(None), total),
+                Metric::MonotonicCounter(total) => {
+                    writeln!(output, "{}_total{} {}", name, labels(None), total)
+                }
+                Metric::Histogram {
+                    buckets,
+                    counts,
+                    sum,
+                    count,
+                } => {
+                    let mut cumulative = 0;
+                    for (bucket, bucket_count) in buckets.iter().zip(counts) {
+                        cumulative += bucket_count;
+                        let _ = writeln!(
+                            output,
+                            "{}_bucket{} {}",
+                            name,
+                            labels(Some(format!("le=\"{}\"", bucket))),
+                            cumulative
+                        );
+                    }
+                    let _ = writeln!(
+                        output,
+                        "{}_bucket{} {}",
+                        name,
+                        labels(Some("le=\"+Inf\"".to_string())),
+                        count
+                    );
+                    let _ = writeln!(output, "{}_sum{} {}", name, labels(None), sum);
+                    writeln!(output, "{}_count{} {}", name, labels(None), count)
+                }
+            };
+        }
+
+        output
+    }
+}
+
+impl<S: Subscriber> Layer<S> for PrometheusMetrics {
+    fn on_event(&self, event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+        let mut visitor = MetricVisitor::default();
+        event.record(&mut visitor);
+
+        if !visitor.values.is_empty() {
+            self.record(visitor);
+        }
+    }
+}
+
+fn get_prometheus_name(name: &str) -> String {
+    name.chars()
+        .map(|character| match character.is_ascii_alphanumeric() {
+            true => character,
+            false => '_',
+        })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
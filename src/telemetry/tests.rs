@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex};
+
+use opentelemetry::{
+    trace::{Span as _, Status, TraceContextExt, TraceResult, Tracer, TracerProvider as _},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{
+    export::trace::SpanData,
+    trace::{Span, SpanProcessor, TracerProvider},
+};
+use tokio::net::TcpListener;
+use tracing_subscriber::layer::SubscriberExt;
+use uuid::Uuid;
+
+use super::{check_collector, PrometheusMetrics, SamplingSpanProcessor, MAX_LABEL_VALUES};
+
+#[tokio::test]
+async fn collector_reachability() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    assert!(check_collector(&format!("http://{}", address))
+        .await
+        .is_ok());
+
+    drop(listener);
+
+    assert!(check_collector(&format!("http://{}", address))
+        .await
+        .is_err());
+    assert!(check_collector("not a url").await.is_err());
+}
+
+#[derive(Debug, Clone, Default)]
+struct RecordingProcessor(Arc<Mutex<Vec<SpanData>>>);
+
+impl RecordingProcessor {
+    fn take_names(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|span| span.name.to_string())
+            .collect()
+    }
+}
+
+impl SpanProcessor for RecordingProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.0.lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+fn trace_request(provider: &TracerProvider, status: i64, child_status: Status) {
+    let tracer = provider.tracer("test");
+
+    let cx = Context::current_with_span(tracer.start("request"));
+    let mut child = tracer.start_with_context("child", &cx);
+    child.set_status(child_status);
+    child.end();
+
+    cx.span()
+        .set_attribute(KeyValue::new("http.response.status_code", status));
+    cx.span().end();
+}
+
+#[test]
+fn error_trace_sampling() {
+    let recorder = RecordingProcessor::default();
+    let provider = TracerProvider::builder()
+        .with_span_processor(SamplingSpanProcessor::new(recorder.clone(), 0.0, 16))
+        .build();
+
+    trace_request(&provider, 200, Status::Unset);
+    assert!(recorder.take_names().is_empty());
+
+    trace_request(&provider, 429, Status::Unset);
+    assert_eq!(recorder.take_names(), vec!["child", "request"]);
+
+    trace_request(&provider, 200, Status::error("backend error"));
+    assert_eq!(recorder.take_names(), vec!["child", "request"]);
+
+    let recorder = RecordingProcessor::default();
+    let provider = TracerProvider::builder()
+        .with_span_processor(SamplingSpanProcessor::new(recorder.clone(), 1.0, 16))
+        .build();
+
+    trace_request(&provider, 200, Status::Unset);
+    assert_eq!(recorder.take_names(), vec!["child", "request"]);
+
+    // Once the buffer is full, the oldest trace is sampled using the spans which have ended so far.
+    let recorder = RecordingProcessor::default();
+    let provider = TracerProvider::builder()
+        .with_span_processor(SamplingSpanProcessor::new(recorder.clone(), 0.0, 2))
+        .build();
+    let tracer = provider.tracer("test");
+
+    let cx = Context::current_with_span(tracer.start("request"));
+    let mut child = tracer.start_with_context("child", &cx);
+    child.set_status(Status::error("backend error"));
+    child.end();
+    tracer.start_with_context("child", &cx).end();
+    assert!(recorder.take_names().is_empty());
+
+    tracer.start_with_context("child", &cx).end();
+    assert_eq!(recorder.take_names(), vec!["child", "child", "child"]);
+
+    cx.span().end();
+    assert!(recorder.take_names().is_empty());
+}
+
+#[test]
+fn prometheus_metrics() {
+    let metrics = PrometheusMetrics::default();
+    let model = Uuid::nil();
+
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(metrics.clone()), || {
+        tracing::debug!(histogram.http.server.request.duration = 0.2, unit = "s");
+        tracing::debug!(histogram.http.server.request.duration = 3.0, unit = "s");
+        tracing::debug!(histogram.request.count = 2_u64);
+        tracing::debug!(monotonic_counter.upstream.retries = 1_u64);
+        tracing::debug!(monotonic_counter.upstream.retries = 1_u64);
+        tracing::debug!(counter.http.server.active_requests = 1_i64);
+        tracing::debug!(histogram.model.request.duration = 1.0, model = %model, unit = "s");
+        tracing::debug!("Not a metric");
+
+        // Label values over the limit are combined, so that scraping stays cheap.
+        for _ in 0..MAX_LABEL_VALUES + 10 {
+            tracing::debug!(histogram.quota.wait.duration = 0.0, quota = %Uuid::new_v4());
+        }
+    });
+
+    let output = metrics.render();
+    assert!(output.contains("# TYPE http_server_request_duration histogram\n"));
+    assert!(output.contains("http_server_request_duration_bucket{le=\"0.25\"} 1\n"));
+    assert!(output.contains("http_server_request_duration_bucket{le=\"5\"} 2\n"));
+    assert!(output.contains("http_server_request_duration_bucket{le=\"+Inf\"} 2\n"));
+    assert!(output.contains("http_server_request_duration_sum 3.2\n"));
+    assert!(output.contains("http_server_request_duration_count 2\n"));
+    assert!(output.contains("request_count_bucket{le=\"4\"} 1\n"));
+    assert!(output.contains("# TYPE upstream_retries counter\nupstream_retries_total 2\n"));
+    assert!(output.contains("# TYPE http_server_active_requests gauge\n"));
+    assert!(output.contains(&format!(
+        "model_request_duration_count{{model=\"{}\"}} 1\n",
+        model
+    )));
+
+    assert_eq!(
+        output.matches("quota_wait_duration_count{").count(),
+        MAX_LABEL_VALUES + 1
+    );
+    assert!(output.contains("quota_wait_duration_count{quota=\"other\"} 10\n"));
+}